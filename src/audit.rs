@@ -0,0 +1,277 @@
+/// Append-only audit trail of the commands which produce or publish artifacts: every build and push appends a json
+/// line recording when, by whom and from which commit it ran, its arguments, the digests it produced and its exit
+/// status to a local file, and optionally sends the same record to a remote sink so that it survives ephemeral CI
+/// runners. Recording is best effort, a failure to write the record is logged but does not fail the command.
+use crate::config::{find_config_dir, OpsConfig};
+use crate::docker::build::DockerBuildOutput;
+use crate::docker::build_node_workspace::DockerBuildNodeWorkspaceOutput;
+use crate::docker::build_rust_workspace::{DockerBuildRustWorkspaceOutput, DockerBuildRustWorkspaceServicesOutput};
+use crate::docker::push::DockerPushOutput;
+use crate::docker::warm_cache::DockerWarmCacheOutput;
+use crate::dry_run::is_dry_run;
+use crate::error::Error;
+use crate::exec::{run, ExecOptions};
+use crate::k8s::deploy::DeployOutput;
+use crate::report::serialize_duration;
+use serde::Serialize;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{self, Command};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, warn};
+
+/// environment variable overriding the path of the local audit log
+pub const OPS_AUDIT_LOG_ENV: &str = "OPS_AUDIT_LOG";
+
+/// environment variable overriding the remote audit sink, an `http(s)://` url or an `s3://<bucket>/<prefix>` location
+pub const OPS_AUDIT_SINK_ENV: &str = "OPS_AUDIT_SINK";
+
+/// matched case-insensitively against argument names, the values of matching arguments are redacted
+const SENSITIVE_ARGS: [&str; 4] = ["password", "secret", "token", "key"];
+
+#[derive(Clone, Debug, Serialize)]
+pub struct AuditRecord {
+    /// start of the command as an RFC 3339 UTC timestamp
+    pub timestamp: String,
+    pub user: String,
+    /// command name as used in ops.toml, e.g. `docker-push`
+    pub command: String,
+    /// command line arguments with the values of password, secret, token and key arguments redacted
+    pub args: Vec<String>,
+    /// HEAD of the repository the command ran in
+    pub git_sha: Option<String>,
+    /// digests or ids of the images built or pushed
+    pub digests: Vec<String>,
+    pub exit_status: u8,
+    pub error: Option<String>,
+    #[serde(serialize_with = "serialize_duration")]
+    pub duration: Duration,
+}
+
+/// Results of audited commands.
+pub trait Audited {
+    /// digests or ids of the artifacts produced by the command
+    fn digests(&self) -> Vec<String>;
+}
+
+impl Audited for DockerBuildOutput {
+    fn digests(&self) -> Vec<String> {
+        self.image_id.iter().cloned().collect()
+    }
+}
+
+impl Audited for DockerBuildRustWorkspaceOutput {
+    fn digests(&self) -> Vec<String> {
+        self.pre_build
+            .iter()
+            .chain(self.build.iter())
+            .flat_map(Audited::digests)
+            .collect()
+    }
+}
+
+impl Audited for DockerBuildNodeWorkspaceOutput {
+    fn digests(&self) -> Vec<String> {
+        self.build
+            .digests()
+            .into_iter()
+            .chain(self.pushed.iter().flat_map(Audited::digests))
+            .collect()
+    }
+}
+
+impl Audited for DockerBuildRustWorkspaceServicesOutput {
+    fn digests(&self) -> Vec<String> {
+        self.services.iter().flat_map(Audited::digests).collect()
+    }
+}
+
+impl Audited for DockerPushOutput {
+    fn digests(&self) -> Vec<String> {
+        self.digest
+            .iter()
+            .map(|digest| format!("{}@{digest}", self.image))
+            .collect()
+    }
+}
+
+impl Audited for DeployOutput {
+    fn digests(&self) -> Vec<String> {
+        self.workloads
+            .iter()
+            .flat_map(|workload| workload.images.iter().filter(|image| image.contains('@')).cloned())
+            .collect()
+    }
+}
+
+impl Audited for DockerWarmCacheOutput {
+    fn digests(&self) -> Vec<String> {
+        self.images
+            .iter()
+            .filter_map(|image| Some(format!("{}@{}", image.image, image.digest.as_ref()?)))
+            .collect()
+    }
+}
+
+/// Runs `f` and records its outcome in the audit log under `command_name`, nothing is recorded in dry-run mode.
+pub fn audit<T: Audited>(command_name: &str, f: impl FnOnce() -> Result<T, Error>) -> Result<T, Error> {
+    let timestamp = format_timestamp(SystemTime::now());
+    let start = Instant::now();
+    let result = f();
+    if is_dry_run() {
+        return result;
+    }
+
+    let record = AuditRecord {
+        timestamp,
+        user: get_user(),
+        command: command_name.to_string(),
+        args: redact_args(env::args().skip(1)),
+        git_sha: get_git_sha(),
+        digests: result.as_ref().map(Audited::digests).unwrap_or_default(),
+        exit_status: result.as_ref().err().map(Error::exit_code).unwrap_or_default(),
+        error: result.as_ref().err().map(ToString::to_string),
+        duration: start.elapsed(),
+    };
+    if let Err(err) = write_record(&record) {
+        warn!("failed to write audit record: {err}");
+    }
+    result
+}
+
+fn write_record(record: &AuditRecord) -> Result<(), Error> {
+    let cwd = env::current_dir()?;
+    let config = OpsConfig::discover(&cwd)?.audit;
+    if config.disabled {
+        return Ok(());
+    }
+    let line = serde_json::to_string(record)?;
+
+    let path = match (env::var_os(OPS_AUDIT_LOG_ENV), config.log) {
+        (Some(path), _) => PathBuf::from(path),
+        (None, Some(path)) => find_config_dir(&cwd).unwrap_or(&cwd).join(path),
+        (None, None) => get_state_dir().join("ops").join("audit.jsonl"),
+    };
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // a single write of a whole line so that concurrent commands appending to the same file do not interleave
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)?
+        .write_all(format!("{line}\n").as_bytes())?;
+    debug!("recorded audit record in {}", path.display());
+
+    let Some(sink) = env::var(OPS_AUDIT_SINK_ENV).ok().or(config.sink) else {
+        return Ok(());
+    };
+    let command = match sink.strip_prefix("s3://") {
+        // objects cannot be appended to so every record is written as its own object
+        Some(location) => {
+            let mut command = Command::new("aws");
+            command.args(["s3", "cp", "-"]).arg(format!(
+                "s3://{}/{}-{}-{}.json",
+                location.trim_end_matches('/'),
+                record.timestamp,
+                record.command,
+                process::id()
+            ));
+            command
+        }
+        None => {
+            let mut command = Command::new("curl");
+            command
+                .args(["--silent", "--show-error", "--fail", "--request", "POST"])
+                .args(["--header", "Content-Type: application/json", "--data-binary", "@-"])
+                .arg(&sink);
+            command
+        }
+    };
+    run(
+        &command,
+        &ExecOptions {
+            stdin: Some(line.into_bytes()),
+            ..ExecOptions::idempotent()
+        },
+    )?;
+    Ok(())
+}
+
+/// HEAD of the current repository, `None` outside of a repository without retrying or logging git's error.
+pub(crate) fn get_git_sha() -> Option<String> {
+    let mut command = Command::new("git");
+    command.args(["rev-parse", "HEAD"]);
+    let options = ExecOptions {
+        stream: false,
+        ..Default::default()
+    };
+    let output = run(&command, &options).ok()?;
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn get_user() -> String {
+    ["GITHUB_ACTOR", "USER", "USERNAME"]
+        .into_iter()
+        .find_map(|name| env::var(name).ok().filter(|user| !user.is_empty()))
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Directory for persistent application state: `$XDG_STATE_HOME`, `~/.local/state` or `%LOCALAPPDATA%` on windows.
+fn get_state_dir() -> PathBuf {
+    if let Some(dir) = env::var_os("XDG_STATE_HOME").or_else(|| env::var_os("LOCALAPPDATA")) {
+        return PathBuf::from(dir);
+    }
+    PathBuf::from(env::var_os("HOME").unwrap_or_default())
+        .join(".local")
+        .join("state")
+}
+
+fn redact_args(args: impl Iterator<Item = String>) -> Vec<String> {
+    let is_sensitive = |name: &str| {
+        let name = name.to_lowercase();
+        SENSITIVE_ARGS.iter().any(|sensitive| name.contains(sensitive))
+    };
+    let mut redact_next = false;
+    let mut redacted = vec![];
+    for arg in args {
+        if redact_next {
+            redact_next = false;
+            redacted.push("***".to_string());
+            continue;
+        }
+        match arg.split_once('=') {
+            Some((name, _)) if is_sensitive(name) => redacted.push(format!("{name}=***")),
+            Some(_) => redacted.push(arg),
+            None => {
+                redact_next = arg.starts_with("--") && is_sensitive(&arg);
+                redacted.push(arg);
+            }
+        }
+    }
+    redacted
+}
+
+/// Formats `time` as an RFC 3339 UTC timestamp with second precision.
+fn format_timestamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
@@ -0,0 +1,12 @@
+use ops::cli::*;
+use ops::error::{exit, Error};
+use ops::k8s::deploy::DeployArgs;
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    exit(run())
+}
+
+fn run() -> Result<(), Error> {
+    run_bin::<DeployArgs>(OpsCommand::Deploy)
+}
@@ -1,7 +1,12 @@
-use anyhow::Error;
-use clap::Parser;
-use ops::docker::build_rust_workspace::*;
+use ops::cli::*;
+use ops::docker::build_rust_workspace::DockerBuildRustWorkspaceArgs;
+use ops::error::{exit, Error};
+use std::process::ExitCode;
 
-fn main() -> Result<(), Error> {
-    docker_build_rust_workspace(DockerBuildRustWorkspaceArgs::parse())
+fn main() -> ExitCode {
+    exit(run())
+}
+
+fn run() -> Result<(), Error> {
+    run_bin::<DockerBuildRustWorkspaceArgs>(|args| OpsCommand::Docker(DockerCommand::BuildRustWorkspaceService(args)))
 }
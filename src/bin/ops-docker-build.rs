@@ -1,7 +1,12 @@
-use anyhow::Error;
-use clap::Parser;
-use ops::docker::build::*;
+use ops::cli::*;
+use ops::docker::build::DockerBuildArgs;
+use ops::error::{exit, Error};
+use std::process::ExitCode;
 
-fn main() -> Result<(), Error> {
-    docker_build(DockerBuildArgs::parse())
+fn main() -> ExitCode {
+    exit(run())
+}
+
+fn run() -> Result<(), Error> {
+    run_bin::<DockerBuildArgs>(|args| OpsCommand::Docker(DockerCommand::Build(args)))
 }
@@ -0,0 +1,12 @@
+use ops::cli::*;
+use ops::docker::compose::DockerComposeGenArgs;
+use ops::error::{exit, Error};
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    exit(run())
+}
+
+fn run() -> Result<(), Error> {
+    run_bin::<DockerComposeGenArgs>(|args| OpsCommand::Docker(DockerCommand::ComposeGen(args)))
+}
@@ -1,7 +1,12 @@
-use anyhow::Error;
-use clap::Parser;
-use ops::eslint::*;
+use ops::cli::*;
+use ops::error::{exit, Error};
+use ops::eslint::EslintArgs;
+use std::process::ExitCode;
 
-fn main() -> Result<(), Error> {
-    eslint(EslintArgs::parse())
+fn main() -> ExitCode {
+    exit(run())
+}
+
+fn run() -> Result<(), Error> {
+    run_bin::<EslintArgs>(OpsCommand::Eslint)
 }
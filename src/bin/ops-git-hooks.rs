@@ -0,0 +1,12 @@
+use ops::cli::*;
+use ops::error::{exit, Error};
+use ops::git::hooks::GitHooksArgs;
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    exit(run())
+}
+
+fn run() -> Result<(), Error> {
+    run_bin::<GitHooksArgs>(|args| OpsCommand::Git(GitCommand::Hooks(args.command)))
+}
@@ -0,0 +1,12 @@
+use ops::cli::*;
+use ops::error::{exit, Error};
+use ops::k8s::manifest::K8sGenArgs;
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    exit(run())
+}
+
+fn run() -> Result<(), Error> {
+    run_bin::<K8sGenArgs>(|args| OpsCommand::K8s(K8sCommand::Gen(args)))
+}
@@ -0,0 +1,12 @@
+use ops::cli::*;
+use ops::error::{exit, Error};
+use ops::workspace_audit::WorkspaceAuditArgs;
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    exit(run())
+}
+
+fn run() -> Result<(), Error> {
+    run_bin::<WorkspaceAuditArgs>(|args| OpsCommand::Workspace(WorkspaceCommand::Audit(args)))
+}
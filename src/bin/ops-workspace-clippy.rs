@@ -1,7 +1,12 @@
-use anyhow::Error;
-use clap::Parser;
-use ops::workspace_clippy::*;
+use ops::cli::*;
+use ops::error::{exit, Error};
+use ops::workspace_clippy::WorkspaceClippyArgs;
+use std::process::ExitCode;
 
-fn main() -> Result<(), Error> {
-    workspace_clippy(WorkspaceClippyArgs::parse())
+fn main() -> ExitCode {
+    exit(run())
+}
+
+fn run() -> Result<(), Error> {
+    run_bin::<WorkspaceClippyArgs>(|args| OpsCommand::Workspace(WorkspaceCommand::Clippy(args)))
 }
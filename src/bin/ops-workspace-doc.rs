@@ -0,0 +1,12 @@
+use ops::cli::*;
+use ops::error::{exit, Error};
+use ops::workspace_doc::WorkspaceDocArgs;
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    exit(run())
+}
+
+fn run() -> Result<(), Error> {
+    run_bin::<WorkspaceDocArgs>(|args| OpsCommand::Workspace(WorkspaceCommand::Doc(args)))
+}
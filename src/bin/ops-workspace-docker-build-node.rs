@@ -0,0 +1,12 @@
+use ops::cli::*;
+use ops::docker::build_node_workspace::DockerBuildNodeWorkspaceArgs;
+use ops::error::{exit, Error};
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    exit(run())
+}
+
+fn run() -> Result<(), Error> {
+    run_bin::<DockerBuildNodeWorkspaceArgs>(|args| OpsCommand::Workspace(WorkspaceCommand::DockerBuildNode(args)))
+}
@@ -0,0 +1,12 @@
+use ops::cli::*;
+use ops::error::{exit, Error};
+use ops::workspace_run::WorkspaceRunArgs;
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    exit(run())
+}
+
+fn run() -> Result<(), Error> {
+    run_bin::<WorkspaceRunArgs>(|args| OpsCommand::Workspace(WorkspaceCommand::Run(args)))
+}
@@ -0,0 +1,12 @@
+use ops::cli::*;
+use ops::error::{exit, Error};
+use ops::workspace_test::WorkspaceTestArgs;
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    exit(run())
+}
+
+fn run() -> Result<(), Error> {
+    run_bin::<WorkspaceTestArgs>(|args| OpsCommand::Workspace(WorkspaceCommand::Test(args)))
+}
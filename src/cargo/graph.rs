@@ -0,0 +1,452 @@
+/// Model of a cargo workspace's internal packages and the dependency graph between them, either loaded directly from
+/// the workspace's manifests (without invoking cargo) so that it is cheap enough to be used from git hooks, or built
+/// from `cargo metadata` where accuracy matters more. Used to determine which packages a change affects: the packages
+/// containing the changed files and every package which transitively depends on them.
+use crate::cargo::metadata::Metadata;
+use crate::config::{get_pattern_matcher, OpsConfig};
+use crate::error::Error;
+use fancy_regex::Regex;
+use ignore::gitignore::Gitignore;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashSet, VecDeque};
+use std::fs;
+use std::iter::once;
+use std::path::{Path, PathBuf};
+use toml::Value;
+use tracing::{debug, warn};
+
+/// workspace level files whose changes affect every package: the manifests and the toolchain, cargo and clippy
+/// configuration
+const WORKSPACE_FILES: [&str; 8] = [
+    "Cargo.toml",
+    "Cargo.lock",
+    "rust-toolchain",
+    "rust-toolchain.toml",
+    ".cargo/config",
+    ".cargo/config.toml",
+    "clippy.toml",
+    ".clippy.toml",
+];
+
+/// manifest tables (also nested under `target.<cfg>`) whose dependencies are needed to build a package
+const DEPENDENCY_TABLES: [&str; 2] = ["dependencies", "build-dependencies"];
+
+#[derive(Clone, Debug, Serialize)]
+pub struct WorkspacePackage {
+    pub name: String,
+    /// directory of the package relative to the workspace root
+    pub path: PathBuf,
+    /// names of the internal packages this package depends on
+    pub dependencies: BTreeSet<String>,
+    /// whether the package builds a binary target
+    pub binary: bool,
+    /// whether the package is a workspace member rather than only a path dependency of `workspace.dependencies`
+    pub member: bool,
+    /// whether the package is declared as a path dependency under `workspace.dependencies`
+    pub workspace_dependency: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct WorkspaceGraph {
+    root: PathBuf,
+    packages: BTreeMap<String, WorkspacePackage>,
+    /// reverse edges of the graph: names of the packages directly depending on each package
+    dependents: BTreeMap<String, BTreeSet<String>>,
+    /// matchers of the `sources` set under `[packages.<name>]` in ops.toml, by package name
+    sources: BTreeMap<String, Gitignore>,
+    /// matcher of the `full_run_paths` set in ops.toml
+    full_run_paths: Gitignore,
+}
+
+impl WorkspaceGraph {
+    /// Loads every internal package of the workspace rooted at `workspace_dir`: all workspace members as well as
+    /// any path dependencies listed under `workspace.dependencies`. Normal and build dependencies are part of the
+    /// graph, including target specific ones, dev dependencies are not.
+    pub fn load(workspace_dir: &Path) -> Result<Self, Error> {
+        let member_paths = get_workspace_member_paths(workspace_dir)?
+            .into_iter()
+            .map(|member_path| workspace_dir.join(member_path))
+            .collect::<BTreeSet<_>>();
+        let workspace_dependency_paths = get_workspace_dependency_paths(workspace_dir)?;
+        let package_paths = member_paths
+            .union(&workspace_dependency_paths)
+            .cloned()
+            .collect::<BTreeSet<_>>();
+
+        let mut package_cargos = vec![];
+        for package_path in package_paths {
+            let package_cargo = fs::read_to_string(package_path.join("Cargo.toml"))?.parse::<Value>()?;
+            let package_name = package_cargo
+                .get("package")
+                .and_then(|package| package.get("name"))
+                .and_then(Value::as_str)
+                .ok_or_else(|| Error::manifest(package_path.join("Cargo.toml"), "missing string key `package.name`"))?
+                .to_string();
+            package_cargos.push((package_name, package_path, package_cargo));
+        }
+
+        let package_names = package_cargos
+            .iter()
+            .map(|(package_name, _, _)| package_name.clone())
+            .collect::<HashSet<_>>();
+
+        let mut packages = vec![];
+        for (package_name, package_path, package_cargo) in package_cargos {
+            let targets = package_cargo
+                .get("target")
+                .and_then(Value::as_table)
+                .into_iter()
+                .flat_map(|targets| targets.values());
+            let dependency_tables = once(&package_cargo)
+                .chain(targets)
+                .flat_map(|table| DEPENDENCY_TABLES.map(|key| table.get(key).map(|dependencies| (key, dependencies))))
+                .flatten();
+            let mut dependencies = BTreeSet::default();
+            for (key, package_dependencies) in dependency_tables {
+                let package_dependencies = package_dependencies.as_table().ok_or_else(|| {
+                    Error::manifest(package_path.join("Cargo.toml"), format!("key `{key}` must be a table"))
+                })?;
+                for (dependency_name, spec) in package_dependencies {
+                    // renamed dependencies reference the actual package through the `package` key
+                    let dependency_name = spec.get("package").and_then(Value::as_str).unwrap_or(dependency_name);
+                    if package_names.contains(dependency_name) {
+                        dependencies.insert(dependency_name.to_string());
+                    }
+                }
+            }
+            let binary = package_cargo.get("bin").is_some() || package_path.join("src/main.rs").exists();
+            let member = member_paths.contains(&package_path);
+            let workspace_dependency = workspace_dependency_paths.contains(&package_path);
+            let path = package_path
+                .strip_prefix(workspace_dir)
+                .map(Path::to_path_buf)
+                .unwrap_or(package_path);
+            packages.push(WorkspacePackage {
+                name: package_name,
+                path,
+                dependencies,
+                binary,
+                member,
+                workspace_dependency,
+            });
+        }
+
+        Self::from_packages(workspace_dir, packages)
+    }
+
+    /// Builds the graph from cargo's own view of the workspace, which unlike [`WorkspaceGraph::load`] also
+    /// resolves dependencies renamed in `workspace.dependencies` and local packages which are only path
+    /// dependencies of member manifests. Dev dependencies are not part of the graph.
+    pub fn from_metadata(metadata: &Metadata) -> Result<Self, Error> {
+        let workspace_dir = &metadata.workspace_root;
+        let workspace_dependency_paths = get_workspace_dependency_paths(workspace_dir)?;
+        let local_packages = metadata.local_packages().collect::<Vec<_>>();
+        let package_names = local_packages
+            .iter()
+            .map(|package| &*package.name)
+            .collect::<HashSet<_>>();
+
+        let packages = local_packages
+            .iter()
+            .map(|package| WorkspacePackage {
+                name: package.name.clone(),
+                path: metadata.package_dir(package).unwrap_or(package.dir()).to_path_buf(),
+                dependencies: package
+                    .dependencies
+                    .iter()
+                    .filter(|dependency| {
+                        !dependency.is_dev() && dependency.path.is_some() && package_names.contains(&*dependency.name)
+                    })
+                    .map(|dependency| dependency.name.clone())
+                    .collect(),
+                binary: package.is_binary(),
+                member: metadata.workspace_members.contains(&package.id),
+                workspace_dependency: workspace_dependency_paths.contains(package.dir()),
+            })
+            .collect();
+
+        Self::from_packages(workspace_dir, packages)
+    }
+
+    fn from_packages(workspace_dir: &Path, packages: Vec<WorkspacePackage>) -> Result<Self, Error> {
+        let mut dependents = BTreeMap::<String, BTreeSet<String>>::default();
+        for package in packages.iter() {
+            for dependency in package.dependencies.iter() {
+                dependents
+                    .entry(dependency.clone())
+                    .or_default()
+                    .insert(package.name.clone());
+            }
+        }
+        let packages = packages
+            .into_iter()
+            .map(|package| (package.name.clone(), package))
+            .collect::<BTreeMap<_, _>>();
+        let config = OpsConfig::load(workspace_dir)?;
+        let full_run_paths = get_pattern_matcher("full_run_paths", &config.full_run_paths)?;
+        let mut sources = BTreeMap::default();
+        for (name, config) in config.packages {
+            if !packages.contains_key(&name) {
+                warn!("`[packages.{name}]` in ops.toml is not a package of the workspace");
+                continue;
+            }
+            if !config.sources.is_empty() {
+                let matcher = get_pattern_matcher(&format!("[packages.{name}] sources"), &config.sources)?;
+                sources.insert(name, matcher);
+            }
+        }
+        Ok(Self {
+            root: workspace_dir.to_path_buf(),
+            packages,
+            dependents,
+            sources,
+            full_run_paths,
+        })
+    }
+
+    /// Directory of the workspace root.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Every internal package by name.
+    pub fn packages(&self) -> &BTreeMap<String, WorkspacePackage> {
+        &self.packages
+    }
+
+    pub fn package(&self, name: &str) -> Option<&WorkspacePackage> {
+        self.packages.get(name)
+    }
+
+    /// Packages which are workspace members.
+    pub fn members(&self) -> impl Iterator<Item = &WorkspacePackage> {
+        self.packages.values().filter(|package| package.member)
+    }
+
+    /// Names of the internal packages `name` directly depends on.
+    pub fn dependencies_of(&self, name: &str) -> BTreeSet<&str> {
+        self.packages
+            .get(name)
+            .map(|package| package.dependencies.iter().map(String::as_str).collect())
+            .unwrap_or_default()
+    }
+
+    /// Names of the packages directly depending on `name`.
+    pub fn dependents_of(&self, name: &str) -> BTreeSet<&str> {
+        self.dependents
+            .get(name)
+            .map(|dependents| dependents.iter().map(String::as_str).collect())
+            .unwrap_or_default()
+    }
+
+    /// Names of the internal packages any of `names` transitively depends on, excluding `names` themselves unless
+    /// they depend on each other.
+    pub fn transitive_dependencies_of<'a>(&'a self, names: impl IntoIterator<Item = &'a str>) -> BTreeSet<&'a str> {
+        self.walk(names, |name| self.dependencies_of(name))
+    }
+
+    /// Names of the packages transitively depending on any of `names`, excluding `names` themselves unless they
+    /// depend on each other.
+    pub fn transitive_dependents_of<'a>(&'a self, names: impl IntoIterator<Item = &'a str>) -> BTreeSet<&'a str> {
+        self.walk(names, |name| self.dependents_of(name))
+    }
+
+    /// Package containing `path` (relative to the workspace root), nested packages take precedence over the
+    /// packages containing them.
+    pub fn package_of_path(&self, path: &Path) -> Option<&WorkspacePackage> {
+        self.packages
+            .values()
+            .filter(|package| path.starts_with(&package.path))
+            .max_by_key(|package| package.path.components().count())
+    }
+
+    /// Whether changes to `path` (relative to the workspace root) affect every package: the workspace level
+    /// Cargo.toml and Cargo.lock, the rust-toolchain file, `.cargo/config.toml` and `clippy.toml`, as well as any
+    /// path matching the `full_run_paths` of ops.toml.
+    pub fn affects_every_package(&self, path: &Path) -> bool {
+        WORKSPACE_FILES.iter().any(|file| path == Path::new(file))
+            || self.full_run_paths.matched_path_or_any_parents(path, false).is_ignore()
+    }
+
+    /// Packages whose ops.toml `sources` match `path` (relative to the workspace root), i.e. which are built from
+    /// it although they do not contain it.
+    pub fn packages_sourcing(&self, path: &Path) -> Vec<&WorkspacePackage> {
+        self.sources
+            .iter()
+            .filter(|(_, sources)| sources.matched_path_or_any_parents(path, false).is_ignore())
+            .map(|(name, _)| &self.packages[name])
+            .collect()
+    }
+
+    /// Names of the packages containing or sourcing any of `paths` (relative to the workspace root).
+    pub fn packages_changed_by<'a>(&self, paths: impl IntoIterator<Item = &'a str>) -> BTreeSet<&str> {
+        paths
+            .into_iter()
+            .flat_map(|path| {
+                let path = Path::new(path);
+                self.package_of_path(path)
+                    .into_iter()
+                    .chain(self.packages_sourcing(path))
+            })
+            .map(|package| &*package.name)
+            .collect()
+    }
+
+    /// Names of the workspace members containing or sourcing any of `paths` (relative to the workspace root).
+    pub fn members_changed_by<'a>(&self, paths: impl IntoIterator<Item = &'a str>) -> BTreeSet<&str> {
+        self.packages_changed_by(paths)
+            .into_iter()
+            .filter(|name| self.packages[*name].member)
+            .collect()
+    }
+
+    /// Names of the packages containing or sourcing any of `paths` (relative to the workspace root) along with every package
+    /// which transitively depends on them. Changes to workspace level files affect every package, see
+    /// [`WorkspaceGraph::affects_every_package`].
+    pub fn affected_by<'a>(&self, paths: impl IntoIterator<Item = &'a str>) -> BTreeSet<&str> {
+        let paths = paths.into_iter().collect::<Vec<_>>();
+        if paths.iter().any(|path| self.affects_every_package(Path::new(path))) {
+            return self.packages.keys().map(String::as_str).collect();
+        }
+        let changed = self.packages_changed_by(paths);
+        let mut affected = self.transitive_dependents_of(changed.iter().copied());
+        affected.extend(changed);
+        affected
+    }
+
+    /// Breadth first walk from `names` along the edges returned by `next`.
+    fn walk<'a>(
+        &'a self,
+        names: impl IntoIterator<Item = &'a str>,
+        next: impl Fn(&'a str) -> BTreeSet<&'a str>,
+    ) -> BTreeSet<&'a str> {
+        let mut visited = BTreeSet::default();
+        let mut queue = names.into_iter().flat_map(&next).collect::<VecDeque<_>>();
+        while let Some(name) = queue.pop_front() {
+            if visited.insert(name) {
+                queue.extend(next(name));
+            }
+        }
+        visited
+    }
+}
+
+/// Absolute directories of the path dependencies declared under `workspace.dependencies` of the workspace rooted at
+/// `workspace_dir`.
+fn get_workspace_dependency_paths(workspace_dir: &Path) -> Result<BTreeSet<PathBuf>, Error> {
+    let workspace_manifest_path = workspace_dir.join("Cargo.toml");
+    let workspace_cargo = fs::read_to_string(&workspace_manifest_path)?.parse::<Value>()?;
+    let workspace = workspace_cargo
+        .get("workspace")
+        .ok_or_else(|| Error::manifest(&workspace_manifest_path, "missing key `workspace`"))?;
+
+    let mut workspace_dependency_paths = BTreeSet::default();
+    if let Some(workspace_dependencies) = workspace.get("dependencies") {
+        let workspace_dependencies = workspace_dependencies
+            .as_table()
+            .ok_or_else(|| Error::manifest(&workspace_manifest_path, "key `workspace.dependencies` must be a table"))?;
+        for (package_name, spec) in workspace_dependencies {
+            if let Some(path) = spec.get("path") {
+                let path = path.as_str().ok_or_else(|| {
+                    Error::manifest(
+                        &workspace_manifest_path,
+                        format!("key `workspace.dependencies.{package_name}.path` must be a string"),
+                    )
+                })?;
+                workspace_dependency_paths.insert(workspace_dir.join(path));
+            }
+        }
+    }
+    Ok(workspace_dependency_paths)
+}
+
+/// Resolves the member package directories of the workspace rooted at `workspace_dir`, relative to it: every
+/// directory containing a Cargo.toml which is matched by an entry of `workspace.members` (entries may contain `*`
+/// and `?` wildcards) and not listed under `workspace.exclude`, plus the root package if there is one.
+pub(crate) fn get_workspace_member_paths(workspace_dir: &Path) -> Result<BTreeSet<PathBuf>, Error> {
+    let workspace_manifest_path = workspace_dir.join("Cargo.toml");
+    let workspace_cargo = fs::read_to_string(&workspace_manifest_path)?.parse::<Value>()?;
+    let workspace = workspace_cargo
+        .get("workspace")
+        .ok_or_else(|| Error::manifest(&workspace_manifest_path, "missing key `workspace`"))?;
+
+    let get_paths = |key: &str| -> Result<Vec<&str>, Error> {
+        match workspace.get(key) {
+            Some(paths) => paths
+                .as_array()
+                .and_then(|paths| paths.iter().map(Value::as_str).collect::<Option<Vec<_>>>())
+                .ok_or_else(|| {
+                    Error::manifest(
+                        &workspace_manifest_path,
+                        format!("key `workspace.{key}` must be an array of strings"),
+                    )
+                }),
+            None => Ok(vec![]),
+        }
+    };
+
+    let excluded = get_paths("exclude")?
+        .into_iter()
+        .map(|path| Path::new(path.trim_end_matches('/')).to_path_buf())
+        .collect::<Vec<_>>();
+
+    let mut member_paths = BTreeSet::default();
+    if workspace_cargo.get("package").is_some() {
+        member_paths.insert(PathBuf::new());
+    }
+    for member in get_paths("members")? {
+        for member_path in expand_member_pattern(workspace_dir, &workspace_manifest_path, member)? {
+            if excluded.iter().any(|excluded| member_path.starts_with(excluded)) {
+                debug!("excluding workspace member {}", member_path.display());
+                continue;
+            }
+            if workspace_dir.join(&member_path).join("Cargo.toml").exists() {
+                member_paths.insert(member_path);
+            }
+        }
+    }
+    Ok(member_paths)
+}
+
+/// Expands the wildcards of a workspace member pattern (a `workspace.members` entry or a node workspace pattern)
+/// declared in `manifest_path` into the matching directories relative to `workspace_dir`.
+pub(crate) fn expand_member_pattern(
+    workspace_dir: &Path,
+    manifest_path: &Path,
+    pattern: &str,
+) -> Result<Vec<PathBuf>, Error> {
+    let mut paths = vec![PathBuf::new()];
+    for component in pattern
+        .split('/')
+        .filter(|component| !component.is_empty() && *component != ".")
+    {
+        if !component.contains(['*', '?']) {
+            paths = paths.into_iter().map(|path| path.join(component)).collect();
+            continue;
+        }
+        let component_regex = Regex::new(&format!(
+            "^{}$",
+            fancy_regex::escape(component).replace(r"\*", ".*").replace(r"\?", ".")
+        ))
+        .map_err(|err| Error::manifest(manifest_path, format!("invalid member `{pattern}`: {err}")))?;
+        let mut expanded = vec![];
+        for path in paths {
+            let Ok(entries) = fs::read_dir(workspace_dir.join(&path)) else {
+                continue;
+            };
+            for entry in entries {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().to_string();
+                if entry.file_type()?.is_dir()
+                    && !name.starts_with('.')
+                    && component_regex.is_match(&name).unwrap_or_default()
+                {
+                    expanded.push(path.join(name));
+                }
+            }
+        }
+        paths = expanded;
+    }
+    paths.sort();
+    Ok(paths)
+}
@@ -0,0 +1,229 @@
+/// Queries cargo's own view of the workspace through `cargo metadata` for cases where reading manifests by hand
+/// falls short: the resolved dependency graph accounts for renamed dependencies, target specific dependencies,
+/// dependency kinds, inherited workspace dependencies and path dependencies declared directly in member manifests
+/// rather than in `workspace.dependencies`.
+use crate::error::Error;
+use crate::exec::read;
+use crate::timing::{time, Phase};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::iter::once;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tracing::warn;
+
+/// Output of `cargo metadata --format-version 1`, only the fields ops relies on are parsed.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Metadata {
+    /// every package of the resolved dependency graph, local and external
+    pub packages: Vec<MetadataPackage>,
+    /// ids of the workspace's member packages
+    pub workspace_members: Vec<String>,
+    pub workspace_root: PathBuf,
+    pub target_directory: PathBuf,
+    pub resolve: Option<MetadataResolve>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MetadataPackage {
+    pub id: String,
+    pub name: String,
+    pub version: String,
+    /// none for local (path) packages
+    pub source: Option<String>,
+    pub manifest_path: PathBuf,
+    pub edition: String,
+    /// SPDX license expression, none if the package only points to a license file
+    pub license: Option<String>,
+    /// dependencies as declared in the package's manifest, with inherited workspace dependencies resolved
+    pub dependencies: Vec<MetadataDependency>,
+    pub targets: Vec<MetadataTarget>,
+    /// features declared by the package, including the implicit features of optional dependencies
+    #[serde(default)]
+    pub features: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MetadataDependency {
+    /// name of the depended on package, regardless of whether the dependency is renamed
+    pub name: String,
+    /// name the dependency is imported under if it is renamed
+    pub rename: Option<String>,
+    /// none for normal dependencies, otherwise `dev` or `build`
+    pub kind: Option<String>,
+    /// cfg expression or target triple the dependency is limited to
+    pub target: Option<String>,
+    /// directory of path dependencies
+    pub path: Option<PathBuf>,
+    #[serde(default)]
+    pub optional: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MetadataTarget {
+    pub name: String,
+    /// e.g. `lib`, `bin`, `test` or `custom-build`
+    pub kind: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MetadataResolve {
+    pub nodes: Vec<MetadataNode>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MetadataNode {
+    pub id: String,
+    pub deps: Vec<MetadataDep>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MetadataDep {
+    pub pkg: String,
+    pub dep_kinds: Vec<MetadataDepKind>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MetadataDepKind {
+    /// none for normal dependencies, otherwise `dev` or `build`
+    pub kind: Option<String>,
+}
+
+/// Build output locations of a workspace along with the names of every crate which may have artifacts in them.
+#[derive(Clone, Debug)]
+pub(crate) struct TargetInfo {
+    pub target_dir: PathBuf,
+    /// names of every package and target (lib, bin, build script, ...) in the resolved dependency graph with `-`
+    /// replaced by `_`, matching how cargo names artifacts
+    pub crate_names: BTreeSet<String>,
+}
+
+impl Metadata {
+    /// Runs `cargo metadata` in `workspace_dir`, resolving the full dependency graph.
+    pub fn load(workspace_dir: &Path) -> Result<Self, Error> {
+        time(Phase::DependencyResolution, "cargo metadata", || {
+            let stdout = read(
+                Command::new("cargo")
+                    .args(["metadata", "--format-version", "1"])
+                    .current_dir(workspace_dir),
+            )?;
+            Ok(serde_json::from_str::<Self>(&stdout)?)
+        })
+    }
+
+    /// Package with the id `id`.
+    pub fn package(&self, id: &str) -> Option<&MetadataPackage> {
+        self.packages.iter().find(|package| package.id == id)
+    }
+
+    /// Workspace members.
+    pub fn members(&self) -> impl Iterator<Item = &MetadataPackage> {
+        self.packages
+            .iter()
+            .filter(|package| self.workspace_members.contains(&package.id))
+    }
+
+    /// Local packages: workspace members and every path dependency of the workspace.
+    pub fn local_packages(&self) -> impl Iterator<Item = &MetadataPackage> {
+        self.packages.iter().filter(|package| package.source.is_none())
+    }
+
+    /// Directory of `package` relative to the workspace root, none if it lives outside of the workspace.
+    pub fn package_dir<'a>(&self, package: &'a MetadataPackage) -> Option<&'a Path> {
+        package.dir().strip_prefix(&self.workspace_root).ok()
+    }
+
+    pub(crate) fn target_info(&self) -> TargetInfo {
+        let crate_names = self
+            .packages
+            .iter()
+            .flat_map(|package| once(&package.name).chain(package.targets.iter().map(|target| &target.name)))
+            .map(|name| name.replace('-', "_"))
+            .collect();
+        TargetInfo {
+            target_dir: self.target_directory.clone(),
+            crate_names,
+        }
+    }
+
+    /// Directories (relative to the workspace root) of the local packages needed to build the workspace member
+    /// `package_name`: the package itself and every path dependency it transitively depends on through normal or
+    /// build dependencies. Dev dependencies are excluded as they are not needed to build the package's binaries.
+    pub(crate) fn build_dependency_dirs(&self, package_name: &str) -> Result<BTreeSet<PathBuf>, Error> {
+        let nodes = self
+            .resolve
+            .as_ref()
+            .ok_or_else(|| Error::msg("cargo metadata did not resolve the dependency graph"))?
+            .nodes
+            .iter()
+            .map(|node| (&*node.id, node))
+            .collect::<HashMap<_, _>>();
+        let packages = self
+            .packages
+            .iter()
+            .map(|package| (&*package.id, package))
+            .collect::<HashMap<_, _>>();
+
+        let root = self
+            .members()
+            .find(|package| package.name == package_name)
+            .ok_or_else(|| Error::InvalidInput(format!("`{package_name}` is not a member of the workspace")))?;
+
+        let mut dirs = BTreeSet::<PathBuf>::default();
+        let mut visited = BTreeSet::<&str>::default();
+        let mut queue = VecDeque::from([&*root.id]);
+        while let Some(id) = queue.pop_front() {
+            if !visited.insert(id) {
+                continue;
+            }
+            let Some(package) = packages.get(id).filter(|package| package.source.is_none()) else {
+                continue;
+            };
+            match self.package_dir(package) {
+                Some(dir) => {
+                    dirs.insert(dir.to_path_buf());
+                }
+                None => warn!(
+                    "path dependency `{}` is outside of the workspace and will be missing from docker builds",
+                    package.name
+                ),
+            }
+            let Some(node) = nodes.get(id) else {
+                continue;
+            };
+            for dep in node.deps.iter() {
+                if dep.dep_kinds.iter().any(|kind| kind.kind.as_deref() != Some("dev")) {
+                    queue.push_back(&dep.pkg);
+                }
+            }
+        }
+        Ok(dirs)
+    }
+}
+
+impl MetadataPackage {
+    /// Directory containing the package's manifest.
+    pub fn dir(&self) -> &Path {
+        self.manifest_path.parent().unwrap_or(Path::new(""))
+    }
+
+    /// Whether the package builds a binary target.
+    pub fn is_binary(&self) -> bool {
+        self.bins().next().is_some()
+    }
+
+    /// Names of the package's binary targets.
+    pub fn bins(&self) -> impl Iterator<Item = &str> {
+        self.targets
+            .iter()
+            .filter(|target| target.kind.iter().any(|kind| kind == "bin"))
+            .map(|target| &*target.name)
+    }
+}
+
+impl MetadataDependency {
+    /// Whether the dependency is only needed to build tests, examples and benchmarks.
+    pub fn is_dev(&self) -> bool {
+        self.kind.as_deref() == Some("dev")
+    }
+}
@@ -0,0 +1,11 @@
+pub mod graph;
+pub mod metadata;
+
+pub use graph::*;
+pub use metadata::*;
+
+pub mod prelude {
+    use super::*;
+    pub use graph::*;
+    pub use metadata::*;
+}
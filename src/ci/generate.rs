@@ -0,0 +1,124 @@
+/// Renders CI workflow files from the `ci` and `services` sections of `ops.toml` so that the commands
+/// run in CI stay in sync with the commands run locally.
+use crate::config::prelude::*;
+use crate::dry_run::{enable_dry_run, is_dry_run, write_file};
+use crate::error::Error;
+use crate::logging::LogArgs;
+use clap::{Parser, ValueEnum};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info_span};
+
+const GITHUB_WORKFLOW: &str = include_str!("github_workflow.yml");
+
+const DEFAULT_INSTALL: &str = "cargo install --locked --git https://github.com/tlowerison/ops";
+
+#[derive(Clone, Debug, Parser)]
+#[clap(author, version, about, long_about = None)]
+pub struct CiGenerateArgs {
+    /// CI provider to render workflows for
+    #[clap(short, long, value_enum, env = "OPS_CI_PROVIDER")]
+    pub provider: CiProvider,
+
+    /// path to write the workflow to, relative paths are relative to the current working directory
+    /// - defaults to printing the workflow to stdout
+    #[clap(short, long, env = "OPS_CI_OUTPUT")]
+    pub output_file: Option<PathBuf>,
+
+    #[clap(flatten)]
+    pub log: LogArgs,
+
+    /// print the files which would be written instead of writing them
+    /// - can also be enabled by setting the OPS_DRY_RUN environment variable
+    #[clap(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum CiProvider {
+    Github,
+}
+
+pub fn ci_generate(ci_generate_args: CiGenerateArgs) -> Result<(), Error> {
+    let CiGenerateArgs {
+        provider,
+        output_file,
+        log: _,
+        dry_run,
+    } = ci_generate_args;
+
+    if dry_run {
+        enable_dry_run();
+    }
+
+    let _span = info_span!("ci_generate").entered();
+
+    let config = OpsConfig::load(Path::new("."))?;
+
+    let workflow = match provider {
+        CiProvider::Github => get_github_workflow(&config.ci),
+    };
+
+    match output_file {
+        Some(output_file) => {
+            if let Some(parent) = output_file.parent().filter(|_| !is_dry_run()) {
+                fs::create_dir_all(parent)?;
+            }
+            write_file(&output_file, &workflow)?;
+            debug!("wrote workflow to: {}", output_file.display());
+        }
+        None => print!("{workflow}"),
+    }
+
+    Ok(())
+}
+
+pub fn get_github_workflow(ci_config: &CiConfig) -> String {
+    let branches = if ci_config.branches.is_empty() {
+        vec!["main".to_string()]
+    } else {
+        ci_config.branches.clone()
+    };
+    let branches = format!(
+        "[{}]",
+        branches
+            .iter()
+            .map(|branch| format!(r#""{branch}""#))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    let image_tag = match ci_config.registry.as_ref() {
+        Some(registry) => format!(
+            "{}/${{{{ matrix.service }}}}:${{{{ github.sha }}}}",
+            registry.trim_end_matches('/')
+        ),
+        None => "${{ matrix.service }}:${{ github.sha }}".to_string(),
+    };
+
+    let profile = ci_config.profile.as_deref().unwrap_or("release");
+
+    let push_step = match ci_config.registry.as_ref() {
+        Some(_) => {
+            let mut push_step = String::new();
+            if let Some(login) = ci_config.login.as_ref() {
+                push_step.push_str(&format!(
+                    "\n      - name: registry login\n        if: github.event_name == 'push'\n        run: {login}"
+                ));
+            }
+            push_step.push_str(&format!(
+                "\n      - name: push ${{{{ matrix.service }}}}\n        if: github.event_name == 'push'\n        run: docker push {image_tag}-{profile}"
+            ));
+            push_step
+        }
+        None => String::new(),
+    };
+
+    GITHUB_WORKFLOW
+        .replace("$workflow_name", ci_config.workflow_name.as_deref().unwrap_or("ops"))
+        .replace("$branches", &branches)
+        .replace("$install", ci_config.install.as_deref().unwrap_or(DEFAULT_INSTALL))
+        .replace("$image_tag", &image_tag)
+        .replace("$profile", profile)
+        .replace("$push_step", &push_step)
+}
@@ -0,0 +1,136 @@
+/// Outputs the services affected by the current git diff as a GitHub Actions matrix so that CI can
+/// fan out one build job per service.
+use crate::cargo::WorkspaceGraph;
+use crate::config::prelude::*;
+use crate::error::Error;
+use crate::git::diff_name_status_since_branched::DiffBaseArgs;
+use crate::logging::LogArgs;
+use crate::workspace::graph::*;
+use clap::Parser;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info_span};
+
+#[derive(Clone, Debug, Parser)]
+#[clap(author, version, about, long_about = None)]
+pub struct CiMatrixArgs {
+    /// include every service regardless of whether it is affected by the current git diff
+    #[clap(short, long, env = "OPS_ALL")]
+    pub all: bool,
+
+    /// pretty print the matrix json
+    #[clap(short, long, env = "OPS_PRETTY")]
+    pub pretty: bool,
+
+    #[clap(flatten)]
+    pub diff_base: DiffBaseArgs,
+
+    #[clap(flatten)]
+    pub log: LogArgs,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CiMatrix {
+    pub include: Vec<CiMatrixEntry>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CiMatrixEntry {
+    pub service: String,
+    pub package: String,
+    pub path: PathBuf,
+    pub dockerfile: Option<PathBuf>,
+    pub feature_sets: Vec<String>,
+    pub default_feature_set: bool,
+    /// arguments to pass to ops-docker-build-rust-workspace-service to build the service's binaries
+    pub build_args: Vec<String>,
+}
+
+pub fn ci_matrix(ci_matrix_args: CiMatrixArgs) -> Result<(), Error> {
+    let CiMatrixArgs {
+        all,
+        pretty,
+        diff_base,
+        log: _,
+    } = ci_matrix_args;
+
+    let _span = info_span!("ci_matrix").entered();
+
+    let matrix = get_ci_matrix(Path::new("."), all, &diff_base)?;
+
+    if pretty {
+        println!("{}", serde_json::to_string_pretty(&matrix)?);
+    } else {
+        println!("{}", serde_json::to_string(&matrix)?);
+    }
+
+    Ok(())
+}
+
+/// Builds the matrix of services in the workspace rooted at `workspace_dir` which are affected by the changes
+/// since `diff_base` (every service if `all`). Services are read from the `services` section of `ops.toml`,
+/// falling back to every binary package in the workspace.
+pub fn get_ci_matrix(workspace_dir: &Path, all: bool, diff_base: &DiffBaseArgs) -> Result<CiMatrix, Error> {
+    let config = OpsConfig::load(workspace_dir)?;
+    let graph = WorkspaceGraph::load(workspace_dir)?;
+    let packages = graph.packages();
+
+    let services = if config.services.is_empty() {
+        packages
+            .values()
+            .filter(|package| package.binary)
+            .map(|package| (package.name.clone(), ServiceConfig::default()))
+            .collect()
+    } else {
+        config.services
+    };
+
+    let affected_package_names = if all { None } else { Some(get_affected_package_names(&graph, diff_base)?) };
+
+    let mut include = vec![];
+    for (service_name, service_config) in services.iter() {
+        let package_name = service_config.package_name(service_name);
+        let package = packages.get(package_name).ok_or_else(|| {
+            Error::InvalidInput(format!(
+                "service `{service_name}` refers to package `{package_name}` which is not a member of the workspace"
+            ))
+        })?;
+
+        if let Some(affected_package_names) = affected_package_names.as_ref() {
+            if !affected_package_names.contains(package_name) {
+                continue;
+            }
+        }
+
+        let mut build_args = service_config
+            .feature_sets
+            .iter()
+            .map(|feature_set| format!("--feature-set={feature_set}"))
+            .collect::<Vec<_>>();
+        if service_config.default_feature_set {
+            build_args.push("--default-feature-set".to_string());
+        }
+
+        include.push(CiMatrixEntry {
+            service: service_name.clone(),
+            package: package_name.to_string(),
+            path: package.path.clone(),
+            dockerfile: service_config.dockerfile.clone(),
+            feature_sets: service_config.feature_sets.clone(),
+            default_feature_set: service_config.default_feature_set,
+            build_args,
+        });
+    }
+
+    let included = include.iter().map(|entry| &*entry.service).collect::<HashSet<_>>();
+    for service_name in services.keys() {
+        if included.contains(&**service_name) {
+            debug!(" - {service_name}: affected");
+        } else {
+            debug!(" - {service_name}: unaffected");
+        }
+    }
+
+    Ok(CiMatrix { include })
+}
@@ -0,0 +1,10 @@
+pub mod generate;
+pub mod matrix;
+pub mod run;
+
+pub mod prelude {
+    use super::*;
+    pub use generate::*;
+    pub use matrix::*;
+    pub use run::*;
+}
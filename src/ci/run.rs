@@ -0,0 +1,549 @@
+/// End-to-end CI pipeline for the changes since the current branch diverged: runs `ops verify` against the
+/// affected packages, builds the affected service images, pushes them when a registry is configured, writes a
+/// manifest of everything that was built and, when running in GitHub Actions, annotates failures and writes a job
+/// summary. The pipeline continues past failures of individual services with `--keep-going` and exits with the code
+/// of the first failing stage.
+use crate::audit::{audit, get_git_sha};
+use crate::ci::matrix::get_ci_matrix;
+use crate::config::OpsConfig;
+use crate::docker::build::{docker_build, DockerBuildArgs};
+use crate::docker::build_rust_workspace::{docker_build_rust_workspace, DockerBuildRustWorkspaceArgs};
+use crate::docker::dockerfile::CacheMounts;
+use crate::docker::push::{docker_push, DockerPushArgs, RegistryProvider};
+use crate::docker::scan::Severity;
+use crate::dry_run::{enable_dry_run, is_dry_run, write_file};
+use crate::error::{Error, EXIT_FAILURE, EXIT_LINT};
+use crate::git::diff_name_status_since_branched::DiffBaseArgs;
+use crate::git::signature::SignatureArgs;
+use crate::lock::{lock, LockArgs};
+use crate::logging::LogArgs;
+use crate::progress::format_duration;
+use crate::report::{serialize_duration, Report};
+use crate::verify::{verify, VerifyArgs, VerifyOutput, VerifyStatus};
+use crate::workspace::root::get_workspace_root;
+use clap::Parser;
+use colored::Colorize;
+use serde::Serialize;
+use std::env;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tracing::{info_span, warn};
+
+/// default path of the manifest, relative to the workspace root
+const DEFAULT_MANIFEST: &str = "ops-ci.json";
+
+#[derive(Clone, Debug, Parser)]
+#[clap(author, version, about, long_about = None)]
+pub struct CiRunArgs {
+    /// run the pipeline for every package and service regardless of whether it is affected by the current git diff
+    #[clap(short, long, env = "OPS_ALL")]
+    pub all: bool,
+
+    /// image tag of every service, `{service}` is replaced with the service's name and `{sha}` with the commit
+    /// being built -- defaults to `{service}:{sha}` prefixed by `ci.registry` in ops.toml
+    #[clap(short, long, env = "OPS_TAG")]
+    pub tag: Option<String>,
+
+    /// which rust profile to build rust binaries, defaults to `ci.profile` in ops.toml or release
+    #[clap(long, env = "OPS_PROFILE")]
+    pub profile: Option<String>,
+
+    /// rust docker image version, defaults to latest
+    #[clap(short, long, env = "OPS_RUST_VERSION")]
+    pub rust_version: Option<String>,
+
+    /// keep cargo's downloads between builds in BuildKit cache mounts, `--cache-mounts=sccache` additionally
+    /// compiles through sccache -- see ops-docker-build-rust-workspace-service --cache-mounts
+    #[clap(
+        long,
+        value_enum,
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "registry",
+        env = "OPS_CACHE_MOUNTS"
+    )]
+    pub cache_mounts: Option<CacheMounts>,
+
+    /// external cache sources of every build (e.g. `type=gha,scope={service}`), `{service}` is replaced with the
+    /// service's name -- only applied to the pre-build stage of rust workspace services
+    #[clap(long, value_delimiter = '\n', env = "OPS_CACHE_FROM")]
+    pub cache_from: Vec<String>,
+
+    /// cache exports of every build (e.g. `type=gha,scope={service},mode=max`), `{service}` is replaced with the
+    /// service's name -- only applied to the pre-build stage of rust workspace services
+    #[clap(long, value_delimiter = '\n', env = "OPS_CACHE_TO")]
+    pub cache_to: Vec<String>,
+
+    /// registry repository caching the pre-build stage of rust workspace services (e.g. `ghcr.io/org/cache`), see
+    /// ops-docker-build-rust-workspace-service --pre-build-cache
+    #[clap(long, env = "OPS_PRE_BUILD_CACHE")]
+    pub pre_build_cache: Option<String>,
+
+    /// skip fmt, clippy, tests and the dependency audit
+    #[clap(long, env = "OPS_SKIP_VERIFY")]
+    pub skip_verify: bool,
+
+    /// build images without pushing them, pushing is otherwise enabled when `ci.registry` is set in ops.toml or
+    /// --tag is passed
+    #[clap(long, env = "OPS_NO_PUSH")]
+    pub no_push: bool,
+
+    /// registry provider used to authenticate pushes
+    #[clap(long, value_enum, default_value = "docker", env = "OPS_REGISTRY_PROVIDER")]
+    pub provider: RegistryProvider,
+
+    /// docker credential helper used to authenticate pushes, cannot be combined with --provider
+    #[clap(long, env = "OPS_CREDENTIAL_HELPER")]
+    pub credential_helper: Option<String>,
+
+    /// keep building and pushing the remaining services after a stage fails
+    #[clap(short, long, env = "OPS_KEEP_GOING")]
+    pub keep_going: bool,
+
+    /// path to write the manifest of built and pushed images to, relative to the workspace root -- defaults to
+    /// `ops-ci.json`
+    #[clap(short, long, env = "OPS_CI_MANIFEST")]
+    pub manifest: Option<PathBuf>,
+
+    #[clap(flatten)]
+    pub diff_base: DiffBaseArgs,
+
+    #[clap(flatten)]
+    pub signature: SignatureArgs,
+
+    #[clap(flatten)]
+    pub lock: LockArgs,
+
+    #[clap(flatten)]
+    pub log: LogArgs,
+
+    /// print the commands and files instead of running and writing them
+    /// - can also be enabled by setting the OPS_DRY_RUN environment variable
+    #[clap(long)]
+    pub dry_run: bool,
+}
+
+/// Manifest of a pipeline run, also written to `--manifest`.
+#[derive(Clone, Debug, Serialize)]
+pub struct CiRunOutput {
+    pub commit: Option<String>,
+    pub profile: String,
+    pub verify: Option<VerifyOutput>,
+    pub services: Vec<CiServiceOutput>,
+    #[serde(skip)]
+    pub manifest: PathBuf,
+    #[serde(serialize_with = "serialize_duration")]
+    pub duration: Duration,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CiServiceOutput {
+    pub service: String,
+    pub image: String,
+    pub status: CiServiceStatus,
+    /// id of the built image, unavailable in dry-run mode
+    pub image_id: Option<String>,
+    /// digest of the pushed image, unavailable in dry-run mode
+    pub digest: Option<String>,
+    /// why building or pushing the image failed or was skipped
+    pub message: Option<String>,
+    /// exit code of the failure
+    #[serde(skip)]
+    pub exit_code: Option<u8>,
+    #[serde(serialize_with = "serialize_duration")]
+    pub duration: Duration,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CiServiceStatus {
+    Built,
+    Pushed,
+    BuildFailed,
+    PushFailed,
+    Skipped,
+}
+
+impl CiServiceStatus {
+    pub fn description(&self) -> &'static str {
+        match self {
+            Self::Built => "built",
+            Self::Pushed => "pushed",
+            Self::BuildFailed => "build failed",
+            Self::PushFailed => "push failed",
+            Self::Skipped => "skipped",
+        }
+    }
+
+    pub fn is_failed(&self) -> bool {
+        matches!(self, Self::BuildFailed | Self::PushFailed)
+    }
+}
+
+impl Report for CiRunOutput {
+    fn human(&self) -> String {
+        let mut lines = vec![];
+        if let Some(verify) = self.verify.as_ref() {
+            lines.push(verify.human());
+        }
+        lines.push("ci summary".bold().to_string());
+        if self.services.is_empty() {
+            lines.push("  no affected services".dimmed().to_string());
+        }
+        let width = self
+            .services
+            .iter()
+            .map(|service| service.service.len())
+            .max()
+            .unwrap_or_default();
+        for service in &self.services {
+            let status = match service.status {
+                CiServiceStatus::Built | CiServiceStatus::Pushed => service.status.description().green(),
+                CiServiceStatus::BuildFailed | CiServiceStatus::PushFailed => service.status.description().red(),
+                CiServiceStatus::Skipped => service.status.description().dimmed(),
+            };
+            let detail = match (service.status, service.message.as_ref()) {
+                (CiServiceStatus::Skipped, Some(message)) => message.clone(),
+                (_, Some(message)) => format!("{} {message}", format_duration(service.duration)),
+                (_, None) => format!("{} {}", format_duration(service.duration), service.image),
+            };
+            lines.push(format!("  {:width$}  {status:12}  {detail}", service.service));
+        }
+        lines.push(format!("manifest: {}", self.manifest.display()));
+        lines.join("\n")
+    }
+}
+
+impl CiRunOutput {
+    /// Converts the output into an error if any stage failed, exiting with the code of the first failure.
+    pub fn result(&self) -> Result<(), Error> {
+        let mut failed = vec![];
+        let mut exit_code = None;
+        if let Some(verify) = self.verify.as_ref() {
+            for step in verify.steps.iter().filter(|step| step.status == VerifyStatus::Failed) {
+                failed.push(step.step.name().to_string());
+                exit_code.get_or_insert(EXIT_LINT);
+            }
+        }
+        for service in self.services.iter().filter(|service| service.status.is_failed()) {
+            failed.push(service.service.clone());
+            if let Some(code) = service.exit_code {
+                exit_code.get_or_insert(code);
+            }
+        }
+        match failed.is_empty() {
+            true => Ok(()),
+            false => Err(Error::CiFailed {
+                failed,
+                exit_code: exit_code.unwrap_or(EXIT_FAILURE),
+            }),
+        }
+    }
+}
+
+pub fn ci_run(ci_run_args: CiRunArgs) -> Result<CiRunOutput, Error> {
+    let CiRunArgs {
+        all,
+        tag,
+        profile,
+        rust_version,
+        cache_mounts,
+        cache_from,
+        cache_to,
+        pre_build_cache,
+        skip_verify,
+        no_push,
+        provider,
+        credential_helper,
+        keep_going,
+        manifest,
+        diff_base,
+        signature,
+        lock: lock_args,
+        log,
+        dry_run,
+    } = ci_run_args;
+
+    let _span = info_span!("ci_run").entered();
+
+    if dry_run {
+        enable_dry_run();
+    }
+
+    let start = Instant::now();
+    let workspace_dir = get_workspace_root(&env::current_dir()?)?;
+    env::set_current_dir(&workspace_dir)?;
+
+    let config = OpsConfig::load(&workspace_dir)?;
+    let commit = get_git_sha();
+    let profile = profile.or(config.ci.profile).unwrap_or_else(|| "release".to_string());
+    let push = !no_push && (tag.is_some() || config.ci.registry.is_some());
+    let tag = tag.unwrap_or_else(|| match config.ci.registry.as_ref() {
+        Some(registry) => format!("{}/{{service}}:{{sha}}", registry.trim_end_matches('/')),
+        None => "{service}:{sha}".to_string(),
+    });
+
+    let verify = match skip_verify {
+        true => None,
+        false => Some(verify(VerifyArgs {
+            step: vec![],
+            all,
+            keep_going,
+            diff_base: diff_base.clone(),
+            log: log.clone(),
+            dry_run,
+        })?),
+    };
+    let verify_failed = verify.as_ref().is_some_and(|verify| verify.result().is_err());
+
+    let matrix = get_ci_matrix(&workspace_dir, all, &diff_base)?;
+    let mut services = vec![];
+    let mut failed = verify_failed;
+    for entry in matrix.include {
+        let service_tag = tag
+            .replace("{service}", &entry.service)
+            .replace("{sha}", commit.as_deref().unwrap_or("latest"));
+        // rust workspace services are additionally tagged with their profile, Dockerfile services are tagged the
+        // same way so that every pushed image follows the same naming
+        let image = format!("{service_tag}-{profile}");
+        if failed && !keep_going {
+            services.push(CiServiceOutput {
+                service: entry.service,
+                image,
+                status: CiServiceStatus::Skipped,
+                image_id: None,
+                digest: None,
+                message: Some("a previous stage failed".to_string()),
+                exit_code: None,
+                duration: Duration::ZERO,
+            });
+            continue;
+        }
+
+        let with_service = |caches: &[String]| {
+            caches
+                .iter()
+                .map(|cache| cache.replace("{service}", &entry.service))
+                .collect::<Vec<_>>()
+        };
+        let service_start = Instant::now();
+        // the image is locked across its build and push so that no other build retags it in between, the inner
+        // commands therefore don't lock it again
+        let inner_lock = LockArgs {
+            no_lock: true,
+            ..lock_args.clone()
+        };
+        let build = || match entry.dockerfile.as_ref() {
+            Some(dockerfile) => audit("docker-build", || {
+                docker_build(DockerBuildArgs {
+                    file: Some(dockerfile.clone()),
+                    file_text: None,
+                    ignore_file: None,
+                    context_include: vec![],
+                    buildx: false,
+                    cache_from: with_service(&cache_from),
+                    cache_to: with_service(&cache_to),
+                    build_arg_file: vec![],
+                    secret: vec![],
+                    export: vec![],
+                    load: false,
+                    dir: None,
+                    log: log.clone(),
+                    dry_run,
+                    dry_run_dir: None,
+                    docker_args: vec![format!("--tag={image}"), ".".to_string()],
+                })
+            })
+            .map(|output| output.image_id),
+            None => audit("docker-build-rust-workspace-service", || {
+                docker_build_rust_workspace(DockerBuildRustWorkspaceArgs {
+                    copy: vec![],
+                    default_feature_set: entry.default_feature_set,
+                    feature_set: entry.feature_sets.clone(),
+                    feature_matrix: vec![],
+                    bin: vec![],
+                    ignore_file: None,
+                    pre_build_omit: vec![],
+                    profile: Some(profile.clone()),
+                    rust_version: rust_version.clone(),
+                    service: vec![workspace_dir.join(&entry.path)],
+                    all_services: false,
+                    jobs: None,
+                    use_entrypoint: false,
+                    entrypoint: None,
+                    build_image: None,
+                    runtime_image: None,
+                    platform: vec![],
+                    buildx: false,
+                    cache_from: with_service(&cache_from),
+                    cache_to: with_service(&cache_to),
+                    pre_build_cache: pre_build_cache.clone(),
+                    cache_mounts,
+                    vendor: false,
+                    pre_build_template: None,
+                    build_template: None,
+                    force: false,
+                    full_context: false,
+                    scan: None,
+                    scan_fail_on: Severity::High,
+                    scan_report: None,
+                    signature: signature.clone(),
+                    lock: inner_lock.clone(),
+                    log: log.clone(),
+                    dry_run,
+                    dry_run_dir: None,
+                    docker_args: vec![format!("--tag={service_tag}"), ".".to_string()],
+                })
+            })
+            .map(|output| output.build.and_then(|build| build.image_id)),
+        };
+        let (_service_lock, built) = match lock(&image, &lock_args) {
+            Ok(service_lock) => (service_lock, build()),
+            Err(err) => (None, Err(err)),
+        };
+        let image_id = match built {
+            Ok(image_id) => image_id,
+            Err(Error::Cancelled) => return Err(Error::Cancelled),
+            Err(err) => {
+                failed = true;
+                services.push(CiServiceOutput {
+                    service: entry.service,
+                    image,
+                    status: CiServiceStatus::BuildFailed,
+                    image_id: None,
+                    digest: None,
+                    message: Some(err.to_string()),
+                    exit_code: Some(err.exit_code()),
+                    duration: service_start.elapsed(),
+                });
+                continue;
+            }
+        };
+
+        let mut output = CiServiceOutput {
+            service: entry.service,
+            image: image.clone(),
+            status: CiServiceStatus::Built,
+            image_id,
+            digest: None,
+            message: None,
+            exit_code: None,
+            duration: Duration::ZERO,
+        };
+        if push {
+            let pushed = audit("docker-push", || {
+                docker_push(DockerPushArgs {
+                    image,
+                    provider,
+                    credential_helper: credential_helper.clone(),
+                    harbor_url: None,
+                    create_project: false,
+                    retain: None,
+                    retries: None,
+                    retry_delay: None,
+                    oci_archive: None,
+                    digest_file: None,
+                    signature: signature.clone(),
+                    lock: inner_lock.clone(),
+                    log: log.clone(),
+                    dry_run,
+                    dry_run_dir: None,
+                })
+            });
+            match pushed {
+                Ok(pushed) => {
+                    output.status = CiServiceStatus::Pushed;
+                    output.digest = pushed.digest;
+                }
+                Err(Error::Cancelled) => return Err(Error::Cancelled),
+                Err(err) => {
+                    failed = true;
+                    output.status = CiServiceStatus::PushFailed;
+                    output.message = Some(err.to_string());
+                    output.exit_code = Some(err.exit_code());
+                }
+            }
+        }
+        output.duration = service_start.elapsed();
+        services.push(output);
+    }
+
+    let output = CiRunOutput {
+        commit,
+        profile,
+        verify,
+        services,
+        manifest: workspace_dir.join(manifest.unwrap_or_else(|| PathBuf::from(DEFAULT_MANIFEST))),
+        duration: start.elapsed(),
+    };
+    write_file(&output.manifest, &serde_json::to_string_pretty(&output)?)?;
+    if env::var("GITHUB_ACTIONS").is_ok_and(|value| value == "true") && !is_dry_run() {
+        if let Err(err) = annotate_github(&output) {
+            warn!("failed to write GitHub Actions annotations: {err}");
+        }
+    }
+    Ok(output)
+}
+
+/// Prints an error annotation for every failed stage and appends a summary table to the job summary.
+fn annotate_github(output: &CiRunOutput) -> Result<(), Error> {
+    let escape = |message: &str| message.replace('%', "%25").replace('\r', "%0D").replace('\n', "%0A");
+    if let Some(verify) = output.verify.as_ref() {
+        for step in verify.steps.iter().filter(|step| step.status == VerifyStatus::Failed) {
+            println!(
+                "::error title=ops ci: {}::{}",
+                step.step.name(),
+                escape(step.message.as_deref().unwrap_or("failed"))
+            );
+        }
+    }
+    for service in output.services.iter().filter(|service| service.status.is_failed()) {
+        println!(
+            "::error title=ops ci: {} {}::{}",
+            service.service,
+            service.status.description(),
+            escape(service.message.as_deref().unwrap_or("failed"))
+        );
+    }
+
+    let Some(summary) = env::var_os("GITHUB_STEP_SUMMARY") else {
+        return Ok(());
+    };
+    let mut lines = vec!["### ops ci".to_string(), String::new()];
+    if let Some(verify) = output.verify.as_ref() {
+        lines.push("| step | status | duration |".to_string());
+        lines.push("| --- | --- | --- |".to_string());
+        for step in &verify.steps {
+            lines.push(format!(
+                "| {} | {} | {} |",
+                step.step.name(),
+                serde_json::to_value(step.status)?.as_str().unwrap_or_default(),
+                format_duration(step.duration)
+            ));
+        }
+        lines.push(String::new());
+    }
+    lines.push("| service | status | image | digest |".to_string());
+    lines.push("| --- | --- | --- | --- |".to_string());
+    for service in &output.services {
+        lines.push(format!(
+            "| {} | {} | `{}` | {} |",
+            service.service,
+            service.status.description(),
+            service.image,
+            service
+                .digest
+                .as_deref()
+                .map(|digest| format!("`{digest}`"))
+                .unwrap_or_default()
+        ));
+    }
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(summary)?
+        .write_all(format!("{}\n", lines.join("\n")).as_bytes())?;
+    Ok(())
+}
@@ -0,0 +1,383 @@
+/// Entry point for the umbrella `ops` binary which exposes the ops tooling as subcommands. The standalone `ops-*`
+/// binaries are shims running their subcommand, e.g. `ops-docker-build` runs `ops docker build`.
+use crate::audit::audit;
+use crate::ci::prelude::*;
+use crate::config::prelude::*;
+use crate::docker::prelude::*;
+use crate::dry_run::print_plan;
+use crate::du::*;
+use crate::error::Error;
+use crate::eslint::{eslint, EslintArgs};
+use crate::exec::{set_jobs, set_timeout};
+use crate::git::prelude::*;
+use crate::js::prelude::*;
+use crate::k8s::prelude::*;
+use crate::logging::parse;
+use crate::report::report;
+use crate::verify::*;
+use crate::workspace::prelude::*;
+use crate::workspace_audit::{workspace_audit, WorkspaceAuditArgs};
+use crate::workspace_clippy::{workspace_clippy, WorkspaceClippyArgs};
+use crate::workspace_doc::{workspace_doc, WorkspaceDocArgs};
+use crate::workspace_fmt::{workspace_fmt, WorkspaceFmtArgs};
+use crate::workspace_run::{workspace_run, WorkspaceRunArgs};
+use crate::workspace_test::{workspace_test, WorkspaceTestArgs};
+use clap::{Command, CommandFactory, Parser, Subcommand};
+use std::time::Duration;
+
+#[derive(Clone, Debug, Parser)]
+#[clap(author, version, about, long_about = None)]
+pub struct OpsArgs {
+    /// maximum number of subprocesses to run concurrently, defaults to the available parallelism
+    #[clap(short, long, global = true, env = "OPS_JOBS")]
+    pub jobs: Option<usize>,
+
+    /// timeout in seconds after which any subprocess is killed, defaults to no timeout
+    #[clap(long, global = true, env = "OPS_TIMEOUT")]
+    pub timeout: Option<u64>,
+
+    #[clap(subcommand)]
+    pub command: OpsCommand,
+}
+
+// parsed once per invocation, boxing the larger args is not worth the indirection
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug, Subcommand)]
+pub enum OpsCommand {
+    /// continuous integration helpers
+    #[clap(subcommand)]
+    Ci(CiCommand),
+    /// inspect the layered ops configuration
+    #[clap(subcommand)]
+    Config(ConfigCommand),
+    /// apply kubernetes manifests and wait for their rollout, rolling back on failure
+    Deploy(DeployArgs),
+    /// docker helpers
+    #[clap(subcommand)]
+    Docker(DockerCommand),
+    /// summarize the disk usage of build artifacts, cargo caches and docker images
+    Du(DuArgs),
+    /// run eslint on the changed javascript and typescript files
+    Eslint(EslintArgs),
+    /// git hooks
+    #[clap(subcommand)]
+    Git(GitCommand),
+    /// create an ops.toml for the workspace, asking for its services, registry and profile
+    Init(InitArgs),
+    /// kubernetes helpers
+    #[clap(subcommand)]
+    K8s(K8sCommand),
+    /// run prettier on the changed frontend files
+    Prettier(PrettierArgs),
+    /// run stylelint on the changed stylesheets
+    Stylelint(StylelintArgs),
+    /// run fmt, clippy, tests and a dependency audit against the affected packages
+    Verify(VerifyArgs),
+    /// cargo workspace tooling
+    #[clap(subcommand)]
+    Workspace(WorkspaceCommand),
+}
+
+// parsed once per invocation, boxing the larger args is not worth the indirection
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug, Subcommand)]
+pub enum CiCommand {
+    /// render CI workflow files from ops.toml
+    Generate(CiGenerateArgs),
+    /// output the affected services as a GitHub Actions matrix
+    Matrix(CiMatrixArgs),
+    /// verify the affected packages, then build and push the affected services
+    Run(CiRunArgs),
+}
+
+#[derive(Clone, Debug, Subcommand)]
+pub enum ConfigCommand {
+    /// print the effective configuration of a command and where each value comes from
+    Show(ConfigShowArgs),
+}
+
+// parsed once per invocation, boxing the larger args is not worth the indirection
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug, Subcommand)]
+pub enum DockerCommand {
+    /// render a `docker buildx bake` file building every affected service
+    BakeFile(DockerBakeFileArgs),
+    /// build an image from a Dockerfile
+    Build(DockerBuildArgs),
+    /// build services of the cargo workspace from generated Dockerfiles
+    BuildRustWorkspaceService(DockerBuildRustWorkspaceArgs),
+    /// manage the build cache and pre-build images
+    #[clap(subcommand)]
+    Cache(DockerCacheCommand),
+    /// generate a docker-compose file running the workspace's services
+    ComposeGen(DockerComposeGenArgs),
+    /// push a built image to its registry
+    Push(DockerPushArgs),
+    /// render the generated Dockerfiles of services or check them against golden files
+    Render(DockerRenderArgs),
+    /// run a locally built service image with the settings declared in ops.toml
+    Run(DockerRunArgs),
+    /// build and push the dependency stage of services to a cache registry
+    WarmCache(DockerWarmCacheArgs),
+}
+
+#[derive(Clone, Debug, Subcommand)]
+pub enum DockerCacheCommand {
+    /// apply a retention policy to the build cache and pre-build images
+    Prune(DockerCachePruneArgs),
+}
+
+#[derive(Clone, Debug, Subcommand)]
+pub enum GitCommand {
+    /// install, remove or run the managed git hooks
+    #[clap(subcommand)]
+    Hooks(GitHooksCommand),
+    /// server-side pre-receive hook which runs checks against the packages affected by each pushed branch
+    PreReceive(GitPreReceiveArgs),
+}
+
+#[derive(Clone, Debug, Subcommand)]
+pub enum K8sCommand {
+    /// generate kubernetes manifests deploying the built service images
+    Gen(K8sGenArgs),
+}
+
+// parsed once per invocation, boxing the larger args is not worth the indirection
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug, Subcommand)]
+pub enum WorkspaceCommand {
+    /// audit the dependencies for advisories and license violations when their lockfiles change
+    Audit(WorkspaceAuditArgs),
+    /// run clippy on the changed packages
+    Clippy(WorkspaceClippyArgs),
+    /// build the rustdoc of the changed packages, optionally checking its links and publishing it
+    Doc(WorkspaceDocArgs),
+    /// build the services of the node workspace
+    DockerBuildNode(DockerBuildNodeWorkspaceArgs),
+    /// check the formatting of the changed rust files
+    Fmt(WorkspaceFmtArgs),
+    /// emit the internal crate dependency graph
+    Graph(WorkspaceGraphArgs),
+    /// remove stale build artifacts and old pre-build images
+    Prune(WorkspacePruneArgs),
+    /// run a command in every changed package
+    Run(WorkspaceRunArgs),
+    /// run the tests of the changed packages
+    Test(WorkspaceTestArgs),
+}
+
+pub fn ops(ops_args: OpsArgs) -> Result<(), Error> {
+    if let Some(jobs) = ops_args.jobs {
+        set_jobs(jobs);
+    }
+    if let Some(timeout) = ops_args.timeout {
+        set_timeout(Duration::from_secs(timeout));
+    }
+    // the plan is printed even if the command failed, e.g. a dry-run of a pipeline with failing checks
+    let result = run_command(ops_args.command);
+    print_plan()?;
+    result
+}
+
+fn run_command(command: OpsCommand) -> Result<(), Error> {
+    match command {
+        OpsCommand::Ci(CiCommand::Generate(args)) => ci_generate(args),
+        OpsCommand::Ci(CiCommand::Matrix(args)) => ci_matrix(args),
+        OpsCommand::Ci(CiCommand::Run(args)) => {
+            let output = ci_run(args)?;
+            report(&output)?;
+            output.result()
+        }
+        OpsCommand::Config(ConfigCommand::Show(args)) => config_show(args),
+        OpsCommand::Deploy(args) => {
+            let output = audit("deploy", || deploy(args))?;
+            report(&output)
+        }
+        OpsCommand::Docker(DockerCommand::BakeFile(args)) => {
+            let output = docker_bake_file(args)?;
+            report(&output)
+        }
+        OpsCommand::Docker(DockerCommand::Build(args)) => {
+            let output = audit("docker-build", || docker_build(args))?;
+            report(&output)
+        }
+        OpsCommand::Docker(DockerCommand::BuildRustWorkspaceService(args)) => {
+            let output = audit("docker-build-rust-workspace-service", || {
+                docker_build_rust_workspace_services(args)
+            })?;
+            report(&output)
+        }
+        OpsCommand::Docker(DockerCommand::Cache(DockerCacheCommand::Prune(args))) => {
+            let output = docker_cache_prune(args)?;
+            report(&output)
+        }
+        OpsCommand::Docker(DockerCommand::ComposeGen(args)) => {
+            let output = docker_compose_gen(args)?;
+            report(&output)
+        }
+        OpsCommand::Docker(DockerCommand::Push(args)) => {
+            let output = audit("docker-push", || docker_push(args))?;
+            report(&output)
+        }
+        OpsCommand::Docker(DockerCommand::Render(args)) => {
+            let output = docker_render(args)?;
+            report(&output)?;
+            output.result()
+        }
+        OpsCommand::Docker(DockerCommand::Run(args)) => docker_run(args),
+        OpsCommand::Docker(DockerCommand::WarmCache(args)) => {
+            let output = audit("docker-warm-cache", || docker_warm_cache(args))?;
+            report(&output)
+        }
+        OpsCommand::Du(args) => {
+            let output = du(args)?;
+            report(&output)
+        }
+        OpsCommand::Eslint(args) => {
+            let output = eslint(args)?;
+            report(&output)
+        }
+        OpsCommand::Git(GitCommand::Hooks(GitHooksCommand::Install(args))) => {
+            let output = git_hooks_install(args)?;
+            report(&output)
+        }
+        OpsCommand::Git(GitCommand::Hooks(GitHooksCommand::Uninstall(args))) => {
+            let output = git_hooks_uninstall(args)?;
+            report(&output)
+        }
+        OpsCommand::Git(GitCommand::Hooks(GitHooksCommand::Run(args))) => {
+            let output = git_hooks_run(args)?;
+            report(&output)?;
+            output.result()
+        }
+        OpsCommand::Git(GitCommand::PreReceive(args)) => {
+            let output = git_pre_receive(args)?;
+            report(&output)?;
+            output.result()
+        }
+        OpsCommand::Init(args) => {
+            let output = init(args)?;
+            report(&output)
+        }
+        OpsCommand::K8s(K8sCommand::Gen(args)) => {
+            let output = k8s_gen(args)?;
+            report(&output)
+        }
+        OpsCommand::Prettier(args) => {
+            let output = prettier(args)?;
+            report(&output)
+        }
+        OpsCommand::Stylelint(args) => {
+            let output = stylelint(args)?;
+            report(&output)
+        }
+        OpsCommand::Verify(args) => {
+            let output = verify(args)?;
+            report(&output)?;
+            output.result()
+        }
+        OpsCommand::Workspace(WorkspaceCommand::Audit(args)) => {
+            let output = workspace_audit(args)?;
+            report(&output)?;
+            output.result()
+        }
+        OpsCommand::Workspace(WorkspaceCommand::Clippy(args)) => {
+            let output = workspace_clippy(args)?;
+            report(&output)
+        }
+        OpsCommand::Workspace(WorkspaceCommand::Doc(args)) => {
+            let output = workspace_doc(args)?;
+            report(&output)
+        }
+        OpsCommand::Workspace(WorkspaceCommand::DockerBuildNode(args)) => {
+            let output = audit("workspace-docker-build-node", || docker_build_node_workspace(args))?;
+            report(&output)
+        }
+        OpsCommand::Workspace(WorkspaceCommand::Fmt(args)) => {
+            let output = workspace_fmt(args)?;
+            report(&output)?;
+            output.result()
+        }
+        OpsCommand::Workspace(WorkspaceCommand::Graph(args)) => workspace_graph(args),
+        OpsCommand::Workspace(WorkspaceCommand::Prune(args)) => {
+            let output = workspace_prune(args)?;
+            report(&output)
+        }
+        OpsCommand::Workspace(WorkspaceCommand::Run(args)) => {
+            let output = workspace_run(args)?;
+            report(&output)?;
+            output.result()
+        }
+        OpsCommand::Workspace(WorkspaceCommand::Test(args)) => {
+            let output = workspace_test(args)?;
+            report(&output)
+        }
+    }
+}
+
+/// Runs the `ops` subcommand built by `command` from the arguments of a standalone `ops-*` binary.
+pub fn run_bin<T: Parser>(command: impl FnOnce(T) -> OpsCommand) -> Result<(), Error> {
+    ops(OpsArgs {
+        jobs: None,
+        timeout: None,
+        command: command(parse::<T>()?),
+    })
+}
+
+/// Looks up a command by the name used in ops.toml `[defaults.<name>]` tables and `ops config show`, returning the
+/// binary providing it, the binary's root command and the subcommands leading to it.
+pub fn find_command(name: &str) -> Option<(String, Command<'static>, Vec<String>)> {
+    // commands of the standalone binaries are also subcommands of `ops`, without its global flags
+    let bins = [
+        ("ops-deploy", DeployArgs::command()),
+        ("ops-docker-build", DockerBuildArgs::command()),
+        (
+            "ops-docker-build-rust-workspace-service",
+            DockerBuildRustWorkspaceArgs::command(),
+        ),
+        ("ops-docker-compose-gen", DockerComposeGenArgs::command()),
+        ("ops-docker-push", DockerPushArgs::command()),
+        ("ops-eslint", EslintArgs::command()),
+        ("ops-git-hooks", GitHooksArgs::command()),
+        ("ops-k8s-gen", K8sGenArgs::command()),
+        ("ops-prettier", PrettierArgs::command()),
+        ("ops-stylelint", StylelintArgs::command()),
+        (
+            "ops-workspace-docker-build-node",
+            DockerBuildNodeWorkspaceArgs::command(),
+        ),
+        ("ops-workspace-audit", WorkspaceAuditArgs::command()),
+        ("ops-workspace-clippy", WorkspaceClippyArgs::command()),
+        ("ops-workspace-doc", WorkspaceDocArgs::command()),
+        ("ops-workspace-fmt", WorkspaceFmtArgs::command()),
+        ("ops-workspace-run", WorkspaceRunArgs::command()),
+        ("ops-workspace-test", WorkspaceTestArgs::command()),
+        ("ops", OpsArgs::command()),
+    ];
+    for (bin_name, command) in bins {
+        if let Some(subcommands) = find_subcommands(bin_name, &command, &mut vec![], name) {
+            return Some((bin_name.to_string(), command, subcommands));
+        }
+    }
+    None
+}
+
+fn find_subcommands(
+    bin_name: &str,
+    command: &Command,
+    subcommands: &mut Vec<String>,
+    name: &str,
+) -> Option<Vec<String>> {
+    let names = subcommands.iter().map(String::as_str).collect::<Vec<_>>();
+    if !command.has_subcommands() && command_name(bin_name, &names) == name {
+        return Some(subcommands.clone());
+    }
+    for subcommand in command.get_subcommands() {
+        subcommands.push(subcommand.get_name().to_string());
+        if let Some(found) = find_subcommands(bin_name, subcommand, subcommands, name) {
+            return Some(found);
+        }
+        subcommands.pop();
+    }
+    None
+}
@@ -0,0 +1,196 @@
+/// Creates the ops.toml of a workspace which has none yet: the binary members of the cargo workspace are offered
+/// as services, and the registry images are pushed to and the profile they are built with are asked for. The
+/// managed git hooks can be installed along with it.
+use crate::cargo::Metadata;
+use crate::config::OPS_CONFIG_FILE_NAME;
+use crate::dry_run::{enable_dry_run, write_file};
+use crate::error::Error;
+use crate::git::hooks::{git_hooks_install, GitHooksInstallArgs, GitHooksInstallOutput};
+use crate::logging::LogArgs;
+use crate::prompt::{confirm, input};
+use crate::report::Report;
+use crate::workspace::root::get_workspace_root;
+use clap::Parser;
+use serde::Serialize;
+use std::env;
+use std::path::PathBuf;
+use toml::Value;
+use tracing::{debug, info_span};
+
+const DEFAULT_PROFILE: &str = "release";
+
+#[derive(Clone, Debug, Parser)]
+#[clap(author, version, about, long_about = None)]
+pub struct InitArgs {
+    /// image repository prefix service images are pushed to (e.g. `ghcr.io/my-org`), prompted for if not passed
+    #[clap(long, env = "OPS_REGISTRY")]
+    pub registry: Option<String>,
+
+    /// rust profile service images are built with, prompted for if not passed -- defaults to release
+    #[clap(long, env = "OPS_PROFILE")]
+    pub profile: Option<String>,
+
+    /// packages to build as services, may be passed several times -- defaults to the binary members of the
+    /// workspace which are confirmed when prompted
+    #[clap(short, long, value_delimiter = '\n', env = "OPS_SERVICE")]
+    pub service: Vec<String>,
+
+    /// install the git hooks managed by ops-git-hooks without asking
+    #[clap(long, env = "OPS_HOOKS")]
+    pub hooks: bool,
+
+    /// overwrite an existing ops.toml without asking
+    #[clap(long, env = "OPS_FORCE")]
+    pub force: bool,
+
+    #[clap(flatten)]
+    pub log: LogArgs,
+
+    /// print the ops.toml which would be written instead of writing it
+    /// - can also be enabled by setting the OPS_DRY_RUN environment variable
+    #[clap(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct InitOutput {
+    pub path: PathBuf,
+    pub services: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub registry: Option<String>,
+    pub profile: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hooks: Option<GitHooksInstallOutput>,
+}
+
+impl Report for InitOutput {
+    fn human(&self) -> String {
+        let mut lines = vec![match self.services.is_empty() {
+            true => format!("wrote {}", self.path.display()),
+            false => format!("wrote {}: {}", self.path.display(), self.services.join(", ")),
+        }];
+        if let Some(hooks) = self.hooks.as_ref() {
+            lines.push(hooks.human());
+        }
+        lines.join("\n")
+    }
+}
+
+pub fn init(init_args: InitArgs) -> Result<InitOutput, Error> {
+    let InitArgs {
+        registry,
+        profile,
+        service,
+        hooks,
+        force,
+        log,
+        dry_run,
+    } = init_args;
+
+    let _span = info_span!("init").entered();
+
+    if dry_run {
+        enable_dry_run();
+    }
+
+    let workspace_dir = get_workspace_root(&env::current_dir()?)?;
+    env::set_current_dir(&workspace_dir)?;
+
+    let path = PathBuf::from(OPS_CONFIG_FILE_NAME);
+    if path.exists() && !force && !confirm(&format!("overwrite the existing {OPS_CONFIG_FILE_NAME}?"), false)? {
+        return Err(Error::InvalidInput(format!(
+            "`{}` already exists, pass --force to overwrite it",
+            workspace_dir.join(&path).display()
+        )));
+    }
+
+    let metadata = Metadata::load(&workspace_dir)?;
+    let binaries = metadata
+        .members()
+        .filter(|package| package.is_binary())
+        .collect::<Vec<_>>();
+    if let Some(unknown) = service
+        .iter()
+        .find(|name| !metadata.members().any(|package| package.name == **name))
+    {
+        return Err(Error::InvalidInput(format!(
+            "`{unknown}` is not a member of the cargo workspace"
+        )));
+    }
+
+    let registry = match registry {
+        Some(registry) => Some(registry),
+        None => input(
+            "registry to push service images to, e.g. ghcr.io/my-org (empty to not push)",
+            None,
+        )?,
+    }
+    .filter(|registry| !registry.is_empty());
+    let profile = match profile {
+        Some(profile) => profile,
+        None => input("rust profile to build service images with", Some(DEFAULT_PROFILE))?
+            .unwrap_or_else(|| DEFAULT_PROFILE.to_string()),
+    };
+    let services = match service.is_empty() {
+        true => {
+            let mut services = vec![];
+            for package in &binaries {
+                if confirm(&format!("build `{}` as a service?", package.name), true)? {
+                    services.push(package.name.clone());
+                }
+            }
+            services
+        }
+        false => service,
+    };
+
+    let mut contents = format!(
+        "# generated by `ops init`\n\n[ci]\nprofile = {}\n",
+        Value::from(&*profile)
+    );
+    if let Some(registry) = registry.as_ref() {
+        contents.push_str(&format!("registry = {}\n", Value::from(&**registry)));
+    }
+    for name in &services {
+        contents.push_str(&format!("\n[services.{name}]\n"));
+        // services with a Dockerfile of their own keep building from it
+        let dockerfile = metadata
+            .members()
+            .find(|package| package.name == *name)
+            .map(|package| package.dir().join("Dockerfile"))
+            .filter(|dockerfile| dockerfile.exists());
+        if let Some(dockerfile) = dockerfile {
+            let dockerfile = dockerfile.strip_prefix(&workspace_dir).unwrap_or(&dockerfile);
+            debug!("{name}: building from {}", dockerfile.display());
+            contents.push_str(&format!(
+                "dockerfile = {}\n",
+                Value::from(&*dockerfile.to_string_lossy())
+            ));
+        }
+    }
+    write_file(&path, &contents)?;
+
+    let install_hooks = workspace_dir.join(".git").exists()
+        && (hooks
+            || confirm(
+                "install the git hooks running fmt, clippy and eslint on changes?",
+                false,
+            )?);
+    let hooks = match install_hooks {
+        true => Some(git_hooks_install(GitHooksInstallArgs {
+            hook: vec![],
+            force: false,
+            log,
+            dry_run,
+        })?),
+        false => None,
+    };
+
+    Ok(InitOutput {
+        path: workspace_dir.join(path),
+        services,
+        registry,
+        profile,
+        hooks,
+    })
+}
@@ -0,0 +1,406 @@
+/// Loads the optional `ops.toml` configuration file from the root of a cargo workspace. Besides service and CI
+/// settings, ops.toml can set defaults for any command line flag which are resolved with the precedence
+/// built-in defaults < ops.toml < environment variables < command line flags.
+use crate::error::Error;
+use crate::git::hooks::HookCheck;
+use crate::git::pre_receive::PreReceiveCheck;
+use clap::{Arg, ArgMatches, Command};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::iter::once;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::{env, fs};
+use toml::Value;
+
+pub mod init;
+pub mod package;
+pub mod show;
+
+pub mod prelude {
+    use super::*;
+    pub use super::{
+        command_name, export_config_defaults, exported_env_vars, find_config_dir, flag_name, AuditConfig, CiConfig,
+        ClippyConfig, ComposeConfig, DependencyAuditConfig, DockerConfig, DockerContextConfig, GitConfig, HooksConfig,
+        LockConfig, OpsConfig, PackageConfig, PreReceiveConfig, RunConfig, ServiceConfig, SigningConfig,
+        OPS_CONFIG_FILE_NAME,
+    };
+    pub use init::*;
+    pub use package::*;
+    pub use show::*;
+}
+
+pub const OPS_CONFIG_FILE_NAME: &str = "ops.toml";
+
+lazy_static! {
+    static ref EXPORTED_ENV_VARS: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OpsConfig {
+    /// services keyed by the name used to refer to them in ops commands
+    pub services: BTreeMap<String, ServiceConfig>,
+    pub ci: CiConfig,
+    pub git: GitConfig,
+    pub audit: AuditConfig,
+    pub lock: LockConfig,
+    pub docker: DockerConfig,
+    pub clippy: ClippyConfig,
+    pub dependency_audit: DependencyAuditConfig,
+    /// settings of the workspace's packages keyed by their cargo package name
+    pub packages: BTreeMap<String, PackageConfig>,
+    /// patterns in `.gitignore` syntax, relative to the workspace root, of files whose changes affect every package
+    /// (e.g. a shared `build-support/` directory), in addition to the workspace Cargo.toml and Cargo.lock, the
+    /// rust-toolchain file, `.cargo/config.toml` and `clippy.toml`
+    pub full_run_paths: Vec<String>,
+    /// flag defaults keyed by the flag's long name (e.g. `profile = "release"`): top level keys apply to every
+    /// command accepting the flag, tables keyed by a command name (e.g. `[defaults.ci-matrix]`) only apply to
+    /// that command and take precedence
+    pub defaults: BTreeMap<String, Value>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServiceConfig {
+    /// cargo package name of the service, defaults to the service's key
+    pub package: Option<String>,
+    /// Dockerfile used to build the service, relative to the workspace root: if omitted the service
+    /// is expected to be built with ops-docker-build-rust-workspace-service
+    pub dockerfile: Option<PathBuf>,
+    /// comma separated feature sets to build binaries for, see `--feature-set`
+    pub feature_sets: Vec<String>,
+    /// whether to build the default binary in addition to the listed feature sets
+    pub default_feature_set: bool,
+    pub run: RunConfig,
+    pub compose: ComposeConfig,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RunConfig {
+    /// image to run, defaults to `{package}:latest` (the tag ops-docker-build-rust-workspace-service prompts with)
+    pub image: Option<String>,
+    /// published ports in `docker run --publish` syntax (e.g. `8080:80`)
+    pub ports: Vec<String>,
+    /// env files passed to `docker run --env-file`, relative to the directory of ops.toml
+    pub env_files: Vec<PathBuf>,
+    /// volumes in `docker run --volume` syntax, relative host paths are relative to the directory of ops.toml
+    pub volumes: Vec<String>,
+    /// entrypoint override, defaults to the binary built for the selected feature set
+    pub entrypoint: Option<String>,
+    /// comma separated feature set whose binary is run, defaults to the default feature set binary
+    pub feature_set: Option<String>,
+}
+
+/// Settings of the service in the compose files generated by ops-docker-compose-gen, in addition to its run settings.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ComposeConfig {
+    /// published ports in `docker run --publish` syntax, default to the run settings' or package's ports
+    pub ports: Vec<String>,
+    /// environment variables, added to the ones declared under `[package.metadata.ops.docker]`
+    pub env: BTreeMap<String, String>,
+    /// services started before this one
+    pub depends_on: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GitConfig {
+    pub pre_receive: PreReceiveConfig,
+    pub hooks: HooksConfig,
+    pub signing: SigningConfig,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PreReceiveConfig {
+    /// checks run against every pushed branch, defaults to clippy and fmt
+    pub checks: Vec<PreReceiveCheck>,
+    /// branch new branches are compared against to determine their changes, defaults to `main`
+    pub default_branch: Option<String>,
+    /// only pushes to these branches are checked, defaults to every branch
+    pub branches: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HooksConfig {
+    /// checks run by the pre-commit hook installed with `ops-git-hooks install`, defaults to fmt and eslint
+    pub pre_commit: Vec<HookCheck>,
+    /// checks run by the pre-push hook, defaults to clippy
+    pub pre_push: Vec<HookCheck>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SigningConfig {
+    /// ssh allowed signers file (see `gpg.ssh.allowedSignersFile` in git-config) used by `--require-signed`,
+    /// relative to the directory of ops.toml, defaults to the file configured in git
+    pub allowed_signers: Option<PathBuf>,
+    /// fingerprints of the GPG or SSH keys allowed to sign release refs, any valid signature is accepted if empty
+    pub allowed_keys: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    /// don't record builds and pushes in the audit log
+    pub disabled: bool,
+    /// local audit log, relative to the directory of ops.toml, defaults to `ops/audit.jsonl` in the user's state
+    /// directory (e.g. `~/.local/state`)
+    pub log: Option<PathBuf>,
+    /// remote sink every record is also sent to: an `http(s)://` url records are POSTed to or an
+    /// `s3://<bucket>/<prefix>` location each record is uploaded into
+    pub sink: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DockerConfig {
+    pub context: DockerContextConfig,
+    /// production registries or repository prefixes (e.g. `ghcr.io/my-org/prod`), ops-docker-push asks for
+    /// confirmation before pushing to them when attached to a terminal
+    pub prod_registries: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DockerContextConfig {
+    /// patterns in `.gitignore` syntax, relative to the directory of each crate, of the files besides rust sources
+    /// and manifests which are inputs of the crate's build (e.g. `*.proto` or `migrations/` read by a build script,
+    /// or assets embedded with `include_str!`): changes to them mark the crate as changed
+    pub include: Vec<String>,
+}
+
+/// Settings of a package of the workspace, `[packages.<name>]` in ops.toml.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PackageConfig {
+    /// patterns in `.gitignore` syntax, relative to the workspace root, of the files outside of the package which are
+    /// inputs of its build (e.g. shared `proto/**/*.proto` files compiled by its build script): changes to them mark
+    /// the package as changed
+    pub sources: Vec<String>,
+}
+
+/// Allowlists of ops-workspace-audit.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DependencyAuditConfig {
+    /// ids of the advisories which are not reported (e.g. `RUSTSEC-2023-0071` or `GHSA-xxxx-xxxx-xxxx`)
+    pub ignore: Vec<String>,
+    /// SPDX ids of the licenses external crates may be published under, licenses are not checked if empty
+    pub allowed_licenses: Vec<String>,
+    /// external crates exempt from the license check (e.g. crates which only point to a license file)
+    pub license_exceptions: Vec<String>,
+}
+
+/// Defaults of ops-workspace-clippy, combined with its flags.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClippyConfig {
+    /// report lints without applying clippy's fixes, see `--no-fix`
+    pub no_fix: bool,
+    /// features to lint with instead of every feature, replaced by `--features`
+    pub features: Vec<String>,
+    /// don't lint the workspace members the linted packages depend on, see `--no-deps`
+    pub no_deps: bool,
+    /// arguments passed to clippy itself after `-D warnings` (e.g. `["-W", "clippy::pedantic"]`), followed by the
+    /// ones passed on the command line
+    pub lint_args: Vec<String>,
+    /// lint every changed package with a single cargo invocation unless `--jobs` is passed, see `--batch`
+    pub batch: bool,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LockConfig {
+    /// `s3://<bucket>/<prefix>` location to additionally take image locks in, serializing builds and pushes across
+    /// machines
+    pub remote: Option<String>,
+    /// seconds after which a remote lock is considered abandoned and may be broken, defaults to an hour
+    pub ttl: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CiConfig {
+    /// name of the generated workflow, defaults to `ops`
+    pub workflow_name: Option<String>,
+    /// branches whose pushes trigger the generated workflow, defaults to `["main"]`
+    pub branches: Vec<String>,
+    /// command used to install the ops binaries in CI
+    pub install: Option<String>,
+    /// image repository prefix for built service images (e.g. `ghcr.io/my-org`), pushing is only
+    /// enabled when a registry is set
+    pub registry: Option<String>,
+    /// command used to log into the registry prior to pushing
+    pub login: Option<String>,
+    /// rust profile used to build service images, defaults to release
+    pub profile: Option<String>,
+}
+
+impl OpsConfig {
+    /// Reads `ops.toml` from `workspace_dir`, returning the default (empty) config if no file exists.
+    pub fn load(workspace_dir: &Path) -> Result<Self, Error> {
+        let path = workspace_dir.join(OPS_CONFIG_FILE_NAME);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        Self::parse(&fs::read_to_string(&path)?, &path)
+    }
+
+    /// Parses the contents of an `ops.toml`, `path` is where they were read from for error messages.
+    pub fn parse(text: &str, path: &Path) -> Result<Self, Error> {
+        toml::from_str(text).map_err(|err| Error::manifest(path, err.to_string()))
+    }
+
+    /// Loads the `ops.toml` closest to `dir`, searching its ancestors, returning the default (empty) config if
+    /// none is found.
+    pub fn discover(dir: &Path) -> Result<Self, Error> {
+        match find_config_dir(dir) {
+            Some(dir) => Self::load(dir),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// Value configured in `[defaults]` for the flag `long` of the command named `command_name`. Flags taking
+    /// several values may be configured with an array, which is joined with the flag's `delimiter`.
+    pub fn flag_default(
+        &self,
+        command_name: &str,
+        long: &str,
+        delimiter: Option<char>,
+    ) -> Result<Option<String>, Error> {
+        let value = self
+            .defaults
+            .get(command_name)
+            .and_then(Value::as_table)
+            .and_then(|defaults| defaults.get(long))
+            .or_else(|| self.defaults.get(long).filter(|value| !value.is_table()));
+        let invalid =
+            |expected: &str| Error::manifest(OPS_CONFIG_FILE_NAME, format!("default for `{long}` must be {expected}"));
+        Ok(match value {
+            None => None,
+            Some(Value::Array(values)) => {
+                let Some(delimiter) = delimiter else {
+                    return Err(invalid("a string, number or boolean"));
+                };
+                let values = values
+                    .iter()
+                    .map(|value| {
+                        scalar_default(value).ok_or_else(|| invalid("an array of strings, numbers or booleans"))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                Some(values.join(&delimiter.to_string()))
+            }
+            Some(value) => Some(scalar_default(value).ok_or_else(|| match delimiter {
+                Some(_) => invalid("a string, number, boolean or an array of them"),
+                None => invalid("a string, number or boolean"),
+            })?),
+        })
+    }
+
+    /// Exports the configured defaults of `command`'s flags through the flags' environment variables, leaving
+    /// variables which are already set untouched, so that clap resolves each flag from the command line, then
+    /// the environment, then ops.toml. Returns the names of the variables which were set.
+    pub fn export_flag_defaults(&self, command_name: &str, command: &clap::Command) -> Result<Vec<String>, Error> {
+        let mut exported = vec![];
+        for arg in command.get_arguments() {
+            let Some(env_name) = arg.get_env() else {
+                continue;
+            };
+            if env::var_os(env_name).is_some() {
+                continue;
+            }
+            if let Some(value) = self.flag_default(command_name, &flag_name(arg), arg.get_value_delimiter())? {
+                env::set_var(env_name, value);
+                let env_name = env_name.to_string_lossy().to_string();
+                EXPORTED_ENV_VARS.lock().unwrap().insert(env_name.clone());
+                exported.push(env_name);
+            }
+        }
+        Ok(exported)
+    }
+}
+
+/// String form of a scalar ops.toml default, as clap would parse it from the flag's environment variable.
+fn scalar_default(value: &Value) -> Option<String> {
+    match value {
+        Value::String(value) => Some(value.clone()),
+        Value::Integer(_) | Value::Float(_) | Value::Boolean(_) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+/// Closest directory to `dir` (inclusive) containing an `ops.toml`, relative paths in the config are relative to it.
+pub fn find_config_dir(dir: &Path) -> Option<&Path> {
+    dir.ancestors().find(|dir| dir.join(OPS_CONFIG_FILE_NAME).exists())
+}
+
+/// Exports the ops.toml flag defaults of the (sub)command invoked in `matches` and of its parent commands (whose
+/// flags may be global), see [`OpsConfig::export_flag_defaults`].
+pub fn export_config_defaults(bin_name: &str, command: &Command, matches: &ArgMatches) -> Result<Vec<String>, Error> {
+    let config = OpsConfig::discover(&env::current_dir()?)?;
+    let mut subcommands = vec![];
+    let mut commands = vec![command];
+    let mut matches = matches;
+    while let Some((name, sub_matches)) = matches.subcommand() {
+        match commands.last().unwrap().find_subcommand(name) {
+            Some(subcommand) => commands.push(subcommand),
+            None => break,
+        }
+        subcommands.push(name);
+        matches = sub_matches;
+    }
+    let command_name = command_name(bin_name, &subcommands);
+    let mut exported = vec![];
+    for command in commands {
+        exported.append(&mut config.export_flag_defaults(&command_name, command)?);
+    }
+    Ok(exported)
+}
+
+/// Environment variables which were set from ops.toml flag defaults by this process.
+pub fn exported_env_vars() -> BTreeSet<String> {
+    EXPORTED_ENV_VARS.lock().unwrap().clone()
+}
+
+/// Name identifying a (sub)command in ops.toml `[defaults.<name>]` tables and `ops config show`: the binary name
+/// without its `ops-` prefix followed by any subcommands, joined by `-` (e.g. `docker-build`, `ci-matrix`).
+pub fn command_name(bin_name: &str, subcommands: &[&str]) -> String {
+    let bin_name = bin_name.strip_prefix("ops").unwrap_or(bin_name).trim_start_matches('-');
+    once(bin_name)
+        .chain(subcommands.iter().copied())
+        .filter(|name| !name.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Matcher of the `.gitignore` syntax `patterns` set by `key` in ops.toml, matched against relative paths.
+pub(crate) fn get_pattern_matcher(key: &str, patterns: &[String]) -> Result<Gitignore, Error> {
+    let mut builder = GitignoreBuilder::new("");
+    for pattern in patterns {
+        builder
+            .add_line(None, pattern)
+            .map_err(|err| Error::InvalidInput(format!("invalid `{key}` pattern `{pattern}`: {err}")))?;
+    }
+    builder
+        .build()
+        .map_err(|err| Error::InvalidInput(format!("invalid `{key}` patterns: {err}")))
+}
+
+/// Key of a flag in ops.toml: its long name, or its kebab-cased id for positional arguments.
+pub fn flag_name(arg: &Arg) -> String {
+    arg.get_long()
+        .map(String::from)
+        .unwrap_or_else(|| arg.get_id().replace('_', "-"))
+}
+
+impl ServiceConfig {
+    pub fn package_name<'a>(&'a self, service_name: &'a str) -> &'a str {
+        self.package.as_deref().unwrap_or(service_name)
+    }
+}
@@ -0,0 +1,118 @@
+/// Per-package ops configuration declared in a package's own Cargo.toml under `[package.metadata.ops]` (or under
+/// the `ops` key of a node package's package.json), keeping service specific build knowledge next to the service.
+/// Values set here are defaults which command line flags override.
+use crate::error::Error;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use toml::Value;
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PackageOpsConfig {
+    /// comma separated feature sets to build binaries for, see `--feature-set`
+    pub feature_sets: Vec<String>,
+    /// feature matrices expanded to additional feature sets, see `--feature-matrix`
+    pub feature_matrix: Vec<String>,
+    /// whether to build the default binary in addition to the listed feature sets
+    pub default_feature_set: bool,
+    /// binary targets to build, see `--bin`
+    pub bins: Vec<String>,
+    /// rust profile to build binaries with
+    pub profile: Option<String>,
+    /// additional COPY commands included in the image prior to building, see `--copy`
+    pub copy: Vec<String>,
+    /// base image of the pre-build stage, see `--build-image`
+    pub build_image: Option<String>,
+    /// base image of the final (runtime) stage
+    pub runtime_image: Option<String>,
+    /// path of the image's entrypoint (e.g. `/app/my-service`)
+    pub entrypoint: Option<String>,
+    /// how the service runs, see [`PackageDockerConfig`]
+    pub docker: PackageDockerConfig,
+}
+
+/// Per-package ops configuration of node services, declared under the `ops` key of their package.json.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NodePackageOpsConfig {
+    /// node docker image version (e.g. `20` or `20.11-bookworm`)
+    pub node_version: Option<String>,
+    /// additional COPY commands included in the image prior to building, see `--copy`
+    pub copy: Vec<String>,
+    /// base image of the final (runtime) stage
+    pub runtime_image: Option<String>,
+    /// path of the image's entrypoint, the service's start script is run if unset
+    pub entrypoint: Option<String>,
+    /// how the service runs, see [`PackageDockerConfig`]
+    pub docker: PackageDockerConfig,
+}
+
+/// Runtime settings of a service declared under `[package.metadata.ops.docker]`: they are baked into images built
+/// by ops-docker-build-rust-workspace-service and applied by `ops docker run`, so that both describe the service
+/// the same way. Node services declare the same settings under `ops.docker` in their package.json.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PackageDockerConfig {
+    /// ports in `docker run --publish` syntax (e.g. `8080:80`), the container ports are exposed by the image
+    pub ports: Vec<String>,
+    pub healthcheck: Option<HealthcheckConfig>,
+    /// environment variables set in the image
+    pub env: BTreeMap<String, String>,
+    /// user (and optionally group) the service runs as, e.g. `nobody` or `1000:1000`
+    pub user: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthcheckConfig {
+    /// command run inside the container, in exec form (e.g. `["/app/my-service", "healthcheck"]`)
+    pub command: Vec<String>,
+    /// duration in docker syntax (e.g. `30s`)
+    pub interval: Option<String>,
+    pub timeout: Option<String>,
+    pub start_period: Option<String>,
+    pub retries: Option<u32>,
+}
+
+impl PackageDockerConfig {
+    /// Container side of each published port, e.g. `80/tcp` for `127.0.0.1:8080:80/tcp`.
+    pub fn container_ports(&self) -> impl Iterator<Item = &str> {
+        self.ports.iter().filter_map(|port| port.rsplit(':').next())
+    }
+}
+
+impl PackageOpsConfig {
+    /// Reads `[package.metadata.ops]` from the manifest of the package in `package_dir`, returning the default
+    /// (empty) config if the table is absent.
+    pub fn load(package_dir: &Path) -> Result<Self, Error> {
+        let manifest_path = package_dir.join("Cargo.toml");
+        let manifest = fs::read_to_string(&manifest_path)?.parse::<Value>()?;
+        let config = manifest
+            .get("package")
+            .and_then(|package| package.get("metadata"))
+            .and_then(|metadata| metadata.get("ops"));
+        match config {
+            Some(config) => config
+                .clone()
+                .try_into()
+                .map_err(|err| Error::manifest(&manifest_path, format!("invalid `package.metadata.ops`: {err}"))),
+            None => Ok(Self::default()),
+        }
+    }
+}
+
+impl NodePackageOpsConfig {
+    /// Reads the `ops` key of the package.json of the package in `package_dir`, returning the default (empty)
+    /// config if the key is absent.
+    pub fn load(package_dir: &Path) -> Result<Self, Error> {
+        let manifest_path = package_dir.join("package.json");
+        let manifest = serde_json::from_str::<serde_json::Value>(&fs::read_to_string(&manifest_path)?)?;
+        match manifest.get("ops") {
+            Some(config) => Self::deserialize(config)
+                .map_err(|err| Error::manifest(&manifest_path, format!("invalid `ops`: {err}"))),
+            None => Ok(Self::default()),
+        }
+    }
+}
@@ -0,0 +1,198 @@
+/// Prints the effective configuration of a command: the value every flag resolves to and which layer (command
+/// line, environment, ops.toml or built-in default) it was resolved from.
+use crate::cli::find_command;
+use crate::config::{exported_env_vars, flag_name, OPS_CONFIG_FILE_NAME};
+use crate::error::Error;
+use crate::logging::{output_format, LogArgs};
+use crate::report::{Report, ReportFormat};
+use clap::{ArgMatches, Parser, ValueSource};
+use colored::Colorize;
+use serde::Serialize;
+use std::env;
+use std::iter::once;
+use std::path::PathBuf;
+
+#[derive(Clone, Debug, Parser)]
+#[clap(author, version, about, long_about = None)]
+pub struct ConfigShowArgs {
+    /// command to resolve the configuration of, e.g. `docker-build` or `ci-matrix`
+    pub command: String,
+
+    /// output format, defaults to the format selected with `--output`
+    #[clap(short, long, value_enum, env = "OPS_CONFIG_FORMAT")]
+    pub format: Option<ReportFormat>,
+
+    #[clap(flatten)]
+    pub log: LogArgs,
+
+    /// command line arguments of the command, these take precedence over every other layer
+    #[clap(raw = true)]
+    pub args: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct EffectiveConfig {
+    pub command: String,
+    /// ops.toml which was applied, if any
+    pub config_file: Option<PathBuf>,
+    pub flags: Vec<EffectiveFlag>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct EffectiveFlag {
+    pub name: String,
+    pub env: Option<String>,
+    pub value: Option<String>,
+    pub source: FlagSource,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlagSource {
+    CommandLine,
+    Environment,
+    Config,
+    Default,
+    Unset,
+}
+
+impl Report for EffectiveConfig {
+    fn human(&self) -> String {
+        let width = self.flags.iter().map(|flag| flag.name.len()).max().unwrap_or_default();
+        let mut lines = vec![format!("{} configuration", self.command).bold().to_string()];
+        if let Some(config_file) = self.config_file.as_ref() {
+            lines.push(format!("config file: {}", config_file.display()));
+        }
+        for flag in &self.flags {
+            let source = match flag.source {
+                FlagSource::CommandLine => "command line".to_string(),
+                FlagSource::Environment => format!("env {}", flag.env.as_deref().unwrap_or_default()),
+                FlagSource::Config => OPS_CONFIG_FILE_NAME.to_string(),
+                FlagSource::Default => "default".to_string(),
+                FlagSource::Unset => "unset".to_string(),
+            };
+            lines.push(format!(
+                "  {:width$}  {}  ({})",
+                flag.name,
+                flag.value.as_deref().unwrap_or("-"),
+                source.dimmed()
+            ));
+        }
+        lines.join("\n")
+    }
+}
+
+pub fn config_show(config_show_args: ConfigShowArgs) -> Result<(), Error> {
+    let ConfigShowArgs {
+        command,
+        format,
+        log: _,
+        args,
+    } = config_show_args;
+    format
+        .unwrap_or_else(output_format)
+        .report(&get_effective_config(&command, &args)?)
+}
+
+/// Resolves the configuration the command named `command_name` would run with if invoked with `args`.
+pub fn get_effective_config(command_name: &str, args: &[String]) -> Result<EffectiveConfig, Error> {
+    let (bin_name, command, subcommands) =
+        find_command(command_name).ok_or_else(|| Error::InvalidInput(format!("unknown command `{command_name}`")))?;
+
+    let cwd = env::current_dir()?;
+    let config_file = cwd
+        .ancestors()
+        .map(|dir| dir.join(OPS_CONFIG_FILE_NAME))
+        .find(|path| path.exists());
+
+    let argv = once(bin_name.clone())
+        .chain(subcommands.iter().cloned())
+        .chain(args.iter().cloned())
+        .collect::<Vec<_>>();
+    let matches = command
+        .clone()
+        .ignore_errors(true)
+        .try_get_matches_from(&argv)
+        .map_err(|err| Error::InvalidInput(err.to_string()))?;
+    let subcommand_names = subcommands.iter().map(String::as_str).collect::<Vec<_>>();
+    super::export_config_defaults(&bin_name, &command, &matches)?;
+    // clap reads environment variables when args are defined so the command is rebuilt after exporting defaults
+    let (_, command, _) = find_command(command_name).unwrap();
+    let matches = command
+        .clone()
+        .ignore_errors(true)
+        .try_get_matches_from(&argv)
+        .map_err(|err| Error::InvalidInput(err.to_string()))?;
+
+    let exported = exported_env_vars();
+    let mut flags = vec![];
+    let mut command = &command;
+    let mut matches = &matches;
+    let mut depth = 0;
+    loop {
+        for arg in command.get_arguments() {
+            let id = arg.get_id();
+            if id == "help" || id == "version" || flags.iter().any(|flag: &EffectiveFlag| flag.name == flag_name(arg)) {
+                continue;
+            }
+            let env_name = arg.get_env().map(|env| env.to_string_lossy().to_string());
+            let source = match matches.value_source(id) {
+                Some(ValueSource::CommandLine) => FlagSource::CommandLine,
+                Some(ValueSource::EnvVariable)
+                    if env_name.as_ref().map(|env| exported.contains(env)).unwrap_or_default() =>
+                {
+                    FlagSource::Config
+                }
+                Some(ValueSource::EnvVariable) => FlagSource::Environment,
+                Some(ValueSource::DefaultValue) => FlagSource::Default,
+                _ => FlagSource::Unset,
+            };
+            flags.push(EffectiveFlag {
+                name: flag_name(arg),
+                env: env_name,
+                value: get_value(matches, id, source),
+                source,
+            });
+        }
+        let Some(subcommand_name) = subcommand_names.get(depth) else {
+            break;
+        };
+        match (
+            command.find_subcommand(*subcommand_name),
+            matches.subcommand_matches(subcommand_name),
+        ) {
+            (Some(subcommand), Some(sub_matches)) => {
+                command = subcommand;
+                matches = sub_matches;
+                depth += 1;
+            }
+            _ => break,
+        }
+    }
+
+    Ok(EffectiveConfig {
+        command: command_name.to_string(),
+        config_file,
+        flags,
+    })
+}
+
+fn get_value(matches: &ArgMatches, id: &str, source: FlagSource) -> Option<String> {
+    if source == FlagSource::Unset {
+        return None;
+    }
+    let values = matches
+        .try_get_raw(id)
+        .ok()
+        .flatten()
+        .map(|values| {
+            values
+                .map(|value| value.to_string_lossy().to_string())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    Some(match values.is_empty() {
+        true => "true".to_string(),
+        false => values.join(", "),
+    })
+}
@@ -0,0 +1,365 @@
+/// Renders a `docker buildx bake` file building every affected service with the same generated Dockerfiles as
+/// ops-docker-build-rust-workspace-service, so that bake can build all services in parallel while sharing layers
+/// and cache settings. Each service gets a `<service>-pre-build` target which its `<service>` target builds on.
+use crate::cargo::Metadata;
+use crate::ci::matrix::get_ci_matrix;
+use crate::config::package::PackageOpsConfig;
+use crate::config::OpsConfig;
+use crate::docker::build_rust_workspace::*;
+use crate::docker::dockerfile::{CacheMounts, RuntimeBase, ServiceDockerfile};
+use crate::docker::lint::check_dockerfile;
+use crate::docker::template::{DockerfileTemplate, TemplateKind};
+use crate::dry_run::{enable_dry_run, write_file};
+use crate::error::Error;
+use crate::git::diff_name_status_since_branched::DiffBaseArgs;
+use crate::logging::LogArgs;
+use crate::report::Report;
+use crate::workspace::root::get_workspace_root;
+use clap::{Parser, ValueEnum};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::path::PathBuf;
+use tracing::{debug, info_span};
+
+#[derive(Clone, Debug, Parser)]
+#[clap(author, version, about, long_about = None)]
+pub struct DockerBakeFileArgs {
+    /// include every service regardless of whether it is affected by the current git diff
+    #[clap(short, long, env = "OPS_ALL")]
+    pub all: bool,
+
+    /// bake file format
+    #[clap(short, long, value_enum, default_value = "json", env = "OPS_BAKE_FORMAT")]
+    pub format: BakeFormat,
+
+    /// path to write the bake file to, relative to the workspace root -- defaults to `docker-bake.json` or
+    /// `docker-bake.hcl` depending on the format
+    #[clap(short, long, env = "OPS_BAKE_OUTPUT")]
+    pub output_file: Option<PathBuf>,
+
+    /// which rust profile to build rust binaries, defaults to release
+    #[clap(long, env = "OPS_PROFILE")]
+    pub profile: Option<String>,
+
+    /// rust docker image version, defaults to latest
+    #[clap(short, long, env = "OPS_RUST_VERSION")]
+    pub rust_version: Option<String>,
+
+    /// keep cargo's downloads between builds in BuildKit cache mounts, `--cache-mounts=sccache` additionally
+    /// compiles through sccache -- see ops-docker-build-rust-workspace-service --cache-mounts
+    #[clap(
+        long,
+        value_enum,
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "registry",
+        env = "OPS_CACHE_MOUNTS"
+    )]
+    pub cache_mounts: Option<CacheMounts>,
+
+    /// image tag of every service, `{service}` is replaced with the service's name -- defaults to
+    /// `{service}:latest` prefixed by `ci.registry` in ops.toml
+    #[clap(short, long, env = "OPS_TAG")]
+    pub tag: Option<String>,
+
+    /// cache sources shared by every target (e.g. `type=gha`)
+    #[clap(long, value_delimiter = '\n', env = "OPS_CACHE_FROM")]
+    pub cache_from: Vec<String>,
+
+    /// cache exports shared by every target (e.g. `type=gha,mode=max`)
+    #[clap(long, value_delimiter = '\n', env = "OPS_CACHE_TO")]
+    pub cache_to: Vec<String>,
+
+    #[clap(flatten)]
+    pub diff_base: DiffBaseArgs,
+
+    #[clap(flatten)]
+    pub log: LogArgs,
+
+    /// print the bake file which would be written instead of writing it
+    /// - can also be enabled by setting the OPS_DRY_RUN environment variable
+    #[clap(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum BakeFormat {
+    Json,
+    Hcl,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct BakeFile {
+    pub group: BTreeMap<String, BakeGroup>,
+    pub target: BTreeMap<String, BakeTarget>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct BakeGroup {
+    pub targets: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BakeTarget {
+    pub context: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dockerfile: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dockerfile_inline: Option<String>,
+    /// named contexts, used to resolve a pre-build image to its target
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub contexts: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub args: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub cache_from: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub cache_to: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub secret: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub ssh: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DockerBakeFileOutput {
+    pub path: PathBuf,
+    /// targets of the default group, i.e. the service images
+    pub targets: Vec<String>,
+}
+
+impl Report for DockerBakeFileOutput {
+    fn human(&self) -> String {
+        match self.targets.is_empty() {
+            true => format!("wrote {} without any affected services", self.path.display()),
+            false => format!(
+                "wrote {}, build with `docker buildx bake -f {}`: {}",
+                self.path.display(),
+                self.path.display(),
+                self.targets.join(", ")
+            ),
+        }
+    }
+}
+
+pub fn docker_bake_file(docker_bake_file_args: DockerBakeFileArgs) -> Result<DockerBakeFileOutput, Error> {
+    let DockerBakeFileArgs {
+        all,
+        format,
+        output_file,
+        profile,
+        rust_version,
+        cache_mounts,
+        tag,
+        cache_from,
+        cache_to,
+        diff_base,
+        log: _,
+        dry_run,
+    } = docker_bake_file_args;
+
+    let _span = info_span!("docker_bake_file").entered();
+
+    if dry_run {
+        enable_dry_run();
+    }
+
+    let workspace_dir = get_workspace_root(&env::current_dir()?)?;
+    env::set_current_dir(&workspace_dir)?;
+
+    let config = OpsConfig::load(&workspace_dir)?;
+    let matrix = get_ci_matrix(&workspace_dir, all, &diff_base)?;
+
+    let profile = profile.unwrap_or_else(|| "release".to_string());
+    let build_profile = get_build_profile(&profile);
+    let tag = tag.unwrap_or_else(|| match config.ci.registry.as_ref() {
+        Some(registry) => format!("{}/{{service}}:latest", registry.trim_end_matches('/')),
+        None => "{service}:latest".to_string(),
+    });
+
+    let mut bake_file = BakeFile::default();
+    let mut targets = vec![];
+    let pre_build_template = DockerfileTemplate::load(&workspace_dir, TemplateKind::PreBuild, None)?;
+    let build_template = DockerfileTemplate::load(&workspace_dir, TemplateKind::Service, None)?;
+    let metadata = Metadata::load(&workspace_dir)?;
+    for entry in matrix.include {
+        let service_tag = tag.replace("{service}", &entry.service);
+        let target = BakeTarget {
+            context: ".".to_string(),
+            tags: vec![service_tag.clone(), format!("{service_tag}-{profile}")],
+            cache_from: cache_from.clone(),
+            cache_to: cache_to.clone(),
+            ..Default::default()
+        };
+
+        // services with their own Dockerfile are built as is
+        if let Some(dockerfile) = entry.dockerfile.as_ref() {
+            debug!("{}: using {}", entry.service, dockerfile.display());
+            bake_file.target.insert(
+                entry.service.clone(),
+                BakeTarget {
+                    dockerfile: Some(get_docker_path(dockerfile)),
+                    ..target
+                },
+            );
+            targets.push(entry.service);
+            continue;
+        }
+
+        // ops.toml feature sets take precedence over the ones declared in the package's metadata
+        let package_config = PackageOpsConfig::load(&workspace_dir.join(&entry.path))?;
+        let package = metadata
+            .local_packages()
+            .find(|package| package.name == entry.package)
+            .ok_or_else(|| Error::InvalidInput(format!("`{}` is not a package of the workspace", entry.package)))?;
+        let package_feature_sets =
+            with_feature_matrix(package, package_config.feature_sets, &package_config.feature_matrix)?;
+        let feature_sets = match entry.feature_sets.is_empty() {
+            true => get_feature_sets(&package_feature_sets, package_config.default_feature_set)?,
+            false => get_feature_sets(&entry.feature_sets, entry.default_feature_set)?,
+        };
+        let bins = get_bins(package, &package_config.bins)?;
+        let pre_build_target_name = format!("{}-pre-build", entry.service);
+        let pre_build_image_tag = format!("{service_tag}-{profile}-pre-build");
+        let pre_build_dockerfile = get_pre_build_service_dockerfile(
+            &workspace_dir,
+            rust_version.as_deref(),
+            package_config.build_image.as_deref(),
+            &entry.package,
+            &profile,
+            &feature_sets,
+            &[],
+            &[],
+            &[],
+            RuntimeBase::of(package_config.runtime_image.as_deref()).musl(),
+            cache_mounts,
+            None,
+            pre_build_template.as_ref(),
+        )?;
+        let service_dockerfile = ServiceDockerfile {
+            pre_build_image: &pre_build_image_tag,
+            service_name: &entry.package,
+            service_dir: &entry.path,
+            profile: &profile,
+            feature_sets: &feature_sets,
+            bins: &bins,
+            runtime_image: package_config.runtime_image.as_deref(),
+            entrypoint: package_config.entrypoint.as_deref(),
+            docker: &package_config.docker,
+            platforms: &[],
+            cache_mounts,
+            template: build_template.as_ref(),
+        }
+        .render()?;
+        // bake builds with the workspace root as context
+        check_dockerfile("pre-build", &pre_build_dockerfile, Some(&workspace_dir))?;
+        check_dockerfile("service", &service_dockerfile, Some(&workspace_dir))?;
+
+        let CargoCredentials { secret, ssh } = get_cargo_credentials(&pre_build_dockerfile);
+        bake_file.target.insert(
+            pre_build_target_name.clone(),
+            BakeTarget {
+                context: ".".to_string(),
+                dockerfile_inline: Some(pre_build_dockerfile),
+                args: BTreeMap::from_iter([("build_profile".to_string(), build_profile.trim().to_string())]),
+                tags: vec![pre_build_image_tag.clone()],
+                cache_from: cache_from.clone(),
+                cache_to: cache_to.clone(),
+                secret,
+                ssh,
+                ..Default::default()
+            },
+        );
+        bake_file.target.insert(
+            entry.service.clone(),
+            BakeTarget {
+                dockerfile_inline: Some(service_dockerfile),
+                contexts: BTreeMap::from_iter([(pre_build_image_tag, format!("target:{pre_build_target_name}"))]),
+                ..target
+            },
+        );
+        targets.push(entry.service);
+    }
+    bake_file.group.insert(
+        "default".to_string(),
+        BakeGroup {
+            targets: targets.clone(),
+        },
+    );
+
+    let (contents, default_output) = match format {
+        BakeFormat::Json => (serde_json::to_string_pretty(&bake_file)? + "\n", "docker-bake.json"),
+        BakeFormat::Hcl => (get_hcl(&bake_file), "docker-bake.hcl"),
+    };
+    let path = output_file.unwrap_or_else(|| PathBuf::from(default_output));
+    write_file(&path, &contents)?;
+
+    Ok(DockerBakeFileOutput { path, targets })
+}
+
+fn get_hcl(bake_file: &BakeFile) -> String {
+    let mut blocks = vec![];
+    for (name, group) in &bake_file.group {
+        blocks.push(format!(
+            "group {} {{\n  targets = {}\n}}\n",
+            hcl_string(name),
+            hcl_list(&group.targets)
+        ));
+    }
+    for (name, target) in &bake_file.target {
+        let mut attributes = vec![format!("  context = {}", hcl_string(&target.context))];
+        if let Some(dockerfile) = target.dockerfile.as_ref() {
+            attributes.push(format!("  dockerfile = {}", hcl_string(dockerfile)));
+        }
+        if let Some(dockerfile_inline) = target.dockerfile_inline.as_ref() {
+            attributes.push(format!("  dockerfile-inline = {}", hcl_string(dockerfile_inline)));
+        }
+        for (attribute, map) in [("contexts", &target.contexts), ("args", &target.args)] {
+            if !map.is_empty() {
+                let entries = map
+                    .iter()
+                    .map(|(key, value)| format!("    {} = {}\n", hcl_string(key), hcl_string(value)))
+                    .collect::<String>();
+                attributes.push(format!("  {attribute} = {{\n{entries}  }}"));
+            }
+        }
+        for (attribute, list) in [
+            ("tags", &target.tags),
+            ("cache-from", &target.cache_from),
+            ("cache-to", &target.cache_to),
+        ] {
+            if !list.is_empty() {
+                attributes.push(format!("  {attribute} = {}", hcl_list(list)));
+            }
+        }
+        blocks.push(format!(
+            "target {} {{\n{}\n}}\n",
+            hcl_string(name),
+            attributes.join("\n")
+        ));
+    }
+    blocks.join("\n")
+}
+
+fn hcl_list(values: &[String]) -> String {
+    format!(
+        "[{}]",
+        values
+            .iter()
+            .map(|value| hcl_string(value))
+            .collect::<Vec<_>>()
+            .join(", ")
+    )
+}
+
+/// Quotes `value` as an hcl string literal: json escapes are valid in hcl, only template sequences need escaping.
+fn hcl_string(value: &str) -> String {
+    serde_json::Value::String(value.to_string())
+        .to_string()
+        .replace("${", "$${")
+        .replace("%{", "%%{")
+}
@@ -1,10 +1,27 @@
-use anyhow::Error;
+use crate::dry_run::{enable_dry_run, is_dry_run, record_build_context, set_dry_run_dir, write_file};
+use crate::error::Error;
+use crate::exec::output;
+use crate::logging::LogArgs;
+use crate::progress::format_duration;
+use crate::report::{serialize_duration, Report};
+use crate::temp::temp_dir;
+use crate::timing::{time, Phase};
 use clap::Parser;
-use colored::Colorize;
-use std::fs::{read_to_string, File};
+use serde::Serialize;
+use std::fs::read_to_string;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
-use std::{env, ffi::OsStr, io::Write};
+use std::time::{Duration, Instant};
+use std::{env, ffi::OsStr, iter::once};
+use tracing::{debug, info_span, trace};
+
+/// printed by the docker cli when the daemon is not running or its socket is inaccessible
+const DOCKER_DAEMON_UNREACHABLE: &str = "Cannot connect to the Docker daemon";
+
+/// printed by the docker cli when the buildx plugin is not installed
+const BUILDX_UNAVAILABLE: &str = "'buildx' is not a docker command";
+/// number of trailing output lines of the failing step included in the error of a failed build
+const FAILED_STEP_LOG_LINES: usize = 20;
 
 #[derive(Clone, Debug, Parser)]
 #[clap(author, version, about, long_about = None, trailing_var_arg=true)]
@@ -12,11 +29,11 @@ pub struct DockerBuildArgs {
     /// Dockerfile path
     /// - defaults to a file named `Dockerfile` in the current working directory
     /// - relative paths are relative to current working directory
-    #[clap(short, long)]
+    #[clap(short, long, env = "OPS_DOCKERFILE")]
     pub file: Option<PathBuf>,
 
     /// Dockerfile contents, overrides any --file flag passed in
-    #[clap(long)]
+    #[clap(long, env = "OPS_DOCKERFILE_TEXT")]
     pub file_text: Option<String>,
 
     /// .dockerignore file override
@@ -49,115 +66,436 @@ pub struct DockerBuildArgs {
     /// - path/to/.Dockerfile.foo.dockerignore
     /// - path/to/Dockerfile.foo.dockerignore
     /// - path/to/.dockerignore
-    #[clap(short, long)]
+    #[clap(short, long, env = "OPS_DOCKER_IGNORE_FILE")]
     pub ignore_file: Option<PathBuf>,
 
-    /// log commands prior to running them
-    #[clap(short, long)]
-    pub verbose: bool,
+    /// paths of the build context sent to docker, relative to it: every other path is excluded ahead of the ignore
+    /// file's patterns, which can still exclude paths within them -- the whole context is sent if none are passed
+    #[clap(long, value_delimiter = '\n', env = "OPS_CONTEXT_INCLUDE")]
+    pub context_include: Vec<String>,
+
+    /// build with `docker buildx build` instead of `docker build`, the image is loaded into the local image store
+    /// unless the docker args pass `--push`, `--load` or `--output`
+    #[clap(long, env = "OPS_BUILDX")]
+    pub buildx: bool,
+
+    /// external cache sources (e.g. `type=registry,ref=ghcr.io/my-org/cache:api`, `type=local,src=.cache` or
+    /// `type=gha`), implies --buildx
+    #[clap(long, value_delimiter = '\n', env = "OPS_CACHE_FROM")]
+    pub cache_from: Vec<String>,
+
+    /// cache exports (e.g. `type=registry,ref=ghcr.io/my-org/cache:api,mode=max`, `type=local,dest=.cache` or
+    /// `type=gha,mode=max`), implies --buildx -- exporting to anything but `type=inline` requires a builder using
+    /// the docker-container driver (e.g. created with `docker buildx create --use`)
+    #[clap(long, value_delimiter = '\n', env = "OPS_CACHE_TO")]
+    pub cache_to: Vec<String>,
+
+    /// files of `KEY=value` lines (e.g. `.env.build`) passed as build args -- values are handed to docker through
+    /// its environment rather than its command line, but like any build arg they are recorded in the image's
+    /// history, credentials should be passed with --secret instead
+    #[clap(long, value_delimiter = '\n', env = "OPS_BUILD_ARG_FILE")]
+    pub build_arg_file: Vec<PathBuf>,
+
+    /// build secrets, mounted by `RUN --mount=type=secret,id=<id>` instructions without ending up in the image
+    /// (e.g. `id=cargo,src=.cargo/credentials.toml` or `id=npm,env=NPM_TOKEN`)
+    #[clap(long, value_delimiter = '\n', env = "OPS_SECRET")]
+    pub secret: Vec<String>,
+
+    /// export the built image with buildx's `--output` instead of loading it into the local image store (e.g.
+    /// `type=oci,dest=image.tar`, `type=docker,dest=image.tar` or `type=local,dest=out`), implies --buildx
+    #[clap(long, value_delimiter = '\n', env = "OPS_EXPORT")]
+    pub export: Vec<String>,
+
+    /// also load the built image into the local image store when exporting it with --export
+    #[clap(long, env = "OPS_LOAD")]
+    pub load: bool,
+
+    /// directory the build runs in, which the build context and relative paths are resolved against -- defaults to
+    /// the current working directory
+    #[clap(skip)]
+    pub dir: Option<PathBuf>,
+
+    #[clap(flatten)]
+    pub log: LogArgs,
+
+    /// print the generated files and docker commands instead of running them
+    /// - can also be enabled by setting the OPS_DRY_RUN environment variable
+    #[clap(long)]
+    pub dry_run: bool,
+
+    /// write the dry-run plan to this directory instead of printing it, implies --dry-run: the generated
+    /// Dockerfiles, the commands as a shell script and the paths copied from the build context
+    #[clap(long, env = "OPS_DRY_RUN_DIR")]
+    pub dry_run_dir: Option<PathBuf>,
 
     /// docker build args
     #[clap(value_parser)]
     pub docker_args: Vec<String>,
 }
 
-pub fn docker_build(docker_build_args: DockerBuildArgs) -> Result<(), Error> {
+#[derive(Clone, Debug, Serialize)]
+pub struct DockerBuildOutput {
+    /// tags applied to the built image
+    pub tags: Vec<String>,
+    /// id of the built image, unavailable in dry-run mode
+    pub image_id: Option<String>,
+    /// files and directories the image was exported to with --export
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub artifacts: Vec<BuildArtifact>,
+    #[serde(serialize_with = "serialize_duration")]
+    pub duration: Duration,
+}
+
+/// Image exported to the build host, e.g. for `ops-docker-push --oci-archive`.
+#[derive(Clone, Debug, Serialize)]
+pub struct BuildArtifact {
+    /// buildx exporter, e.g. `oci`, `docker`, `tar` or `local`
+    pub format: String,
+    pub path: PathBuf,
+    /// name the image is exported as, its first tag
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+}
+
+impl Report for DockerBuildOutput {
+    fn human(&self) -> String {
+        let image = self
+            .tags
+            .first()
+            .cloned()
+            .or_else(|| self.image_id.clone())
+            .unwrap_or_default();
+        let mut lines = vec![format!(
+            "successfully built image {image} in {}",
+            format_duration(self.duration)
+        )];
+        for artifact in &self.artifacts {
+            lines.push(format!("exported {} to {}", artifact.format, artifact.path.display()));
+        }
+        lines.join("\n")
+    }
+}
+
+pub fn docker_build(docker_build_args: DockerBuildArgs) -> Result<DockerBuildOutput, Error> {
+    let label = get_docker_tags(&docker_build_args.docker_args).join(", ");
+    time(Phase::DockerBuild, label, || build(docker_build_args))
+}
+
+fn build(docker_build_args: DockerBuildArgs) -> Result<DockerBuildOutput, Error> {
+    let start = Instant::now();
+
     let DockerBuildArgs {
         docker_args,
         file: docker_file,
         file_text,
         ignore_file,
-        verbose,
+        context_include,
+        buildx,
+        cache_from,
+        cache_to,
+        build_arg_file,
+        secret,
+        export,
+        load,
+        dir,
+        log: _,
+        dry_run,
+        dry_run_dir,
     } = docker_build_args;
 
-    let cwd = env::current_dir()?;
+    let _span = info_span!("docker_build").entered();
+
+    if dry_run {
+        enable_dry_run();
+    }
+    if let Some(dry_run_dir) = dry_run_dir.as_deref() {
+        set_dry_run_dir(dry_run_dir);
+    }
+
+    let cwd = match dir {
+        Some(dir) => dir,
+        None => env::current_dir()?,
+    };
 
     let cwd = Path::new(&cwd);
 
     let DockerConfig {
         docker_file,
         ignore_file,
-    } = get_docker_file_and_docker_ignore_file(cwd, file_text, docker_file, ignore_file, verbose)?;
-
-    // NOTE: tmp_dir and all of its contents are deleted on drop, only need
-    let tmp_dir = tempfile::tempdir()?;
+    } = get_docker_file_and_docker_ignore_file(
+        cwd,
+        file_text,
+        docker_file.map(|docker_file| cwd.join(docker_file)),
+        ignore_file.map(|ignore_file| cwd.join(ignore_file)),
+    )?;
+
+    // NOTE: generated files are only ever written to tmp_dir so that builds never mutate the workspace (the
+    // workspace itself is the build context and is never copied or archived), tmp_dir and all of its contents
+    // are deleted on drop
+    let tmp_dir = temp_dir()?;
     let tmp_dir = tmp_dir.path();
 
-    if verbose {
-        println!(
-            "{}",
-            format!(
-                "created temporary directory for Dockerfile and .dockerignore at path: {}",
-                tmp_dir.display()
-            )
-            .dimmed()
-        );
-    }
+    trace!(
+        "created temporary directory for Dockerfile and .dockerignore at path: {}",
+        tmp_dir.display()
+    );
     let tmp_docker_file_path = tmp_dir.join("Dockerfile.tmp");
     let tmp_ignore_file_path = tmp_dir.join("Dockerfile.tmp.dockerignore");
+    let tmp_image_id_file_path = tmp_dir.join("image_id");
 
-    if verbose {
-        println!(
-            "{}",
-            format!("creating Dockerfile at: {}", tmp_docker_file_path.display()).dimmed()
-        );
-    }
-    let mut docker_file_file = File::create(&tmp_docker_file_path)?;
-    if verbose {
-        println!("{}", "created Dockerfile successfully".to_string().dimmed());
+    trace!("writing to Dockerfile at path: {}", tmp_docker_file_path.display());
+    write_file(&tmp_docker_file_path, &format!("{docker_file}\n"))?;
+    if is_dry_run() {
+        record_build_context(&tmp_docker_file_path, &docker_file);
     }
 
-    if verbose {
-        println!(
-            "{}",
-            format!("writing to Dockerfile at path: {}", tmp_docker_file_path.display()).dimmed()
-        );
-    }
-    writeln!(docker_file_file, "{docker_file}")?;
+    trace!("writing to ignore file at path: {}", tmp_ignore_file_path.display());
+    let ignore_file = with_context_include(&context_include, ignore_file);
+    write_file(&tmp_ignore_file_path, &format!("{}\n", ignore_file.unwrap_or_default()))?;
 
-    if verbose {
-        println!(
-            "{}",
-            format!("creating ignore file at: {}", tmp_ignore_file_path.display()).dimmed()
-        );
+    let cmd = "docker";
+    let buildx = buildx || !cache_from.is_empty() || !cache_to.is_empty() || !export.is_empty();
+    let mut args = match buildx {
+        true => vec!["buildx", "build"],
+        false => vec!["build"],
+    };
+    args.append(&mut docker_args.iter().map(|x| &**x).collect());
+    let cache_args = cache_from
+        .iter()
+        .map(|cache| format!("--cache-from={cache}"))
+        .chain(cache_to.iter().map(|cache| format!("--cache-to={cache}")))
+        .collect::<Vec<_>>();
+    args.extend(cache_args.iter().map(String::as_str));
+    let mut build_args = vec![];
+    for path in &build_arg_file {
+        build_args.extend(read_env_file(&cwd.join(path))?);
     }
-    let mut ignore_file_file = File::create(&tmp_ignore_file_path)?;
-    if verbose {
-        println!("{}", "created ignore file successfully".to_string().dimmed());
+    for spec in &secret {
+        check_secret(spec, cwd)?;
     }
-
-    if verbose {
-        println!("{}", "writing to ignore file".to_string().dimmed());
+    // `--build-arg KEY` takes the value from docker's environment, keeping it out of the process list
+    let passthrough_args = build_args
+        .iter()
+        .flat_map(|(key, _)| ["--build-arg".to_string(), key.clone()])
+        .chain(secret.iter().map(|spec| format!("--secret={spec}")))
+        .collect::<Vec<_>>();
+    args.extend(passthrough_args.iter().map(String::as_str));
+    let export_args = export.iter().map(|spec| format!("--output={spec}")).collect::<Vec<_>>();
+    args.extend(export_args.iter().map(String::as_str));
+    // builders using the docker-container driver only keep the result in their cache otherwise
+    if buildx && (load || (export.is_empty() && !has_output_arg(&docker_args))) {
+        args.push("--load");
     }
-    writeln!(ignore_file_file, "{}", ignore_file.unwrap_or_default())?;
-
-    let cmd = "docker";
-    let mut args = vec!["build"];
-    args.append(&mut docker_args.iter().map(|x| &**x).collect());
     let tmp_docker_file_path_display = tmp_docker_file_path.display().to_string();
     args.append(&mut vec!["--file", &tmp_docker_file_path_display]);
-    if verbose {
-        println!("{}", format!("{cmd} {}", args.join(" ")).dimmed());
-        let docker_file = read_to_string(tmp_docker_file_path)?;
-        println!("{}", docker_file.dimmed());
+    let tmp_image_id_file_path_display = tmp_image_id_file_path.display().to_string();
+    args.append(&mut vec!["--iidfile", &tmp_image_id_file_path_display]);
+    debug!("{cmd} {}", args.join(" "));
+    trace!("{docker_file}");
+
+    let tags = get_docker_tags(&docker_args)
+        .into_iter()
+        .map(String::from)
+        .collect::<Vec<_>>();
+    let artifacts = export
+        .iter()
+        .filter_map(|spec| get_build_artifact(spec, tags.first()))
+        .collect::<Vec<_>>();
+
+    let output = match output(
+        Command::new(cmd)
+            .args(args)
+            .envs(build_args)
+            .current_dir(cwd)
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit()),
+    )? {
+        Some(output) => output,
+        None => {
+            return Ok(DockerBuildOutput {
+                tags,
+                image_id: None,
+                artifacts,
+                duration: start.elapsed(),
+            })
+        }
+    };
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if stderr.contains(DOCKER_DAEMON_UNREACHABLE) {
+            return Err(Error::ToolUnavailable {
+                program: "docker".to_string(),
+                message: "cannot connect to the docker daemon, is it running?".to_string(),
+            });
+        }
+        if buildx && stderr.contains(BUILDX_UNAVAILABLE) {
+            return Err(Error::ToolUnavailable {
+                program: "docker buildx".to_string(),
+                message: "the buildx plugin is not installed".to_string(),
+            });
+        }
+        let (step, log) = get_failed_step(&output.stdout, &output.stderr);
+        return Err(Error::DockerFailed {
+            stage: "build".to_string(),
+            status: output.status.code(),
+            step,
+            log,
+        });
     }
 
-    let output = Command::new(cmd)
-        .args(args)
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .output()?;
+    Ok(DockerBuildOutput {
+        tags,
+        image_id: read_to_string(&tmp_image_id_file_path)
+            .ok()
+            .map(|image_id| image_id.trim().to_string()),
+        artifacts,
+        duration: start.elapsed(),
+    })
+}
 
-    if !output.status.success() {
-        return Err(Error::msg(format!(
-            "docker failed with status {}",
-            output.status.code().unwrap()
+/// Failing instruction of a build and the end of its output, parsed from BuildKit's error summary (` > [3/9] RUN
+/// ...:` followed by the last lines the step printed) or from the `Step 3/9 : RUN ...` lines of the legacy builder.
+/// The error docker failed with is appended, the end of stderr is returned if no step is found.
+fn get_failed_step(stdout: &[u8], stderr: &[u8]) -> (Option<String>, String) {
+    let stdout = String::from_utf8_lossy(stdout);
+    let stderr = String::from_utf8_lossy(stderr);
+    let stdout = stdout.lines().collect::<Vec<_>>();
+    let stderr = stderr.lines().collect::<Vec<_>>();
+    let error = stderr.iter().rev().find(|line| line.starts_with("ERROR:")).copied();
+
+    let (step, mut log) = if let Some(start) = stderr
+        .iter()
+        .rposition(|line| line.starts_with(" > [") && line.ends_with(':'))
+    {
+        let step = stderr[start].trim_start_matches(" > ").trim_end_matches(':');
+        let log = stderr[start + 1..]
+            .iter()
+            .take_while(|line| **line != "------")
+            .copied()
+            .collect::<Vec<_>>();
+        (Some(step.to_string()), log)
+    } else if let Some(start) = stdout.iter().rposition(|line| line.starts_with("Step ")) {
+        let step = stdout[start]
+            .trim_start_matches("Step ")
+            .split_once(" : ")
+            .map(|(number, instruction)| format!("[{number}] {instruction}"));
+        // the legacy builder prints a step's output on stdout and why it failed on stderr
+        let log = stdout[start + 1..]
+            .iter()
+            .filter(|line| !line.starts_with(" ---> "))
+            .chain(stderr.iter())
+            .copied()
+            .collect();
+        (step, log)
+    } else {
+        (None, stderr.clone())
+    };
+    log = log[log.len().saturating_sub(FAILED_STEP_LOG_LINES)..].to_vec();
+    if let Some(error) = error.filter(|error| !log.contains(error)) {
+        log.push(error);
+    }
+    (step, log.join("\n").trim().to_string())
+}
+
+/// Whether the docker args already choose where buildx sends the result (`--push`, `--load` or `-o`/`--output`).
+fn has_output_arg(docker_args: &[String]) -> bool {
+    docker_args.iter().any(|arg| {
+        matches!(&**arg, "--push" | "--load" | "-o" | "--output")
+            || arg.starts_with("--output=")
+            || arg.starts_with("-o=")
+    })
+}
+
+/// `KEY=value` pairs of a dotenv style file: blank lines and `#` comments are skipped, keys may be prefixed with
+/// `export` and values may be wrapped in single or double quotes.
+pub(crate) fn read_env_file(path: &Path) -> Result<Vec<(String, String)>, Error> {
+    let contents = read_to_string(path)
+        .map_err(|err| Error::InvalidInput(format!("cannot read build arg file `{}`: {err}", path.display())))?;
+    let mut pairs = vec![];
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((key, value)) = line.split_once('=').filter(|(key, _)| !key.trim().is_empty()) else {
+            return Err(Error::InvalidInput(format!(
+                "{}:{}: expected `KEY=value`",
+                path.display(),
+                index + 1
+            )));
+        };
+        let value = value.trim();
+        let value = ['"', '\'']
+            .iter()
+            .find_map(|quote| value.strip_prefix(*quote)?.strip_suffix(*quote))
+            .unwrap_or(value);
+        pairs.push((key.trim().to_string(), value.to_string()));
+    }
+    Ok(pairs)
+}
+
+/// Checks that a `--secret` spec names its `id` and that the file it reads, if any, exists relative to `dir`.
+fn check_secret(spec: &str, dir: &Path) -> Result<(), Error> {
+    let attrs = spec
+        .split(',')
+        .filter_map(|attr| attr.split_once('='))
+        .collect::<Vec<_>>();
+    let attr = |name: &str| attrs.iter().find(|(key, _)| *key == name).map(|(_, value)| *value);
+    if attr("id").is_none() {
+        return Err(Error::InvalidInput(format!(
+            "secret `{spec}` has no id, pass e.g. `id=cargo,src=.cargo/credentials.toml`"
         )));
     }
+    match attr("src").or_else(|| attr("source")) {
+        Some(src) if !dir.join(src).exists() => Err(Error::InvalidInput(format!(
+            "source `{src}` of secret `{spec}` does not exist"
+        ))),
+        _ => Ok(()),
+    }
+}
 
-    println!("successfully built image");
+/// Artifact written to the build host by the buildx output `spec`, e.g. `oci` at `image.tar` for
+/// `type=oci,dest=image.tar`. A bare path is buildx's shorthand for `type=local,dest=<path>`, outputs which are not
+/// written to the host (e.g. `type=registry`) have no artifact.
+fn get_build_artifact(spec: &str, tag: Option<&String>) -> Option<BuildArtifact> {
+    if !spec.contains('=') {
+        return Some(BuildArtifact {
+            format: "local".to_string(),
+            path: PathBuf::from(spec),
+            image: None,
+        });
+    }
+    let attr = |name: &str| {
+        spec.split(',')
+            .find_map(|attr| attr.strip_prefix(name)?.strip_prefix('='))
+    };
+    let format = attr("type").unwrap_or("local");
+    let path = attr("dest").filter(|dest| *dest != "-")?;
+    Some(BuildArtifact {
+        format: format.to_string(),
+        path: PathBuf::from(path),
+        // buildx names image archives after the build's tags
+        image: match format {
+            "oci" | "docker" => tag.cloned(),
+            _ => None,
+        },
+    })
+}
 
-    Ok(())
+/// Returns every image tag passed in the docker args through `-t`, `--tag` or `--tag=`.
+pub fn get_docker_tags(docker_args: &[String]) -> Vec<&str> {
+    let mut tags = vec![];
+    for (i, arg) in docker_args.iter().enumerate() {
+        if (arg == "-t" || arg == "--tag") && docker_args.len() > i + 1 {
+            tags.push(&*docker_args[i + 1]);
+        }
+        if let Some(tag) = arg.strip_prefix("--tag=") {
+            tags.push(tag);
+        }
+    }
+    tags
 }
 
 pub struct SplitDockerArgs<'a> {
@@ -181,7 +519,7 @@ pub fn split_docker_args(docker_args: &[String]) -> Result<SplitDockerArgs<'_>,
             tag_slice_index = 6;
         }
     }
-    let tag_index = tag_index.ok_or_else(|| Error::msg("no image tag provided"))?;
+    let tag_index = tag_index.ok_or_else(|| Error::InvalidInput("no image tag provided".to_string()))?;
     Ok(SplitDockerArgs {
         tag: &docker_args[tag_index][tag_slice_index..],
         other: docker_args
@@ -192,6 +530,23 @@ pub fn split_docker_args(docker_args: &[String]) -> Result<SplitDockerArgs<'_>,
     })
 }
 
+/// Ignore file excluding every path of the build context but `context_include`, followed by the patterns of
+/// `ignore_file`.
+fn with_context_include(context_include: &[String], ignore_file: Option<String>) -> Option<String> {
+    if context_include.is_empty() {
+        return ignore_file;
+    }
+    let patterns = once("*".to_string())
+        .chain(
+            context_include
+                .iter()
+                .map(|path| format!("!{}", path.trim_start_matches("./"))),
+        )
+        .chain(ignore_file)
+        .collect::<Vec<_>>();
+    Some(patterns.join("\n"))
+}
+
 #[derive(Clone, Debug)]
 struct DockerConfig {
     docker_file: String,
@@ -203,7 +558,6 @@ fn get_docker_file_and_docker_ignore_file(
     file_text: Option<String>,
     docker_file: Option<PathBuf>,
     ignore_file: Option<PathBuf>,
-    verbose: bool,
 ) -> Result<DockerConfig, Error> {
     if let Some(file_text) = file_text {
         return Ok(DockerConfig {
@@ -215,14 +569,14 @@ fn get_docker_file_and_docker_ignore_file(
     let docker_file = docker_file.unwrap_or_else(|| cwd.join("Dockerfile"));
 
     if !docker_file.exists() {
-        return Err(Error::msg(format!(
+        return Err(Error::InvalidInput(format!(
             "no Dockerfile found at path: {}",
             docker_file.display()
         )));
     }
 
     let docker_file_parent = docker_file.parent().ok_or_else(|| {
-        Error::msg(format!(
+        Error::InvalidInput(format!(
             "unable to process path to Dockerfile: {}",
             docker_file.display()
         ))
@@ -233,7 +587,7 @@ fn get_docker_file_and_docker_ignore_file(
         .and_then(OsStr::to_str)
         .map(Path::new)
         .ok_or_else(|| {
-            Error::msg(format!(
+            Error::InvalidInput(format!(
                 "unable to process path to Dockerfile: {}",
                 docker_file.display()
             ))
@@ -253,7 +607,7 @@ fn get_docker_file_and_docker_ignore_file(
                         .and_then(OsStr::to_str)
                         .map(Path::new)
                         .ok_or_else(|| {
-                            Error::msg(format!(
+                            Error::InvalidInput(format!(
                                 "unable to process path to Dockerfile: {}",
                                 docker_file.display()
                             ))
@@ -294,36 +648,21 @@ fn get_docker_file_and_docker_ignore_file(
         }
     }
 
-    if verbose {
-        println!(
-            "{}",
-            format!("using Dockerfile at path: {}", docker_file.display()).dimmed()
-        );
-        match &ignore_file {
-            Some(ignore_file) => println!(
-                "{}",
-                format!("using .dockerignore at path: {}", ignore_file.display()).dimmed()
-            ),
-            None => {
-                if ignore_files.len() == 1 {
-                    println!(
-                        "{}",
-                        format!("no .dockerignore file found at path: {}", ignore_files[0].display()).dimmed()
-                    );
-                } else {
-                    println!(
-                        "{}",
-                        format!(
-                            "no .dockerignore files found at paths:{}",
-                            ignore_files
-                                .iter()
-                                .map(|x| x.display().to_string())
-                                .collect::<Vec<_>>()
-                                .join("\n - ")
-                        )
-                        .dimmed()
-                    );
-                }
+    debug!("using Dockerfile at path: {}", docker_file.display());
+    match &ignore_file {
+        Some(ignore_file) => debug!("using .dockerignore at path: {}", ignore_file.display()),
+        None => {
+            if ignore_files.len() == 1 {
+                debug!("no .dockerignore file found at path: {}", ignore_files[0].display());
+            } else {
+                debug!(
+                    "no .dockerignore files found at paths:\n - {}",
+                    ignore_files
+                        .iter()
+                        .map(|x| x.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join("\n - ")
+                );
             }
         }
     }
@@ -0,0 +1,331 @@
+/// Builds the image of a node (JavaScript/TypeScript) service of a monorepo, the node counterpart of
+/// ops-docker-build-rust-workspace-service: dependencies are installed from the lockfile in a stage which only
+/// changes along with the workspace's manifests, after which only the service and the workspace packages it depends
+/// on are copied and built.
+use crate::config::package::NodePackageOpsConfig;
+use crate::docker::build::*;
+use crate::docker::dockerfile::{NodeServiceDockerfile, DEFAULT_NODE_VERSION};
+use crate::docker::lint::check_dockerfile;
+use crate::docker::push::*;
+use crate::dry_run::{enable_dry_run, set_dry_run_dir};
+use crate::error::Error;
+use crate::logging::LogArgs;
+use crate::node::{get_node_workspace_root, NodeWorkspace, PackageManager};
+use crate::progress::{Progress, SummaryRow};
+use crate::prompt::input;
+use crate::report::{summary_table, Report};
+use clap::Parser;
+use path_absolutize::*;
+use pathdiff::diff_paths;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+use tracing::{debug, info_span};
+
+#[derive(Clone, Debug, Parser)]
+#[clap(author, version, about, long_about = None, trailing_var_arg=true)]
+pub struct DockerBuildNodeWorkspaceArgs {
+    /// path to the service to build, defaults to current working directory
+    #[clap(short, long, env = "OPS_SERVICE")]
+    pub service: Option<PathBuf>,
+
+    /// package manager to install and build with, detected from the `packageManager` field of the workspace's
+    /// package.json or its lockfile by default
+    #[clap(long, value_enum, env = "OPS_PACKAGE_MANAGER")]
+    pub package_manager: Option<PackageManager>,
+
+    /// node docker image version -- defaults to the workspace's `.nvmrc` if it pins a version, otherwise lts
+    #[clap(long, env = "OPS_NODE_VERSION")]
+    pub node_version: Option<String>,
+
+    /// script run in the service and every workspace package it depends on which declares it
+    #[clap(long, default_value = "build", env = "OPS_BUILD_SCRIPT")]
+    pub build_script: String,
+
+    /// script of the service the image runs unless an entrypoint is set, the image runs `node .` if the service
+    /// does not declare it
+    #[clap(long, default_value = "start", env = "OPS_START_SCRIPT")]
+    pub start_script: String,
+
+    /// additional COPY commands to be included in this docker image prior to building
+    #[clap(short, long, value_delimiter = '\n', env = "OPS_COPY")]
+    pub copy: Vec<String>,
+
+    /// path of the image's entrypoint, overrides --start-script
+    #[clap(long, env = "OPS_ENTRYPOINT")]
+    pub entrypoint: Option<String>,
+
+    /// base image of the final (runtime) stage -- defaults to the slim variant of the node image
+    #[clap(long, env = "OPS_RUNTIME_IMAGE")]
+    pub runtime_image: Option<String>,
+
+    /// .dockerignore file override -- defaults to the `.dockerignore` at the workspace root, which should exclude
+    /// `node_modules` so that local installs are not copied into the image
+    #[clap(short, long, env = "OPS_DOCKER_IGNORE_FILE")]
+    pub ignore_file: Option<PathBuf>,
+
+    /// build with `docker buildx build` instead of `docker build`
+    #[clap(long, env = "OPS_BUILDX")]
+    pub buildx: bool,
+
+    /// external cache sources (e.g. `type=registry,ref=ghcr.io/my-org/cache:{service}`), implies --buildx --
+    /// `{service}` is replaced with the service's image name
+    #[clap(long, value_delimiter = '\n', env = "OPS_CACHE_FROM")]
+    pub cache_from: Vec<String>,
+
+    /// cache exports (e.g. `type=registry,ref=ghcr.io/my-org/cache:{service},mode=max`), implies --buildx
+    #[clap(long, value_delimiter = '\n', env = "OPS_CACHE_TO")]
+    pub cache_to: Vec<String>,
+
+    /// push every tag of the built image once it is built, authenticating like ops-docker-push
+    #[clap(long, env = "OPS_PUSH")]
+    pub push: bool,
+
+    /// how to obtain credentials for the registry pushed to
+    #[clap(long, value_enum, default_value = "docker", env = "OPS_REGISTRY_PROVIDER")]
+    pub provider: RegistryProvider,
+
+    /// docker credential helper to authenticate pushes with, cannot be combined with --provider
+    #[clap(long, env = "OPS_CREDENTIAL_HELPER")]
+    pub credential_helper: Option<String>,
+
+    #[clap(flatten)]
+    pub log: LogArgs,
+
+    /// print the generated files and commands instead of running them
+    /// - can also be enabled by setting the OPS_DRY_RUN environment variable
+    #[clap(long)]
+    pub dry_run: bool,
+
+    /// write the dry-run plan to this directory instead of printing it, implies --dry-run: the generated
+    /// Dockerfiles, the commands as a shell script and the paths copied from the build context
+    #[clap(long, env = "OPS_DRY_RUN_DIR")]
+    pub dry_run_dir: Option<PathBuf>,
+
+    /// docker build args
+    #[clap(value_parser)]
+    pub docker_args: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DockerBuildNodeWorkspaceOutput {
+    /// package name of the service
+    pub service: String,
+    pub package_manager: PackageManager,
+    /// workspace packages copied into the image, in build order
+    pub packages: Vec<String>,
+    pub build: DockerBuildOutput,
+    /// pushed tags, empty unless --push was passed
+    pub pushed: Vec<DockerPushOutput>,
+    pub steps: Vec<SummaryRow>,
+}
+
+impl Report for DockerBuildNodeWorkspaceOutput {
+    fn human(&self) -> String {
+        let mut lines = vec![summary_table(&format!("{} image", self.service), &self.steps)];
+        lines.push(format!("image: {}", self.build.tags.join(", ")));
+        if self.packages.len() > 1 {
+            lines.push(format!("workspace packages: {}", self.packages.join(", ")));
+        }
+        for pushed in self.pushed.iter() {
+            match pushed.reference.as_ref() {
+                Some(reference) => lines.push(format!("pushed: {reference}")),
+                None => lines.push(format!("pushed: {}", pushed.image)),
+            }
+        }
+        lines.join("\n")
+    }
+}
+
+pub fn docker_build_node_workspace(
+    args: DockerBuildNodeWorkspaceArgs,
+) -> Result<DockerBuildNodeWorkspaceOutput, Error> {
+    let DockerBuildNodeWorkspaceArgs {
+        service,
+        package_manager,
+        node_version,
+        build_script,
+        start_script,
+        copy,
+        entrypoint,
+        runtime_image,
+        ignore_file,
+        buildx,
+        cache_from,
+        cache_to,
+        push,
+        provider,
+        credential_helper,
+        log,
+        dry_run,
+        dry_run_dir,
+        docker_args,
+    } = args;
+
+    let _span = info_span!("docker_build_node_workspace").entered();
+
+    if dry_run {
+        enable_dry_run();
+    }
+    if let Some(dry_run_dir) = dry_run_dir.as_deref() {
+        set_dry_run_dir(dry_run_dir);
+    }
+
+    let cwd = env::current_dir()?;
+    let service_dir = match service {
+        Some(service) => cwd.join(service).absolutize()?.to_path_buf(),
+        None => cwd,
+    };
+    let workspace_dir = get_node_workspace_root(&service_dir)?;
+    debug!("using node workspace root {}", workspace_dir.display());
+    // the workspace is the build context
+    env::set_current_dir(&workspace_dir)?;
+    let relative_service_dir = diff_paths(&service_dir, &workspace_dir).ok_or_else(|| {
+        Error::InvalidInput(format!(
+            "unable to determine relative path from workspace (`{}`) to service (`{}`)",
+            workspace_dir.display(),
+            service_dir.display()
+        ))
+    })?;
+
+    let workspace = NodeWorkspace::load(&workspace_dir)?;
+    let service = workspace.package_at(&relative_service_dir).ok_or_else(|| {
+        Error::InvalidInput(format!(
+            "`{}` is not a package of the node workspace at `{}`",
+            service_dir.display(),
+            workspace_dir.display()
+        ))
+    })?;
+    let package_manager = match package_manager {
+        Some(package_manager) => package_manager,
+        None => workspace.package_manager()?,
+    };
+    if !workspace_dir.join(package_manager.lockfile()).exists() {
+        return Err(Error::InvalidInput(format!(
+            "no {} found in `{}`, dependencies can only be installed from a lockfile",
+            package_manager.lockfile(),
+            workspace_dir.display()
+        )));
+    }
+
+    // flags override the defaults declared under `ops` in the service's package.json
+    let package_config = NodePackageOpsConfig::load(&service_dir)?;
+    let node_version = node_version
+        .or(package_config.node_version)
+        .or_else(|| get_nvmrc_version(&workspace_dir))
+        .unwrap_or_else(|| DEFAULT_NODE_VERSION.to_string());
+    let copy = match copy.is_empty() {
+        true => package_config.copy,
+        false => copy,
+    };
+    let runtime_image = runtime_image.or(package_config.runtime_image);
+    let entrypoint = entrypoint.or(package_config.entrypoint);
+
+    let config_files = package_manager
+        .config_files()
+        .iter()
+        .copied()
+        .filter(|file| workspace_dir.join(file).exists())
+        .collect::<Vec<_>>();
+    let workspace_packages = workspace.packages().values().collect::<Vec<_>>();
+    let packages = workspace.build_order(&service.name);
+
+    let dockerfile = NodeServiceDockerfile {
+        package_manager,
+        node_version: &node_version,
+        service_dir: &relative_service_dir,
+        config_files: &config_files,
+        workspace_packages: &workspace_packages,
+        packages: &packages,
+        build_script: &build_script,
+        start_script: &start_script,
+        copy: &copy,
+        runtime_image: runtime_image.as_deref(),
+        entrypoint: entrypoint.as_deref(),
+        docker: &package_config.docker,
+    }
+    .render()?;
+    check_dockerfile("service", &dockerfile, Some(&workspace_dir))?;
+
+    let image_name = get_image_name(&service.name);
+    let with_service_name = |args: Vec<String>| {
+        args.into_iter()
+            .map(|arg| arg.replace("{service}", &image_name))
+            .collect::<Vec<_>>()
+    };
+    let mut docker_args = with_service_name(docker_args);
+    let cache_from = with_service_name(cache_from);
+    let cache_to = with_service_name(cache_to);
+    if get_docker_tags(&docker_args).is_empty() {
+        let default_tag = format!("{image_name}:latest");
+        let tag = input("image tag", Some(&default_tag))?
+            .ok_or_else(|| Error::InvalidInput("no image tag provided".to_string()))?;
+        docker_args.push(format!("--tag={tag}"));
+    }
+    let tags = get_docker_tags(&docker_args)
+        .into_iter()
+        .map(String::from)
+        .collect::<Vec<_>>();
+
+    let ignore_file = ignore_file.or_else(|| {
+        let ignore_file = workspace_dir.join(".dockerignore");
+        ignore_file.exists().then_some(ignore_file)
+    });
+
+    let mut progress = Progress::new(format!("{} image", service.name), 1 + push as usize * tags.len());
+    let build = progress.run(format!("build {}", tags.join(", ")), || {
+        docker_build(DockerBuildArgs {
+            file: None,
+            file_text: Some(dockerfile),
+            ignore_file,
+            context_include: vec![],
+            buildx,
+            cache_from,
+            cache_to,
+            build_arg_file: vec![],
+            secret: vec![],
+            export: vec![],
+            load: false,
+            dir: None,
+            log,
+            dry_run,
+            dry_run_dir: None,
+            docker_args,
+        })
+    })?;
+
+    let mut pushed = vec![];
+    if push {
+        let options = push_options(None, None);
+        for tag in tags.iter() {
+            pushed.push(progress.run(format!("push {tag}"), || {
+                push_image(tag, provider, credential_helper.as_deref(), &options)
+            })?);
+        }
+    }
+
+    Ok(DockerBuildNodeWorkspaceOutput {
+        service: service.name.clone(),
+        package_manager,
+        packages: packages.iter().map(|package| package.name.clone()).collect(),
+        build,
+        pushed,
+        steps: progress.rows().to_vec(),
+    })
+}
+
+/// Docker image name of the node package `package_name`: scoped packages (`@my-org/api`) become `my-org-api`.
+fn get_image_name(package_name: &str) -> String {
+    package_name.trim_start_matches('@').replace('/', "-").to_lowercase()
+}
+
+/// Node version pinned in the workspace's `.nvmrc`, aliases like `lts/hydrogen` or `node` are not image tags and
+/// are ignored.
+fn get_nvmrc_version(workspace_dir: &Path) -> Option<String> {
+    let version = fs::read_to_string(workspace_dir.join(".nvmrc")).ok()?;
+    let version = version.trim().trim_start_matches('v');
+    match version.starts_with(|c: char| c.is_ascii_digit()) {
+        true => Some(version.to_string()),
+        false => None,
+    }
+}
@@ -1,363 +1,1245 @@
+use crate::cargo::{Metadata, MetadataPackage, WorkspaceGraph};
+use crate::config::package::PackageOpsConfig;
 use crate::docker::build::*;
-use anyhow::Error;
+use crate::docker::content_hash::*;
+use crate::docker::dockerfile::{
+    get_binary_names, CacheMounts, PreBuildDockerfile, RuntimeBase, ServiceDockerfile, CARGO_CREDENTIALS_SECRET,
+    VENDOR_CONTEXT,
+};
+use crate::docker::lint::{check_dockerfile, get_build_context_sources};
+use crate::docker::platform::{get_platforms, Platform};
+use crate::docker::push::{push_image, push_options, RegistryProvider};
+use crate::docker::scan::{scan_image, ImageScanOutput, ImageScanner, Severity, SCAN_REPORT_DIR};
+use crate::docker::template::{DockerfileTemplate, TemplateKind};
+use crate::dry_run::{enable_dry_run, set_dry_run_dir};
+use crate::du::get_cargo_home;
+use crate::error::Error;
+use crate::exec::{output, run, run_concurrently, run_or_record, with_output_prefix, ExecOptions};
+use crate::git::signature::{verify_signed_head, SignatureArgs, SignatureOutput};
+use crate::lock::{lock, LockArgs};
+use crate::logging::LogArgs;
+use crate::progress::{Progress, SummaryRow};
+use crate::prompt::{input, select};
+use crate::report::{summary_table, Report};
+use crate::temp::temp_dir;
+use crate::timing::{time, Phase};
+use crate::workspace::root::get_workspace_root;
 use clap::Parser;
 use path_absolutize::*;
 use pathdiff::diff_paths;
+use serde::Serialize;
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet};
 use std::path::{Path, PathBuf};
+use std::process::Command;
 use std::{env, fs, iter::once};
+use tempfile::TempDir;
 use toml::Value;
+use tracing::{debug, error, info, info_span, warn};
 
-const PRE_BUILD_SERVICE_DOCKERFILE: &str = include_str!("Dockerfile.pre_build_service");
-const BUILD_SERVICE_DOCKERFILE: &str = include_str!("Dockerfile.build_service");
+/// services built concurrently unless `--jobs` is passed
+const DEFAULT_SERVICE_JOBS: usize = 2;
 
 #[derive(Clone, Debug, Parser)]
 #[clap(author, version, about, long_about = None, trailing_var_arg=true)]
 pub struct DockerBuildRustWorkspaceArgs {
     /// additional COPY commands to be included in this docker image prior to building
-    #[clap(short, long)]
+    #[clap(short, long, value_delimiter = '\n', env = "OPS_COPY")]
     pub copy: Vec<String>,
 
     /// whether to build the default binary: enabled if no feature sets are passed in, otherwise defaults to false
-    #[clap(long)]
+    #[clap(long, env = "OPS_DEFAULT_FEATURE_SET")]
     pub default_feature_set: bool,
 
-    /// comma separated set of features to use for a binary build: the build will include this binary as `{package_name}_{feature_set.join("_")}`
-    #[clap(long, value_parser, action = clap::ArgAction::Append)]
+    /// comma separated set of features to use for a binary build: the build will include this binary as
+    /// `{package_name}_{feature_set.join("_")}`, or as `{package_name}_{name}` for `name={name}:{features}`
+    #[clap(long, value_parser, action = clap::ArgAction::Append, value_delimiter = '\n', env = "OPS_FEATURE_SET")]
     pub feature_set: Vec<String>,
 
+    /// feature sets to build as the cross-product of groups of alternative features (e.g.
+    /// `db:{postgres,mysql} x tls:{rustls,native}` builds four binaries), added to --feature-set -- groups are
+    /// separated by ` x `, their `name:` prefixes are optional and a single feature needs no braces
+    #[clap(long, value_parser, action = clap::ArgAction::Append, value_delimiter = '\n', env = "OPS_FEATURE_MATRIX")]
+    pub feature_matrix: Vec<String>,
+
+    /// binary target to build, may be passed several times -- defaults to every binary target of the package,
+    /// each binary is copied into the image as `/app/{bin}` (suffixed with its feature set)
+    #[clap(long, value_parser, action = clap::ArgAction::Append, value_delimiter = '\n', env = "OPS_BIN")]
+    pub bin: Vec<String>,
+
     /// .dockerignore file override
     /// - defaults to a file named `.dockerignore` in the current working directory or if a Dockerfile
     ///   is specified in it looks for a `.dockerignore` in the Dockerfile's directory with a corresponding name
     /// - relative paths are relative to current working directory
-    #[clap(short, long)]
+    #[clap(short, long, env = "OPS_DOCKER_IGNORE_FILE")]
     pub ignore_file: Option<PathBuf>,
 
     /// service dependencies to omit during pre-build (e.g. if one service depends on another, you should omit the service dependency during
     /// pre-build if both are likely to change frequently)
-    #[clap(short, long, value_parser, action = clap::ArgAction::Append)]
+    #[clap(short, long, value_parser, action = clap::ArgAction::Append, value_delimiter = '\n', env = "OPS_PRE_BUILD_OMIT")]
     pub pre_build_omit: Vec<String>,
 
     /// which rust profile to build rust binaries -- as opposed to cargo, debug and release
     /// can be specified through this flag in addition to any profiles listed in a manifest
     /// defaults to release
-    #[clap(long)]
+    #[clap(long, env = "OPS_PROFILE")]
     pub profile: Option<String>,
 
     /// rust docker image version -- actual rust version used in built binaries should be
     /// specified with a workspace level rust-toolchain.toml file -- defaults to latest
-    #[clap(short, long)]
+    #[clap(short, long, env = "OPS_RUST_VERSION")]
     pub rust_version: Option<String>,
 
-    /// path to service to build, defaults to current working directory
-    #[clap(short, long)]
-    pub service: Option<PathBuf>,
+    /// path to service to build, defaults to current working directory -- may be passed several times to build
+    /// the services concurrently, in which case every `--tag` must contain `{service}` which is replaced with the
+    /// service's package name (tags default to `{service}:latest`)
+    #[clap(short, long, value_delimiter = '\n', env = "OPS_SERVICE")]
+    pub service: Vec<PathBuf>,
+
+    /// build every binary package of the workspace concurrently
+    #[clap(long, conflicts_with = "service", env = "OPS_ALL_SERVICES")]
+    pub all_services: bool,
+
+    /// maximum number of services to build concurrently, defaults to 2: every build runs its own cargo, which uses
+    /// every core already
+    #[clap(short, long, env = "OPS_SERVICE_JOBS")]
+    pub jobs: Option<usize>,
 
     /// whether to use the default feature set built binary as the entrypoint
-    #[clap(long)]
+    #[clap(long, env = "OPS_USE_ENTRYPOINT")]
     pub use_entrypoint: bool,
 
-    /// log commands prior to running them
-    #[clap(short, long)]
-    pub verbose: bool,
+    /// path of the image's entrypoint, overrides --use-entrypoint
+    #[clap(long, env = "OPS_ENTRYPOINT")]
+    pub entrypoint: Option<String>,
+
+    /// base image of the pre-build stage, which the binaries are also compiled in, overrides --rust-version -- must
+    /// be debian based like the rust images (e.g. an internal hardened rust image), defaults to `rust:{rust_version}`
+    #[clap(long, env = "OPS_BUILD_IMAGE")]
+    pub build_image: Option<String>,
+
+    /// base image of the final (runtime) stage (e.g. `gcr.io/distroless/cc`) -- defaults to debian:11-slim, binaries
+    /// are linked against musl for alpine, `scratch` and `gcr.io/distroless/static`
+    #[clap(long, env = "OPS_RUNTIME_IMAGE")]
+    pub runtime_image: Option<String>,
+
+    /// platforms to build the image for (e.g. `linux/amd64,linux/arm64`), binaries are cross-compiled on the build
+    /// host and a multi-platform image is built with buildx -- defaults to the build host's platform
+    #[clap(long, value_delimiter = ',', env = "OPS_PLATFORM")]
+    pub platform: Vec<String>,
+
+    /// build the pre-build stage with `docker buildx build` instead of `docker build`, the service stage is only
+    /// built with buildx for --platform so that it otherwise finds the pre-build image in the local image store
+    #[clap(long, env = "OPS_BUILDX")]
+    pub buildx: bool,
+
+    /// external cache sources of the pre-build stage (e.g. `type=registry,ref=ghcr.io/my-org/cache:{service}`,
+    /// `type=local,src=.cache/{service}` or `type=gha,scope={service}`), implies --buildx -- `{service}` is replaced
+    /// with the service's package name
+    #[clap(long, value_delimiter = '\n', env = "OPS_CACHE_FROM")]
+    pub cache_from: Vec<String>,
+
+    /// cache exports of the pre-build stage (e.g. `type=registry,ref=ghcr.io/my-org/cache:{service},mode=max`,
+    /// `type=local,dest=.cache/{service}` or `type=gha,scope={service},mode=max`), implies --buildx
+    #[clap(long, value_delimiter = '\n', env = "OPS_CACHE_TO")]
+    pub cache_to: Vec<String>,
+
+    /// registry repository pre-build images are cached in (e.g. `ghcr.io/my-org/cache`), keyed on a hash of
+    /// Cargo.lock, rust-toolchain.toml, the feature sets and the internal crates the service depends on: a cached
+    /// pre-build is pulled instead of being built, otherwise the pre-build is pushed once built -- `{service}` is
+    /// replaced with the service's package name
+    #[clap(long, env = "OPS_PRE_BUILD_CACHE")]
+    pub pre_build_cache: Option<String>,
+
+    /// keep cargo's downloads between builds in BuildKit cache mounts of both stages: `--cache-mounts` mounts
+    /// cargo's registry, `--cache-mounts=sccache` additionally compiles through sccache with its cache mounted --
+    /// requires BuildKit
+    #[clap(
+        long,
+        value_enum,
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "registry",
+        env = "OPS_CACHE_MOUNTS"
+    )]
+    pub cache_mounts: Option<CacheMounts>,
+
+    /// vendor the dependencies into a temporary directory with `cargo vendor` on the host and compile the pre-build
+    /// stage offline from them, for builds which cannot reach crates.io or the workspace's registries and git
+    /// repositories -- the directory is passed to docker as a named build context, which requires BuildKit
+    #[clap(long, env = "OPS_VENDOR")]
+    pub vendor: bool,
+
+    /// template rendered instead of the embedded pre-build Dockerfile, relative to the workspace root -- defaults to
+    /// `.ops/templates/Dockerfile.pre_build_service` if it exists
+    #[clap(long, env = "OPS_PRE_BUILD_TEMPLATE")]
+    pub pre_build_template: Option<PathBuf>,
+
+    /// template rendered instead of the embedded service Dockerfile, relative to the workspace root -- defaults to
+    /// `.ops/templates/Dockerfile.build_service` if it exists
+    #[clap(long, env = "OPS_BUILD_TEMPLATE")]
+    pub build_template: Option<PathBuf>,
+
+    /// build even if an image built from identical sources, Dockerfiles and build args already exists locally or
+    /// the pre-build is cached
+    #[clap(long, env = "OPS_FORCE")]
+    pub force: bool,
+
+    /// send the whole workspace as the build context instead of only the paths the generated Dockerfiles copy
+    #[clap(long, env = "OPS_FULL_CONTEXT")]
+    pub full_context: bool,
+
+    /// scan the built image for vulnerabilities with trivy or grype, which has to be installed
+    #[clap(long, value_enum, env = "OPS_SCAN")]
+    pub scan: Option<ImageScanner>,
+
+    /// fail if the scan finds a vulnerability of this severity or higher
+    #[clap(long, value_enum, default_value = "high", env = "OPS_SCAN_FAIL_ON")]
+    pub scan_fail_on: Severity,
+
+    /// file the scanner's json report is written to, `{service}` is replaced with the service's name -- defaults to
+    /// `ops-scan/<service>.json` in the target directory
+    #[clap(long, env = "OPS_SCAN_REPORT")]
+    pub scan_report: Option<PathBuf>,
+
+    #[clap(flatten)]
+    pub signature: SignatureArgs,
+
+    #[clap(flatten)]
+    pub lock: LockArgs,
+
+    #[clap(flatten)]
+    pub log: LogArgs,
+
+    /// print the generated files and commands instead of running them
+    /// - can also be enabled by setting the OPS_DRY_RUN environment variable
+    #[clap(long)]
+    pub dry_run: bool,
+
+    /// write the dry-run plan to this directory instead of printing it, implies --dry-run: the generated
+    /// Dockerfiles, the commands as a shell script and the paths copied from the build context
+    #[clap(long, env = "OPS_DRY_RUN_DIR")]
+    pub dry_run_dir: Option<PathBuf>,
 
     /// docker build args
     #[clap(value_parser)]
     pub docker_args: Vec<String>,
 }
 
-pub fn docker_build_rust_workspace(args: DockerBuildRustWorkspaceArgs) -> Result<(), Error> {
+#[derive(Clone, Debug, Serialize)]
+pub struct DockerBuildRustWorkspaceOutput {
+    pub service: String,
+    pub profile: String,
+    /// final image of the service
+    pub image: String,
+    /// hash of the service's sources and build inputs, recorded in the image's `ops.content-hash` label
+    pub content_hash: String,
+    /// whether the build was skipped as `image` was already built from the same content
+    pub skipped: bool,
+    pub pre_build: Option<DockerBuildOutput>,
+    /// image the pre-build is cached as if --pre-build-cache was passed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pre_build_cache: Option<PreBuildCacheOutput>,
+    pub build: Option<DockerBuildOutput>,
+    /// paths of the binaries copied into the final image
+    pub binaries: Vec<String>,
+    /// binaries built per feature set
+    pub feature_sets: Vec<FeatureSetOutput>,
+    pub steps: Vec<SummaryRow>,
+    /// signed ref the image was built from if `--require-signed` was passed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signature: Option<SignatureOutput>,
+    /// vulnerability scan of the image if `--scan` was passed and the image was built
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scan: Option<ImageScanOutput>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct FeatureSetOutput {
+    /// name the binaries are suffixed with instead of the features, if the feature set is named
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// empty for the default binary
+    pub features: Vec<String>,
+    /// paths of the binaries built with the features
+    pub binaries: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PreBuildCacheOutput {
+    pub image: String,
+    /// whether the pre-build was pulled from the cache rather than built
+    pub hit: bool,
+}
+
+impl Report for DockerBuildRustWorkspaceOutput {
+    fn human(&self) -> String {
+        let mut lines = vec![];
+        if self.skipped {
+            lines.push(format!("image: {} (unchanged, build skipped)", self.image));
+        } else {
+            lines.push(summary_table(&format!("{} image", self.service), &self.steps));
+            lines.push(format!("image: {}", self.image));
+        }
+        if let Some(PreBuildCacheOutput { image, hit: true }) = self.pre_build_cache.as_ref() {
+            lines.push(format!("pre-build: {image} (cached)"));
+        }
+        if !self.binaries.is_empty() {
+            lines.push(format!("binaries: {}", self.binaries.join(", ")));
+        }
+        if let Some(scan) = self.scan.as_ref() {
+            lines.push(format!("scan: {} ({})", scan.summary(), scan.report.display()));
+        }
+        lines.join("\n")
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DockerBuildRustWorkspaceServicesOutput {
+    pub services: Vec<DockerBuildRustWorkspaceOutput>,
+}
+
+impl Report for DockerBuildRustWorkspaceServicesOutput {
+    fn human(&self) -> String {
+        self.services.iter().map(Report::human).collect::<Vec<_>>().join("\n\n")
+    }
+}
+
+/// Builds a single service, see [`docker_build_rust_workspace_services`] to build several.
+pub fn docker_build_rust_workspace(
+    args: DockerBuildRustWorkspaceArgs,
+) -> Result<DockerBuildRustWorkspaceOutput, Error> {
+    let _span = info_span!("docker_build_rust_workspace").entered();
+
+    if args.dry_run {
+        enable_dry_run();
+    }
+    if let Some(dry_run_dir) = args.dry_run_dir.as_deref() {
+        set_dry_run_dir(dry_run_dir);
+    }
+
+    let mut service_dirs = get_service_dirs(&env::current_dir()?, &args.service, args.all_services)?;
+    if service_dirs.len() != 1 {
+        return Err(Error::InvalidInput(format!(
+            "expected a single service to build, found {}",
+            service_dirs.len()
+        )));
+    }
+    let service_dir = service_dirs.remove(0);
+    let vendor = args
+        .vendor
+        .then(|| vendor_dependencies(&get_workspace_root(&service_dir)?))
+        .transpose()?;
+    build_service(args, service_dir, false, vendor.as_ref())
+}
+
+/// Builds every requested service, at most `--jobs` of them concurrently. The first service is built on its own:
+/// the workspace wide dependency layers of its pre-build stage are identical in every service's pre-build, so the
+/// remaining services reuse them from docker's layer cache instead of compiling the same dependencies side by side.
+/// Builds which already started are finished after a failure but no further builds are started.
+pub fn docker_build_rust_workspace_services(
+    args: DockerBuildRustWorkspaceArgs,
+) -> Result<DockerBuildRustWorkspaceServicesOutput, Error> {
+    let _span = info_span!("docker_build_rust_workspace_services").entered();
+
+    if args.dry_run {
+        enable_dry_run();
+    }
+    if let Some(dry_run_dir) = args.dry_run_dir.as_deref() {
+        set_dry_run_dir(dry_run_dir);
+    }
+
+    let service_dirs = get_service_dirs(&env::current_dir()?, &args.service, args.all_services)?;
+    let several = service_dirs.len() > 1;
+    if several {
+        if let Some(tag) = get_docker_tags(&args.docker_args)
+            .into_iter()
+            .find(|tag| !tag.contains("{service}"))
+        {
+            return Err(Error::InvalidInput(format!(
+                "tag `{tag}` must contain `{{service}}` when building several services"
+            )));
+        }
+    }
+
+    // the services of a workspace share its vendored dependencies
+    let mut vendored = BTreeMap::new();
+    if args.vendor {
+        for service_dir in &service_dirs {
+            if let Entry::Vacant(entry) = vendored.entry(get_workspace_root(service_dir)?) {
+                let vendor = vendor_dependencies(entry.key())?;
+                entry.insert(vendor);
+            }
+        }
+    }
+    let vendor = |service_dir: &Path| -> Result<_, Error> { Ok(vendored.get(&get_workspace_root(service_dir)?)) };
+
+    let mut service_dirs = service_dirs.into_iter();
+    let Some(first_service_dir) = service_dirs.next() else {
+        return Err(Error::InvalidInput("no services to build".to_string()));
+    };
+    let first_vendor = vendor(&first_service_dir)?;
+    let mut services = vec![build_service(args.clone(), first_service_dir, several, first_vendor)?];
+
+    let results = run_concurrently(
+        service_dirs.collect(),
+        args.jobs.unwrap_or(DEFAULT_SERVICE_JOBS),
+        |_, _, service_dir| {
+            let result = vendor(&service_dir)
+                .and_then(|vendor| build_service(args.clone(), service_dir.clone(), several, vendor));
+            if let Err(err) = result.as_ref() {
+                error!("failed to build {}: {err}", service_dir.display());
+            }
+            result
+        },
+        Result::is_err,
+    )
+    .into_iter()
+    .flatten()
+    .collect::<Vec<_>>();
+    if let Some(err) = results.iter().find_map(|result| match result {
+        Err(Error::Cancelled) => Some(Error::Cancelled),
+        _ => None,
+    }) {
+        return Err(err);
+    }
+    for result in results {
+        services.push(result?);
+    }
+
+    Ok(DockerBuildRustWorkspaceServicesOutput { services })
+}
+
+/// Dependencies vendored with `--vendor`, shared by the services of a workspace.
+struct VendoredDependencies {
+    /// temporary directory holding the vendored sources, removed once dropped
+    dir: TempDir,
+    /// source replacement printed by `cargo vendor`
+    source_replacement: String,
+}
+
+/// Vendors the dependencies of the workspace at `workspace_dir` into a temporary directory with `cargo vendor`. The
+/// services of a workspace share the vendored dependencies, so they are vendored once before any of them is built.
+fn vendor_dependencies(workspace_dir: &Path) -> Result<VendoredDependencies, Error> {
+    let dir = temp_dir()?;
+    info!("vendoring dependencies into {}", dir.path().display());
+    let mut command = Command::new("cargo");
+    command
+        .args(["vendor", "--locked"])
+        .arg(dir.path())
+        .current_dir(workspace_dir);
+    let output = run_or_record(
+        &command,
+        &ExecOptions {
+            stream: false,
+            ..Default::default()
+        },
+    )?;
+    let source_replacement = match output {
+        Some(output) => String::from_utf8_lossy(&output.stdout).to_string(),
+        // nothing is vendored in dry-run mode, the plan shows the replacement of crates.io
+        None => format!(
+            "[source.crates-io]\nreplace-with = \"vendored-sources\"\n\n[source.vendored-sources]\ndirectory = {}\n",
+            Value::from(&*dir.path().to_string_lossy())
+        ),
+    };
+    Ok(VendoredDependencies {
+        dir,
+        source_replacement,
+    })
+}
+
+/// Builds the service in `service_dir`, `several` is set if other services are built alongside it and `vendor` are
+/// the dependencies vendored with `--vendor`.
+fn build_service(
+    args: DockerBuildRustWorkspaceArgs,
+    service_dir: PathBuf,
+    several: bool,
+    vendor: Option<&VendoredDependencies>,
+) -> Result<DockerBuildRustWorkspaceOutput, Error> {
     let DockerBuildRustWorkspaceArgs {
         copy,
         docker_args,
         default_feature_set,
         feature_set,
+        feature_matrix,
+        bin,
         ignore_file,
         pre_build_omit,
         profile,
         rust_version,
-        service: provided_service_dir,
+        service: _,
+        all_services: _,
+        jobs: _,
         use_entrypoint,
-        verbose,
+        entrypoint,
+        build_image,
+        runtime_image,
+        platform,
+        buildx,
+        cache_from,
+        cache_to,
+        pre_build_cache,
+        cache_mounts,
+        vendor: _,
+        pre_build_template,
+        build_template,
+        force,
+        full_context,
+        scan,
+        scan_fail_on,
+        scan_report,
+        signature,
+        lock: lock_args,
+        log,
+        dry_run,
+        dry_run_dir: _,
     } = args;
 
-    let cwd = env::current_dir()?;
-    let cwd = Path::new(&cwd);
-
-    let mut service_dir = cwd.to_path_buf();
-    if let Some(provided_service_dir) = provided_service_dir.as_ref() {
-        if !provided_service_dir.has_root() {
-            service_dir = cwd.join(provided_service_dir).absolutize()?.to_path_buf();
-        } else {
-            service_dir = provided_service_dir.clone();
-        }
-    }
-
-    let service_manifest = fs::read_to_string(service_dir.join("Cargo.toml"))?.parse::<Value>()?;
-    let service_name = service_manifest
-        .get("package")
-        .ok_or_else(|| Error::msg("cannot parse service level Cargo.toml: missing key `package`"))?
-        .get("name")
-        .ok_or_else(|| Error::msg("cannot parse service level Cargo.toml: missing key `package.name`"))?
-        .as_str()
-        .ok_or_else(|| Error::msg("cannot parse service level Cargo.toml: key `package.name` must be a string"))?;
+    let workspace_dir = get_workspace_root(&service_dir)?;
+    let workspace_dir = workspace_dir.as_path();
+    let metadata = Metadata::load(workspace_dir)?;
+    // cargo metadata reports canonical paths
+    let canonical_service_dir = service_dir.canonicalize()?;
+    let package = metadata
+        .local_packages()
+        .find(|package| package.dir() == canonical_service_dir)
+        .ok_or_else(|| {
+            Error::InvalidInput(format!(
+                "`{}` is not a package of the workspace at `{}`",
+                service_dir.display(),
+                workspace_dir.display()
+            ))
+        })?;
+    let service_name = &*package.name;
 
-    let mut feature_sets: Vec<Vec<&str>> = feature_set.iter().map(|x| x.split(',').collect()).collect();
+    // flags override the defaults declared in the service's `[package.metadata.ops]`
+    let package_config = PackageOpsConfig::load(&service_dir)?;
+    let feature_set = match feature_set.is_empty() && feature_matrix.is_empty() {
+        true => with_feature_matrix(package, package_config.feature_sets, &package_config.feature_matrix)?,
+        false => with_feature_matrix(package, feature_set, &feature_matrix)?,
+    };
+    let default_feature_set = default_feature_set || package_config.default_feature_set;
+    let profile = profile.or(package_config.profile);
+    let copy = match copy.is_empty() {
+        true => package_config.copy,
+        false => copy,
+    };
+    let build_image = build_image.or(package_config.build_image);
+    let runtime_image = runtime_image.or(package_config.runtime_image);
+    let bins = match bin.is_empty() {
+        true => get_bins(package, &package_config.bins)?,
+        false => get_bins(package, &bin)?,
+    };
+    let entrypoint = match (entrypoint, use_entrypoint) {
+        (Some(entrypoint), _) => Some(entrypoint),
+        (None, true) => Some(format!("/app/{}", bins.first().map_or(service_name, String::as_str))),
+        (None, false) => package_config.entrypoint,
+    };
 
-    if feature_sets.is_empty() || default_feature_set {
-        // important to push the default binary to the back so that as we build each binary,
-        // we can rename them with their features and the first binary isn't replaced (would
-        // be if it is the default binary since it doesn't receive a rename)
-        feature_sets.push(vec![]);
-    }
+    let feature_sets = get_feature_sets(&feature_set, default_feature_set)?;
+    let platforms = get_platforms(&platform)?;
 
-    let workspace_dir = get_workspace_dir(&service_dir)?;
-    env::set_current_dir(&workspace_dir)?;
+    let signature = verify_signed_head(&signature, workspace_dir)?;
 
-    let relative_service_dir = diff_paths(&service_dir, &workspace_dir).ok_or_else(|| {
-        Error::msg("unable to determine relative path from workspace (`{workspace_dir}`) to service (`{service_dir}`)")
+    let relative_service_dir = diff_paths(&service_dir, workspace_dir).ok_or_else(|| {
+        Error::InvalidInput(format!(
+            "unable to determine relative path from workspace (`{}`) to service (`{}`)",
+            workspace_dir.display(),
+            service_dir.display()
+        ))
     })?;
 
+    let with_service_name = |args: Vec<String>| {
+        args.into_iter()
+            .map(|arg| arg.replace("{service}", service_name))
+            .collect::<Vec<_>>()
+    };
+    let mut docker_args = with_service_name(docker_args);
+    let cache_from = with_service_name(cache_from);
+    let cache_to = with_service_name(cache_to);
+    let pre_build_cache = pre_build_cache.map(|repository| repository.replace("{service}", service_name));
+    let scan_report = match scan_report {
+        Some(scan_report) => workspace_dir.join(scan_report.to_string_lossy().replace("{service}", service_name)),
+        None => metadata
+            .target_directory
+            .join(SCAN_REPORT_DIR)
+            .join(format!("{service_name}.json")),
+    };
+    if get_docker_tags(&docker_args).is_empty() {
+        let default_tag = format!("{service_name}:latest");
+        let tag = match several {
+            true => default_tag,
+            false => input("image tag", Some(&default_tag))?
+                .ok_or_else(|| Error::InvalidInput("no image tag provided".to_string()))?,
+        };
+        docker_args.push(format!("--tag={tag}"));
+    }
+
     let SplitDockerArgs { tag, other } = split_docker_args(&docker_args)?;
     let args_without_image_tag = other.into_iter().map(String::from).collect::<Vec<_>>();
     let profile = profile.unwrap_or_else(|| "release".to_string());
-    let build_profile = if profile == "debug" {
-        "".to_string()
-    } else if profile == "release" {
-        " --release".to_string()
-    } else {
-        format!(" --profile={profile}")
-    };
+    let build_profile = get_build_profile(&profile);
 
     let build_service_image_tag = format!("{tag}-{profile}");
     let pre_build_service_image_tag = format!("{tag}-{profile}-pre-build");
+    // held until the image is built and tagged, keyed by the produced image as ops-docker-push locks the image it
+    // pushes
+    let _lock = lock(&build_service_image_tag, &lock_args)?;
+
+    // the pre-build image is always built for the build host, which cross-compiles for every other platform
+    if !platforms.is_empty() {
+        let platforms = platforms.iter().map(|platform| platform.name).collect::<Vec<_>>();
+        docker_args.push(format!("--platform={}", platforms.join(",")));
+    }
 
     let mut pre_build_service_docker_args = args_without_image_tag;
-    let build_profile_arg = match &*profile {
-        "debug" => "build_profile=".to_string(),
-        "release" => "build_profile=--release".to_string(),
-        _ => format!("build_profile=--profile={profile}"),
-    };
+    let build_profile_arg = format!("build_profile={}", build_profile.trim());
     pre_build_service_docker_args.append(&mut vec!["--build-arg".to_string(), build_profile_arg]);
 
+    let pre_build_template =
+        DockerfileTemplate::load(workspace_dir, TemplateKind::PreBuild, pre_build_template.as_deref())?;
+    let build_template = DockerfileTemplate::load(workspace_dir, TemplateKind::Service, build_template.as_deref())?;
+    let pre_build_service_dockerfile = get_pre_build_service_dockerfile(
+        workspace_dir,
+        rust_version.as_deref(),
+        build_image.as_deref(),
+        service_name,
+        &profile,
+        &feature_sets,
+        &copy,
+        &pre_build_omit,
+        &platforms,
+        RuntimeBase::of(runtime_image.as_deref()).musl(),
+        cache_mounts,
+        vendor.map(|vendor| &*vendor.source_replacement),
+        pre_build_template.as_ref(),
+    )?;
+    let build_service_dockerfile = ServiceDockerfile {
+        pre_build_image: &pre_build_service_image_tag,
+        service_name,
+        service_dir: &relative_service_dir,
+        profile: &profile,
+        feature_sets: &feature_sets,
+        bins: &bins,
+        runtime_image: runtime_image.as_deref(),
+        entrypoint: entrypoint.as_deref(),
+        docker: &package_config.docker,
+        platforms: &platforms,
+        cache_mounts,
+        template: build_template.as_ref(),
+    }
+    .render()?;
+
+    check_dockerfile("pre-build", &pre_build_service_dockerfile, Some(workspace_dir))?;
+    check_dockerfile("service", &build_service_dockerfile, Some(workspace_dir))?;
+
+    let feature_sets_output = feature_sets
+        .iter()
+        .map(|feature_set| FeatureSetOutput {
+            name: feature_set.name.map(String::from),
+            features: feature_set.features.iter().map(|feature| feature.to_string()).collect(),
+            binaries: get_binary_names(service_name, &bins, std::slice::from_ref(feature_set))
+                .into_iter()
+                .map(|binary| format!("/app/{binary}"))
+                .collect(),
+        })
+        .collect::<Vec<_>>();
+    let binaries = feature_sets_output
+        .iter()
+        .flat_map(|feature_set| feature_set.binaries.clone())
+        .collect();
+
+    let ContentHash { hash: content_hash, .. } = get_content_hash(
+        workspace_dir,
+        service_name,
+        &[
+            &pre_build_service_dockerfile,
+            &build_service_dockerfile,
+            &pre_build_service_docker_args.join(" "),
+            &docker_args.join(" "),
+        ],
+    )?;
+    if !force && get_image_content_hash(&build_service_image_tag).as_ref() == Some(&content_hash) {
+        info!("{build_service_image_tag} is up to date, skipping build");
+        // the existing image may not carry every requested tag yet
+        for tag in get_docker_tags(&docker_args) {
+            let output = output(Command::new("docker").args(["tag", &build_service_image_tag, tag]))?;
+            if let Some(output) = output.filter(|output| !output.status.success()) {
+                return Err(Error::DockerFailed {
+                    stage: "tag".to_string(),
+                    status: output.status.code(),
+                    step: None,
+                    log: String::new(),
+                });
+            }
+        }
+        // the image was scanned when it was built, its report is kept as is
+        if scan.is_some() {
+            info!("not scanning {build_service_image_tag}, pass --force to rebuild and scan it");
+        }
+        return Ok(DockerBuildRustWorkspaceOutput {
+            service: service_name.to_string(),
+            profile,
+            image: build_service_image_tag,
+            content_hash,
+            skipped: true,
+            pre_build: None,
+            pre_build_cache: None,
+            build: None,
+            binaries,
+            feature_sets: feature_sets_output,
+            steps: vec![],
+            signature,
+            scan: None,
+        });
+    }
+
+    // the pre-build only compiles dependencies, so it can be reused from the cache until they change
+    let pre_build_cache = match pre_build_cache {
+        Some(repository) => {
+            // names only rename binaries, which the pre-build does not keep
+            let feature_sets = format!(
+                "{:?}",
+                feature_sets
+                    .iter()
+                    .map(|feature_set| &feature_set.features)
+                    .collect::<Vec<_>>()
+            );
+            let ContentHash { hash, .. } = get_pre_build_hash(
+                workspace_dir,
+                service_name,
+                &relative_service_dir,
+                &[
+                    &pre_build_service_dockerfile,
+                    &pre_build_service_docker_args.join(" "),
+                    &feature_sets,
+                ],
+            )?;
+            let image = format!("{repository}:{service_name}-{profile}-pre-build-{}", &hash[..16]);
+            let hit = !force && remote_image_exists(&image);
+            debug!("pre-build cache {}: {image}", if hit { "hit" } else { "miss" });
+            Some(PreBuildCacheOutput { image, hit })
+        }
+        None => None,
+    };
+    let push_pre_build = pre_build_cache.as_ref().is_some_and(|cache| !cache.hit);
+
+    let cargo_credentials = get_cargo_credentials(&pre_build_service_dockerfile);
+    let (pre_build_context_include, build_context_include) = match full_context {
+        true => (vec![], vec![]),
+        false => (
+            get_context_include(&pre_build_service_dockerfile),
+            get_context_include(&build_service_dockerfile),
+        ),
+    };
+    let mut progress = Progress::new(
+        format!("{service_name} image"),
+        2 + push_pre_build as usize + scan.is_some() as usize,
+    );
+    // forwarded docker output is prefixed by stage, services may be built side by side
+    let pre_build_label = format!("{service_name} pre-build");
+    let build_label = format!("{service_name} build");
+
     // pre-build
-    docker_build(DockerBuildArgs {
-        docker_args: pre_build_service_docker_args
-            .clone()
-            .into_iter()
-            .chain(once(format!("--tag={pre_build_service_image_tag}")))
-            .collect(),
-        file: None,
-        file_text: Some(get_pre_build_service_dockerfile(
-            workspace_dir,
-            &rust_version,
-            service_name,
-            &profile,
-            &build_profile,
-            &feature_sets,
-            &copy,
-            &pre_build_omit,
-        )?),
-        ignore_file: ignore_file.clone(),
-        verbose,
-    })?;
+    let pre_build = match pre_build_cache.as_ref().filter(|cache| cache.hit) {
+        Some(cache) => {
+            progress.run(format!("pull pre-build {}", cache.image), || {
+                with_output_prefix(&pre_build_label, || {
+                    time(Phase::DockerPreBuild, &cache.image, || {
+                        pull_pre_build(&cache.image, &pre_build_service_image_tag)
+                    })
+                })
+            })?;
+            None
+        }
+        None => Some(progress.run(format!("pre-build {pre_build_service_image_tag}"), || {
+            with_output_prefix(&pre_build_label, || {
+                time(Phase::DockerPreBuild, &pre_build_service_image_tag, || {
+                    docker_build(DockerBuildArgs {
+                        docker_args: pre_build_service_docker_args
+                            .clone()
+                            .into_iter()
+                            .chain(once(format!("--tag={pre_build_service_image_tag}")))
+                            .chain(cargo_credentials.ssh.iter().map(|ssh| format!("--ssh={ssh}")))
+                            .chain(vendor.map(|vendor| {
+                                format!("--build-context={VENDOR_CONTEXT}={}", vendor.dir.path().display())
+                            }))
+                            .collect(),
+                        file: None,
+                        file_text: Some(pre_build_service_dockerfile),
+                        ignore_file: ignore_file.clone(),
+                        context_include: pre_build_context_include,
+                        buildx: buildx || !platforms.is_empty(),
+                        cache_from,
+                        cache_to,
+                        build_arg_file: vec![],
+                        secret: cargo_credentials.secret.clone(),
+                        export: vec![],
+                        load: false,
+                        dir: Some(workspace_dir.to_path_buf()),
+                        log: log.clone(),
+                        dry_run,
+                        dry_run_dir: None,
+                    })
+                })
+            })
+            .map_err(|err| with_docker_stage(err, "pre-build"))
+        })?),
+    };
+
+    // a failed push only costs the next build the cache hit, so it does not fail this build
+    if let Some(cache) = pre_build_cache.as_ref().filter(|_| push_pre_build) {
+        let pushed = progress.run(format!("push pre-build {}", cache.image), || {
+            with_output_prefix(&pre_build_label, || {
+                tag_and_push_pre_build(&pre_build_service_image_tag, &cache.image)
+            })
+        });
+        if let Err(err) = pushed {
+            warn!("unable to cache the pre-build as {}: {err}", cache.image);
+        }
+    }
 
     // build service
-    docker_build(DockerBuildArgs {
-        file: None,
-        file_text: Some(get_build_service_dockerfile(
-            &pre_build_service_image_tag,
-            service_name,
-            &relative_service_dir,
-            &profile,
-            &build_profile,
-            &feature_sets,
-            use_entrypoint,
-        )?),
-        docker_args: docker_args
-            .clone()
-            .into_iter()
-            .chain(once(format!("--tag={build_service_image_tag}")))
-            .collect(),
-        ignore_file,
-        verbose,
+    let build = progress.run(format!("build {build_service_image_tag}"), || {
+        with_output_prefix(&build_label, || {
+            docker_build(DockerBuildArgs {
+                file: None,
+                file_text: Some(build_service_dockerfile),
+                docker_args: docker_args
+                    .clone()
+                    .into_iter()
+                    .chain(once(format!("--tag={build_service_image_tag}")))
+                    .chain(once(format!("--label={CONTENT_HASH_LABEL}={content_hash}")))
+                    .collect(),
+                ignore_file,
+                context_include: build_context_include,
+                buildx: !platforms.is_empty(),
+                cache_from: vec![],
+                cache_to: vec![],
+                build_arg_file: vec![],
+                secret: vec![],
+                export: vec![],
+                load: false,
+                dir: Some(workspace_dir.to_path_buf()),
+                log,
+                dry_run,
+                dry_run_dir: None,
+            })
+        })
+        .map_err(|err| with_docker_stage(err, "service build"))
     })?;
 
-    Ok(())
+    let scan = match scan {
+        Some(scanner) => Some(progress.run(format!("{scanner} scan {build_service_image_tag}"), || {
+            scan_image(scanner, &build_service_image_tag, &scan_report, scan_fail_on)
+        })?),
+        None => None,
+    };
+
+    Ok(DockerBuildRustWorkspaceOutput {
+        service: service_name.to_string(),
+        profile,
+        image: build_service_image_tag,
+        content_hash,
+        skipped: false,
+        pre_build,
+        pre_build_cache,
+        build: Some(build),
+        binaries,
+        feature_sets: feature_sets_output,
+        steps: progress.rows().to_vec(),
+        signature,
+        scan,
+    })
 }
 
-fn get_features_flag(feature_set: &[&str]) -> String {
-    if feature_set.is_empty() {
-        "".into()
-    } else {
-        format!(" --features={}", feature_set.join(","))
+/// Whether `image` exists in its registry, checked without pulling it.
+fn remote_image_exists(image: &str) -> bool {
+    let options = ExecOptions {
+        stream: false,
+        ..Default::default()
+    };
+    run(Command::new("docker").args(["manifest", "inspect", image]), &options).is_ok()
+}
+
+/// Pulls the cached pre-build `image` and tags it as `pre_build_image`, which the service stage builds on.
+fn pull_pre_build(image: &str, pre_build_image: &str) -> Result<(), Error> {
+    for args in [vec!["pull", image], vec!["tag", image, pre_build_image]] {
+        let output = output(Command::new("docker").args(&args))?;
+        if let Some(output) = output.filter(|output| !output.status.success()) {
+            return Err(Error::DockerFailed {
+                stage: format!("pre-build {}", args[0]),
+                status: output.status.code(),
+                step: None,
+                log: String::new(),
+            });
+        }
     }
+    Ok(())
 }
 
-fn get_pre_build_service_dockerfile(
-    workspace_dir: &Path,
-    rust_version: &Option<String>,
-    service_name: &str,
-    profile: &str,
-    build_profile: &str,
-    feature_sets: &[Vec<&str>],
-    copy: &[String],
-    pre_build_omit: &[String],
-) -> Result<String, Error> {
-    let rust_toolchain_path = workspace_dir.join("rust-toolchain.toml");
-
-    let rustup_toolchain_override = "COPY rust-toolchain.toml rust-toolchain.toml\n  RUN cat rust-toolchain.toml | tomlq -t '.toolchain.profile = \"minimal\"' > rust-toolchain2.toml && mv rust-toolchain2.toml rust-toolchain.toml";
-    let rustup_update = "RUN rustup update";
-    let rustup_toolchain = if rust_toolchain_path.exists() {
-        format!("{rustup_toolchain_override}\n  {rustup_update}")
-    } else {
-        rustup_update.to_string()
-    };
+/// Tags the built `pre_build_image` as the cache `image` and pushes it with the credentials docker is logged in
+/// with.
+fn tag_and_push_pre_build(pre_build_image: &str, image: &str) -> Result<(), Error> {
+    let output = output(Command::new("docker").args(["tag", pre_build_image, image]))?;
+    if let Some(output) = output.filter(|output| !output.status.success()) {
+        return Err(Error::DockerFailed {
+            stage: "pre-build tag".to_string(),
+            status: output.status.code(),
+            step: None,
+            log: String::new(),
+        });
+    }
+    push_image(image, RegistryProvider::Docker, None, &push_options(None, None))?;
+    Ok(())
+}
 
-    let full_cargo_lock = fs::read_to_string(workspace_dir.join("Cargo.lock"))?.parse::<Value>()?;
+fn with_docker_stage(err: Error, stage: &str) -> Error {
+    match err {
+        Error::DockerFailed { status, step, log, .. } => Error::DockerFailed {
+            stage: stage.to_string(),
+            status,
+            step,
+            log,
+        },
+        err => err,
+    }
+}
 
-    let mut full_cargo_lock = match full_cargo_lock {
-        Value::Table(table) => table,
-        _ => return Err(Error::msg("unable to parse Cargo.lock: file is not a toml table")),
-    };
+/// Features of a binary to build, parsed from a `--feature-set`: comma separated features optionally preceded by
+/// `name={name}:`, which names the binary after `name` rather than after its features.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct FeatureSet<'a> {
+    pub name: Option<&'a str>,
+    /// empty for the default binary
+    pub features: Vec<&'a str>,
+}
 
-    let cargo_lock_package = full_cargo_lock
-        .remove("package")
-        .ok_or_else(|| Error::msg("unable to parse Cargo.lock: no package field found"))?;
+impl<'a> FeatureSet<'a> {
+    pub fn parse(feature_set: &'a str) -> Result<Self, Error> {
+        let (name, features) = match feature_set.strip_prefix("name=") {
+            Some(named) => {
+                let (name, features) = named.split_once(':').unwrap_or((named, ""));
+                if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+                    return Err(Error::InvalidInput(format!(
+                        "invalid feature set `{feature_set}`: names may only contain letters, digits, `_` and `-`"
+                    )));
+                }
+                (Some(name), features)
+            }
+            None => (None, feature_set),
+        };
+        Ok(Self {
+            name,
+            features: features.split(',').filter(|feature| !feature.is_empty()).collect(),
+        })
+    }
 
-    let packages = match cargo_lock_package {
-        Value::Array(packages) => packages,
-        _ => {
-            return Err(Error::msg(
-                "unable to parse Cargo.lock: no package field is not an array",
-            ))
+    /// Suffix of the names of binaries built with the feature set, e.g. `_metrics_tracing` or `_{name}`, empty for
+    /// the default binary.
+    pub fn suffix(&self) -> String {
+        match self.name {
+            Some(name) => format!("_{name}"),
+            None => self.features.iter().map(|feature| format!("_{feature}")).collect(),
         }
-    };
-    let packages = packages
-        .into_iter()
-        .filter(|package| match package {
-            Value::Table(table) => table.contains_key("source") && table.contains_key("checksum"),
-            _ => true,
-        })
-        .collect();
+    }
+}
 
-    let fetch_cargo_lock_toml = Value::Table(toml::value::Map::from_iter([(
-        "package".to_string(),
-        Value::Array(packages),
-    )]));
-    let fetch_cargo_lock_toml = toml::ser::to_string(&fetch_cargo_lock_toml)?;
+/// Feature sets to build a binary for from `feature_set`s (see [`FeatureSet::parse`]), the default binary is built
+/// if no feature sets are given or `default_feature_set` is set. Fails if two feature sets would name their binaries
+/// the same.
+pub(crate) fn get_feature_sets(
+    feature_set: &[String],
+    default_feature_set: bool,
+) -> Result<Vec<FeatureSet<'_>>, Error> {
+    let mut feature_sets = feature_set
+        .iter()
+        .map(|feature_set| FeatureSet::parse(feature_set))
+        .collect::<Result<Vec<_>, _>>()?;
 
-    let mut additional_copies = copy
+    if feature_sets.is_empty() || default_feature_set {
+        // important to push the default binary to the back so that as we build each binary,
+        // we can rename them with their features and the first binary isn't replaced (would
+        // be if it is the default binary since it doesn't receive a rename)
+        feature_sets.push(FeatureSet::default());
+    }
+    let mut suffixes = BTreeSet::new();
+    if let Some(duplicate) = feature_sets
         .iter()
-        .enumerate()
-        .map(|(i, line)| {
-            let line = line.trim();
-            if i == 0 {
-                line.to_string()
-            } else {
-                format!("  {line}")
-            }
-        })
-        .collect::<Vec<_>>()
-        .join("\n");
+        .find(|feature_set| !suffixes.insert(feature_set.suffix()))
+    {
+        return Err(Error::InvalidInput(format!(
+            "several feature sets build the binary suffixed `{}`",
+            duplicate.suffix()
+        )));
+    }
+    Ok(feature_sets)
+}
+
+/// Binary targets of `package` to build: `bin` if passed, which must be binary targets of the package, otherwise
+/// every binary target. Empty if the package only has the binary named after it, which cargo builds without `--bin`.
+pub(crate) fn get_bins(package: &MetadataPackage, bin: &[String]) -> Result<Vec<String>, Error> {
+    if let Some(unknown) = bin.iter().find(|bin| !package.bins().any(|name| name == *bin)) {
+        return Err(Error::InvalidInput(format!(
+            "`{unknown}` is not a binary target of `{}`, its binary targets are: {}",
+            package.name,
+            package.bins().collect::<Vec<_>>().join(", ")
+        )));
+    }
+    if !bin.is_empty() {
+        return Ok(bin.to_vec());
+    }
+    let bins = package.bins().map(String::from).collect::<Vec<_>>();
+    match bins.as_slice() {
+        [] => Err(Error::InvalidInput(format!(
+            "`{}` has no binary targets to build",
+            package.name
+        ))),
+        [name] if *name == package.name => Ok(vec![]),
+        _ => Ok(bins),
+    }
+}
 
-    if !additional_copies.is_empty() {
-        additional_copies = format!("\n\n{additional_copies}");
+/// Comma separated `feature_set`s followed by the feature sets `feature_matrix` expands to, see
+/// [`expand_feature_matrix`].
+pub(crate) fn with_feature_matrix(
+    package: &MetadataPackage,
+    mut feature_set: Vec<String>,
+    feature_matrix: &[String],
+) -> Result<Vec<String>, Error> {
+    for matrix in feature_matrix {
+        feature_set.extend(expand_feature_matrix(package, matrix)?);
     }
+    Ok(feature_set)
+}
 
-    let pre_build_omit_deps = format!(
-        r#"'{{{}}}'"#,
-        pre_build_omit
+/// Comma separated feature sets of the cross-product of the groups of `matrix`, e.g. `postgres,rustls`,
+/// `postgres,native`, `mysql,rustls` and `mysql,native` for `db:{postgres,mysql} x tls:{rustls,native}`. Fails if
+/// a feature is not declared by `package`, features of dependencies (`dependency/feature`) are left to cargo.
+pub(crate) fn expand_feature_matrix(package: &MetadataPackage, matrix: &str) -> Result<Vec<String>, Error> {
+    let invalid = |message: String| Error::InvalidInput(format!("invalid feature matrix `{matrix}`: {message}"));
+    let mut feature_sets = vec![vec![]];
+    for group in matrix.split(" x ").map(str::trim) {
+        // the group's name only documents the matrix
+        let features = match group.split_once(':') {
+            Some((_, features)) if !group.starts_with('{') => features.trim(),
+            _ => group,
+        };
+        let features = match features.strip_prefix('{') {
+            Some(features) => features
+                .strip_suffix('}')
+                .ok_or_else(|| invalid(format!("unclosed `{{` in `{group}`")))?
+                .split(',')
+                .map(str::trim)
+                .collect::<Vec<_>>(),
+            None => vec![features],
+        };
+        if let Some(feature) = features
             .iter()
-            .map(|x| format!(r#""{x}": true"#))
-            .collect::<Vec<_>>()
-            .join(",")
-    );
+            .find(|feature| feature.is_empty() || feature.contains(['{', '}', ',', ' ']))
+        {
+            return Err(invalid(format!("`{feature}` of `{group}` is not a feature")));
+        }
+        if let Some(unknown) = features
+            .iter()
+            .find(|feature| !feature.contains('/') && !package.features.contains_key(**feature))
+        {
+            return Err(invalid(format!(
+                "`{unknown}` is not a feature of `{}`, its features are: {}",
+                package.name,
+                package.features.keys().cloned().collect::<Vec<_>>().join(", ")
+            )));
+        }
+        feature_sets = feature_sets
+            .into_iter()
+            .flat_map(|feature_set: Vec<&str>| {
+                features.iter().map(move |feature| {
+                    let mut feature_set = feature_set.clone();
+                    feature_set.push(feature);
+                    feature_set
+                })
+            })
+            .collect();
+    }
+    Ok(feature_sets
+        .into_iter()
+        .map(|feature_set| feature_set.join(","))
+        .collect())
+}
 
-    let mut service_docker_pre_builds = feature_sets
-        .iter()
-        .map(|feature_set| format!("  RUN cargo build{build_profile}{}", get_features_flag(feature_set)))
-        .collect::<Vec<_>>();
-    service_docker_pre_builds.push(format!(
-        "  RUN rm /app/target/{profile}/rust_build && rm /app/target/{profile}/{service_name}"
-    ));
-
-    let dockerfile = PRE_BUILD_SERVICE_DOCKERFILE
-        .replace(
-            "$rust_version",
-            rust_version.as_ref().map(|x| &**x).unwrap_or_else(|| "latest"),
-        )
-        .replace("$rustup_toolchain", &rustup_toolchain)
-        .replace(
-            "$fetch_cargo_lock",
-            &format!("RUN echo '{}' > Cargo.lock", fetch_cargo_lock_toml).replace('\n', "\\n\\\n"),
-        )
-        .replace("$service", service_name)
-        .replace("$profile", profile)
-        .replace("$file_copy", &additional_copies)
-        .replace("$pre_build_omit_deps", &pre_build_omit_deps)
-        .replace("$pre_build_service", service_docker_pre_builds.join("\n").trim());
-
-    Ok(dockerfile.trim().to_string())
+/// `cargo build` flag selecting `profile`, with a leading space.
+pub(crate) fn get_build_profile(profile: &str) -> String {
+    match profile {
+        "debug" => "".to_string(),
+        "release" => " --release".to_string(),
+        _ => format!(" --profile={profile}"),
+    }
 }
 
-fn get_build_service_dockerfile(
-    pre_build_service_image_tag: &str,
+/// Renders the pre-build Dockerfile of a service from the Cargo.lock, rust-toolchain.toml and internal crates of
+/// `workspace_dir`, `musl` should be set for runtime images binaries are linked against musl for (see
+/// [`RuntimeBase::musl`]).
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn get_pre_build_service_dockerfile(
+    workspace_dir: &Path,
+    rust_version: Option<&str>,
+    build_image: Option<&str>,
     service_name: &str,
-    relative_service_dir: &PathBuf,
     profile: &str,
-    build_profile: &str,
-    feature_sets: &[Vec<&str>],
-    use_entrypoint: bool,
+    feature_sets: &[FeatureSet],
+    copy: &[String],
+    pre_build_omit: &[String],
+    platforms: &[Platform],
+    musl: bool,
+    cache_mounts: Option<CacheMounts>,
+    vendor: Option<&str>,
+    template: Option<&DockerfileTemplate>,
 ) -> Result<String, Error> {
-    let service_docker_build_binaries = feature_sets
-        .iter()
-        .map(|feature_set| {
-            let features_flag = get_features_flag(feature_set);
-            let build_cmd = format!("  RUN cargo build{build_profile}{features_flag}");
-            if feature_set.is_empty() {
-                return build_cmd;
-            }
-            let feature_set = feature_set.iter().map(|x| format!("_{x}")).collect::<Vec<_>>().join("");
-            format!("{build_cmd}\n  RUN mv /app/target/{profile}/{service_name} /app/target/{profile}/{service_name}{feature_set}")
-        })
+    let cargo_lock_path = workspace_dir.join("Cargo.lock");
+    let cargo_lock = fs::read_to_string(&cargo_lock_path)?;
+    let cargo_config = fs::read_to_string(workspace_dir.join(".cargo").join("config.toml")).ok();
+    let graph = WorkspaceGraph::from_metadata(&Metadata::load(workspace_dir)?)?;
+    let local_crates = graph
+        .transitive_dependencies_of([service_name])
+        .into_iter()
+        .filter(|name| *name != service_name)
+        .map(|name| &graph.packages()[name])
         .collect::<Vec<_>>();
+    PreBuildDockerfile {
+        service_name,
+        profile,
+        feature_sets,
+        rust_version,
+        build_image,
+        rust_toolchain: workspace_dir.join("rust-toolchain.toml").exists(),
+        cargo_lock: &cargo_lock,
+        cargo_config: cargo_config.as_deref(),
+        vendor,
+        copy,
+        pre_build_omit,
+        platforms,
+        musl,
+        local_crates: &local_crates,
+        cache_mounts,
+        template,
+    }
+    .render()
+    .map_err(|err| match err {
+        Error::ManifestParse { path, message } if path == Path::new("Cargo.lock") => {
+            Error::manifest(&cargo_lock_path, message)
+        }
+        err => err,
+    })
+}
 
-    let service_docker_copy_binaries = feature_sets
-        .iter()
-        .map(|feature_set| {
-            let feature_set = feature_set.iter().map(|x| format!("_{x}")).collect::<Vec<_>>().join("");
-            format!("  COPY --from=build /app/target/{profile}/{service_name}{feature_set} /app/{service_name}{feature_set}")
-        })
-        .collect::<Vec<_>>();
+/// Secrets and ssh agents forwarded to a build.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct CargoCredentials {
+    /// `--secret` specs
+    pub secret: Vec<String>,
+    /// `--ssh` specs
+    pub ssh: Vec<String>,
+}
 
-    let build_service_dockerfile = BUILD_SERVICE_DOCKERFILE
-        .replace("$pre_build_service_image_tag", pre_build_service_image_tag)
-        .replace("$service_dir", &relative_service_dir.display().to_string())
-        .replace("$build", service_docker_build_binaries.join("\n").trim())
-        .replace("$binary_copy", service_docker_copy_binaries.join("\n").trim())
-        .replace(
-            "$entrypoint",
-            &match use_entrypoint {
-                true => format!(r#"ENTRYPOINT ["/app/{service_name}"]"#),
-                false => format!(""),
-            },
-        );
-
-    Ok(build_service_dockerfile.trim().to_string())
+/// Cargo's credentials (`$CARGO_HOME/credentials.toml`) and the ssh agent, forwarded to pre-build stages fetching
+/// from private registries or git repositories (see [`CARGO_CREDENTIALS_SECRET`]) if they are available.
+pub(crate) fn get_cargo_credentials(pre_build_dockerfile: &str) -> CargoCredentials {
+    if !pre_build_dockerfile.contains(&format!("id={CARGO_CREDENTIALS_SECRET},")) {
+        return CargoCredentials::default();
+    }
+    let credentials = get_cargo_home().join("credentials.toml");
+    CargoCredentials {
+        secret: match credentials.is_file() {
+            true => vec![format!("id={CARGO_CREDENTIALS_SECRET},src={}", credentials.display())],
+            false => vec![],
+        },
+        ssh: match env::var_os("SSH_AUTH_SOCK") {
+            Some(_) => vec!["default".to_string()],
+            None => vec![],
+        },
+    }
 }
 
-fn get_workspace_dir(service_dir: &Path) -> Result<&Path, Error> {
-    let mut dir = service_dir;
-    Ok(loop {
-        dir = dir
-            .parent()
-            .ok_or_else(|| Error::msg("unable to locate cargo workspace root"))?;
-        let manifest_path = dir.join("Cargo.toml");
-        if manifest_path.exists() {
-            let manifest = fs::read_to_string(manifest_path)?.parse::<Value>()?;
-            if manifest.get("workspace").is_some() {
-                break dir;
-            }
+/// Paths of the workspace the build context of `dockerfile` is restricted to: the paths it copies, which for the
+/// generated Dockerfiles are the workspace's manifest, Cargo.lock and toolchain file and the service along with the
+/// internal crates it transitively depends on. Empty, sending the whole workspace, if a copied path is only known
+/// once the build runs or is the workspace itself.
+pub(crate) fn get_context_include(dockerfile: &str) -> Vec<String> {
+    let mut paths = BTreeSet::new();
+    for source in get_build_context_sources(dockerfile) {
+        if source.contains('$') || Path::new(&source) == Path::new(".") {
+            return vec![];
         }
-    })
+        paths.insert(source);
+    }
+    paths.into_iter().collect()
+}
+
+/// `path` with `/` separators as expected in Dockerfiles and by docker regardless of the host's separator.
+pub(crate) fn get_docker_path(path: &Path) -> String {
+    path.components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Absolute directories of the services to build: every binary package of the workspace with `all_services`,
+/// otherwise the `services` passed (relative to `cwd`) or, if none were, `cwd` itself if it is a package and the
+/// package picked by the user if it is not.
+fn get_service_dirs(cwd: &Path, services: &[PathBuf], all_services: bool) -> Result<Vec<PathBuf>, Error> {
+    if all_services {
+        let workspace_dir = get_workspace_root(cwd)?;
+        return Ok(WorkspaceGraph::from_metadata(&Metadata::load(&workspace_dir)?)?
+            .members()
+            .filter(|package| package.binary)
+            .map(|package| workspace_dir.join(&package.path))
+            .collect());
+    }
+    if services.is_empty() {
+        return Ok(vec![match is_package_dir(cwd)? {
+            true => cwd.to_path_buf(),
+            false => select_service_dir(cwd)?,
+        }]);
+    }
+    let mut service_dirs = Vec::<PathBuf>::with_capacity(services.len());
+    for service in services {
+        let service_dir = match service.is_relative() {
+            true => cwd.join(service).absolutize()?.to_path_buf(),
+            false => service.clone(),
+        };
+        if !service_dirs.contains(&service_dir) {
+            service_dirs.push(service_dir);
+        }
+    }
+    Ok(service_dirs)
+}
+
+fn is_package_dir(dir: &Path) -> Result<bool, Error> {
+    let manifest_path = dir.join("Cargo.toml");
+    if !manifest_path.exists() {
+        return Ok(false);
+    }
+    let manifest = fs::read_to_string(manifest_path)?.parse::<Value>()?;
+    Ok(manifest.get("package").is_some())
+}
+
+/// Prompts for which of the workspace's binary packages to build when not run from within a package.
+fn select_service_dir(cwd: &Path) -> Result<PathBuf, Error> {
+    let missing_service = || {
+        Error::InvalidInput(format!(
+            "`{}` is not a cargo package, pass the path of the service to build with --service",
+            cwd.display()
+        ))
+    };
+    let workspace_dir = get_workspace_root(cwd).map_err(|_| missing_service())?;
+    let graph = WorkspaceGraph::from_metadata(&Metadata::load(&workspace_dir)?)?;
+    let services = graph
+        .packages()
+        .values()
+        .filter(|package| package.binary)
+        .collect::<Vec<_>>();
+    let names = services.iter().map(|package| package.name.clone()).collect::<Vec<_>>();
+    let index = select("service to build", &names)?.ok_or_else(missing_service)?;
+    Ok(workspace_dir.join(&services[index].path))
 }
@@ -0,0 +1,250 @@
+/// Applies a retention policy to the docker build cache and to the pre-build images (tagged `*-pre-build`) created
+/// by ops-docker-build-rust-workspace-service and `ops docker warm-cache`, which otherwise accumulate on long-lived
+/// runners with every dependency change. Pre-build images are grouped per service (image repository) and removed
+/// once they exceed the maximum age, the number of images kept per service or, oldest first, the size budget.
+use crate::dry_run::{enable_dry_run, is_dry_run};
+use crate::error::Error;
+use crate::exec::{read, run_or_record, ExecOptions};
+use crate::logging::LogArgs;
+use crate::progress::{format_bytes, parse_bytes};
+use crate::report::Report;
+use clap::Parser;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::process::Command;
+use tracing::{debug, info_span};
+
+#[derive(Clone, Debug, Parser)]
+#[clap(author, version, about, long_about = None)]
+pub struct DockerCachePruneArgs {
+    /// pre-build images and build cache entries older than this many days are removed
+    #[clap(long, default_value = "7", env = "OPS_MAX_AGE")]
+    pub max_age: u64,
+
+    /// total size to shrink pre-build images and, separately, the build cache to (e.g. `20GB`), the oldest
+    /// pre-build images are removed first
+    #[clap(long, value_parser = parse_bytes, env = "OPS_MAX_SIZE")]
+    pub max_size: Option<u64>,
+
+    /// number of most recent pre-build images kept per service
+    #[clap(long, env = "OPS_KEEP_PER_SERVICE")]
+    pub keep_per_service: Option<usize>,
+
+    /// only prune pre-build images, leaving the build cache untouched
+    #[clap(long, env = "OPS_SKIP_BUILD_CACHE")]
+    pub skip_build_cache: bool,
+
+    #[clap(flatten)]
+    pub log: LogArgs,
+
+    /// report what would be removed instead of removing it
+    /// - can also be enabled by setting the OPS_DRY_RUN environment variable
+    #[clap(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DockerCachePruneOutput {
+    pub removed: Vec<CachePruneItem>,
+    /// space reclaimed from the build cache as reported by docker, unknown in dry-run mode
+    pub build_cache_bytes: u64,
+    /// total space reclaimed
+    pub bytes: u64,
+    pub dry_run: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CachePruneItem {
+    pub image: String,
+    pub service: String,
+    pub reason: CachePruneReason,
+    pub bytes: u64,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CachePruneReason {
+    /// older than the maximum age
+    MaxAge,
+    /// more pre-build images of the service than are kept
+    KeepPerService,
+    /// removed to shrink pre-build images below the maximum size
+    MaxSize,
+}
+
+impl CachePruneReason {
+    fn description(&self) -> &'static str {
+        match self {
+            Self::MaxAge => "older than max age",
+            Self::KeepPerService => "over per-service count",
+            Self::MaxSize => "over max size",
+        }
+    }
+}
+
+struct PreBuildImage {
+    image: String,
+    service: String,
+    bytes: u64,
+    expired: bool,
+}
+
+impl Report for DockerCachePruneOutput {
+    fn human(&self) -> String {
+        let verb = if self.dry_run { "would free" } else { "freed" };
+        let mut lines = vec![format!("{verb} {}", format_bytes(self.bytes))];
+        let mut totals = BTreeMap::<CachePruneReason, (usize, u64)>::default();
+        for item in &self.removed {
+            let total = totals.entry(item.reason).or_default();
+            total.0 += 1;
+            total.1 += item.bytes;
+        }
+        for (reason, (count, bytes)) in totals {
+            lines.push(format!(
+                "  {:24} {count:6}  {}",
+                reason.description(),
+                format_bytes(bytes)
+            ));
+        }
+        if self.build_cache_bytes > 0 {
+            lines.push(format!(
+                "  {:24} {:6}  {}",
+                "build cache",
+                "",
+                format_bytes(self.build_cache_bytes)
+            ));
+        }
+        lines.join("\n")
+    }
+}
+
+pub fn docker_cache_prune(docker_cache_prune_args: DockerCachePruneArgs) -> Result<DockerCachePruneOutput, Error> {
+    let DockerCachePruneArgs {
+        max_age,
+        max_size,
+        keep_per_service,
+        skip_build_cache,
+        log: _,
+        dry_run,
+    } = docker_cache_prune_args;
+
+    let _span = info_span!("docker_cache_prune").entered();
+
+    if dry_run {
+        enable_dry_run();
+    }
+
+    let until = format!("until={}h", max_age * 24);
+
+    // images are listed newest first so the most recent images of every service are kept
+    let mut removed = vec![];
+    let mut kept_per_service = BTreeMap::<String, usize>::default();
+    let mut kept = vec![];
+    for image in get_pre_build_images(&until)? {
+        let kept_count = kept_per_service.entry(image.service.clone()).or_default();
+        let reason = if image.expired {
+            Some(CachePruneReason::MaxAge)
+        } else if keep_per_service.is_some_and(|keep_per_service| *kept_count >= keep_per_service) {
+            Some(CachePruneReason::KeepPerService)
+        } else {
+            None
+        };
+        match reason {
+            Some(reason) => removed.push((image, reason)),
+            None => {
+                *kept_count += 1;
+                kept.push(image);
+            }
+        }
+    }
+    if let Some(max_size) = max_size {
+        let mut size = kept.iter().map(|image| image.bytes).sum::<u64>();
+        while size > max_size {
+            let Some(image) = kept.pop() else {
+                break;
+            };
+            size -= image.bytes;
+            removed.push((image, CachePruneReason::MaxSize));
+        }
+    }
+
+    let mut items = vec![];
+    for (image, reason) in removed {
+        debug!(
+            "removing {} ({}): {}",
+            image.image,
+            format_bytes(image.bytes),
+            reason.description()
+        );
+        let mut command = Command::new("docker");
+        command.args(["image", "rm", &image.image]);
+        run_or_record(&command, &ExecOptions::default())?;
+        items.push(CachePruneItem {
+            image: image.image,
+            service: image.service,
+            reason,
+            bytes: image.bytes,
+        });
+    }
+
+    let mut build_cache_bytes = 0;
+    if !skip_build_cache {
+        let mut command = Command::new("docker");
+        command.args(["builder", "prune", "--force", "--filter", &until]);
+        if let Some(max_size) = max_size {
+            command.args(["--keep-storage", &max_size.to_string()]);
+        }
+        if let Some(output) = run_or_record(&command, &ExecOptions::default())? {
+            build_cache_bytes = String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .find_map(|line| {
+                    line.strip_prefix("Total:")
+                        .or_else(|| line.strip_prefix("Total reclaimed space:"))
+                })
+                .and_then(|size| parse_bytes(size).ok())
+                .unwrap_or_default();
+        }
+    }
+
+    let image_bytes = items.iter().map(|item| item.bytes).sum::<u64>();
+    Ok(DockerCachePruneOutput {
+        removed: items,
+        build_cache_bytes,
+        bytes: image_bytes + build_cache_bytes,
+        dry_run: is_dry_run(),
+    })
+}
+
+/// Pre-build images newest first, `until` selects the images which have exceeded the maximum age.
+fn get_pre_build_images(until: &str) -> Result<Vec<PreBuildImage>, Error> {
+    let format = "{{.Repository}}:{{.Tag}}\t{{.Repository}}\t{{.ID}}";
+    let all = read(Command::new("docker").args(["image", "ls", "--format", format]))?;
+    let expired = read(Command::new("docker").args(["image", "ls", "--filter", until, "--format", format]))?;
+    let expired = expired.lines().collect::<BTreeSet<_>>();
+
+    let mut ids = BTreeSet::default();
+    let mut images = vec![];
+    for line in all.lines() {
+        let mut fields = line.split('\t');
+        let (Some(image), Some(service), Some(id)) = (fields.next(), fields.next(), fields.next()) else {
+            continue;
+        };
+        if !image.ends_with("-pre-build") {
+            continue;
+        }
+        // tags of the same image (e.g. retagged warm cache images) only free its space once
+        let bytes = match ids.insert(id) {
+            true => read(Command::new("docker").args(["image", "inspect", "--format", "{{.Size}}", image]))?
+                .parse::<u64>()
+                .unwrap_or_default(),
+            false => 0,
+        };
+        images.push(PreBuildImage {
+            image: image.to_string(),
+            service: service.rsplit('/').next().unwrap_or(service).to_string(),
+            bytes,
+            expired: expired.contains(line),
+        });
+    }
+    Ok(images)
+}
@@ -0,0 +1,192 @@
+/// Generates a docker-compose file running the workspace's services from their locally built images, so that they
+/// can be integration tested together with `docker compose up`. Every binary member of the cargo workspace and
+/// every service of ops.toml becomes a compose service, configured from `[services.<name>.compose]` and
+/// `[services.<name>.run]` in ops.toml and the `[package.metadata.ops.docker]` of its package.
+use crate::cargo::Metadata;
+use crate::config::package::PackageOpsConfig;
+use crate::config::{ComposeConfig, OpsConfig, RunConfig};
+use crate::docker::run::get_binary_path;
+use crate::dry_run::{enable_dry_run, write_file};
+use crate::error::Error;
+use crate::logging::LogArgs;
+use crate::report::Report;
+use crate::workspace::root::get_workspace_root;
+use clap::Parser;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::path::PathBuf;
+use tracing::{debug, info_span};
+
+#[derive(Clone, Debug, Parser)]
+#[clap(author, version, about, long_about = None)]
+pub struct DockerComposeGenArgs {
+    /// path to write the compose file to, relative to the workspace root
+    #[clap(short, long, default_value = "docker-compose.yml", env = "OPS_COMPOSE_OUTPUT")]
+    pub output_file: PathBuf,
+
+    /// services to include along with the services they depend on, defaults to every service
+    #[clap(short, long, value_delimiter = '\n', env = "OPS_SERVICE")]
+    pub service: Vec<String>,
+
+    #[clap(flatten)]
+    pub log: LogArgs,
+
+    /// print the compose file which would be written instead of writing it
+    /// - can also be enabled by setting the OPS_DRY_RUN environment variable
+    #[clap(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ComposeFile {
+    pub services: BTreeMap<String, ComposeService>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ComposeService {
+    pub image: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entrypoint: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub ports: Vec<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub environment: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub env_file: Vec<PathBuf>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub volumes: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub depends_on: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DockerComposeGenOutput {
+    pub path: PathBuf,
+    pub services: Vec<String>,
+}
+
+impl Report for DockerComposeGenOutput {
+    fn human(&self) -> String {
+        format!(
+            "wrote {}, run with `docker compose -f {} up`: {}",
+            self.path.display(),
+            self.path.display(),
+            self.services.join(", ")
+        )
+    }
+}
+
+pub fn docker_compose_gen(docker_compose_gen_args: DockerComposeGenArgs) -> Result<DockerComposeGenOutput, Error> {
+    let DockerComposeGenArgs {
+        output_file,
+        service,
+        log: _,
+        dry_run,
+    } = docker_compose_gen_args;
+
+    let _span = info_span!("docker_compose_gen").entered();
+
+    if dry_run {
+        enable_dry_run();
+    }
+
+    let workspace_dir = get_workspace_root(&env::current_dir()?)?;
+    env::set_current_dir(&workspace_dir)?;
+
+    let config = OpsConfig::load(&workspace_dir)?;
+    let metadata = Metadata::load(&workspace_dir)?;
+
+    let mut compose_file = ComposeFile::default();
+    let mut services = config
+        .services
+        .iter()
+        .map(|(name, service_config)| (name.clone(), Some(service_config)))
+        .collect::<BTreeMap<_, _>>();
+    // binary members without an ops.toml entry are services named after their package
+    for package in metadata.members().filter(|package| package.is_binary()) {
+        let configured = config
+            .services
+            .iter()
+            .any(|(name, service_config)| service_config.package_name(name) == package.name);
+        if !configured {
+            services.insert(package.name.clone(), None);
+        }
+    }
+
+    for (name, service_config) in &services {
+        let package = service_config.map_or(name.as_str(), |service_config| service_config.package_name(name));
+        let (run, compose) = match service_config {
+            Some(service_config) => (service_config.run.clone(), service_config.compose.clone()),
+            None => (RunConfig::default(), ComposeConfig::default()),
+        };
+        if let Some(unknown) = compose.depends_on.iter().find(|name| !services.contains_key(*name)) {
+            return Err(Error::InvalidInput(format!(
+                "service `{name}` depends on `{unknown}`, which is not a service of the workspace"
+            )));
+        }
+
+        let package_dir = metadata
+            .members()
+            .find(|member| member.name == package)
+            .map(|member| member.dir().to_path_buf());
+        let package_config = match package_dir.as_ref() {
+            Some(package_dir) => PackageOpsConfig::load(package_dir)?,
+            None => {
+                debug!("{name}: `{package}` is not a member of the cargo workspace");
+                PackageOpsConfig::default()
+            }
+        };
+
+        // the entrypoint is chosen like `ops docker run` does, images built from their own Dockerfile keep theirs
+        let entrypoint = match (run.entrypoint, run.feature_set) {
+            (Some(entrypoint), _) => Some(entrypoint),
+            (None, Some(feature_set)) => Some(get_binary_path(package, &feature_set)?),
+            (None, None) => package_dir.as_ref().map(|_| get_binary_path(package, "")).transpose()?,
+        };
+        let ports = [compose.ports, run.ports, package_config.docker.ports.clone()]
+            .into_iter()
+            .find(|ports| !ports.is_empty())
+            .unwrap_or_default();
+        let mut environment = package_config.docker.env;
+        environment.extend(compose.env);
+
+        compose_file.services.insert(
+            name.clone(),
+            ComposeService {
+                image: run.image.unwrap_or_else(|| format!("{package}:latest")),
+                entrypoint: entrypoint.map(|entrypoint| vec![entrypoint]),
+                ports,
+                environment,
+                env_file: run.env_files,
+                volumes: run.volumes,
+                depends_on: compose.depends_on,
+            },
+        );
+    }
+
+    if !service.is_empty() {
+        if let Some(unknown) = service.iter().find(|name| !compose_file.services.contains_key(*name)) {
+            return Err(Error::InvalidInput(format!("unknown service `{unknown}`")));
+        }
+        let mut included = service.clone();
+        let mut index = 0;
+        while let Some(name) = included.get(index) {
+            for dependency in compose_file.services[name].depends_on.clone() {
+                if !included.contains(&dependency) {
+                    included.push(dependency);
+                }
+            }
+            index += 1;
+        }
+        compose_file.services.retain(|name, _| included.contains(name));
+    }
+
+    let contents = serde_yaml::to_string(&compose_file)?;
+    write_file(&output_file, &contents)?;
+
+    Ok(DockerComposeGenOutput {
+        path: output_file,
+        services: compose_file.services.into_keys().collect(),
+    })
+}
@@ -0,0 +1,270 @@
+/// Content hashes of service images: a sha256 over the sources of a service's internal dependency closure, the
+/// workspace manifests and any build inputs (generated Dockerfiles, build args). The hash is recorded as a label
+/// on built images so that rebuilding an unchanged service can be skipped in favor of the existing image.
+use crate::cargo::Metadata;
+use crate::docker::build_rust_workspace::get_docker_path;
+use crate::error::Error;
+use crate::exec::{jobs, run, ExecOptions};
+use crate::progress::format_duration;
+use ignore::WalkBuilder;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Instant;
+use std::{fs, thread};
+use tracing::{debug, info, trace};
+
+/// image label holding the content hash an image was built from
+pub const CONTENT_HASH_LABEL: &str = "ops.content-hash";
+
+/// workspace level files which affect every package's build
+const WORKSPACE_FILES: [&str; 3] = ["Cargo.toml", "Cargo.lock", "rust-toolchain.toml"];
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ContentHash {
+    pub hash: String,
+    /// number of source files hashed
+    pub files: usize,
+    /// total size of the source files hashed
+    pub bytes: u64,
+}
+
+/// Kind of a source entry, mirroring what docker preserves when sending the workspace as build context.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum SourceKind {
+    File {
+        executable: bool,
+    },
+    /// hashed by its target rather than the contents it points to
+    Symlink,
+    /// empty directories are kept as build scripts may expect them to exist
+    EmptyDir,
+}
+
+/// Hashes the sources of `package_name` and its internal dependencies within the workspace rooted at
+/// `workspace_dir` along with `inputs`. Build output (`target`), hidden entries and paths matched by the
+/// workspace's `.gitignore` or `.dockerignore` files are ignored, see [`get_source_files`]. Files are
+/// discovered and read in parallel, overlapping (nested) package directories are only hashed once. Besides file
+/// contents, executable bits, symlink targets and empty directories are part of the hash.
+pub fn get_content_hash(workspace_dir: &Path, package_name: &str, inputs: &[&str]) -> Result<ContentHash, Error> {
+    let package_paths = Metadata::load(workspace_dir)?.build_dependency_dirs(package_name)?;
+    let content_hash = hash_sources(
+        workspace_dir,
+        &package_paths,
+        &[],
+        inputs,
+        &format!("{package_name} and its dependencies"),
+    )?;
+    debug!("content hash of {package_name}: {}", content_hash.hash);
+    Ok(content_hash)
+}
+
+/// Hash identifying the pre-build stage of `package_name`, whose directory relative to `workspace_dir` is
+/// `package_dir`: the workspace manifests, Cargo.lock, rust-toolchain.toml, the package's manifest and the sources
+/// of the internal crates it depends on along with `inputs` (the pre-build Dockerfile, build args and feature sets).
+/// The package's own sources are excluded as the pre-build stage only compiles its dependencies, so the hash stays
+/// the same while only the service itself changes.
+pub fn get_pre_build_hash(
+    workspace_dir: &Path,
+    package_name: &str,
+    package_dir: &Path,
+    inputs: &[&str],
+) -> Result<ContentHash, Error> {
+    let mut package_paths = Metadata::load(workspace_dir)?.build_dependency_dirs(package_name)?;
+    package_paths.remove(package_dir);
+    let content_hash = hash_sources(
+        workspace_dir,
+        &package_paths,
+        &[package_dir.join("Cargo.toml")],
+        inputs,
+        &format!("the dependencies of {package_name}"),
+    )?;
+    debug!("pre-build hash of {package_name}: {}", content_hash.hash);
+    Ok(content_hash)
+}
+
+/// Hashes the workspace level files, `files` and the sources of the packages at `package_paths` (relative to
+/// `workspace_dir`) along with `inputs`, `description` names what is hashed in logs.
+fn hash_sources(
+    workspace_dir: &Path,
+    package_paths: &BTreeSet<PathBuf>,
+    files: &[PathBuf],
+    inputs: &[&str],
+    description: &str,
+) -> Result<ContentHash, Error> {
+    let start = Instant::now();
+    let package_paths = package_paths
+        .iter()
+        .map(|dir| workspace_dir.join(dir))
+        .collect::<BTreeSet<_>>();
+    // nested packages are already walked as part of their ancestor package
+    let package_paths = package_paths
+        .iter()
+        .filter(|path| {
+            !package_paths
+                .iter()
+                .any(|other| other != *path && path.starts_with(other))
+        })
+        .collect::<Vec<_>>();
+
+    let mut files = WORKSPACE_FILES
+        .iter()
+        .map(PathBuf::from)
+        .chain(files.iter().cloned())
+        .filter(|file| workspace_dir.join(file).exists())
+        .map(|file| (file, SourceKind::File { executable: false }))
+        .collect::<BTreeMap<_, _>>();
+    let discovered = thread::scope(|scope| {
+        package_paths
+            .iter()
+            .map(|package_path| scope.spawn(|| get_source_files(workspace_dir, package_path)))
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|handle| handle.join().expect("source file discovery panicked"))
+            .collect::<Result<Vec<_>, Error>>()
+    })?;
+    files.extend(discovered.into_iter().flatten());
+
+    // each file is hashed separately in parallel and the per-file digests are combined in path order so that the
+    // result does not depend on scheduling
+    let files = files.into_iter().collect::<Vec<_>>();
+    let chunk_size = files.len().div_ceil(jobs()).max(1);
+    let digests = thread::scope(|scope| {
+        files
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|(file, kind)| {
+                            trace!("hashing {}", file.display());
+                            let contents = match kind {
+                                SourceKind::File { .. } => fs::read(workspace_dir.join(file))?,
+                                SourceKind::Symlink => fs::read_link(workspace_dir.join(file))?
+                                    .to_string_lossy()
+                                    .as_bytes()
+                                    .to_vec(),
+                                SourceKind::EmptyDir => vec![],
+                            };
+                            Ok((contents.len() as u64, Sha256::digest(&contents)))
+                        })
+                        .collect::<Result<Vec<_>, Error>>()
+                })
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|handle| handle.join().expect("source file hashing panicked"))
+            .collect::<Result<Vec<_>, Error>>()
+    })?;
+
+    let mut hasher = Sha256::new();
+    let mut bytes = 0;
+    for ((file, kind), (len, digest)) in files.iter().zip(digests.into_iter().flatten()) {
+        bytes += len;
+        // `/` separated so that hashes computed on windows hosts match
+        hasher.update(get_docker_path(file).as_bytes());
+        hasher.update(match kind {
+            SourceKind::File { executable: false } => [0],
+            SourceKind::File { executable: true } => [1],
+            SourceKind::Symlink => [2],
+            SourceKind::EmptyDir => [3],
+        });
+        hasher.update(len.to_le_bytes());
+        hasher.update(digest);
+    }
+    for input in inputs {
+        hasher.update((input.len() as u64).to_le_bytes());
+        hasher.update(input.as_bytes());
+    }
+    let hash = hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<String>();
+    info!(
+        "hashed {} files ({bytes} bytes) of {description} in {}",
+        files.len(),
+        format_duration(start.elapsed())
+    );
+    Ok(ContentHash {
+        hash,
+        files: files.len(),
+        bytes,
+    })
+}
+
+/// Source entries of the package at `package_path`, relative to `workspace_dir`. Symlinks are not followed.
+/// `.gitignore` files of the repository and `.dockerignore` files of the package's directory and its ancestors are
+/// honored, so that fixtures or data kept out of git or the build context do not slow hashing down -- the patterns of
+/// a `.dockerignore` are matched like those of a `.gitignore`. Global and repository-local git excludes are not
+/// honored as they differ between machines, which would make hashes differ as well.
+fn get_source_files(workspace_dir: &Path, package_path: &Path) -> Result<Vec<(PathBuf, SourceKind)>, Error> {
+    let walk = WalkBuilder::new(package_path)
+        .hidden(true)
+        .ignore(false)
+        .git_global(false)
+        .git_exclude(false)
+        .add_custom_ignore_filename(".dockerignore")
+        .filter_entry(|entry| {
+            entry.depth() == 0
+                || !(entry.file_type().is_some_and(|kind| kind.is_dir()) && entry.file_name() == "target")
+        })
+        .build();
+    let mut files = vec![];
+    for entry in walk {
+        let entry = entry.map_err(|err| Error::msg(format!("unable to read package sources: {err}")))?;
+        let Some(file_type) = entry.file_type() else {
+            continue;
+        };
+        let kind = if file_type.is_symlink() {
+            SourceKind::Symlink
+        } else if file_type.is_file() {
+            SourceKind::File {
+                executable: is_executable(&entry.metadata().map_err(|err| Error::msg(err.to_string()))?),
+            }
+        } else if entry.depth() > 0 && fs::read_dir(entry.path())?.next().is_none() {
+            SourceKind::EmptyDir
+        } else {
+            continue;
+        };
+        if let Ok(file) = entry.path().strip_prefix(workspace_dir) {
+            files.push((file.to_path_buf(), kind));
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_: &fs::Metadata) -> bool {
+    false
+}
+
+/// Content hash label of the local image `image`, `None` if the image does not exist or has no such label.
+pub fn get_image_content_hash(image: &str) -> Option<String> {
+    let mut command = Command::new("docker");
+    command.args([
+        "image",
+        "inspect",
+        "--format",
+        &format!(r#"{{{{ index .Config.Labels "{CONTENT_HASH_LABEL}" }}}}"#),
+        image,
+    ]);
+    let options = ExecOptions {
+        stream: false,
+        ..Default::default()
+    };
+    let output = run(&command, &options).ok()?;
+    let hash = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    match hash.is_empty() || hash == "<no value>" {
+        true => None,
+        false => Some(hash),
+    }
+}
@@ -0,0 +1,861 @@
+/// Rendering of the Dockerfiles generated for rust workspace services: the pre-build stage which compiles only a
+/// service's dependencies and the service stage which builds its binaries on top of it and assembles the runtime
+/// image. Node services are built from a single multi-stage Dockerfile. Rendering is pure so that the output for a given set of inputs can be pinned, e.g. with
+/// [`crate::snapshot::assert_snapshot`], and reviewed whenever an ops upgrade changes it.
+use crate::cargo::WorkspacePackage;
+use crate::config::package::PackageDockerConfig;
+use crate::docker::build_rust_workspace::{get_build_profile, get_docker_path, FeatureSet};
+use crate::docker::platform::Platform;
+use crate::docker::template::{render_template, DockerfileTemplate, TemplateKind};
+use crate::error::Error;
+use crate::node::{NodePackage, PackageManager};
+use clap::ValueEnum;
+use std::iter::once;
+use std::path::Path;
+use toml::Value;
+
+/// template of the pre-build stage, see [`PreBuildDockerfile`] for the substituted variables
+pub const PRE_BUILD_SERVICE_DOCKERFILE: &str = include_str!("Dockerfile.pre_build_service");
+/// template of the service stage, see [`ServiceDockerfile`] for the substituted variables
+pub const BUILD_SERVICE_DOCKERFILE: &str = include_str!("Dockerfile.build_service");
+
+/// base image of the final stage of service images
+pub const DEFAULT_RUNTIME_IMAGE: &str = "debian:11-slim";
+/// musl target of the build host's architecture, evaluated by the shell of `RUN` instructions
+const MUSL_TARGET: &str = "$(uname -m)-unknown-linux-musl";
+
+/// cargo's registry and git checkouts in the rust image, kept in cache mounts with [`CacheMounts`]
+const CARGO_CACHE_DIRS: [&str; 2] = ["/usr/local/cargo/registry", "/usr/local/cargo/git"];
+/// sccache's local cache in the rust image
+const SCCACHE_DIR: &str = "/root/.cache/sccache";
+/// id of the BuildKit secret holding cargo's `credentials.toml`, mounted into the pre-build stage of workspaces
+/// depending on private registries or git repositories
+pub const CARGO_CREDENTIALS_SECRET: &str = "cargo-credentials";
+/// where cargo reads its credentials from in the rust image
+const CARGO_CREDENTIALS: &str = "/usr/local/cargo/credentials.toml";
+/// directory of `/app` the dependencies vendored with `cargo vendor` are copied to in the pre-build stage
+pub const VENDOR_DIR: &str = "vendor";
+/// named build context holding the dependencies vendored with `cargo vendor`, passed to the pre-build with
+/// `--build-context` so that they are never written into the workspace
+pub const VENDOR_CONTEXT: &str = "vendor";
+/// Cargo.lock sources of crates.io packages, any other source is a private registry or git repository
+const CRATES_IO_SOURCES: [&str; 2] = [
+    "registry+https://github.com/rust-lang/crates.io-index",
+    "sparse+https://index.crates.io/",
+];
+
+/// template of node services, see [`NodeServiceDockerfile`] for the substituted variables
+pub const NODE_SERVICE_DOCKERFILE: &str = include_str!("Dockerfile.node_service");
+/// node docker image version used unless the workspace pins one in an `.nvmrc`
+pub const DEFAULT_NODE_VERSION: &str = "lts";
+
+/// BuildKit cache mounts added to the `RUN` instructions invoking cargo, so that their contents survive between
+/// builds without ending up in the image. Requires BuildKit, the default builder since docker 23 (or
+/// `DOCKER_BUILDKIT=1`).
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum CacheMounts {
+    /// mount cargo's registry and git checkouts, so crates are only downloaded once
+    Registry,
+    /// additionally compile through sccache, installed in the pre-build stage, and mount its cache so that
+    /// compiled crates are reused across builds
+    Sccache,
+}
+
+/// Kind of base image of the final stage, detected from the image's name, which decides how runtime dependencies
+/// are installed and which libc binaries are linked against. Unrecognized images are assumed to be debian based.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RuntimeBase {
+    /// debian or ubuntu based, runtime libraries are installed with apt-get
+    Debian,
+    /// alpine based, binaries are linked against musl and certificates are installed with apk
+    Alpine,
+    /// distroless images shipping glibc (e.g. `gcr.io/distroless/cc`), which have no shell to install anything with
+    Distroless,
+    /// images without a libc (`scratch` or `gcr.io/distroless/static`), binaries are statically linked against musl
+    Static,
+}
+
+impl RuntimeBase {
+    /// Kind of `runtime_image`, [`DEFAULT_RUNTIME_IMAGE`] if none is set.
+    pub fn of(runtime_image: Option<&str>) -> Self {
+        let image = runtime_image.unwrap_or(DEFAULT_RUNTIME_IMAGE).to_lowercase();
+        if image == "scratch" || (image.contains("distroless") && image.contains("static")) {
+            Self::Static
+        } else if image.contains("distroless") {
+            Self::Distroless
+        } else if image.contains("alpine") {
+            Self::Alpine
+        } else {
+            Self::Debian
+        }
+    }
+
+    /// Whether binaries are compiled for the musl target of the build host instead of its gnu target.
+    pub fn musl(&self) -> bool {
+        matches!(self, Self::Alpine | Self::Static)
+    }
+
+    /// Instructions installing the runtime dependencies of services, each preceded by a newline.
+    fn setup(&self) -> &'static str {
+        match self {
+            Self::Debian => "\n\n  RUN apt-get update\n  RUN apt-get -y install \\\n    ca-certificates \\\n    libpq5 \\\n    libssl-dev\n  RUN rm -rf /var/lib/apt/lists/*",
+            Self::Alpine => "\n\n  RUN apk add --no-cache ca-certificates",
+            Self::Distroless | Self::Static => "",
+        }
+    }
+}
+
+/// Inputs of the pre-build stage.
+#[derive(Clone, Debug)]
+pub struct PreBuildDockerfile<'a> {
+    /// cargo package name of the service
+    pub service_name: &'a str,
+    pub profile: &'a str,
+    /// features of every binary to build, an empty set builds the default binary
+    pub feature_sets: &'a [FeatureSet<'a>],
+    /// rust docker image version, defaults to latest
+    pub rust_version: Option<&'a str>,
+    /// base image of the stage, overrides `rust_version` -- must be debian based like the rust images
+    pub build_image: Option<&'a str>,
+    /// whether the workspace pins its toolchain in a `rust-toolchain.toml`
+    pub rust_toolchain: bool,
+    /// contents of the workspace's Cargo.lock
+    pub cargo_lock: &'a str,
+    /// contents of the workspace's `.cargo/config.toml`, whose registries and source replacements are configured in
+    /// the stage if the Cargo.lock references private registries or git repositories
+    pub cargo_config: Option<&'a str>,
+    /// source replacement printed by `cargo vendor` if the dependencies are vendored, their sources are then copied
+    /// into the stage from the [`VENDOR_CONTEXT`] build context and every dependency is fetched from them offline
+    pub vendor: Option<&'a str>,
+    /// additional Dockerfile instructions inserted before the dependencies are built (e.g. `COPY` instructions)
+    pub copy: &'a [String],
+    /// dependencies to omit from the pre-build stage
+    pub pre_build_omit: &'a [String],
+    /// platforms to compile the dependencies for, the build host's platform if empty
+    pub platforms: &'a [Platform],
+    /// whether to compile the dependencies for the musl target of the build host, see [`RuntimeBase::musl`]
+    pub musl: bool,
+    /// internal crates the service transitively depends on, which are copied and compiled along with the
+    /// external dependencies
+    pub local_crates: &'a [&'a WorkspacePackage],
+    pub cache_mounts: Option<CacheMounts>,
+    /// template rendered instead of [`PRE_BUILD_SERVICE_DOCKERFILE`]
+    pub template: Option<&'a DockerfileTemplate>,
+}
+
+/// Inputs of the service stage.
+#[derive(Clone, Debug)]
+pub struct ServiceDockerfile<'a> {
+    /// pre-build image the service stage builds on
+    pub pre_build_image: &'a str,
+    /// cargo package name of the service
+    pub service_name: &'a str,
+    /// directory of the service relative to the workspace root
+    pub service_dir: &'a Path,
+    pub profile: &'a str,
+    /// features of every binary to build, an empty set builds the default binary
+    pub feature_sets: &'a [FeatureSet<'a>],
+    /// binary targets of the package to build, the package's only binary if empty
+    pub bins: &'a [String],
+    /// base image of the final stage, defaults to [`DEFAULT_RUNTIME_IMAGE`]
+    pub runtime_image: Option<&'a str>,
+    pub entrypoint: Option<&'a str>,
+    pub docker: &'a PackageDockerConfig,
+    /// platforms to cross-compile the binaries for, the build host's platform if empty
+    pub platforms: &'a [Platform],
+    /// cache mounts of the cargo builds, should match the ones of the pre-build stage
+    pub cache_mounts: Option<CacheMounts>,
+    /// template rendered instead of [`BUILD_SERVICE_DOCKERFILE`]
+    pub template: Option<&'a DockerfileTemplate>,
+}
+
+/// Inputs of the Dockerfile of a node service.
+#[derive(Clone, Debug)]
+pub struct NodeServiceDockerfile<'a> {
+    pub package_manager: PackageManager,
+    /// node docker image version, see [`DEFAULT_NODE_VERSION`]
+    pub node_version: &'a str,
+    /// directory of the service relative to the workspace root
+    pub service_dir: &'a Path,
+    /// workspace level files and directories configuring installs which exist in the workspace, see
+    /// [`PackageManager::config_files`]
+    pub config_files: &'a [&'a str],
+    /// every package of the workspace, their manifests are copied before dependencies are installed
+    pub workspace_packages: &'a [&'a NodePackage],
+    /// the service and the workspace packages it transitively depends on, dependencies first
+    pub packages: &'a [&'a NodePackage],
+    /// script run in every package of `packages` which declares it
+    pub build_script: &'a str,
+    /// script of the service the image runs unless an entrypoint is set
+    pub start_script: &'a str,
+    /// additional Dockerfile instructions inserted before the packages are built (e.g. `COPY` instructions)
+    pub copy: &'a [String],
+    /// base image of the final stage, defaults to the slim variant of the node image
+    pub runtime_image: Option<&'a str>,
+    pub entrypoint: Option<&'a str>,
+    pub docker: &'a PackageDockerConfig,
+}
+
+impl PreBuildDockerfile<'_> {
+    pub fn render(&self) -> Result<String, Error> {
+        let Self {
+            service_name,
+            profile,
+            feature_sets,
+            rust_version,
+            build_image,
+            rust_toolchain,
+            cargo_lock,
+            cargo_config,
+            vendor,
+            copy,
+            pre_build_omit,
+            platforms,
+            musl,
+            local_crates,
+            cache_mounts,
+            template,
+        } = self;
+        check_musl_platforms(*musl, platforms)?;
+        let build_profile = get_build_profile(profile);
+
+        let rustup_toolchain_override = "COPY rust-toolchain.toml rust-toolchain.toml\n  RUN cat rust-toolchain.toml | tomlq -t '.toolchain.profile = \"minimal\"' > rust-toolchain2.toml && mv rust-toolchain2.toml rust-toolchain.toml";
+        let rustup_update = "RUN rustup update";
+        let mut rustup_toolchain = if *rust_toolchain {
+            format!("{rustup_toolchain_override}\n  {rustup_update}")
+        } else {
+            rustup_update.to_string()
+        };
+        if !platforms.is_empty() {
+            let targets = platforms.iter().map(|platform| platform.target).collect::<Vec<_>>();
+            rustup_toolchain.push_str(&format!("\n  RUN rustup target add {}", targets.join(" ")));
+            for platform in platforms.iter() {
+                rustup_toolchain.push_str(&format!("\n  {}", platform.linker_setup()));
+            }
+        }
+        if *musl {
+            rustup_toolchain.push_str(&format!(
+                "\n  RUN apt-get -y install musl-tools\n  RUN rustup target add {MUSL_TARGET}"
+            ));
+        }
+        if *cache_mounts == Some(CacheMounts::Sccache) {
+            rustup_toolchain.push_str(&format!(
+                "\n  RUN cargo install --locked sccache\n  ENV RUSTC_WRAPPER=sccache SCCACHE_DIR={SCCACHE_DIR}"
+            ));
+        }
+
+        let invalid_cargo_lock = |message: &str| Error::manifest("Cargo.lock", message);
+        let mut full_cargo_lock = match cargo_lock.parse::<Value>()? {
+            Value::Table(table) => table,
+            _ => return Err(invalid_cargo_lock("file is not a toml table")),
+        };
+
+        let cargo_lock_package = full_cargo_lock
+            .remove("package")
+            .ok_or_else(|| invalid_cargo_lock("no package field found"))?;
+
+        let packages = match cargo_lock_package {
+            Value::Array(packages) => packages,
+            _ => return Err(invalid_cargo_lock("package field is not an array")),
+        };
+        // vendored sources need neither registries nor credentials
+        let private_sources = vendor.is_none()
+            && packages
+                .iter()
+                .filter_map(|package| package.get("source")?.as_str())
+                .any(|source| !CRATES_IO_SOURCES.contains(&source));
+        // git dependencies have no checksum but are fetched all the same
+        let packages = packages
+            .into_iter()
+            .filter(|package| match package {
+                Value::Table(table) => match table.get("source").and_then(Value::as_str) {
+                    Some(source) => table.contains_key("checksum") || source.starts_with("git+"),
+                    None => false,
+                },
+                _ => true,
+            })
+            .collect();
+        let cargo_registries = match private_sources {
+            true => format!(
+                "\n  RUN echo '{}' >> $CARGO_HOME/config.toml",
+                get_registry_config(*cargo_config)?.replace('\n', "\\n\\\n")
+            ),
+            false => String::new(),
+        };
+        let cargo_vendor = match vendor {
+            Some(vendor) => format!(
+                "\n  COPY --from={VENDOR_CONTEXT} . /app/{VENDOR_DIR}\n  RUN echo '{}' >> $CARGO_HOME/config.toml",
+                get_vendor_config(vendor)?.replace('\n', "\\n\\\n")
+            ),
+            None => String::new(),
+        };
+
+        let fetch_cargo_lock_toml = Value::Table(toml::value::Map::from_iter([(
+            "package".to_string(),
+            Value::Array(packages),
+        )]));
+        let fetch_cargo_lock_toml = toml::ser::to_string(&fetch_cargo_lock_toml)?;
+
+        let mut additional_copies = copy
+            .iter()
+            .enumerate()
+            .map(|(i, line)| {
+                let line = line.trim();
+                if i == 0 {
+                    line.to_string()
+                } else {
+                    format!("  {line}")
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        if !additional_copies.is_empty() {
+            additional_copies = format!("\n\n{additional_copies}");
+        }
+
+        let pre_build_omit_deps = format!(
+            r#"'{{{}}}'"#,
+            pre_build_omit
+                .iter()
+                .map(|x| format!(r#""{x}": true"#))
+                .collect::<Vec<_>>()
+                .join(",")
+        );
+
+        let local_crate_paths = local_crates
+            .iter()
+            .map(|local_crate| get_docker_path(&local_crate.path))
+            .collect::<Vec<_>>();
+        let local_crate_copy = local_crate_paths
+            .iter()
+            .map(|path| format!("\n  COPY {path} ../{path}"))
+            .collect::<String>();
+        let local_crate_copy = match local_crate_copy.is_empty() {
+            true => String::new(),
+            false => format!("\n{local_crate_copy}"),
+        };
+        // crates which are not declared under `workspace.dependencies` (e.g. members depending on each other
+        // through `path` dependencies in their own manifests) are added to the build crate directly
+        let local_crate_dependencies = local_crates
+            .iter()
+            .filter(|local_crate| !local_crate.workspace_dependency)
+            .map(|local_crate| {
+                format!(
+                    r#"\n[dependencies.{}]\npath = \"../{}\"\n"#,
+                    local_crate.name,
+                    get_docker_path(&local_crate.path)
+                )
+            })
+            .collect::<String>();
+        let local_crate_dependencies = match local_crate_dependencies.is_empty() {
+            true => String::new(),
+            false => format!("\n  RUN printf \"{local_crate_dependencies}\" >> Cargo.toml"),
+        };
+        let pre_build_members = once(service_name.to_string())
+            .chain(
+                local_crates
+                    .iter()
+                    .filter(|local_crate| local_crate.member)
+                    .map(|local_crate| get_docker_path(&local_crate.path)),
+            )
+            .collect::<Vec<_>>();
+
+        let mut service_docker_pre_builds = vec![];
+        let mut pre_build_binaries = vec![format!("/app/target/{profile}/rust_build")];
+        if platforms.is_empty() && !musl {
+            service_docker_pre_builds.extend(feature_sets.iter().map(|feature_set| {
+                format!(
+                    "  RUN cargo build{build_profile}{}",
+                    get_features_flag(&feature_set.features)
+                )
+            }));
+            pre_build_binaries.push(format!("/app/target/{profile}/{service_name}"));
+        }
+        let targets = platforms
+            .iter()
+            .map(|platform| platform.target)
+            .chain(musl.then_some(MUSL_TARGET));
+        for target in targets {
+            service_docker_pre_builds.extend(feature_sets.iter().map(|feature_set| {
+                format!(
+                    "  RUN cargo build{build_profile} --target={target}{}",
+                    get_features_flag(&feature_set.features)
+                )
+            }));
+            pre_build_binaries.push(format!("/app/target/{target}/{profile}/{service_name}"));
+        }
+        service_docker_pre_builds.push(format!(
+            "  RUN {}",
+            pre_build_binaries
+                .iter()
+                .map(|binary| format!("rm {binary}"))
+                .collect::<Vec<_>>()
+                .join(" && ")
+        ));
+
+        let build_image = match build_image {
+            Some(build_image) => build_image.to_string(),
+            None => format!("rust:{}", rust_version.unwrap_or("latest")),
+        };
+        let dockerfile = render_template(
+            *template,
+            TemplateKind::PreBuild,
+            &[
+                ("build_image", &build_image),
+                ("rust_version", rust_version.unwrap_or("latest")),
+                ("rustup_toolchain", &rustup_toolchain),
+                (
+                    "fetch_cargo_lock",
+                    &format!("RUN echo '{}' > Cargo.lock", fetch_cargo_lock_toml).replace('\n', "\\n\\\n"),
+                ),
+                ("cargo_registries", &cargo_registries),
+                ("cargo_vendor", &cargo_vendor),
+                ("service", service_name),
+                ("profile", profile),
+                ("file_copy", &additional_copies),
+                ("pre_build_omit_deps", &pre_build_omit_deps),
+                (
+                    "local_crate_paths",
+                    &format!("'{}'", serde_json::to_string(&local_crate_paths)?),
+                ),
+                ("local_crate_copy", &local_crate_copy),
+                ("local_crate_dependencies", &local_crate_dependencies),
+                (
+                    "pre_build_members",
+                    &format!("'{}'", serde_json::to_string(&pre_build_members)?),
+                ),
+                ("pre_build_service", service_docker_pre_builds.join("\n").trim()),
+            ],
+        )?;
+
+        Ok(with_mounts(dockerfile.trim(), *cache_mounts, private_sources))
+    }
+}
+
+impl ServiceDockerfile<'_> {
+    pub fn render(&self) -> Result<String, Error> {
+        let Self {
+            pre_build_image,
+            service_name,
+            service_dir,
+            profile,
+            feature_sets,
+            bins,
+            runtime_image,
+            entrypoint,
+            docker,
+            platforms,
+            cache_mounts,
+            template,
+        } = self;
+        let runtime_base = RuntimeBase::of(*runtime_image);
+        check_musl_platforms(runtime_base.musl(), platforms)?;
+
+        let binaries = match bins.is_empty() {
+            true => vec![*service_name],
+            false => bins.iter().map(String::as_str).collect(),
+        };
+        let service_docker_build_binaries = match (platforms.is_empty(), runtime_base.musl()) {
+            (true, false) => get_build_binaries(&binaries, bins, profile, feature_sets),
+            (true, true) => get_musl_build_binaries(&binaries, bins, profile, feature_sets),
+            (false, _) => get_cross_build_binaries(&binaries, bins, profile, feature_sets, platforms),
+        };
+        let build_platform = match platforms.is_empty() {
+            true => "",
+            false => "--platform=$BUILDPLATFORM ",
+        };
+
+        let service_docker_copy_binaries = get_binary_names(service_name, bins, feature_sets)
+            .iter()
+            .map(|binary| format!("  COPY --from=build /app/target/{profile}/{binary} /app/{binary}"))
+            .collect::<Vec<_>>();
+
+        let build_service_dockerfile = render_template(
+            *template,
+            TemplateKind::Service,
+            &[
+                ("build_platform", build_platform),
+                ("pre_build_service_image_tag", pre_build_image),
+                ("service", service_name),
+                ("profile", profile),
+                ("service_dir", &get_docker_path(service_dir)),
+                ("build", service_docker_build_binaries.join("\n").trim()),
+                ("runtime_setup", runtime_base.setup()),
+                ("binary_copy", service_docker_copy_binaries.join("\n").trim()),
+                ("runtime_image", runtime_image.unwrap_or(DEFAULT_RUNTIME_IMAGE)),
+                ("runtime_config", &get_runtime_config(docker, *entrypoint)?),
+            ],
+        )?;
+        Ok(with_mounts(build_service_dockerfile.trim(), *cache_mounts, false))
+    }
+}
+
+impl NodeServiceDockerfile<'_> {
+    pub fn render(&self) -> Result<String, Error> {
+        let Self {
+            package_manager,
+            node_version,
+            service_dir,
+            config_files,
+            workspace_packages,
+            packages,
+            build_script,
+            start_script,
+            copy,
+            runtime_image,
+            entrypoint,
+            docker,
+        } = self;
+
+        let package_dir = |package: &NodePackage| match package.path.as_os_str().is_empty() {
+            true => ".".to_string(),
+            false => get_docker_path(&package.path),
+        };
+
+        let manifest_copy = once(format!("COPY package.json {} ./", package_manager.lockfile()))
+            .chain(config_files.iter().map(|file| format!("COPY {file} {file}")))
+            .chain(
+                workspace_packages
+                    .iter()
+                    .filter(|package| !package.path.as_os_str().is_empty())
+                    .map(|package| {
+                        let manifest = format!("{}/package.json", get_docker_path(&package.path));
+                        format!("COPY {manifest} {manifest}")
+                    }),
+            )
+            .collect::<Vec<_>>()
+            .join("\n  ");
+
+        let package_copy = match packages.iter().any(|package| package.path.as_os_str().is_empty()) {
+            true => "COPY . ./".to_string(),
+            false => packages
+                .iter()
+                .map(|package| {
+                    let path = package_dir(package);
+                    format!("COPY {path} {path}")
+                })
+                .collect::<Vec<_>>()
+                .join("\n  "),
+        };
+        let file_copy = copy
+            .iter()
+            .map(|line| format!("\n  {}", line.trim()))
+            .collect::<String>();
+        let file_copy = match file_copy.is_empty() {
+            true => String::new(),
+            false => format!("\n{file_copy}"),
+        };
+
+        let build = packages
+            .iter()
+            .filter(|package| package.scripts.contains(*build_script))
+            .map(|package| {
+                format!(
+                    "RUN cd /app/{} && {}",
+                    package_dir(package),
+                    package_manager.run(build_script)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n  ");
+        let prune = package_manager.prune().map(|prune| format!("RUN {prune}"));
+
+        let mut runtime_config = get_runtime_config(docker, *entrypoint)?;
+        if entrypoint.is_none() {
+            let service_scripts = packages.last().map(|package| &package.scripts);
+            let command = match service_scripts.is_some_and(|scripts| scripts.contains(*start_script)) {
+                // the runtime image only ships npm and yarn classic, which runs yarn berry through `yarnPath`
+                true => match package_manager {
+                    PackageManager::YarnBerry => vec!["yarn", "run", start_script],
+                    _ => vec!["npm", "run", start_script],
+                },
+                false => vec!["node", "."],
+            };
+            if !runtime_config.is_empty() {
+                runtime_config.push_str("\n  ");
+            }
+            runtime_config.push_str(&format!("CMD {}", serde_json::to_string(&command)?));
+        }
+
+        let runtime_image = match runtime_image {
+            Some(runtime_image) => runtime_image.to_string(),
+            None => format!("node:{node_version}-slim"),
+        };
+
+        let dockerfile = NODE_SERVICE_DOCKERFILE
+            .replace("$node_version", node_version)
+            .replace(
+                "$package_manager_setup",
+                &package_manager
+                    .setup()
+                    .map(|setup| format!("\n  {setup}"))
+                    .unwrap_or_default(),
+            )
+            .replace("$manifest_copy", &manifest_copy)
+            .replace("$install", package_manager.install())
+            .replace("$package_copy", &package_copy)
+            .replace("$file_copy", &file_copy)
+            .replace("$build", &build)
+            .replace("$prune", prune.as_deref().unwrap_or_default())
+            .replace("$runtime_image", &runtime_image)
+            .replace("$service_dir", &get_docker_path(service_dir))
+            .replace("$runtime_config", &runtime_config);
+        Ok(dockerfile.trim().to_string())
+    }
+}
+
+/// Names of the binaries copied into the final stage, every binary of `bins` (the one named after the service if
+/// empty) per feature set, suffixed with the features it was built with.
+pub(crate) fn get_binary_names(service_name: &str, bins: &[String], feature_sets: &[FeatureSet]) -> Vec<String> {
+    let binaries = match bins.is_empty() {
+        true => vec![service_name],
+        false => bins.iter().map(String::as_str).collect(),
+    };
+    feature_sets
+        .iter()
+        .flat_map(|feature_set| {
+            let feature_set = feature_set.suffix();
+            binaries.iter().map(move |binary| format!("{binary}{feature_set}"))
+        })
+        .collect()
+}
+
+/// `RUN` instructions building `binaries` per feature set for the build host, binaries built with features are
+/// suffixed with them.
+fn get_build_binaries(binaries: &[&str], bins: &[String], profile: &str, feature_sets: &[FeatureSet]) -> Vec<String> {
+    let build_profile = get_build_profile(profile);
+    let bins_flag = get_bins_flag(bins);
+    feature_sets
+        .iter()
+        .map(|feature_set| {
+            let features_flag = get_features_flag(&feature_set.features);
+            let build_cmd = format!("  RUN cargo build{build_profile}{bins_flag}{features_flag}");
+            if feature_set.suffix().is_empty() {
+                return build_cmd;
+            }
+            let feature_set = feature_set.suffix();
+            let renames = binaries
+                .iter()
+                .map(|binary| {
+                    format!("\n  RUN mv /app/target/{profile}/{binary} /app/target/{profile}/{binary}{feature_set}")
+                })
+                .collect::<String>();
+            format!("{build_cmd}{renames}")
+        })
+        .collect()
+}
+
+/// `RUN` instructions cross-compiling `binaries` per feature set for the platform being built (`$TARGETPLATFORM`),
+/// binaries are moved to where [`get_build_binaries`] leaves them so that the final stage is the same.
+fn get_cross_build_binaries(
+    binaries: &[&str],
+    bins: &[String],
+    profile: &str,
+    feature_sets: &[FeatureSet],
+    platforms: &[Platform],
+) -> Vec<String> {
+    let targets = platforms
+        .iter()
+        .map(|platform| format!("{}) echo {} ;;", platform.name, platform.target))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let mut instructions = vec![
+        "  ARG TARGETPLATFORM".to_string(),
+        format!(
+            r#"  RUN case "$TARGETPLATFORM" in {targets} *) echo "unsupported platform $TARGETPLATFORM" >&2 && exit 1 ;; esac > /app/rust_target"#
+        ),
+    ];
+    instructions.extend(get_target_build_binaries(binaries, bins, profile, feature_sets));
+    instructions
+}
+
+/// `RUN` instructions compiling `binaries` per feature set for the musl target of the build host, binaries are moved
+/// to where [`get_build_binaries`] leaves them so that the final stage is the same.
+fn get_musl_build_binaries(
+    binaries: &[&str],
+    bins: &[String],
+    profile: &str,
+    feature_sets: &[FeatureSet],
+) -> Vec<String> {
+    let mut instructions = vec![format!(r#"  RUN echo "{MUSL_TARGET}" > /app/rust_target"#)];
+    instructions.extend(get_target_build_binaries(binaries, bins, profile, feature_sets));
+    instructions
+}
+
+/// `RUN` instructions compiling `binaries` per feature set for the target written to `/app/rust_target`.
+fn get_target_build_binaries(
+    binaries: &[&str],
+    bins: &[String],
+    profile: &str,
+    feature_sets: &[FeatureSet],
+) -> Vec<String> {
+    let build_profile = get_build_profile(profile);
+    let bins_flag = get_bins_flag(bins);
+    let mut instructions = vec![format!("  RUN mkdir -p /app/target/{profile}")];
+    for feature_set in feature_sets {
+        let features_flag = get_features_flag(&feature_set.features);
+        let feature_set = feature_set.suffix();
+        instructions.push(format!(
+            "  RUN cargo build{build_profile} --target=$(cat /app/rust_target){bins_flag}{features_flag}"
+        ));
+        instructions.extend(binaries.iter().map(|binary| {
+            format!(
+                "  RUN mv /app/target/$(cat /app/rust_target)/{profile}/{binary} /app/target/{profile}/{binary}{feature_set}"
+            )
+        }));
+    }
+    instructions
+}
+
+/// Adds BuildKit mounts to every `RUN` instruction of `dockerfile` invoking cargo, declaring the Dockerfile syntax
+/// which supports them: the caches of `cache_mounts` and, with `credentials`, cargo's credentials and the ssh agent
+/// for fetching from private registries and git repositories. Neither secret is required to build.
+fn with_mounts(dockerfile: &str, cache_mounts: Option<CacheMounts>, credentials: bool) -> String {
+    let mut mounts = vec![];
+    if let Some(cache_mounts) = cache_mounts {
+        let mut targets = CARGO_CACHE_DIRS.to_vec();
+        if cache_mounts == CacheMounts::Sccache {
+            targets.push(SCCACHE_DIR);
+        }
+        mounts.extend(targets.iter().map(|target| format!("type=cache,target={target}")));
+    }
+    if credentials {
+        mounts.push(format!(
+            "type=secret,id={CARGO_CREDENTIALS_SECRET},target={CARGO_CREDENTIALS}"
+        ));
+        mounts.push("type=ssh".to_string());
+    }
+    if mounts.is_empty() {
+        return dockerfile.to_string();
+    }
+    let mounts = mounts
+        .iter()
+        .map(|mount| format!("--mount={mount} "))
+        .collect::<String>();
+    let dockerfile = dockerfile.replace("RUN cargo ", &format!("RUN {mounts}cargo "));
+    format!("# syntax=docker/dockerfile:1\n{dockerfile}")
+}
+
+/// Cargo configuration of the private registries and git dependencies of a workspace: the registries and source
+/// replacements of its `.cargo/config.toml` without any tokens, which are mounted as a secret instead, and fetching
+/// git repositories with the git cli so that the ssh agent is used.
+fn get_registry_config(cargo_config: Option<&str>) -> Result<String, Error> {
+    let mut config = toml::value::Table::new();
+    let cargo_config = match cargo_config {
+        Some(cargo_config) => cargo_config
+            .parse::<Value>()
+            .map_err(|err| Error::manifest(".cargo/config.toml", err.to_string()))?,
+        None => Value::Table(Default::default()),
+    };
+    if let Some(Value::Table(registries)) = cargo_config.get("registries") {
+        let mut registries = registries.clone();
+        for (_, registry) in registries.iter_mut() {
+            if let Value::Table(registry) = registry {
+                registry.remove("token");
+            }
+        }
+        config.insert("registries".to_string(), Value::Table(registries));
+    }
+    if let Some(default) = cargo_config
+        .get("registry")
+        .and_then(|registry| registry.get("default"))
+    {
+        let registry = toml::value::Table::from_iter([("default".to_string(), default.clone())]);
+        config.insert("registry".to_string(), Value::Table(registry));
+    }
+    if let Some(source) = cargo_config.get("source") {
+        config.insert("source".to_string(), source.clone());
+    }
+    let net = toml::value::Table::from_iter([("git-fetch-with-cli".to_string(), Value::Boolean(true))]);
+    config.insert("net".to_string(), Value::Table(net));
+    Ok(toml::ser::to_string(&Value::Table(config))?.trim().to_string())
+}
+
+/// Offline cargo configuration of dependencies vendored with `cargo vendor`: the source replacement it printed,
+/// reading the vendored sources from the stage's copy of them in [`VENDOR_DIR`].
+fn get_vendor_config(vendor: &str) -> Result<String, Error> {
+    let invalid = |message: String| Error::InvalidInput(format!("unexpected `cargo vendor` output: {message}"));
+    let mut config = match vendor.parse::<Value>().map_err(|err| invalid(err.to_string()))? {
+        Value::Table(config) => config,
+        _ => return Err(invalid("not a toml table".to_string())),
+    };
+    if let Some(Value::Table(sources)) = config.get_mut("source") {
+        for (_, source) in sources.iter_mut() {
+            if let Some(directory) = source.get_mut("directory") {
+                *directory = Value::String(format!("/app/{VENDOR_DIR}"));
+            }
+        }
+    }
+    let net = toml::value::Table::from_iter([("offline".to_string(), Value::Boolean(true))]);
+    config.insert("net".to_string(), Value::Table(net));
+    Ok(toml::ser::to_string(&Value::Table(config))?.trim().to_string())
+}
+
+/// Binaries are only ever compiled for the build host's musl target, cross-compiling to musl is not supported.
+fn check_musl_platforms(musl: bool, platforms: &[Platform]) -> Result<(), Error> {
+    match musl && !platforms.is_empty() {
+        true => Err(Error::InvalidInput(
+            "--platform cannot be combined with a runtime image binaries are linked against musl for (alpine, \
+             scratch or distroless/static)"
+                .to_string(),
+        )),
+        false => Ok(()),
+    }
+}
+
+/// `--bin` flags selecting `bins`, empty to let cargo build the package's only binary.
+fn get_bins_flag(bins: &[String]) -> String {
+    bins.iter().map(|bin| format!(" --bin {bin}")).collect()
+}
+
+fn get_features_flag(feature_set: &[&str]) -> String {
+    if feature_set.is_empty() {
+        "".into()
+    } else {
+        format!(" --features={}", feature_set.join(","))
+    }
+}
+
+/// Final stage instructions describing how the service runs: its `[package.metadata.ops.docker]` settings and
+/// entrypoint.
+fn get_runtime_config(docker: &PackageDockerConfig, entrypoint: Option<&str>) -> Result<String, Error> {
+    let mut instructions = docker
+        .env
+        .iter()
+        .map(|(key, value)| Ok(format!("ENV {key}={}", serde_json::to_string(value)?)))
+        .collect::<Result<Vec<_>, Error>>()?;
+    instructions.extend(docker.container_ports().map(|port| format!("EXPOSE {port}")));
+    if let Some(healthcheck) = docker.healthcheck.as_ref() {
+        if healthcheck.command.is_empty() {
+            return Err(Error::InvalidInput(
+                "`package.metadata.ops.docker.healthcheck.command` must not be empty".to_string(),
+            ));
+        }
+        let mut options = vec![];
+        for (option, value) in [
+            ("interval", &healthcheck.interval),
+            ("timeout", &healthcheck.timeout),
+            ("start-period", &healthcheck.start_period),
+        ] {
+            if let Some(value) = value {
+                options.push(format!("--{option}={value} "));
+            }
+        }
+        if let Some(retries) = healthcheck.retries {
+            options.push(format!("--retries={retries} "));
+        }
+        instructions.push(format!(
+            "HEALTHCHECK {}CMD {}",
+            options.join(""),
+            serde_json::to_string(&healthcheck.command)?
+        ));
+    }
+    if let Some(user) = docker.user.as_ref() {
+        instructions.push(format!("USER {user}"));
+    }
+    if let Some(entrypoint) = entrypoint {
+        instructions.push(format!("ENTRYPOINT {}", serde_json::to_string(&[entrypoint])?));
+    }
+    Ok(instructions.join("\n  "))
+}
@@ -0,0 +1,184 @@
+/// Minimal client for the [Harbor](https://goharbor.io) v2 API used by the `harbor` registry provider: creates the
+/// project an image is pushed into if it does not exist yet and deletes all but the most recently pushed artifacts
+/// of a repository. Requests are made with `curl`, the robot account's credentials are passed through stdin so
+/// they never show up in the process list.
+use crate::docker::push::{get_registry, RegistryCredentials};
+use crate::error::Error;
+use crate::exec::{run, run_or_record, ExecOptions};
+use serde::{Deserialize, Serialize};
+use std::process::Command;
+use tracing::{debug, info};
+
+/// number of artifacts requested per page when listing a repository
+const PAGE_SIZE: usize = 100;
+
+/// Client of a Harbor instance's API authenticated as a robot account, see [`HarborClient::new`].
+pub struct HarborClient {
+    url: String,
+    credentials: RegistryCredentials,
+}
+
+/// Project and repository an image reference points to within Harbor, e.g. `my-project` and `api/server` for
+/// `harbor.example.com/my-project/api/server:1.2.0`.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct HarborRepository {
+    pub project: String,
+    pub repository: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct HarborPushOutput {
+    pub project: String,
+    pub repository: String,
+    /// whether the project was created before pushing
+    pub project_created: bool,
+    /// digests of the artifacts deleted by tag retention
+    pub deleted: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct Project {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct Artifact {
+    digest: String,
+}
+
+impl HarborRepository {
+    pub fn from_image(image: &str) -> Result<Self, Error> {
+        let registry = get_registry(image);
+        let name = image
+            .strip_prefix(registry)
+            .and_then(|name| name.strip_prefix('/'))
+            .ok_or_else(|| Error::InvalidInput(format!("`{image}` does not name a registry host")))?;
+        let name = name.split_once('@').map(|(name, _)| name).unwrap_or(name);
+        let name = name.rsplit_once(':').map(|(name, _)| name).unwrap_or(name);
+        match name.split_once('/') {
+            Some((project, repository)) if !project.is_empty() && !repository.is_empty() => Ok(Self {
+                project: project.to_string(),
+                repository: repository.to_string(),
+            }),
+            _ => Err(Error::InvalidInput(format!(
+                "`{image}` is not a Harbor image reference (`<registry>/<project>/<repository>[:<tag>]`)"
+            ))),
+        }
+    }
+}
+
+impl HarborClient {
+    /// `url` is the base url of the Harbor instance, e.g. `https://harbor.example.com`.
+    pub fn new(url: &str, credentials: RegistryCredentials) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            credentials,
+        }
+    }
+
+    /// Creates `project` as a private project unless it already exists, returning whether it was created.
+    pub fn ensure_project(&self, project: &str) -> Result<bool, Error> {
+        let projects = self.get(&format!("/projects?name={project}&page_size={PAGE_SIZE}"))?;
+        let projects = serde_json::from_str::<Vec<Project>>(&projects)?;
+        if projects.iter().any(|existing| existing.name == project) {
+            debug!("harbor project {project} already exists");
+            return Ok(false);
+        }
+        info!("creating harbor project {project}");
+        let body = serde_json::json!({ "project_name": project, "metadata": { "public": "false" } });
+        self.send("POST", "/projects", Some(&body))?;
+        Ok(true)
+    }
+
+    /// Deletes every artifact of `repository` apart from the `retain` most recently pushed ones, returning the
+    /// digests of the deleted artifacts.
+    pub fn retain(&self, repository: &HarborRepository, retain: usize) -> Result<Vec<String>, Error> {
+        let path = repository_path(repository);
+        let mut artifacts = vec![];
+        for page in 1.. {
+            let page = self.get(&format!(
+                "{path}/artifacts?page={page}&page_size={PAGE_SIZE}&sort=-push_time&with_tag=false"
+            ))?;
+            let page = serde_json::from_str::<Vec<Artifact>>(&page)?;
+            let last = page.len() < PAGE_SIZE;
+            artifacts.extend(page);
+            if last {
+                break;
+            }
+        }
+
+        let mut deleted = vec![];
+        for Artifact { digest } in artifacts.into_iter().skip(retain) {
+            info!("deleting {}/{}@{digest}", repository.project, repository.repository);
+            self.send("DELETE", &format!("{path}/artifacts/{digest}"), None)?;
+            deleted.push(digest);
+        }
+        Ok(deleted)
+    }
+
+    /// Read-only request, made even in dry-run mode.
+    fn get(&self, path: &str) -> Result<String, Error> {
+        let (command, options) = self.request("GET", path, None)?;
+        let output = run(&command, &options)?;
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// Side-effecting request, recorded instead in dry-run mode.
+    fn send(&self, method: &str, path: &str, body: Option<&serde_json::Value>) -> Result<(), Error> {
+        let (command, options) = self.request(method, path, body)?;
+        run_or_record(&command, &options)?;
+        Ok(())
+    }
+
+    fn request(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<&serde_json::Value>,
+    ) -> Result<(Command, ExecOptions), Error> {
+        let mut command = Command::new("curl");
+        command
+            .args([
+                "--silent",
+                "--show-error",
+                "--fail",
+                "--config",
+                "-",
+                "--request",
+                method,
+            ])
+            .args(["--header", "Accept: application/json"]);
+        if let Some(body) = body {
+            command
+                .args(["--header", "Content-Type: application/json"])
+                .args(["--data", &serde_json::to_string(body)?]);
+        }
+        command.arg(format!("{}/api/v2.0{path}", self.url));
+
+        // robot account names contain `$` and `+`, quoting keeps them intact
+        let RegistryCredentials { username, password } = &self.credentials;
+        let user = format!("{username}:{password}")
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"");
+        let options = ExecOptions {
+            stdin: Some(format!("user = \"{user}\"\n").into_bytes()),
+            ..match method {
+                "GET" => ExecOptions::idempotent(),
+                _ => ExecOptions {
+                    stream: false,
+                    ..Default::default()
+                },
+            }
+        };
+        Ok((command, options))
+    }
+}
+
+/// Nested repository names have to be url encoded twice, Harbor decodes the path once before routing it.
+fn repository_path(repository: &HarborRepository) -> String {
+    format!(
+        "/projects/{}/repositories/{}",
+        repository.project,
+        repository.repository.replace('/', "%252F")
+    )
+}
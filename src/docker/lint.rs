@@ -0,0 +1,276 @@
+/// Checks of rendered Dockerfiles run before they are built, so that a broken template or configuration fails fast
+/// with the offending line instead of as a confusing docker build failure: unknown or incomplete instructions,
+/// malformed exec forms, variables left unreplaced and `COPY` instructions without sources or, when the build
+/// context is known, whose sources do not exist in it.
+use crate::docker::template::TemplateKind;
+use crate::error::Error;
+use std::collections::BTreeSet;
+use std::path::Path;
+
+const INSTRUCTIONS: [&str; 18] = [
+    "ADD",
+    "ARG",
+    "CMD",
+    "COPY",
+    "ENTRYPOINT",
+    "ENV",
+    "EXPOSE",
+    "FROM",
+    "HEALTHCHECK",
+    "LABEL",
+    "MAINTAINER",
+    "ONBUILD",
+    "RUN",
+    "SHELL",
+    "STOPSIGNAL",
+    "USER",
+    "VOLUME",
+    "WORKDIR",
+];
+
+/// instructions whose arguments are run by a shell, which expands their variables itself
+const SHELL_INSTRUCTIONS: [&str; 4] = ["CMD", "ENTRYPOINT", "HEALTHCHECK", "RUN"];
+
+/// build args which are available in `FROM` without being declared
+const PREDEFINED_ARGS: [&str; 8] = [
+    "BUILDPLATFORM",
+    "BUILDOS",
+    "BUILDARCH",
+    "BUILDVARIANT",
+    "TARGETPLATFORM",
+    "TARGETOS",
+    "TARGETARCH",
+    "TARGETVARIANT",
+];
+
+/// Instruction of a Dockerfile with its line continuations joined.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Instruction {
+    /// line the instruction starts on, starting at 1
+    pub line: usize,
+    /// keyword in upper case, e.g. `COPY`
+    pub keyword: String,
+    pub args: String,
+}
+
+/// Splits `dockerfile` into its instructions, skipping comments, blank lines and the bodies of heredocs.
+pub fn parse_dockerfile(dockerfile: &str) -> Vec<Instruction> {
+    let mut instructions = vec![];
+    let mut lines = dockerfile.lines().enumerate();
+    while let Some((index, line)) = lines.next() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut text = line.to_string();
+        while let Some(continued) = text.strip_suffix('\\') {
+            text = continued.to_string();
+            // comment lines within a continuation are dropped by docker as well
+            match lines
+                .by_ref()
+                .map(|(_, line)| line.trim())
+                .find(|line| !line.starts_with('#'))
+            {
+                Some(line) => text.push_str(line),
+                None => break,
+            }
+        }
+        for delimiter in get_heredoc_delimiters(&text) {
+            lines.by_ref().find(|(_, line)| line.trim() == delimiter);
+        }
+        let (keyword, args) = text.split_once(char::is_whitespace).unwrap_or((&text, ""));
+        instructions.push(Instruction {
+            line: index + 1,
+            keyword: keyword.to_uppercase(),
+            args: args.trim().to_string(),
+        });
+    }
+    instructions
+}
+
+/// Problems found in `dockerfile`, as `line <n>: <problem>`. Sources of `COPY` and `ADD` instructions are only
+/// checked for existence if the directory of the build context is passed.
+pub fn lint_dockerfile(dockerfile: &str, context_dir: Option<&Path>) -> Vec<String> {
+    let template_variables = [TemplateKind::PreBuild, TemplateKind::Service]
+        .iter()
+        .flat_map(|kind| kind.variables().iter().map(|variable| variable.name))
+        .collect::<BTreeSet<_>>();
+    let mut declared = BTreeSet::new();
+    let mut from_seen = false;
+    let mut problems = vec![];
+    for Instruction { line, keyword, args } in parse_dockerfile(dockerfile) {
+        let mut problem = |message: String| problems.push(format!("line {line}: {message}"));
+        if !INSTRUCTIONS.contains(&keyword.as_str()) {
+            problem(format!("unknown instruction `{keyword}`"));
+            continue;
+        }
+        if args.is_empty() {
+            problem(format!("`{keyword}` has no arguments"));
+            continue;
+        }
+        if !from_seen && keyword != "FROM" && keyword != "ARG" {
+            problem(format!("`{keyword}` before the first `FROM`"));
+        }
+
+        for name in get_variables(&args) {
+            let predefined = keyword == "FROM" && PREDEFINED_ARGS.contains(&name);
+            if declared.contains(name) || predefined {
+                continue;
+            }
+            // shells expand their own variables, only names ops substitutes are known to be left unreplaced
+            let shell = SHELL_INSTRUCTIONS.contains(&keyword.as_str());
+            if template_variables.contains(name) || (!shell && !name.chars().any(|c| c.is_ascii_uppercase())) {
+                problem(format!("unreplaced variable `${name}`"));
+            }
+        }
+
+        match keyword.as_str() {
+            "FROM" => from_seen = true,
+            "ARG" => {
+                declared.insert(args.split('=').next().unwrap_or_default().trim().to_string());
+            }
+            "ENV" => declared.extend(get_env_keys(&args)),
+            "ADD" | "COPY" => {
+                if let Err(message) = check_copy(&keyword, &args, context_dir) {
+                    problem(message);
+                }
+            }
+            _ => {}
+        }
+        // docker runs anything which is not a json array with a shell (e.g. `RUN [ -f file ]`), so only arguments
+        // which start like a json array of strings are meant as exec form
+        if ["CMD", "ENTRYPOINT", "RUN", "SHELL"].contains(&keyword.as_str())
+            && args.starts_with("[\"")
+            && serde_json::from_str::<Vec<String>>(&args).is_err()
+        {
+            problem(format!("`{keyword}` exec form is not a json array of strings"));
+        }
+    }
+    if !from_seen {
+        problems.push("no `FROM` instruction".to_string());
+    }
+    problems
+}
+
+/// Fails with every problem of the `stage` Dockerfile found by [`lint_dockerfile`].
+pub fn check_dockerfile(stage: &str, dockerfile: &str, context_dir: Option<&Path>) -> Result<(), Error> {
+    let problems = lint_dockerfile(dockerfile, context_dir);
+    match problems.is_empty() {
+        true => Ok(()),
+        false => Err(Error::InvalidDockerfile {
+            stage: stage.to_string(),
+            problems,
+        }),
+    }
+}
+
+/// Paths `dockerfile` copies from the build context with `COPY` and `ADD`.
+pub(crate) fn get_build_context_sources(dockerfile: &str) -> Vec<String> {
+    let mut sources = vec![];
+    for Instruction { keyword, args, .. } in parse_dockerfile(dockerfile) {
+        if keyword != "COPY" && keyword != "ADD" {
+            continue;
+        }
+        let (flags, mut paths) = split_copy_args(&args);
+        // copies from other stages or images do not read the build context
+        if flags.iter().any(|flag| flag.starts_with("--from")) {
+            continue;
+        }
+        paths.pop();
+        sources.extend(paths);
+    }
+    sources
+}
+
+/// Flags and paths, the sources followed by the destination, of `COPY` or `ADD` arguments in either shell or exec
+/// form (`COPY ["src", "dest"]`).
+fn split_copy_args(args: &str) -> (Vec<String>, Vec<String>) {
+    let (flags, words) = args
+        .split_whitespace()
+        .partition::<Vec<_>, _>(|word| word.starts_with("--"));
+    let words = words.join(" ");
+    let paths = match serde_json::from_str::<Vec<String>>(&words) {
+        Ok(paths) => paths,
+        Err(_) => words
+            .split(' ')
+            .filter(|word| !word.is_empty())
+            .map(String::from)
+            .collect(),
+    };
+    (flags.into_iter().map(String::from).collect(), paths)
+}
+
+fn check_copy(keyword: &str, args: &str, context_dir: Option<&Path>) -> Result<(), String> {
+    if args.contains("<<") {
+        return Ok(());
+    }
+    let (flags, paths) = split_copy_args(args);
+    let Some((_, sources)) = paths.split_last().filter(|(_, sources)| !sources.is_empty()) else {
+        return Err(format!("`{keyword}` needs a source and a destination"));
+    };
+    if sources.iter().any(|source| source.is_empty()) {
+        return Err(format!("`{keyword}` has an empty source"));
+    }
+    let Some(context_dir) = context_dir else {
+        return Ok(());
+    };
+    // sources of other stages and images, globs and urls are left to docker
+    if flags.iter().any(|flag| flag.starts_with("--from")) {
+        return Ok(());
+    }
+    let missing = sources.iter().find(|source| {
+        !source.contains(['*', '?', '[', '$']) && !source.contains("://") && !context_dir.join(source).exists()
+    });
+    match missing {
+        Some(source) => Err(format!(
+            "`{keyword}` source `{source}` does not exist in the build context"
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Names of the variables referenced as `$name` or `${name}` (optionally with a modifier, e.g. `${name:-default}`),
+/// escaped `\$` excluded.
+fn get_variables(text: &str) -> Vec<&str> {
+    let mut names = vec![];
+    let mut rest = text;
+    while let Some(index) = rest.find('$') {
+        let escaped = rest[..index].ends_with('\\');
+        let after = rest[index + 1..].strip_prefix('{').unwrap_or(&rest[index + 1..]);
+        let len = after
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(after.len());
+        let name = &after[..len];
+        if !escaped && name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+            names.push(name);
+        }
+        rest = &after[len..];
+    }
+    names
+}
+
+/// Keys set by the arguments of an `ENV` instruction, either `key=value ...` or the legacy `key value`.
+fn get_env_keys(args: &str) -> Vec<String> {
+    let first = args.split_whitespace().next().unwrap_or_default();
+    if !first.contains('=') {
+        return vec![first.to_string()];
+    }
+    args.split_whitespace()
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, _)| key.to_string())
+        .collect()
+}
+
+/// Delimiters of the heredocs started by an instruction (e.g. `EOF` for `RUN <<EOF` or `<<-"EOF"`).
+fn get_heredoc_delimiters(text: &str) -> Vec<String> {
+    text.match_indices("<<")
+        .filter_map(|(index, _)| {
+            let rest = text[index + 2..].trim_start_matches('-');
+            let rest = rest.trim_start_matches(['"', '\'']);
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            (len > 0).then(|| rest[..len].to_string())
+        })
+        .collect()
+}
@@ -1,8 +1,40 @@
+pub mod bake;
 pub mod build;
+pub mod build_node_workspace;
 pub mod build_rust_workspace;
+pub mod cache_prune;
+pub mod compose;
+pub mod content_hash;
+pub mod dockerfile;
+pub mod harbor;
+pub mod lint;
+pub mod platform;
+pub mod push;
+pub mod registry;
+pub mod render;
+pub mod run;
+pub mod scan;
+pub mod template;
+pub mod warm_cache;
 
 pub mod prelude {
     use super::*;
+    pub use bake::*;
     pub use build::*;
+    pub use build_node_workspace::*;
     pub use build_rust_workspace::*;
+    pub use cache_prune::*;
+    pub use compose::*;
+    pub use content_hash::*;
+    pub use dockerfile::*;
+    pub use harbor::*;
+    pub use lint::*;
+    pub use platform::*;
+    pub use push::*;
+    pub use registry::*;
+    pub use render::*;
+    pub use run::*;
+    pub use scan::*;
+    pub use template::*;
+    pub use warm_cache::*;
 }
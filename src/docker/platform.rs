@@ -0,0 +1,86 @@
+/// Docker platforms service images can be cross-compiled for. Binaries are always compiled on the build host
+/// (`$BUILDPLATFORM`) for the rust target of each requested platform, which is much faster than compiling under
+/// emulation, and only the final runtime stage is assembled per platform.
+use crate::error::Error;
+use serde::Serialize;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+pub struct Platform {
+    /// docker platform, e.g. `linux/arm64`
+    pub name: &'static str,
+    /// rust target triple binaries are compiled to
+    pub target: &'static str,
+    /// `uname -m` of build hosts which compile for the platform natively
+    pub machine: &'static str,
+    /// debian package of the cross linker installed on other build hosts
+    pub linker_package: &'static str,
+    pub linker: &'static str,
+}
+
+/// every platform which can be passed to `--platform`
+pub const PLATFORMS: [Platform; 3] = [
+    Platform {
+        name: "linux/amd64",
+        target: "x86_64-unknown-linux-gnu",
+        machine: "x86_64",
+        linker_package: "gcc-x86-64-linux-gnu",
+        linker: "x86_64-linux-gnu-gcc",
+    },
+    Platform {
+        name: "linux/arm64",
+        target: "aarch64-unknown-linux-gnu",
+        machine: "aarch64",
+        linker_package: "gcc-aarch64-linux-gnu",
+        linker: "aarch64-linux-gnu-gcc",
+    },
+    Platform {
+        name: "linux/arm/v7",
+        target: "armv7-unknown-linux-gnueabihf",
+        machine: "armv7l",
+        linker_package: "gcc-arm-linux-gnueabihf",
+        linker: "arm-linux-gnueabihf-gcc",
+    },
+];
+
+impl Platform {
+    /// `RUN` instruction installing the cross linker of the platform and configuring cargo to use it, unless the
+    /// build host compiles for the platform natively.
+    pub fn linker_setup(&self) -> String {
+        let Self {
+            target,
+            machine,
+            linker_package,
+            linker,
+            ..
+        } = self;
+        format!(
+            r#"RUN [ "$(uname -m)" = "{machine}" ] || (apt-get -y install {linker_package} && printf '[target.{target}]\nlinker = "{linker}"\n' >> "$CARGO_HOME/config.toml")"#
+        )
+    }
+}
+
+/// Parses docker platform names, `linux/aarch64` and `linux/x86_64` are accepted as aliases as with docker.
+pub fn get_platforms(names: &[String]) -> Result<Vec<Platform>, Error> {
+    let mut platforms = vec![];
+    for name in names {
+        let normalized = match name.trim() {
+            "linux/x86_64" => "linux/amd64",
+            "linux/aarch64" | "linux/arm64/v8" => "linux/arm64",
+            "linux/arm" | "linux/armhf" => "linux/arm/v7",
+            name => name,
+        };
+        let platform = PLATFORMS
+            .iter()
+            .find(|platform| platform.name == normalized)
+            .ok_or_else(|| {
+                Error::InvalidInput(format!(
+                    "unsupported platform `{name}`, expected one of {}",
+                    PLATFORMS.map(|platform| platform.name).join(", ")
+                ))
+            })?;
+        if !platforms.contains(platform) {
+            platforms.push(*platform);
+        }
+    }
+    Ok(platforms)
+}
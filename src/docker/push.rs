@@ -0,0 +1,608 @@
+/// Pushes an already built image to its registry, first logging into the registry with credentials obtained
+/// from the configured provider. Providers are shared with any other command which talks to a registry so that
+/// authentication only has to be implemented once. Alternatively a docker credential helper can be used, in which
+/// case the push runs against a temporary docker config which only delegates the registry to the helper so that
+/// no password is ever passed to `docker login`. Images pushed to Harbor can additionally have their project
+/// created beforehand and the repository's older artifacts deleted afterwards. Pushes failing with transient errors
+/// are retried with an exponential backoff, and the pushed digest can be written to a file for deploy tooling.
+use crate::config::OpsConfig;
+use crate::docker::harbor::{HarborClient, HarborPushOutput, HarborRepository};
+use crate::docker::registry::push_oci_archive;
+use crate::dry_run::{enable_dry_run, is_dry_run, set_dry_run_dir, write_file};
+use crate::error::Error;
+use crate::exec::{read, run_or_record, ExecOptions};
+use crate::git::signature::{verify_signed_head, SignatureArgs, SignatureOutput};
+use crate::lock::{lock, LockArgs};
+use crate::logging::LogArgs;
+use crate::progress::format_duration;
+use crate::prompt::confirm;
+use crate::report::{serialize_duration, Report};
+use crate::temp::temp_dir;
+use crate::timing::{time, Phase};
+use clap::{Parser, ValueEnum};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::env::consts::EXE_SUFFIX;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tracing::{debug, info_span};
+
+/// environment variable holding the registry username of the `basic` provider
+pub const OPS_REGISTRY_USERNAME_ENV: &str = "OPS_REGISTRY_USERNAME";
+
+/// environment variable holding the registry password (or token) of the `basic` provider
+pub const OPS_REGISTRY_PASSWORD_ENV: &str = "OPS_REGISTRY_PASSWORD";
+
+const DOCKER_HUB_REGISTRY: &str = "docker.io";
+
+/// stderr fragments of push failures which retrying cannot fix
+const PERMANENT_PUSH_ERRORS: [&str; 6] = [
+    "unauthorized",
+    "denied",
+    "authentication required",
+    "forbidden",
+    "does not exist",
+    "no such image",
+];
+
+#[derive(Clone, Debug, Parser)]
+#[clap(author, version, about, long_about = None)]
+pub struct DockerPushArgs {
+    /// image reference to push (e.g. `ghcr.io/my-org/api:1.2.0`)
+    #[clap(value_parser)]
+    pub image: String,
+
+    /// how to obtain credentials for the image's registry
+    #[clap(long, value_enum, default_value = "docker", env = "OPS_REGISTRY_PROVIDER")]
+    pub provider: RegistryProvider,
+
+    /// docker credential helper to authenticate with instead of logging in through a provider (e.g. `ecr-login`,
+    /// `gcloud` or `osxkeychain`), `docker-credential-<helper>` must be on the PATH
+    #[clap(long, env = "OPS_CREDENTIAL_HELPER")]
+    pub credential_helper: Option<String>,
+
+    /// url of the Harbor instance to call the API of, defaults to `https://<registry>`
+    /// - only used by the `harbor` provider
+    #[clap(long, env = "OPS_HARBOR_URL")]
+    pub harbor_url: Option<String>,
+
+    /// create the image's Harbor project before pushing if it does not exist yet, requires a robot account with
+    /// permission to create projects
+    /// - only used by the `harbor` provider
+    #[clap(long, env = "OPS_HARBOR_CREATE_PROJECT")]
+    pub create_project: bool,
+
+    /// number of most recently pushed artifacts to keep in the image's Harbor repository, older artifacts are
+    /// deleted after the push
+    /// - only used by the `harbor` provider
+    #[clap(long, env = "OPS_HARBOR_RETAIN")]
+    pub retain: Option<usize>,
+
+    /// number of times a push failing with a transient error (e.g. a timeout or a 5xx response) is retried,
+    /// defaults to OPS_RETRIES -- authentication errors and missing images are never retried
+    #[clap(long, env = "OPS_PUSH_RETRIES")]
+    pub retries: Option<u32>,
+
+    /// seconds to wait before the first retry of a failed push, doubled after every attempt -- defaults to 1
+    #[clap(long, env = "OPS_PUSH_RETRY_DELAY")]
+    pub retry_delay: Option<u64>,
+
+    /// OCI image layout to push with the registry HTTP API instead of pushing the local image with docker, either a
+    /// tarball (e.g. from `docker buildx build --output type=oci,dest=image.tar`) or a directory -- neither the docker
+    /// daemon nor the docker cli is needed
+    #[clap(long, env = "OPS_OCI_ARCHIVE")]
+    pub oci_archive: Option<PathBuf>,
+
+    /// file to write the pushed image's digest reference (`<repository>@sha256:...`) to, for deploy tooling
+    #[clap(long, env = "OPS_DIGEST_FILE")]
+    pub digest_file: Option<PathBuf>,
+
+    #[clap(flatten)]
+    pub signature: SignatureArgs,
+
+    #[clap(flatten)]
+    pub lock: LockArgs,
+
+    #[clap(flatten)]
+    pub log: LogArgs,
+
+    /// print the commands instead of running them
+    /// - can also be enabled by setting the OPS_DRY_RUN environment variable
+    #[clap(long)]
+    pub dry_run: bool,
+
+    /// write the dry-run plan to this directory instead of printing it, implies --dry-run: the commands as a shell
+    /// script and the plan as json
+    #[clap(long, env = "OPS_DRY_RUN_DIR")]
+    pub dry_run_dir: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum RegistryProvider {
+    /// use the credentials docker is already configured with
+    #[default]
+    Docker,
+    /// username and password from OPS_REGISTRY_USERNAME and OPS_REGISTRY_PASSWORD
+    Basic,
+    /// GitHub container registry, using GITHUB_ACTOR and GITHUB_TOKEN
+    Ghcr,
+    /// AWS elastic container registry, using `aws ecr get-login-password`, or the AWS SDK if ops is built with the
+    /// `aws-sdk` feature
+    Ecr,
+    /// Google container or artifact registry, using `gcloud auth print-access-token`
+    Gcr,
+    /// Azure container registry, using `az acr login --expose-token`
+    Acr,
+    /// Harbor, using the robot account from HARBOR_ROBOT_NAME and HARBOR_ROBOT_SECRET
+    Harbor,
+}
+
+#[derive(Clone)]
+pub struct RegistryCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DockerPushOutput {
+    pub image: String,
+    pub registry: String,
+    pub provider: RegistryProvider,
+    pub credential_helper: Option<String>,
+    /// digest of the pushed manifest as reported by docker
+    pub digest: Option<String>,
+    /// immutable `<repository>@<digest>` reference of the pushed image
+    pub reference: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub harbor: Option<HarborPushOutput>,
+    /// signed ref checked out when the image was pushed if `--require-signed` was passed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signature: Option<SignatureOutput>,
+    #[serde(serialize_with = "serialize_duration")]
+    pub duration: Duration,
+}
+
+impl Report for DockerPushOutput {
+    fn human(&self) -> String {
+        let mut lines = vec![match self.reference.as_ref() {
+            Some(reference) => format!(
+                "successfully pushed {} as {reference} in {}",
+                self.image,
+                format_duration(self.duration)
+            ),
+            None => format!(
+                "successfully pushed {} in {}",
+                self.image,
+                format_duration(self.duration)
+            ),
+        }];
+        if let Some(harbor) = self.harbor.as_ref() {
+            if harbor.project_created {
+                lines.push(format!("created harbor project {}", harbor.project));
+            }
+            if !harbor.deleted.is_empty() {
+                lines.push(format!(
+                    "deleted {} older artifact(s) of {}/{}",
+                    harbor.deleted.len(),
+                    harbor.project,
+                    harbor.repository
+                ));
+            }
+        }
+        lines.join("\n")
+    }
+}
+
+impl RegistryProvider {
+    /// Credentials for `registry`, `None` if docker's existing configuration should be used.
+    pub fn credentials(&self, registry: &str) -> Result<Option<RegistryCredentials>, Error> {
+        let credentials = match self {
+            Self::Docker => return Ok(None),
+            Self::Basic => RegistryCredentials {
+                username: required_env(OPS_REGISTRY_USERNAME_ENV, self)?,
+                password: required_env(OPS_REGISTRY_PASSWORD_ENV, self)?,
+            },
+            Self::Ghcr => RegistryCredentials {
+                username: env::var(OPS_REGISTRY_USERNAME_ENV).or_else(|_| required_env("GITHUB_ACTOR", self))?,
+                password: env::var(OPS_REGISTRY_PASSWORD_ENV).or_else(|_| required_env("GITHUB_TOKEN", self))?,
+            },
+            Self::Ecr => {
+                let region = get_ecr_region(registry).ok_or_else(|| {
+                    Error::InvalidInput(format!(
+                        "`{registry}` is not an ECR registry (`<account>.dkr.ecr.<region>.amazonaws.com`)"
+                    ))
+                })?;
+                get_ecr_credentials(region)?
+            }
+            Self::Gcr => RegistryCredentials {
+                username: "oauth2accesstoken".to_string(),
+                password: read(Command::new("gcloud").args(["auth", "print-access-token"]))?,
+            },
+            Self::Acr => {
+                let name = get_acr_name(registry).ok_or_else(|| {
+                    Error::InvalidInput(format!("`{registry}` is not an ACR registry (`<name>.azurecr.io`)"))
+                })?;
+                RegistryCredentials {
+                    // tokens of `az acr login --expose-token` are only accepted with this fixed username
+                    username: "00000000-0000-0000-0000-000000000000".to_string(),
+                    password: read(Command::new("az").args([
+                        "acr",
+                        "login",
+                        "--name",
+                        name,
+                        "--expose-token",
+                        "--output",
+                        "tsv",
+                        "--query",
+                        "accessToken",
+                    ]))?,
+                }
+            }
+            Self::Harbor => RegistryCredentials {
+                username: env::var(OPS_REGISTRY_USERNAME_ENV).or_else(|_| required_env("HARBOR_ROBOT_NAME", self))?,
+                password: env::var(OPS_REGISTRY_PASSWORD_ENV).or_else(|_| required_env("HARBOR_ROBOT_SECRET", self))?,
+            },
+        };
+        Ok(Some(credentials))
+    }
+
+    /// Logs docker into `registry`, a no-op for the `docker` provider.
+    pub fn login(&self, registry: &str) -> Result<(), Error> {
+        let Some(RegistryCredentials { username, password }) = self.credentials(registry)? else {
+            debug!("using existing docker credentials for {registry}");
+            return Ok(());
+        };
+        let mut command = Command::new("docker");
+        command.args(["login", "--username", &username, "--password-stdin", registry]);
+        run_or_record(
+            &command,
+            &ExecOptions {
+                stdin: Some(password.into_bytes()),
+                ..ExecOptions::idempotent()
+            },
+        )?;
+        Ok(())
+    }
+}
+
+/// Docker client configuration directory which only delegates a registry's credentials to a credential helper,
+/// removed once dropped.
+pub struct ScopedDockerConfig {
+    dir: TempDir,
+}
+
+impl ScopedDockerConfig {
+    pub fn new(registry: &str, credential_helper: &str) -> Result<Self, Error> {
+        let program = format!("docker-credential-{credential_helper}");
+        if !is_dry_run() && !is_on_path(&program) {
+            return Err(Error::ToolUnavailable {
+                program,
+                message: "credential helper not found, is it installed and on the PATH?".to_string(),
+            });
+        }
+        let dir = temp_dir()?;
+        let config = serde_json::json!({ "credHelpers": { registry: credential_helper } });
+        write_file(&dir.path().join("config.json"), &serde_json::to_string_pretty(&config)?)?;
+        Ok(Self { dir })
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Points `command`'s docker client at this config.
+    pub fn apply(&self, command: &mut Command) {
+        command.env("DOCKER_CONFIG", self.path());
+    }
+}
+
+pub fn docker_push(docker_push_args: DockerPushArgs) -> Result<DockerPushOutput, Error> {
+    let DockerPushArgs {
+        image,
+        provider,
+        credential_helper,
+        harbor_url,
+        create_project,
+        retain,
+        retries,
+        retry_delay,
+        oci_archive,
+        digest_file,
+        signature,
+        lock: lock_args,
+        log: _,
+        dry_run,
+        dry_run_dir,
+    } = docker_push_args;
+
+    let _span = info_span!("docker_push", image).entered();
+
+    if dry_run {
+        enable_dry_run();
+    }
+    if let Some(dry_run_dir) = dry_run_dir.as_deref() {
+        set_dry_run_dir(dry_run_dir);
+    }
+
+    let signature = verify_signed_head(&signature, &env::current_dir()?)?;
+    confirm_prod_push(&image)?;
+    let options = push_options(retries, retry_delay.map(Duration::from_secs));
+    // held until the push and any harbor retention complete
+    let _lock = lock(&image, &lock_args)?;
+    let push = || match oci_archive.as_deref() {
+        Some(oci_archive) => push_oci_archive(&image, oci_archive, provider, credential_helper.as_deref(), &options),
+        None => push_image(&image, provider, credential_helper.as_deref(), &options),
+    };
+
+    if provider != RegistryProvider::Harbor {
+        if harbor_url.is_some() || create_project || retain.is_some() {
+            return Err(Error::InvalidInput(
+                "--harbor-url, --create-project and --retain require the `harbor` provider".to_string(),
+            ));
+        }
+        let mut output = push()?;
+        output.signature = signature;
+        write_digest_file(digest_file.as_deref(), &output)?;
+        return Ok(output);
+    }
+
+    if retain == Some(0) {
+        return Err(Error::InvalidInput(
+            "--retain must keep at least the pushed artifact".to_string(),
+        ));
+    }
+
+    let registry = get_registry(&image);
+    let repository = HarborRepository::from_image(&image)?;
+    let client = || -> Result<HarborClient, Error> {
+        let url = harbor_url.clone().unwrap_or_else(|| format!("https://{registry}"));
+        let credentials = provider
+            .credentials(registry)?
+            .expect("harbor provider has credentials");
+        Ok(HarborClient::new(&url, credentials))
+    };
+
+    let project_created = match create_project {
+        true => client()?.ensure_project(&repository.project)?,
+        false => false,
+    };
+    let mut output = push()?;
+    write_digest_file(digest_file.as_deref(), &output)?;
+    let deleted = match retain {
+        Some(retain) => client()?.retain(&repository, retain)?,
+        None => vec![],
+    };
+    output.harbor = Some(HarborPushOutput {
+        project: repository.project,
+        repository: repository.repository,
+        project_created,
+        deleted,
+    });
+    output.signature = signature;
+    Ok(output)
+}
+
+/// Options `docker push` is run with: pushes are retried `retries` times (OPS_RETRIES by default) unless they
+/// failed with an error which retrying cannot fix.
+pub fn push_options(retries: Option<u32>, retry_delay: Option<Duration>) -> ExecOptions {
+    let options = ExecOptions::idempotent();
+    ExecOptions {
+        retries: retries.unwrap_or(options.retries),
+        retry_delay: retry_delay.unwrap_or(options.retry_delay),
+        retryable: Some(is_transient_push_failure),
+        ..options
+    }
+}
+
+/// Pushes `image` with `options`, authenticating with `credential_helper` if given and otherwise logging into the
+/// image's registry with `provider`.
+pub fn push_image(
+    image: &str,
+    provider: RegistryProvider,
+    credential_helper: Option<&str>,
+    options: &ExecOptions,
+) -> Result<DockerPushOutput, Error> {
+    time(Phase::Push, image, || push(image, provider, credential_helper, options))
+}
+
+fn push(
+    image: &str,
+    provider: RegistryProvider,
+    credential_helper: Option<&str>,
+    options: &ExecOptions,
+) -> Result<DockerPushOutput, Error> {
+    let start = Instant::now();
+    let registry = get_registry(image);
+    let push_failed = |err: Error| match err {
+        Error::CommandFailed { stderr, .. } => Error::PushFailed {
+            image: image.to_string(),
+            message: stderr,
+        },
+        err => err,
+    };
+
+    let mut command = Command::new("docker");
+    command.args(["push", image]);
+
+    // kept alive until the push completes
+    let _docker_config = match credential_helper {
+        Some(credential_helper) => {
+            if provider != RegistryProvider::Docker {
+                return Err(Error::InvalidInput(
+                    "a credential helper cannot be combined with a registry provider".to_string(),
+                ));
+            }
+            let docker_config = ScopedDockerConfig::new(registry, credential_helper)?;
+            docker_config.apply(&mut command);
+            Some(docker_config)
+        }
+        None => {
+            provider.login(registry).map_err(push_failed)?;
+            None
+        }
+    };
+    let output = run_or_record(&command, options).map_err(push_failed)?;
+    let digest = output.and_then(|output| {
+        String::from_utf8_lossy(&output.stdout)
+            .split_whitespace()
+            .find(|word| word.starts_with("sha256:"))
+            .map(String::from)
+    });
+    let reference = digest
+        .as_ref()
+        .map(|digest| format!("{}@{digest}", get_repository(image)));
+
+    Ok(DockerPushOutput {
+        image: image.to_string(),
+        registry: registry.to_string(),
+        provider,
+        credential_helper: credential_helper.map(String::from),
+        digest,
+        reference,
+        harbor: None,
+        signature: None,
+        duration: start.elapsed(),
+    })
+}
+
+/// Asks for confirmation before pushing `image` to a registry listed in `docker.prod_registries` of ops.toml. The
+/// push proceeds without asking with `--yes` or when no prompt can be shown, e.g. in CI.
+fn confirm_prod_push(image: &str) -> Result<(), Error> {
+    let config = OpsConfig::discover(&env::current_dir()?)?.docker;
+    let is_prod = config.prod_registries.iter().any(|prod| {
+        let prod = prod.trim_end_matches('/');
+        get_registry(image) == prod || image.starts_with(&format!("{prod}/"))
+    });
+    if is_prod && !confirm(&format!("push {image} to a production registry?"), true)? {
+        return Err(Error::Cancelled);
+    }
+    Ok(())
+}
+
+/// Registry host of an image reference, following docker's rule that the first path component is only a
+/// registry if it contains a `.` or `:` or is `localhost`.
+pub fn get_registry(image: &str) -> &str {
+    match image.split_once('/') {
+        Some((host, _)) if host.contains(['.', ':']) || host == "localhost" => host,
+        _ => DOCKER_HUB_REGISTRY,
+    }
+}
+
+/// Image reference without its tag or digest.
+pub fn get_repository(image: &str) -> &str {
+    let image = image.split_once('@').map_or(image, |(repository, _)| repository);
+    match image.rsplit_once(':') {
+        // a `:` before the last `/` separates the registry's port
+        Some((repository, tag)) if !tag.contains('/') => repository,
+        _ => image,
+    }
+}
+
+/// Writes the pushed image's digest reference to `digest_file`, failing if docker did not report a digest outside
+/// of dry-run mode.
+fn write_digest_file(digest_file: Option<&Path>, output: &DockerPushOutput) -> Result<(), Error> {
+    let Some(digest_file) = digest_file else {
+        return Ok(());
+    };
+    let reference = match output.reference.as_ref() {
+        Some(reference) => reference.clone(),
+        None if is_dry_run() => format!("{}@<digest>", get_repository(&output.image)),
+        None => {
+            return Err(Error::PushFailed {
+                image: output.image.clone(),
+                message: "docker did not report the digest of the pushed image".to_string(),
+            })
+        }
+    };
+    write_file(digest_file, &format!("{reference}\n"))
+}
+
+/// Whether a failed push may succeed when retried, i.e. it did not fail due to missing credentials or permissions
+/// or a missing local image.
+fn is_transient_push_failure(output: &Output) -> bool {
+    let stderr = String::from_utf8_lossy(&output.stderr).to_lowercase();
+    !PERMANENT_PUSH_ERRORS.iter().any(|error| stderr.contains(error))
+}
+
+fn get_ecr_region(registry: &str) -> Option<&str> {
+    let mut parts = registry.split('.');
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(_), Some("dkr"), Some("ecr"), Some(region)) => Some(region),
+        _ => None,
+    }
+}
+
+/// Credentials of the ECR registries of `region` from `aws ecr get-login-password`.
+#[cfg(not(feature = "aws-sdk"))]
+fn get_ecr_credentials(region: &str) -> Result<RegistryCredentials, Error> {
+    Ok(RegistryCredentials {
+        username: "AWS".to_string(),
+        password: read(Command::new("aws").args(["ecr", "get-login-password", "--region", region]))?,
+    })
+}
+
+/// Credentials of the ECR registries of `region` from an authorization token requested in-process with the AWS SDK,
+/// which resolves AWS credentials like the aws cli does (environment, profiles, web identity and instance metadata).
+#[cfg(feature = "aws-sdk")]
+fn get_ecr_credentials(region: &str) -> Result<RegistryCredentials, Error> {
+    use aws_sdk_ecr::error::DisplayErrorContext;
+    use base64::Engine;
+
+    let failed = |message: String| Error::msg(format!("cannot get an ECR authorization token in {region}: {message}"));
+    let token = crate::exec::block_on(async {
+        let config = aws_config::defaults(aws_config::BehaviorVersion::latest())
+            .region(aws_config::Region::new(region.to_string()))
+            .load()
+            .await;
+        let output = aws_sdk_ecr::Client::new(&config)
+            .get_authorization_token()
+            .send()
+            .await
+            .map_err(|err| failed(DisplayErrorContext(err).to_string()))?;
+        output
+            .authorization_data()
+            .iter()
+            .find_map(|data| data.authorization_token())
+            .map(String::from)
+            .ok_or_else(|| failed("no token was returned".to_string()))
+    })?;
+    // the token is the base64 encoded `<username>:<password>` docker logs in with
+    let token = base64::engine::general_purpose::STANDARD
+        .decode(token)
+        .ok()
+        .and_then(|token| String::from_utf8(token).ok())
+        .ok_or_else(|| failed("the token is not valid base64".to_string()))?;
+    let (username, password) = token
+        .split_once(':')
+        .ok_or_else(|| failed("the token is not a username and password".to_string()))?;
+    Ok(RegistryCredentials {
+        username: username.to_string(),
+        password: password.to_string(),
+    })
+}
+
+/// Registry name of an ACR registry (`<name>.azurecr.io`, or `azurecr.cn`/`azurecr.us` in sovereign clouds).
+fn get_acr_name(registry: &str) -> Option<&str> {
+    match registry.split_once('.') {
+        Some((name, domain)) if domain.starts_with("azurecr.") => Some(name),
+        _ => None,
+    }
+}
+
+fn is_on_path(program: &str) -> bool {
+    env::var_os("PATH")
+        .map(|path| env::split_paths(&path).any(|dir| dir.join(format!("{program}{EXE_SUFFIX}")).is_file()))
+        .unwrap_or_default()
+}
+
+fn required_env(name: &str, provider: &RegistryProvider) -> Result<String, Error> {
+    env::var(name).map_err(|_| {
+        Error::InvalidInput(format!(
+            "{name} must be set to log into a registry with the `{}` provider",
+            provider.to_possible_value().unwrap().get_name()
+        ))
+    })
+}
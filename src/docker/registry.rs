@@ -0,0 +1,567 @@
+/// Minimal client for the registry HTTP API (the OCI distribution spec) pushing already built images without a
+/// docker daemon or cli: an OCI image layout, e.g. written by `docker buildx build --output type=oci,dest=image.tar`,
+/// is uploaded blob by blob followed by its manifests. Requests are made with `curl` like the Harbor client's,
+/// registries challenging for a bearer token are sent one requested from the realm they name. Credentials come from
+/// the registry provider, a docker credential helper or, for the `docker` provider, docker's client configuration.
+use crate::docker::push::{get_registry, get_repository, DockerPushOutput, RegistryCredentials, RegistryProvider};
+use crate::error::Error;
+use crate::exec::{run, run_or_record, ExecOptions};
+use crate::temp::temp_dir;
+use crate::timing::{time, Phase};
+use base64::Engine;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Instant;
+use tracing::{debug, info, info_span};
+
+/// key of Docker Hub in docker's client configuration and for credential helpers
+const DOCKER_HUB_SERVER: &str = "https://index.docker.io/v1/";
+
+/// media types of manifests listing other manifests, e.g. one per platform
+const INDEX_MEDIA_TYPES: [&str; 2] = [
+    "application/vnd.oci.image.index.v1+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+];
+
+/// Client of the repository of an image in its registry, authenticated once when created and used for every blob
+/// and manifest of a push.
+pub struct RegistryClient {
+    image: String,
+    /// base url of the repository's endpoints, e.g. `https://ghcr.io/v2/my-org/api`
+    url: String,
+    /// value of the `Authorization` header, if the registry requires one
+    authorization: Option<String>,
+    options: ExecOptions,
+}
+
+/// Descriptor of a blob or manifest within an image layout or manifest.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Descriptor {
+    media_type: String,
+    digest: String,
+}
+
+#[derive(Deserialize)]
+struct Index {
+    manifests: Vec<Descriptor>,
+}
+
+#[derive(Deserialize)]
+struct ImageManifest {
+    config: Descriptor,
+    layers: Vec<Descriptor>,
+}
+
+#[derive(Deserialize)]
+struct Token {
+    token: Option<String>,
+    access_token: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct DockerConfig {
+    auths: BTreeMap<String, DockerAuth>,
+    creds_store: Option<String>,
+    cred_helpers: BTreeMap<String, String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct DockerAuth {
+    /// base64 encoded `<username>:<password>`
+    auth: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HelperCredentials {
+    username: String,
+    secret: String,
+}
+
+#[derive(Debug)]
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Pushes the OCI image layout at `archive`, a tarball or a directory, as `image` with the registry HTTP API.
+pub fn push_oci_archive(
+    image: &str,
+    archive: &Path,
+    provider: RegistryProvider,
+    credential_helper: Option<&str>,
+    options: &ExecOptions,
+) -> Result<DockerPushOutput, Error> {
+    time(Phase::Push, image, || {
+        let _span = info_span!("push_oci_archive", image).entered();
+        let start = Instant::now();
+        let registry = get_registry(image);
+
+        // kept alive until the push completes
+        let extracted;
+        let layout = match archive.is_dir() {
+            true => archive.to_path_buf(),
+            false => {
+                extracted = temp_dir()?;
+                let mut command = Command::new("tar");
+                command
+                    .arg("--extract")
+                    .arg("--file")
+                    .arg(archive)
+                    .arg("--directory")
+                    .arg(extracted.path());
+                // only writes to a temporary directory, so it runs in dry-run mode as well
+                run(
+                    &command,
+                    &ExecOptions {
+                        stream: false,
+                        ..Default::default()
+                    },
+                )?;
+                extracted.path().to_path_buf()
+            }
+        };
+
+        let credentials = get_registry_credentials(registry, provider, credential_helper)?;
+        let client = RegistryClient::new(image, credentials, options)?;
+        let digest = client.push_layout(&layout, get_reference(image))?;
+
+        Ok(DockerPushOutput {
+            image: image.to_string(),
+            registry: registry.to_string(),
+            provider,
+            credential_helper: credential_helper.map(String::from),
+            reference: Some(format!("{}@{digest}", get_repository(image))),
+            digest: Some(digest),
+            harbor: None,
+            signature: None,
+            duration: start.elapsed(),
+        })
+    })
+}
+
+/// Credentials to push to `registry` with: those of `credential_helper` if given, of `provider` unless it is
+/// `docker`, and otherwise those docker's client configuration holds for the registry, if any.
+pub fn get_registry_credentials(
+    registry: &str,
+    provider: RegistryProvider,
+    credential_helper: Option<&str>,
+) -> Result<Option<RegistryCredentials>, Error> {
+    match (credential_helper, provider) {
+        (Some(_), provider) if provider != RegistryProvider::Docker => Err(Error::InvalidInput(
+            "a credential helper cannot be combined with a registry provider".to_string(),
+        )),
+        (Some(credential_helper), _) => get_helper_credentials(credential_helper, registry).map(Some),
+        (None, RegistryProvider::Docker) => get_docker_config_credentials(registry),
+        (None, provider) => provider.credentials(registry),
+    }
+}
+
+impl RegistryClient {
+    /// Client for the repository of `image`, authenticating with `credentials` as the registry demands.
+    pub fn new(image: &str, credentials: Option<RegistryCredentials>, options: &ExecOptions) -> Result<Self, Error> {
+        let registry = get_registry(image);
+        let repository = get_repository_name(image);
+        let base_url = get_registry_url(registry);
+        let mut client = Self {
+            image: image.to_string(),
+            url: format!("{base_url}/v2/{repository}"),
+            authorization: None,
+            options: ExecOptions {
+                stream: false,
+                ..options.clone()
+            },
+        };
+
+        // the version check is answered with the challenge the registry expects to be authenticated with
+        let response = client.read("GET", &format!("{base_url}/v2/"))?;
+        if response.status != 401 {
+            debug!("{registry} does not require authentication");
+            return Ok(client);
+        }
+        let challenge = response.header("www-authenticate").unwrap_or_default();
+        let (scheme, params) = challenge.split_once(' ').unwrap_or((challenge, ""));
+        client.authorization = Some(match scheme.to_lowercase().as_str() {
+            "bearer" => {
+                let token = client.get_token(&parse_challenge(params), &repository, credentials.as_ref())?;
+                format!("Bearer {token}")
+            }
+            _ => {
+                let Some(RegistryCredentials { username, password }) = credentials else {
+                    return Err(client.failed(format!("{registry} requires credentials")));
+                };
+                let basic = base64::engine::general_purpose::STANDARD.encode(format!("{username}:{password}"));
+                format!("Basic {basic}")
+            }
+        });
+        Ok(client)
+    }
+
+    /// Pushes the image of the OCI image layout in `layout` as `reference` (a tag or digest), returning the digest of
+    /// its manifest.
+    pub fn push_layout(&self, layout: &Path, reference: &str) -> Result<String, Error> {
+        let index = fs::read_to_string(layout.join("index.json"))
+            .map_err(|err| Error::InvalidInput(format!("`{}` is not an OCI image layout: {err}", layout.display())))?;
+        let index = serde_json::from_str::<Index>(&index)?;
+        let [descriptor] = index.manifests.as_slice() else {
+            return Err(Error::InvalidInput(format!(
+                "OCI image layout `{}` holds {} images, exactly one can be pushed",
+                layout.display(),
+                index.manifests.len()
+            )));
+        };
+        self.push_manifest(layout, descriptor, reference)?;
+        Ok(descriptor.digest.clone())
+    }
+
+    /// Pushes the manifest `descriptor` points to as `reference`, after every manifest and blob it references.
+    fn push_manifest(&self, layout: &Path, descriptor: &Descriptor, reference: &str) -> Result<(), Error> {
+        let path = get_blob_path(layout, &descriptor.digest)?;
+        let contents = fs::read_to_string(&path)?;
+        if INDEX_MEDIA_TYPES.contains(&descriptor.media_type.as_str()) {
+            for manifest in serde_json::from_str::<Index>(&contents)?.manifests {
+                self.push_manifest(layout, &manifest, &manifest.digest)?;
+            }
+        } else {
+            let manifest = serde_json::from_str::<ImageManifest>(&contents)?;
+            for blob in [&manifest.config].into_iter().chain(&manifest.layers) {
+                self.push_blob(layout, blob)?;
+            }
+        }
+
+        info!("pushing manifest {} as {reference}", descriptor.digest);
+        let content_type = format!("Content-Type: {}", descriptor.media_type);
+        let url = format!("{}/manifests/{reference}", self.url);
+        if let Some(response) = self.send("PUT", &url, &[&content_type], Some(&path))? {
+            self.expect(&response, 201, "pushing the manifest")?;
+        }
+        Ok(())
+    }
+
+    /// Uploads the blob `descriptor` points to in a single request, unless the repository already has it.
+    fn push_blob(&self, layout: &Path, descriptor: &Descriptor) -> Result<(), Error> {
+        let digest = &descriptor.digest;
+        let path = get_blob_path(layout, digest)?;
+        if self.read("HEAD", &format!("{}/blobs/{digest}", self.url))?.status == 200 {
+            debug!("blob {digest} already exists");
+            return Ok(());
+        }
+
+        info!("uploading blob {digest}");
+        let location = match self.send("POST", &format!("{}/blobs/uploads/", self.url), &[], None)? {
+            Some(response) => {
+                self.expect(&response, 202, "starting the upload")?;
+                let location = response
+                    .header("location")
+                    .ok_or_else(|| self.failed("the registry did not return an upload location".to_string()))?;
+                // locations may be relative to the registry's host
+                match location.starts_with('/') {
+                    true => format!("{}{location}", get_registry_url(get_registry(&self.image))),
+                    false => location.to_string(),
+                }
+            }
+            None => "<upload-location>".to_string(),
+        };
+        let separator = if location.contains('?') { '&' } else { '?' };
+        let url = format!("{location}{separator}digest={digest}");
+        let content_type = "Content-Type: application/octet-stream";
+        if let Some(response) = self.send("PUT", &url, &[content_type], Some(&path))? {
+            self.expect(&response, 201, "uploading the blob")?;
+        }
+        Ok(())
+    }
+
+    /// Token for pushing to `repository` from the realm of a bearer challenge.
+    fn get_token(
+        &self,
+        challenge: &BTreeMap<String, String>,
+        repository: &str,
+        credentials: Option<&RegistryCredentials>,
+    ) -> Result<String, Error> {
+        let realm = challenge
+            .get("realm")
+            .ok_or_else(|| self.failed("the registry's challenge names no realm".to_string()))?;
+        let mut command = Command::new("curl");
+        command.args(["--silent", "--show-error", "--fail", "--config", "-", "--get", realm]);
+        if let Some(service) = challenge.get("service") {
+            command.args(["--data-urlencode", &format!("service={service}")]);
+        }
+        command.args(["--data-urlencode", &format!("scope=repository:{repository}:pull,push")]);
+        let config = match credentials {
+            Some(RegistryCredentials { username, password }) => {
+                format!("user = \"{}\"\n", quote(&format!("{username}:{password}")))
+            }
+            None => String::new(),
+        };
+        let output = run(
+            &command,
+            &ExecOptions {
+                stdin: Some(config.into_bytes()),
+                ..self.options.clone()
+            },
+        )?;
+        let token = serde_json::from_slice::<Token>(&output.stdout)?;
+        token
+            .token
+            .or(token.access_token)
+            .ok_or_else(|| self.failed(format!("{realm} returned no token")))
+    }
+
+    /// Read-only request, made even in dry-run mode.
+    fn read(&self, method: &str, url: &str) -> Result<Response, Error> {
+        let (command, options) = self.request(method, url, &[], None);
+        Ok(parse_response(&run(&command, &options)?.stdout))
+    }
+
+    /// Side-effecting request, recorded instead in dry-run mode.
+    fn send(&self, method: &str, url: &str, headers: &[&str], body: Option<&Path>) -> Result<Option<Response>, Error> {
+        let (command, options) = self.request(method, url, headers, body);
+        Ok(run_or_record(&command, &options)?.map(|output| parse_response(&output.stdout)))
+    }
+
+    fn request(&self, method: &str, url: &str, headers: &[&str], body: Option<&Path>) -> (Command, ExecOptions) {
+        let mut command = Command::new("curl");
+        command.args(["--silent", "--show-error", "--include", "--config", "-"]);
+        // curl waits for the body of HEAD requests sent with `--request`
+        match method {
+            "HEAD" => command.arg("--head"),
+            method => command.args(["--request", method]),
+        };
+        for header in headers {
+            command.args(["--header", header]);
+        }
+        // streamed from the file rather than read into memory first as `--data-binary @<file>` does, layers can be
+        // gigabytes
+        if let Some(body) = body {
+            command.arg("--upload-file").arg(body);
+        }
+        command.arg(url);
+
+        // passed through stdin so that the token never shows up in the process list
+        let config = match self.authorization.as_ref() {
+            Some(authorization) => format!("header = \"Authorization: {}\"\n", quote(authorization)),
+            None => String::new(),
+        };
+        let options = ExecOptions {
+            stdin: Some(config.into_bytes()),
+            ..self.options.clone()
+        };
+        (command, options)
+    }
+
+    fn expect(&self, response: &Response, status: u16, action: &str) -> Result<(), Error> {
+        if response.status == status {
+            return Ok(());
+        }
+        // registries describe failures as `{"errors": [{"code": ..., "message": ...}]}`
+        let errors = serde_json::from_str::<serde_json::Value>(&response.body)
+            .ok()
+            .and_then(|body| body["errors"].as_array().cloned())
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|error| error["message"].as_str().map(String::from))
+            .collect::<Vec<_>>();
+        let message = match errors.is_empty() {
+            true => response.body.trim().to_string(),
+            false => errors.join(", "),
+        };
+        Err(self.failed(format!("{action} failed with status {}: {message}", response.status)))
+    }
+
+    fn failed(&self, message: String) -> Error {
+        Error::PushFailed {
+            image: self.image.clone(),
+            message,
+        }
+    }
+}
+
+/// Base url of `registry`'s API. Docker Hub is served from its own host and, as docker does, registries on the
+/// local host are spoken to over plain http.
+fn get_registry_url(registry: &str) -> String {
+    let host = registry.split(':').next().unwrap_or(registry);
+    match registry {
+        "docker.io" => "https://registry-1.docker.io".to_string(),
+        _ if host == "localhost" || host == "127.0.0.1" => format!("http://{registry}"),
+        _ => format!("https://{registry}"),
+    }
+}
+
+/// Repository of `image` within its registry, e.g. `my-org/api` for `ghcr.io/my-org/api:1.2.0`. Official images on
+/// Docker Hub live in `library`.
+fn get_repository_name(image: &str) -> String {
+    let repository = get_repository(image);
+    let registry = get_registry(image);
+    match repository
+        .strip_prefix(registry)
+        .and_then(|name| name.strip_prefix('/'))
+    {
+        Some(name) => name.to_string(),
+        None if repository.contains('/') => repository.to_string(),
+        None => format!("library/{repository}"),
+    }
+}
+
+/// Tag or digest `image` is pushed as, `latest` if it names neither.
+fn get_reference(image: &str) -> &str {
+    if let Some((_, digest)) = image.split_once('@') {
+        return digest;
+    }
+    match image.rsplit_once(':') {
+        Some((_, tag)) if !tag.contains('/') => tag,
+        _ => "latest",
+    }
+}
+
+/// Path of the blob with `digest` (`<algorithm>:<hex>`) within an OCI image layout.
+fn get_blob_path(layout: &Path, digest: &str) -> Result<PathBuf, Error> {
+    let (algorithm, hex) = digest
+        .split_once(':')
+        .ok_or_else(|| Error::InvalidInput(format!("`{digest}` is not a digest")))?;
+    let path = layout.join("blobs").join(algorithm).join(hex);
+    match path.is_file() {
+        true => Ok(path),
+        false => Err(Error::InvalidInput(format!(
+            "blob {digest} is missing from OCI image layout `{}`",
+            layout.display()
+        ))),
+    }
+}
+
+/// Credentials of `registry` from `docker-credential-<credential_helper>`.
+fn get_helper_credentials(credential_helper: &str, registry: &str) -> Result<RegistryCredentials, Error> {
+    let server = match registry {
+        "docker.io" => DOCKER_HUB_SERVER,
+        registry => registry,
+    };
+    let output = run(
+        Command::new(format!("docker-credential-{credential_helper}")).arg("get"),
+        &ExecOptions {
+            stdin: Some(server.as_bytes().to_vec()),
+            stream: false,
+            ..Default::default()
+        },
+    )?;
+    let HelperCredentials { username, secret } = serde_json::from_slice(&output.stdout)?;
+    Ok(RegistryCredentials {
+        username,
+        password: secret,
+    })
+}
+
+/// Credentials docker's client configuration (`$DOCKER_CONFIG/config.json` or `~/.docker/config.json`) holds for
+/// `registry`, either stored inline by `docker login` or in a credential helper.
+fn get_docker_config_credentials(registry: &str) -> Result<Option<RegistryCredentials>, Error> {
+    let dir = env::var_os("DOCKER_CONFIG")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(env::var_os("HOME").unwrap_or_default()).join(".docker"));
+    let config = match fs::read_to_string(dir.join("config.json")) {
+        Ok(config) => serde_json::from_str::<DockerConfig>(&config)?,
+        Err(_) => {
+            debug!("no docker client configuration in {}", dir.display());
+            return Ok(None);
+        }
+    };
+    if let Some(credential_helper) = config.cred_helpers.get(registry) {
+        return get_helper_credentials(credential_helper, registry).map(Some);
+    }
+    let keys = [
+        registry.to_string(),
+        format!("https://{registry}"),
+        DOCKER_HUB_SERVER.to_string(),
+    ];
+    let keys = match registry {
+        "docker.io" => &keys[..],
+        _ => &keys[..2],
+    };
+    let Some(auth) = keys.iter().find_map(|key| config.auths.get(key)) else {
+        return Ok(None);
+    };
+    match (auth.auth.as_ref(), config.creds_store.as_ref()) {
+        (Some(auth), _) => {
+            let auth = base64::engine::general_purpose::STANDARD
+                .decode(auth)
+                .ok()
+                .and_then(|auth| String::from_utf8(auth).ok())
+                .and_then(|auth| {
+                    auth.split_once(':')
+                        .map(|(username, password)| (username.to_string(), password.to_string()))
+                })
+                .ok_or_else(|| Error::InvalidInput(format!("invalid docker credentials for {registry}")))?;
+            Ok(Some(RegistryCredentials {
+                username: auth.0,
+                password: auth.1,
+            }))
+        }
+        (None, Some(creds_store)) => get_helper_credentials(creds_store, registry).map(Some),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Parameters of an authentication challenge, e.g. `realm="https://ghcr.io/token",service="ghcr.io"`.
+fn parse_challenge(params: &str) -> BTreeMap<String, String> {
+    let mut challenge = BTreeMap::new();
+    let mut rest = params.trim();
+    while let Some((key, value)) = rest.split_once('=') {
+        let key = key.trim().trim_start_matches(',').trim().to_lowercase();
+        let (value, remainder) = match value.strip_prefix('"') {
+            // quoted values may contain commas, e.g. `scope="repository:my-org/api:pull,push"`
+            Some(value) => value.split_once('"').unwrap_or((value, "")),
+            None => value.split_once(',').unwrap_or((value, "")),
+        };
+        challenge.insert(key, value.to_string());
+        rest = remainder;
+    }
+    challenge
+}
+
+/// Status, headers and body of the output of `curl --include`, skipping interim responses such as
+/// `100 Continue`.
+fn parse_response(stdout: &[u8]) -> Response {
+    let text = String::from_utf8_lossy(stdout);
+    let mut rest = text.as_ref();
+    loop {
+        let (head, body) = rest.split_once("\r\n\r\n").unwrap_or((rest, ""));
+        if body.starts_with("HTTP/") {
+            rest = body;
+            continue;
+        }
+        let mut lines = head.lines();
+        let status = lines
+            .next()
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|status| status.parse().ok())
+            .unwrap_or_default();
+        let headers = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+            .collect();
+        return Response {
+            status,
+            headers,
+            body: body.to_string(),
+        };
+    }
+}
+
+/// `value` escaped for a double quoted string of a curl config file.
+fn quote(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
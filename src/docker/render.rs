@@ -0,0 +1,237 @@
+/// Renders the Dockerfiles generated for every service of the workspace without building them, either printing them
+/// or checking them against golden files so that changes caused by an ops upgrade or a change of a service's
+/// configuration can be reviewed before they reach a build.
+use crate::cargo::Metadata;
+use crate::ci::matrix::get_ci_matrix;
+use crate::config::package::PackageOpsConfig;
+use crate::docker::build_rust_workspace::*;
+use crate::docker::dockerfile::{CacheMounts, RuntimeBase, ServiceDockerfile};
+use crate::docker::lint::lint_dockerfile;
+use crate::docker::template::{DockerfileTemplate, TemplateKind};
+use crate::error::Error;
+use crate::git::diff_name_status_since_branched::DiffBaseArgs;
+use crate::logging::LogArgs;
+use crate::report::Report;
+use crate::snapshot::{check_snapshot, SnapshotStatus};
+use crate::workspace::root::get_workspace_root;
+use clap::Parser;
+use serde::Serialize;
+use std::env;
+use std::path::PathBuf;
+use tracing::{debug, info_span};
+
+#[derive(Clone, Debug, Parser)]
+#[clap(author, version, about, long_about = None)]
+pub struct DockerRenderArgs {
+    /// services to render, defaults to every service of the workspace
+    #[clap(short, long, value_delimiter = '\n', env = "OPS_SERVICE")]
+    pub service: Vec<String>,
+
+    /// which rust profile to build rust binaries, defaults to release
+    #[clap(long, env = "OPS_PROFILE")]
+    pub profile: Option<String>,
+
+    /// rust docker image version, defaults to latest
+    #[clap(short, long, env = "OPS_RUST_VERSION")]
+    pub rust_version: Option<String>,
+
+    /// keep cargo's downloads between builds in BuildKit cache mounts, `--cache-mounts=sccache` additionally
+    /// compiles through sccache -- see ops-docker-build-rust-workspace-service --cache-mounts
+    #[clap(
+        long,
+        value_enum,
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "registry",
+        env = "OPS_CACHE_MOUNTS"
+    )]
+    pub cache_mounts: Option<CacheMounts>,
+
+    /// directory of golden files, relative to the workspace root, to check the rendered Dockerfiles against instead
+    /// of printing them
+    #[clap(long, env = "OPS_SNAPSHOT_DIR")]
+    pub snapshot_dir: Option<PathBuf>,
+
+    /// create missing golden files and update the ones which differ instead of failing
+    #[clap(short, long, env = "OPS_UPDATE_SNAPSHOTS")]
+    pub update: bool,
+
+    #[clap(flatten)]
+    pub log: LogArgs,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DockerRenderOutput {
+    pub dockerfiles: Vec<RenderedDockerfile>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RenderedDockerfile {
+    pub service: String,
+    /// `pre-build` or `service`
+    pub stage: &'static str,
+    pub dockerfile: String,
+    /// golden file the Dockerfile was checked against
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snapshot: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<SnapshotStatus>,
+    /// problems which would fail the Dockerfile's build, see [`lint_dockerfile`]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub problems: Vec<String>,
+}
+
+impl Report for DockerRenderOutput {
+    fn human(&self) -> String {
+        self.dockerfiles
+            .iter()
+            .map(|rendered| {
+                let text = match (rendered.snapshot.as_ref(), rendered.status.as_ref()) {
+                    (Some(snapshot), Some(status)) => {
+                        let line = format!("{:8} {}", status.description(), snapshot.display());
+                        match status {
+                            SnapshotStatus::Differs { diff } => format!("{line}\n{diff}"),
+                            _ => line,
+                        }
+                    }
+                    _ => format!("# {} ({})\n{}\n", rendered.service, rendered.stage, rendered.dockerfile),
+                };
+                let problems = rendered.problems.iter().map(|problem| format!("\n  {problem}"));
+                text + &problems.collect::<String>()
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+impl DockerRenderOutput {
+    /// Converts the output into an error if any Dockerfile has problems or any golden file is missing or differs.
+    pub fn result(&self) -> Result<(), Error> {
+        if let Some(rendered) = self.dockerfiles.iter().find(|rendered| !rendered.problems.is_empty()) {
+            return Err(Error::InvalidDockerfile {
+                stage: format!("{} {}", rendered.service, rendered.stage),
+                problems: rendered.problems.clone(),
+            });
+        }
+        let paths = self
+            .dockerfiles
+            .iter()
+            .filter(|rendered| rendered.status.as_ref().is_some_and(|status| !status.is_ok()))
+            .filter_map(|rendered| rendered.snapshot.clone())
+            .collect::<Vec<_>>();
+        match paths.is_empty() {
+            true => Ok(()),
+            false => Err(Error::SnapshotMismatch { paths }),
+        }
+    }
+}
+
+pub fn docker_render(docker_render_args: DockerRenderArgs) -> Result<DockerRenderOutput, Error> {
+    let DockerRenderArgs {
+        service,
+        profile,
+        rust_version,
+        cache_mounts,
+        snapshot_dir,
+        update,
+        log: _,
+    } = docker_render_args;
+
+    let _span = info_span!("docker_render").entered();
+
+    let workspace_dir = get_workspace_root(&env::current_dir()?)?;
+    let matrix = get_ci_matrix(&workspace_dir, true, &DiffBaseArgs::default())?;
+    if let Some(unknown) = service
+        .iter()
+        .find(|service| !matrix.include.iter().any(|entry| &entry.service == *service))
+    {
+        return Err(Error::InvalidInput(format!("unknown service `{unknown}`")));
+    }
+
+    let profile = profile.unwrap_or_else(|| "release".to_string());
+    let mut dockerfiles = vec![];
+    let pre_build_template = DockerfileTemplate::load(&workspace_dir, TemplateKind::PreBuild, None)?;
+    let build_template = DockerfileTemplate::load(&workspace_dir, TemplateKind::Service, None)?;
+    let metadata = Metadata::load(&workspace_dir)?;
+    for entry in matrix.include {
+        if !service.is_empty() && !service.contains(&entry.service) {
+            continue;
+        }
+        if entry.dockerfile.is_some() {
+            debug!("skipping {}: built from its own Dockerfile", entry.service);
+            continue;
+        }
+
+        // ops.toml feature sets take precedence over the ones declared in the package's metadata
+        let package_config = PackageOpsConfig::load(&workspace_dir.join(&entry.path))?;
+        let package = metadata
+            .local_packages()
+            .find(|package| package.name == entry.package)
+            .ok_or_else(|| Error::InvalidInput(format!("`{}` is not a package of the workspace", entry.package)))?;
+        let package_feature_sets =
+            with_feature_matrix(package, package_config.feature_sets, &package_config.feature_matrix)?;
+        let feature_sets = match entry.feature_sets.is_empty() {
+            true => get_feature_sets(&package_feature_sets, package_config.default_feature_set)?,
+            false => get_feature_sets(&entry.feature_sets, entry.default_feature_set)?,
+        };
+        let bins = get_bins(package, &package_config.bins)?;
+        let pre_build_dockerfile = get_pre_build_service_dockerfile(
+            &workspace_dir,
+            rust_version.as_deref(),
+            package_config.build_image.as_deref(),
+            &entry.package,
+            &profile,
+            &feature_sets,
+            &[],
+            &[],
+            &[],
+            RuntimeBase::of(package_config.runtime_image.as_deref()).musl(),
+            cache_mounts,
+            None,
+            pre_build_template.as_ref(),
+        )?;
+        let service_dockerfile = ServiceDockerfile {
+            pre_build_image: &format!("{}:latest-{profile}-pre-build", entry.service),
+            service_name: &entry.package,
+            service_dir: &entry.path,
+            profile: &profile,
+            feature_sets: &feature_sets,
+            bins: &bins,
+            runtime_image: package_config.runtime_image.as_deref(),
+            entrypoint: package_config.entrypoint.as_deref(),
+            docker: &package_config.docker,
+            platforms: &[],
+            cache_mounts,
+            template: build_template.as_ref(),
+        }
+        .render()?;
+
+        for (stage, dockerfile, file_name) in [
+            (
+                "pre-build",
+                pre_build_dockerfile,
+                format!("{}.pre-build.Dockerfile", entry.service),
+            ),
+            ("service", service_dockerfile, format!("{}.Dockerfile", entry.service)),
+        ] {
+            let (snapshot, status) = match snapshot_dir.as_ref() {
+                Some(snapshot_dir) => {
+                    let path = workspace_dir.join(snapshot_dir).join(file_name);
+                    let status = check_snapshot(&path, &dockerfile, update)?;
+                    (Some(path), Some(status))
+                }
+                None => (None, None),
+            };
+            dockerfiles.push(RenderedDockerfile {
+                service: entry.service.clone(),
+                stage,
+                problems: lint_dockerfile(&dockerfile, None),
+                dockerfile,
+                snapshot,
+                status,
+            });
+        }
+    }
+
+    Ok(DockerRenderOutput { dockerfiles })
+}
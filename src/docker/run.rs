@@ -0,0 +1,159 @@
+/// Runs a locally built service image with the ports, env files, volumes and binary declared for the service in
+/// ops.toml (`[services.<name>.run]`), streaming the container's logs until it exits. Ports not configured in
+/// ops.toml fall back to the service's `[package.metadata.ops.docker]`, whose env, user and healthcheck are
+/// already baked into the image.
+use crate::cargo::WorkspaceGraph;
+use crate::config::package::PackageOpsConfig;
+use crate::config::{find_config_dir, OpsConfig, RunConfig};
+use crate::docker::build_rust_workspace::FeatureSet;
+use crate::dry_run::enable_dry_run;
+use crate::error::Error;
+use crate::exec::output;
+use crate::logging::LogArgs;
+use crate::workspace::root::get_workspace_root;
+use clap::Parser;
+use path_absolutize::*;
+use std::env;
+use std::path::Path;
+use std::process::Command;
+use tracing::{debug, info_span};
+
+#[derive(Clone, Debug, Parser)]
+#[clap(author, version, about, long_about = None, trailing_var_arg=true)]
+pub struct DockerRunArgs {
+    /// service to run, either a key of `services` in ops.toml or a cargo package name
+    #[clap(value_parser)]
+    pub service: String,
+
+    /// image to run, overrides `services.<name>.run.image` in ops.toml -- defaults to `{package}:latest`
+    #[clap(long, env = "OPS_IMAGE")]
+    pub image: Option<String>,
+
+    /// comma separated feature set whose binary is used as the entrypoint, overrides
+    /// `services.<name>.run.feature_set` in ops.toml -- defaults to the default feature set binary
+    #[clap(long, env = "OPS_FEATURE_SET")]
+    pub feature_set: Option<String>,
+
+    #[clap(flatten)]
+    pub log: LogArgs,
+
+    /// print the docker command instead of running it
+    /// - can also be enabled by setting the OPS_DRY_RUN environment variable
+    #[clap(long)]
+    pub dry_run: bool,
+
+    /// docker run args
+    #[clap(value_parser)]
+    pub docker_args: Vec<String>,
+}
+
+pub fn docker_run(docker_run_args: DockerRunArgs) -> Result<(), Error> {
+    let DockerRunArgs {
+        service,
+        image,
+        feature_set,
+        log: _,
+        dry_run,
+        docker_args,
+    } = docker_run_args;
+
+    let _span = info_span!("docker_run", service).entered();
+
+    if dry_run {
+        enable_dry_run();
+    }
+
+    let cwd = env::current_dir()?;
+    let config_dir = find_config_dir(&cwd).unwrap_or(&cwd);
+    let config = OpsConfig::load(config_dir)?;
+    let (package, run_config) = match config.services.get(&service) {
+        Some(service_config) => (
+            service_config.package_name(&service).to_string(),
+            service_config.run.clone(),
+        ),
+        None => {
+            debug!("service `{service}` is not configured in ops.toml, running it without run settings");
+            (service.clone(), RunConfig::default())
+        }
+    };
+    let RunConfig {
+        image: configured_image,
+        ports,
+        env_files,
+        volumes,
+        entrypoint,
+        feature_set: configured_feature_set,
+    } = run_config;
+
+    let ports = match ports.is_empty() {
+        true => get_package_config(config_dir, &package)?.docker.ports,
+        false => ports,
+    };
+
+    let image = image
+        .or(configured_image)
+        .unwrap_or_else(|| format!("{package}:latest"));
+    let entrypoint = match (feature_set, entrypoint) {
+        (Some(feature_set), _) => get_binary_path(&package, &feature_set)?,
+        (None, Some(entrypoint)) => entrypoint,
+        (None, None) => get_binary_path(&package, configured_feature_set.as_deref().unwrap_or_default())?,
+    };
+
+    // --init so that the container's process receives the SIGINT forwarded by docker on Ctrl-C
+    let mut command = Command::new("docker");
+    command.args(["run", "--rm", "--init"]);
+    for port in ports {
+        command.arg(format!("--publish={port}"));
+    }
+    for env_file in env_files {
+        command.arg(format!(
+            "--env-file={}",
+            config_dir.join(env_file).absolutize()?.display()
+        ));
+    }
+    for volume in volumes {
+        command.arg(format!("--volume={}", resolve_volume(config_dir, &volume)?));
+    }
+    command.arg(format!("--entrypoint={entrypoint}"));
+    command.args(docker_args).arg(&image);
+
+    let output = match output(&mut command)? {
+        Some(output) => output,
+        None => return Ok(()),
+    };
+    if !output.status.success() {
+        return Err(Error::DockerFailed {
+            stage: "run".to_string(),
+            status: output.status.code(),
+            step: None,
+            log: String::new(),
+        });
+    }
+    Ok(())
+}
+
+/// `[package.metadata.ops]` of the workspace package `package`, the default (empty) config if `config_dir` is not
+/// within a workspace containing it.
+fn get_package_config(config_dir: &Path, package: &str) -> Result<PackageOpsConfig, Error> {
+    let Ok(workspace_dir) = get_workspace_root(config_dir) else {
+        return Ok(PackageOpsConfig::default());
+    };
+    match WorkspaceGraph::load(&workspace_dir)?.package(package) {
+        Some(workspace_package) => PackageOpsConfig::load(&workspace_dir.join(&workspace_package.path)),
+        None => Ok(PackageOpsConfig::default()),
+    }
+}
+
+/// Path of the binary built by ops-docker-build-rust-workspace-service for `feature_set`.
+pub(crate) fn get_binary_path(package: &str, feature_set: &str) -> Result<String, Error> {
+    Ok(format!("/app/{package}{}", FeatureSet::parse(feature_set)?.suffix()))
+}
+
+/// Resolves a relative host path (`./data:/data`) against `config_dir`, leaving named volumes untouched.
+fn resolve_volume(config_dir: &Path, volume: &str) -> Result<String, Error> {
+    let (host, container) = match volume.split_once(':') {
+        Some((host, container)) if host.starts_with('.') => (host, container),
+        _ => return Ok(volume.to_string()),
+    };
+    Ok(format!("{}:{container}", config_dir.join(host).absolutize()?.display()))
+}
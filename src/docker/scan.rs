@@ -0,0 +1,202 @@
+/// Vulnerability scans of built images with trivy or grype: the scanner's json report is kept as an artifact and the
+/// vulnerabilities it found are counted by severity, failing the build if any is at or above the threshold.
+use crate::dry_run::{is_dry_run, write_file};
+use crate::error::Error;
+use crate::exec::{run_or_record, ExecOptions};
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::{fmt, fs};
+use tracing::debug;
+
+/// directory of the target directory scan reports are written to unless `--scan-report` is passed
+pub const SCAN_REPORT_DIR: &str = "ops-scan";
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageScanner {
+    /// `trivy image`
+    Trivy,
+    /// `grype`
+    Grype,
+}
+
+impl fmt::Display for ImageScanner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Trivy => write!(f, "trivy"),
+            Self::Grype => write!(f, "grype"),
+        }
+    }
+}
+
+/// Severity of a vulnerability, in increasing order. Severities the scanners use which are unknown to ops count
+/// as [`Severity::Unknown`].
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Unknown,
+    Negligible,
+    Low,
+    Medium,
+    High,
+    Critical,
+}
+
+impl Severity {
+    fn parse(severity: &str) -> Self {
+        match &*severity.to_lowercase() {
+            "negligible" => Self::Negligible,
+            "low" => Self::Low,
+            "medium" => Self::Medium,
+            "high" => Self::High,
+            "critical" => Self::Critical,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", format!("{self:?}").to_lowercase())
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ImageScanOutput {
+    pub scanner: ImageScanner,
+    pub image: String,
+    /// json report of the scanner
+    pub report: PathBuf,
+    /// number of vulnerabilities found per severity, empty in dry-run mode
+    pub counts: BTreeMap<Severity, usize>,
+}
+
+impl ImageScanOutput {
+    /// Summary of the counts, e.g. `2 critical, 5 high`, most severe first.
+    pub fn summary(&self) -> String {
+        if self.counts.is_empty() {
+            return "no vulnerabilities".to_string();
+        }
+        self.counts
+            .iter()
+            .rev()
+            .map(|(severity, count)| format!("{count} {severity}"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// A vulnerability found by a scan.
+struct Vulnerability {
+    id: String,
+    package: String,
+    severity: Severity,
+}
+
+/// Scans `image` with `scanner`, writes the scanner's json report to `report` and fails if any vulnerability is at
+/// least as severe as `fail_on`.
+pub fn scan_image(
+    scanner: ImageScanner,
+    image: &str,
+    report: &Path,
+    fail_on: Severity,
+) -> Result<ImageScanOutput, Error> {
+    let command = match scanner {
+        ImageScanner::Trivy => {
+            let mut command = Command::new("trivy");
+            command.args(["image", "--quiet", "--format", "json", image]);
+            command
+        }
+        ImageScanner::Grype => {
+            let mut command = Command::new("grype");
+            command.args(["--quiet", "--output", "json", image]);
+            command
+        }
+    };
+    debug!("{command:?}");
+    let output = run_or_record(
+        &command,
+        &ExecOptions {
+            stream: false,
+            ..Default::default()
+        },
+    )?;
+    let mut scan_output = ImageScanOutput {
+        scanner,
+        image: image.to_string(),
+        report: report.to_path_buf(),
+        counts: BTreeMap::default(),
+    };
+    let Some(output) = output else {
+        return Ok(scan_output);
+    };
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if let Some(report_dir) = report.parent().filter(|_| !is_dry_run()) {
+        fs::create_dir_all(report_dir)?;
+    }
+    write_file(report, &stdout)?;
+    let report = serde_json::from_str::<Value>(&stdout)?;
+    let vulnerabilities = match scanner {
+        ImageScanner::Trivy => get_trivy_vulnerabilities(&report),
+        ImageScanner::Grype => get_grype_vulnerabilities(&report),
+    };
+    for vulnerability in &vulnerabilities {
+        *scan_output.counts.entry(vulnerability.severity).or_default() += 1;
+    }
+    let failing = vulnerabilities
+        .iter()
+        .filter(|vulnerability| vulnerability.severity >= fail_on)
+        .map(|vulnerability| {
+            format!(
+                "{} ({}, {})",
+                vulnerability.id, vulnerability.package, vulnerability.severity
+            )
+        })
+        .collect::<Vec<_>>();
+    if !failing.is_empty() {
+        return Err(Error::ImageScanFailed {
+            image: image.to_string(),
+            fail_on: fail_on.to_string(),
+            vulnerabilities: failing,
+        });
+    }
+    Ok(scan_output)
+}
+
+/// Vulnerabilities of a `trivy image --format json` report, listed per scanned target (e.g. the OS packages and
+/// every binary).
+fn get_trivy_vulnerabilities(report: &Value) -> Vec<Vulnerability> {
+    report["Results"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .flat_map(|result| result["Vulnerabilities"].as_array().into_iter().flatten())
+        .map(|vulnerability| Vulnerability {
+            id: vulnerability["VulnerabilityID"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            package: vulnerability["PkgName"].as_str().unwrap_or_default().to_string(),
+            severity: Severity::parse(vulnerability["Severity"].as_str().unwrap_or_default()),
+        })
+        .collect()
+}
+
+/// Vulnerabilities of a `grype --output json` report, which lists every vulnerable package as a match.
+fn get_grype_vulnerabilities(report: &Value) -> Vec<Vulnerability> {
+    report["matches"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|entry| Vulnerability {
+            id: entry["vulnerability"]["id"].as_str().unwrap_or_default().to_string(),
+            package: entry["artifact"]["name"].as_str().unwrap_or_default().to_string(),
+            severity: Severity::parse(entry["vulnerability"]["severity"].as_str().unwrap_or_default()),
+        })
+        .collect()
+}
@@ -0,0 +1,291 @@
+/// User supplied templates of the Dockerfiles generated for rust workspace services, replacing the embedded
+/// `Dockerfile.pre_build_service` and `Dockerfile.build_service`: passed with `--pre-build-template` and
+/// `--build-template` or placed under [`TEMPLATE_DIR`] of the workspace root. Templates reference the values ops
+/// computes as `$name` variables (see [`TemplateKind::variables`]) and are validated when they are rendered, any
+/// other `$` expression (e.g. `$(uname -m)` or `$BUILDPLATFORM`) is left as is.
+use crate::docker::dockerfile::{BUILD_SERVICE_DOCKERFILE, PRE_BUILD_SERVICE_DOCKERFILE};
+use crate::error::Error;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+/// directory of the workspace root templates are picked up from, named after the embedded templates
+pub const TEMPLATE_DIR: &str = ".ops/templates";
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TemplateKind {
+    PreBuild,
+    Service,
+}
+
+/// Variable substituted for `$name` in a template.
+#[derive(Clone, Copy, Debug)]
+pub struct TemplateVariable {
+    pub name: &'static str,
+    pub description: &'static str,
+    /// whether templates must reference the variable, the image built is unusable otherwise
+    pub required: bool,
+}
+
+const fn variable(name: &'static str, description: &'static str, required: bool) -> TemplateVariable {
+    TemplateVariable {
+        name,
+        description,
+        required,
+    }
+}
+
+const PRE_BUILD_VARIABLES: &[TemplateVariable] = &[
+    variable("build_image", "base image of the stage, see --build-image", false),
+    variable(
+        "rust_version",
+        "rust docker image version, `latest` unless passed",
+        false,
+    ),
+    variable(
+        "rustup_toolchain",
+        "instructions installing the rust toolchain and targets",
+        false,
+    ),
+    variable(
+        "fetch_cargo_lock",
+        "instruction writing the Cargo.lock without the internal crates",
+        false,
+    ),
+    variable(
+        "cargo_registries",
+        "instruction configuring the private registries and git dependencies of the Cargo.lock",
+        false,
+    ),
+    variable(
+        "cargo_vendor",
+        "instructions copying the dependencies vendored with --vendor and configuring cargo offline",
+        false,
+    ),
+    variable("service", "cargo package name of the service", false),
+    variable("profile", "rust profile binaries are built with", false),
+    variable("file_copy", "the additional instructions passed with --copy", false),
+    variable(
+        "pre_build_omit_deps",
+        "json object of the dependencies omitted with --pre-build-omit",
+        false,
+    ),
+    variable(
+        "local_crate_paths",
+        "json array of the paths of the internal crates the service depends on",
+        false,
+    ),
+    variable(
+        "local_crate_copy",
+        "instructions copying the internal crates the service depends on",
+        false,
+    ),
+    variable(
+        "local_crate_dependencies",
+        "instruction adding internal crates to the dependency build",
+        false,
+    ),
+    variable(
+        "pre_build_members",
+        "json array of the workspace members kept in the stage",
+        false,
+    ),
+    variable(
+        "pre_build_service",
+        "instructions compiling the service's dependencies",
+        true,
+    ),
+];
+
+const SERVICE_VARIABLES: &[TemplateVariable] = &[
+    variable(
+        "pre_build_service_image_tag",
+        "pre-build image the stage builds on",
+        true,
+    ),
+    variable("service", "cargo package name of the service", false),
+    variable("profile", "rust profile binaries are built with", false),
+    variable(
+        "build_platform",
+        "`--platform=$BUILDPLATFORM ` when cross-compiling, empty otherwise",
+        false,
+    ),
+    variable(
+        "service_dir",
+        "directory of the service relative to the workspace root",
+        false,
+    ),
+    variable("build", "instructions compiling the service's binaries", true),
+    variable(
+        "runtime_image",
+        "base image of the final stage, see --runtime-image",
+        false,
+    ),
+    variable(
+        "runtime_setup",
+        "instructions installing the runtime dependencies of the final stage",
+        false,
+    ),
+    variable(
+        "binary_copy",
+        "instructions copying the binaries into the final stage",
+        false,
+    ),
+    variable(
+        "runtime_config",
+        "instructions from `[package.metadata.ops.docker]` and the entrypoint",
+        false,
+    ),
+];
+
+impl TemplateKind {
+    /// File name of the template, both embedded and in [`TEMPLATE_DIR`].
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            Self::PreBuild => "Dockerfile.pre_build_service",
+            Self::Service => "Dockerfile.build_service",
+        }
+    }
+
+    pub fn embedded(&self) -> &'static str {
+        match self {
+            Self::PreBuild => PRE_BUILD_SERVICE_DOCKERFILE,
+            Self::Service => BUILD_SERVICE_DOCKERFILE,
+        }
+    }
+
+    /// Every variable substituted in templates of this kind.
+    pub fn variables(&self) -> &'static [TemplateVariable] {
+        match self {
+            Self::PreBuild => PRE_BUILD_VARIABLES,
+            Self::Service => SERVICE_VARIABLES,
+        }
+    }
+
+    fn other(&self) -> Self {
+        match self {
+            Self::PreBuild => Self::Service,
+            Self::Service => Self::PreBuild,
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match self {
+            Self::PreBuild => "pre-build",
+            Self::Service => "service",
+        }
+    }
+}
+
+/// Template read from a file.
+#[derive(Clone, Debug)]
+pub struct DockerfileTemplate {
+    pub kind: TemplateKind,
+    pub path: PathBuf,
+    pub text: String,
+}
+
+impl DockerfileTemplate {
+    /// Template of `kind` to render instead of the embedded one: `path` (relative to `workspace_dir`) if passed,
+    /// otherwise the template in [`TEMPLATE_DIR`] if there is one.
+    pub fn load(workspace_dir: &Path, kind: TemplateKind, path: Option<&Path>) -> Result<Option<Self>, Error> {
+        let path = match path {
+            Some(path) => workspace_dir.join(path),
+            None => {
+                let path = workspace_dir.join(TEMPLATE_DIR).join(kind.file_name());
+                if !path.is_file() {
+                    return Ok(None);
+                }
+                path
+            }
+        };
+        debug!("using {} template {}", kind.description(), path.display());
+        let text = fs::read_to_string(&path).map_err(|err| Error::manifest(&path, err.to_string()))?;
+        Ok(Some(Self { kind, path, text }))
+    }
+
+    /// Fails if the template misses a required variable or references a variable of the other kind of template.
+    pub fn validate(&self) -> Result<(), Error> {
+        let used = get_variables(&self.text);
+        if let Some(missing) = self
+            .kind
+            .variables()
+            .iter()
+            .find(|variable| variable.required && !used.contains(variable.name))
+        {
+            return Err(Error::manifest(
+                &self.path,
+                format!("missing required variable `${}`: {}", missing.name, missing.description),
+            ));
+        }
+        let other = self.kind.other();
+        let is_variable =
+            |kind: TemplateKind, name: &str| kind.variables().iter().any(|variable| variable.name == name);
+        if let Some(name) = used
+            .iter()
+            .find(|name| !is_variable(self.kind, name) && is_variable(other, name))
+        {
+            return Err(Error::manifest(
+                &self.path,
+                format!("`${name}` is only available in {} templates", other.description()),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Renders `template`, or the embedded template of `kind` if there is none, substituting `values` for their
+/// variables.
+pub(crate) fn render_template(
+    template: Option<&DockerfileTemplate>,
+    kind: TemplateKind,
+    values: &[(&str, &str)],
+) -> Result<String, Error> {
+    let text = match template {
+        Some(template) => {
+            template.validate()?;
+            &template.text
+        }
+        None => kind.embedded(),
+    };
+    Ok(substitute(text, |name| {
+        values
+            .iter()
+            .find(|(variable, _)| *variable == name)
+            .map(|(_, value)| *value)
+    }))
+}
+
+/// Replaces every `$name` of `text` for which `value` returns a value, names are matched in full so that e.g.
+/// `$build_platform` is never taken for `$build`.
+fn substitute<'a>(text: &str, value: impl Fn(&str) -> Option<&'a str>) -> String {
+    let mut rendered = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(index) = rest.find('$') {
+        rendered.push_str(&rest[..index]);
+        let (name, after) = split_name(&rest[index + 1..]);
+        match value(name) {
+            Some(value) => rendered.push_str(value),
+            None => {
+                rendered.push('$');
+                rendered.push_str(name);
+            }
+        }
+        rest = after;
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+/// Names of every `$name` in `text`.
+fn get_variables(text: &str) -> BTreeSet<&str> {
+    text.split('$').skip(1).map(|rest| split_name(rest).0).collect()
+}
+
+fn split_name(text: &str) -> (&str, &str) {
+    let len = text
+        .find(|c: char| !(c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_'))
+        .unwrap_or(text.len());
+    text.split_at(len)
+}
@@ -0,0 +1,231 @@
+/// Builds only the dependency (pre-build) stage of services and pushes it to a cache registry, intended to run
+/// on a schedule so that builds after a lockfile bump can start from a hot cache by passing the pushed image as
+/// `--cache-from` instead of compiling every dependency from scratch.
+use crate::ci::matrix::get_ci_matrix;
+use crate::config::package::PackageOpsConfig;
+use crate::config::OpsConfig;
+use crate::docker::build::*;
+use crate::docker::build_rust_workspace::*;
+use crate::docker::dockerfile::{CacheMounts, RuntimeBase};
+use crate::docker::lint::check_dockerfile;
+use crate::docker::push::*;
+use crate::docker::template::{DockerfileTemplate, TemplateKind};
+use crate::dry_run::enable_dry_run;
+use crate::error::Error;
+use crate::git::diff_name_status_since_branched::DiffBaseArgs;
+use crate::logging::LogArgs;
+use crate::progress::{Progress, SummaryRow};
+use crate::report::{summary_table, Report};
+use crate::timing::{time, Phase};
+use crate::workspace::root::get_workspace_root;
+use clap::Parser;
+use serde::Serialize;
+use std::env;
+use tracing::{debug, info_span};
+
+#[derive(Clone, Debug, Parser)]
+#[clap(author, version, about, long_about = None)]
+pub struct DockerWarmCacheArgs {
+    /// services to warm the cache of, defaults to every service
+    #[clap(short, long, value_delimiter = '\n', env = "OPS_SERVICE")]
+    pub service: Vec<String>,
+
+    /// registry repository prefix the pre-build images are pushed to (e.g. `ghcr.io/my-org/cache`) -- defaults
+    /// to `{ci.registry}/cache` from ops.toml
+    #[clap(long, env = "OPS_CACHE_REGISTRY")]
+    pub cache_registry: Option<String>,
+
+    /// which rust profile to build rust binaries, defaults to release
+    #[clap(long, env = "OPS_PROFILE")]
+    pub profile: Option<String>,
+
+    /// rust docker image version, defaults to latest
+    #[clap(short, long, env = "OPS_RUST_VERSION")]
+    pub rust_version: Option<String>,
+
+    /// keep cargo's downloads between builds in BuildKit cache mounts, `--cache-mounts=sccache` additionally
+    /// compiles through sccache -- see ops-docker-build-rust-workspace-service --cache-mounts
+    #[clap(
+        long,
+        value_enum,
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "registry",
+        env = "OPS_CACHE_MOUNTS"
+    )]
+    pub cache_mounts: Option<CacheMounts>,
+
+    /// how to obtain credentials for the cache registry
+    #[clap(long, value_enum, default_value = "docker", env = "OPS_REGISTRY_PROVIDER")]
+    pub provider: RegistryProvider,
+
+    /// docker credential helper to authenticate with instead of logging in through a provider
+    #[clap(long, env = "OPS_CREDENTIAL_HELPER")]
+    pub credential_helper: Option<String>,
+
+    #[clap(flatten)]
+    pub log: LogArgs,
+
+    /// print the commands instead of running them
+    /// - can also be enabled by setting the OPS_DRY_RUN environment variable
+    #[clap(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DockerWarmCacheOutput {
+    pub images: Vec<WarmCacheImage>,
+    pub steps: Vec<SummaryRow>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct WarmCacheImage {
+    pub service: String,
+    /// pushed pre-build image, pass it to builds as `--cache-from`
+    pub image: String,
+    pub digest: Option<String>,
+}
+
+impl Report for DockerWarmCacheOutput {
+    fn human(&self) -> String {
+        if self.images.is_empty() {
+            return "no services to warm the cache of".to_string();
+        }
+        let mut lines = vec![summary_table("warm cache", &self.steps)];
+        for image in &self.images {
+            lines.push(format!("{}: {}", image.service, image.image));
+        }
+        lines.join("\n")
+    }
+}
+
+pub fn docker_warm_cache(docker_warm_cache_args: DockerWarmCacheArgs) -> Result<DockerWarmCacheOutput, Error> {
+    let DockerWarmCacheArgs {
+        service,
+        cache_registry,
+        profile,
+        rust_version,
+        cache_mounts,
+        provider,
+        credential_helper,
+        log,
+        dry_run,
+    } = docker_warm_cache_args;
+
+    let _span = info_span!("docker_warm_cache").entered();
+
+    if dry_run {
+        enable_dry_run();
+    }
+
+    let workspace_dir = get_workspace_root(&env::current_dir()?)?;
+    env::set_current_dir(&workspace_dir)?;
+
+    let config = OpsConfig::load(&workspace_dir)?;
+    let cache_registry = cache_registry
+        .or_else(|| {
+            config
+                .ci
+                .registry
+                .as_ref()
+                .map(|registry| format!("{}/cache", registry.trim_end_matches('/')))
+        })
+        .ok_or_else(|| {
+            Error::InvalidInput("no cache registry provided, pass --cache-registry or set ci.registry".to_string())
+        })?;
+    let cache_registry = cache_registry.trim_end_matches('/');
+
+    let mut entries = get_ci_matrix(&workspace_dir, true, &DiffBaseArgs::default())?.include;
+    if let Some(unknown) = service
+        .iter()
+        .find(|name| !entries.iter().any(|entry| &entry.service == *name))
+    {
+        return Err(Error::InvalidInput(format!("unknown service `{unknown}`")));
+    }
+    entries.retain(|entry| service.is_empty() || service.contains(&entry.service));
+    // services built from their own Dockerfile have no pre-build stage
+    entries.retain(|entry| {
+        if entry.dockerfile.is_some() {
+            debug!("skipping {}: built from its own Dockerfile", entry.service);
+        }
+        entry.dockerfile.is_none()
+    });
+
+    let profile = profile.unwrap_or_else(|| "release".to_string());
+    let build_profile = get_build_profile(&profile);
+
+    let mut progress = Progress::new("warm cache", entries.len());
+    let mut images = vec![];
+    let pre_build_template = DockerfileTemplate::load(&workspace_dir, TemplateKind::PreBuild, None)?;
+    for entry in entries {
+        let image = format!("{cache_registry}/{}:{profile}-pre-build", entry.service);
+        let feature_sets = get_feature_sets(&entry.feature_sets, entry.default_feature_set)?;
+        let package_config = PackageOpsConfig::load(&workspace_dir.join(&entry.path))?;
+        let dockerfile = get_pre_build_service_dockerfile(
+            &workspace_dir,
+            rust_version.as_deref(),
+            package_config.build_image.as_deref(),
+            &entry.package,
+            &profile,
+            &feature_sets,
+            &[],
+            &[],
+            &[],
+            RuntimeBase::of(package_config.runtime_image.as_deref()).musl(),
+            cache_mounts,
+            None,
+            pre_build_template.as_ref(),
+        )?;
+        check_dockerfile("pre-build", &dockerfile, Some(&workspace_dir))?;
+        let cargo_credentials = get_cargo_credentials(&dockerfile);
+        let context_include = get_context_include(&dockerfile);
+        let pushed = progress.run(format!("{} {image}", entry.service), || {
+            time(Phase::DockerPreBuild, &image, || {
+                docker_build(DockerBuildArgs {
+                    file: None,
+                    file_text: Some(dockerfile),
+                    ignore_file: None,
+                    context_include: context_include.clone(),
+                    buildx: false,
+                    cache_from: vec![],
+                    cache_to: vec![],
+                    build_arg_file: vec![],
+                    secret: cargo_credentials.secret.clone(),
+                    export: vec![],
+                    load: false,
+                    dir: None,
+                    log: log.clone(),
+                    dry_run,
+                    dry_run_dir: None,
+                    docker_args: vec![
+                        "--build-arg".to_string(),
+                        format!("build_profile={}", build_profile.trim()),
+                        // embeds cache metadata in the image so that it can be used as a --cache-from source
+                        "--build-arg".to_string(),
+                        "BUILDKIT_INLINE_CACHE=1".to_string(),
+                        format!("--tag={image}"),
+                    ]
+                    .into_iter()
+                    .chain(cargo_credentials.ssh.iter().map(|ssh| format!("--ssh={ssh}")))
+                    .collect(),
+                })
+            })?;
+            push_image(
+                &image,
+                provider,
+                credential_helper.as_deref(),
+                &push_options(None, None),
+            )
+        })?;
+        images.push(WarmCacheImage {
+            service: entry.service,
+            image,
+            digest: pushed.digest,
+        });
+    }
+
+    Ok(DockerWarmCacheOutput {
+        images,
+        steps: progress.rows().to_vec(),
+    })
+}
@@ -0,0 +1,216 @@
+/// Crate-wide dry-run mode: when enabled, side-effecting subprocesses and file writes are recorded in a
+/// plan instead of being executed. Read-only operations (e.g. git queries used to compute the plan) still run.
+/// The plan is printed to stdout, or written to a directory for review if one was set with [`set_dry_run_dir`].
+use crate::docker::lint::get_build_context_sources;
+use crate::error::Error;
+use serde::Serialize;
+use std::iter::once;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::{env, fs};
+use tracing::info;
+
+/// environment variable which enables dry-run mode when set to anything other than `0`, `false` or an empty string
+pub const OPS_DRY_RUN_ENV: &str = "OPS_DRY_RUN";
+
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+/// whether the plan was already printed as part of a json report
+static PLAN_REPORTED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref PLAN: Mutex<Vec<PlanStep>> = Mutex::new(vec![]);
+    static ref DRY_RUN_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Plan {
+    pub steps: Vec<PlanStep>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PlanStep {
+    Command {
+        program: String,
+        args: Vec<String>,
+        cwd: Option<PathBuf>,
+    },
+    WriteFile {
+        path: PathBuf,
+        contents: String,
+    },
+    RemovePath {
+        path: PathBuf,
+    },
+    /// paths a docker build copies from its build context (`COPY` and `ADD` instructions without `--from`)
+    BuildContext {
+        dockerfile: PathBuf,
+        sources: Vec<String>,
+    },
+}
+
+pub fn enable_dry_run() {
+    DRY_RUN.store(true, Ordering::SeqCst);
+}
+
+pub fn is_dry_run() -> bool {
+    DRY_RUN.load(Ordering::SeqCst)
+        || env::var(OPS_DRY_RUN_ENV)
+            .map(|value| !matches!(&*value.trim().to_lowercase(), "" | "0" | "false"))
+            .unwrap_or_default()
+}
+
+/// Enables dry-run mode and writes the plan to `dir` instead of printing it: `plan.json`, the commands as a shell
+/// script in `commands.sh` and the contents of every written file under `files/`, prefixed with their step's
+/// index.
+pub fn set_dry_run_dir(dir: &Path) {
+    enable_dry_run();
+    *DRY_RUN_DIR.lock().unwrap() = Some(dir.to_path_buf());
+}
+
+/// Removes and returns all steps recorded so far.
+pub fn take_plan() -> Plan {
+    Plan {
+        steps: std::mem::take(&mut *PLAN.lock().unwrap()),
+    }
+}
+
+/// Removes and returns all steps recorded so far for them to be included in a json report, after which
+/// [`print_plan`] no longer prints the plan.
+pub(crate) fn take_reported_plan() -> Result<Plan, Error> {
+    PLAN_REPORTED.store(true, Ordering::SeqCst);
+    let plan = take_plan();
+    if let Some(dir) = DRY_RUN_DIR.lock().unwrap().as_ref() {
+        write_plan(dir, &plan)?;
+    }
+    Ok(plan)
+}
+
+/// Prints the recorded plan as json (or writes it to the directory set with [`set_dry_run_dir`]) if dry-run mode
+/// is enabled, called by [`crate::cli::ops`] once its command has completed. The plan is only printed once.
+pub fn print_plan() -> Result<(), Error> {
+    if !is_dry_run() || PLAN_REPORTED.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+    PLAN_REPORTED.store(true, Ordering::SeqCst);
+    let plan = take_plan();
+    match DRY_RUN_DIR.lock().unwrap().as_ref() {
+        Some(dir) => write_plan(dir, &plan)?,
+        None => println!("{}", serde_json::to_string_pretty(&plan)?),
+    }
+    Ok(())
+}
+
+fn write_plan(dir: &Path, plan: &Plan) -> Result<(), Error> {
+    let files_dir = dir.join("files");
+    fs::create_dir_all(&files_dir)?;
+    fs::write(dir.join("plan.json"), serde_json::to_string_pretty(plan)?)?;
+    let mut commands = vec!["#!/bin/sh".to_string(), "set -e".to_string()];
+    for (index, step) in plan.steps.iter().enumerate() {
+        match step {
+            PlanStep::Command { program, args, cwd } => {
+                let command = once(program)
+                    .chain(args.iter())
+                    .map(|arg| shell_quote(arg))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                commands.push(match cwd {
+                    Some(cwd) => format!("(cd {} && {command})", shell_quote(&cwd.display().to_string())),
+                    None => command,
+                });
+            }
+            PlanStep::WriteFile { path, contents } => {
+                let file_name = path
+                    .file_name()
+                    .map(|file_name| file_name.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                fs::write(files_dir.join(format!("{:02}-{file_name}", index + 1)), contents)?;
+            }
+            PlanStep::RemovePath { .. } | PlanStep::BuildContext { .. } => {}
+        }
+    }
+    fs::write(dir.join("commands.sh"), format!("{}\n", commands.join("\n")))?;
+    info!("[dry-run] wrote the plan to {}", dir.display());
+    Ok(())
+}
+
+/// `arg` quoted for a POSIX shell unless it only contains characters which need no quoting.
+fn shell_quote(arg: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_./=:@,+%".contains(c);
+    match !arg.is_empty() && arg.chars().all(plain) {
+        true => arg.to_string(),
+        false => format!("'{}'", arg.replace('\'', r"'\''")),
+    }
+}
+
+fn record(step: PlanStep) {
+    match &step {
+        PlanStep::Command { program, args, cwd } => {
+            let cwd = cwd
+                .as_ref()
+                .map(|cwd| format!(" (in {})", cwd.display()))
+                .unwrap_or_default();
+            info!("[dry-run] would run: {program} {}{cwd}", args.join(" "));
+        }
+        PlanStep::WriteFile { path, .. } => {
+            info!("[dry-run] would write file: {}", path.display());
+        }
+        PlanStep::RemovePath { path } => {
+            info!("[dry-run] would remove: {}", path.display());
+        }
+        PlanStep::BuildContext { sources, .. } => {
+            info!("[dry-run] would copy from the build context: {}", sources.join(", "));
+        }
+    }
+    PLAN.lock().unwrap().push(step);
+}
+
+/// Records a side-effecting command in the plan instead of running it.
+pub(crate) fn record_command(command: &Command) {
+    record(PlanStep::Command {
+        program: command.get_program().to_string_lossy().to_string(),
+        args: command
+            .get_args()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect(),
+        cwd: command.get_current_dir().map(Path::to_path_buf),
+    });
+}
+
+/// Records the paths the Dockerfile `dockerfile` (rendered from `contents`) copies from the build context.
+pub(crate) fn record_build_context(dockerfile: &Path, contents: &str) {
+    record(PlanStep::BuildContext {
+        dockerfile: dockerfile.to_path_buf(),
+        sources: get_build_context_sources(contents),
+    });
+}
+
+/// Writes `contents` to `path`, or records the write in dry-run mode.
+pub fn write_file(path: &Path, contents: &str) -> Result<(), Error> {
+    if is_dry_run() {
+        record(PlanStep::WriteFile {
+            path: path.to_path_buf(),
+            contents: contents.to_string(),
+        });
+        return Ok(());
+    }
+    std::fs::write(path, contents)?;
+    Ok(())
+}
+
+/// Removes the file or directory (recursively) at `path`, or records the removal in dry-run mode.
+pub fn remove_path(path: &Path) -> Result<(), Error> {
+    if is_dry_run() {
+        record(PlanStep::RemovePath {
+            path: path.to_path_buf(),
+        });
+        return Ok(());
+    }
+    match path.symlink_metadata()?.is_dir() {
+        true => std::fs::remove_dir_all(path)?,
+        false => std::fs::remove_file(path)?,
+    }
+    Ok(())
+}
@@ -0,0 +1,223 @@
+/// Summarizes the disk usage of everything ops and cargo build for a workspace: the target directory broken down
+/// per profile, cargo's registry and git caches, leftover ops temporary directories and the docker images built
+/// by ops (content hash labelled service images and pre-build images).
+use crate::cargo::Metadata;
+use crate::docker::content_hash::CONTENT_HASH_LABEL;
+use crate::error::Error;
+use crate::exec::read;
+use crate::lock::OPS_LOCK_DIR_NAME;
+use crate::logging::LogArgs;
+use crate::progress::format_bytes;
+use crate::report::Report;
+use crate::temp::OPS_TEMP_DIR_PREFIX;
+use crate::workspace::prune::{get_profile_dirs, get_size};
+use crate::workspace::root::get_workspace_root;
+use clap::Parser;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::process::Command;
+use std::{env, fs};
+use tracing::{info_span, warn};
+
+#[derive(Clone, Debug, Parser)]
+#[clap(author, version, about, long_about = None)]
+pub struct DuArgs {
+    /// only report the workspace and cargo caches, skipping docker images
+    #[clap(long, env = "OPS_SKIP_DOCKER")]
+    pub skip_docker: bool,
+
+    #[clap(flatten)]
+    pub log: LogArgs,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DuOutput {
+    pub entries: Vec<DuEntry>,
+    /// total size of every entry
+    pub bytes: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DuEntry {
+    pub category: DuCategory,
+    /// profile, cache directory or image reference
+    pub name: String,
+    pub bytes: u64,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuCategory {
+    Target,
+    CargoRegistry,
+    CargoGit,
+    TempDirs,
+    DockerImages,
+}
+
+impl DuCategory {
+    fn description(&self) -> &'static str {
+        match self {
+            Self::Target => "target directory",
+            Self::CargoRegistry => "cargo registry",
+            Self::CargoGit => "cargo git",
+            Self::TempDirs => "ops temp dirs",
+            Self::DockerImages => "docker images",
+        }
+    }
+}
+
+impl Report for DuOutput {
+    fn human(&self) -> String {
+        let mut lines = vec![];
+        let mut category = None;
+        for entry in &self.entries {
+            if category != Some(entry.category) {
+                category = Some(entry.category);
+                let bytes = self
+                    .entries
+                    .iter()
+                    .filter(|other| other.category == entry.category)
+                    .map(|other| other.bytes)
+                    .sum();
+                lines.push(format!(
+                    "{:40} {:>10}",
+                    entry.category.description(),
+                    format_bytes(bytes)
+                ));
+            }
+            lines.push(format!("  {:38} {:>10}", entry.name, format_bytes(entry.bytes)));
+        }
+        lines.push(format!("{:40} {:>10}", "total", format_bytes(self.bytes)));
+        lines.join("\n")
+    }
+}
+
+pub fn du(du_args: DuArgs) -> Result<DuOutput, Error> {
+    let DuArgs { skip_docker, log: _ } = du_args;
+
+    let _span = info_span!("du").entered();
+
+    let workspace_dir = get_workspace_root(&env::current_dir()?)?;
+    let target_dir = Metadata::load(&workspace_dir)?.target_directory;
+
+    let mut entries = vec![];
+    if target_dir.exists() {
+        let mut profiles_bytes = 0;
+        let mut profile_dirs = get_profile_dirs(&target_dir)?;
+        profile_dirs.sort();
+        for profile_dir in profile_dirs {
+            let bytes = get_size(&profile_dir);
+            profiles_bytes += bytes;
+            entries.push(DuEntry {
+                category: DuCategory::Target,
+                name: profile_dir
+                    .strip_prefix(&target_dir)
+                    .unwrap_or(&profile_dir)
+                    .display()
+                    .to_string(),
+                bytes,
+            });
+        }
+        let other_bytes = get_size(&target_dir).saturating_sub(profiles_bytes);
+        if other_bytes > 0 {
+            entries.push(DuEntry {
+                category: DuCategory::Target,
+                name: "other".to_string(),
+                bytes: other_bytes,
+            });
+        }
+    }
+
+    let cargo_home = get_cargo_home();
+    for (category, dir, subdirs) in [
+        (DuCategory::CargoRegistry, "registry", &["cache", "index", "src"][..]),
+        (DuCategory::CargoGit, "git", &["db", "checkouts"][..]),
+    ] {
+        for subdir in subdirs {
+            let path = cargo_home.join(dir).join(subdir);
+            if path.exists() {
+                entries.push(DuEntry {
+                    category,
+                    name: path.display().to_string(),
+                    bytes: get_size(&path),
+                });
+            }
+        }
+    }
+
+    // temporary directories are removed once ops is done with them, leftovers belong to interrupted commands
+    for entry in fs::read_dir(env::temp_dir())? {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        if file_name.starts_with(OPS_TEMP_DIR_PREFIX) && file_name != OPS_LOCK_DIR_NAME && entry.file_type()?.is_dir() {
+            entries.push(DuEntry {
+                category: DuCategory::TempDirs,
+                name: entry.path().display().to_string(),
+                bytes: get_size(&entry.path()),
+            });
+        }
+    }
+
+    if !skip_docker {
+        match get_docker_image_sizes() {
+            Ok(images) => entries.extend(images.into_iter().map(|(name, bytes)| DuEntry {
+                category: DuCategory::DockerImages,
+                name,
+                bytes,
+            })),
+            Err(err) => warn!("skipping docker images: {err}"),
+        }
+    }
+
+    let bytes = entries.iter().map(|entry| entry.bytes).sum();
+    Ok(DuOutput { entries, bytes })
+}
+
+/// Cargo's home directory: `$CARGO_HOME` or `~/.cargo`.
+pub(crate) fn get_cargo_home() -> PathBuf {
+    match env::var_os("CARGO_HOME") {
+        Some(cargo_home) => PathBuf::from(cargo_home),
+        None => PathBuf::from(env::var_os("HOME").unwrap_or_default()).join(".cargo"),
+    }
+}
+
+/// Sizes of the images built by ops keyed by one of their references, images with several tags are only counted
+/// once.
+fn get_docker_image_sizes() -> Result<Vec<(String, u64)>, Error> {
+    let labelled = read(Command::new("docker").args([
+        "image",
+        "ls",
+        "--filter",
+        &format!("label={CONTENT_HASH_LABEL}"),
+        "--format",
+        "{{.Repository}}:{{.Tag}}",
+    ]))?;
+    let all = read(Command::new("docker").args(["image", "ls", "--format", "{{.Repository}}:{{.Tag}}"]))?;
+    let images = labelled
+        .lines()
+        .chain(all.lines().filter(|image| image.ends_with("-pre-build")))
+        .filter(|image| !image.ends_with(":<none>"))
+        .collect::<BTreeSet<_>>();
+    if images.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let inspected = read(
+        Command::new("docker")
+            .args(["image", "inspect", "--format", "{{.Id}} {{.Size}}"])
+            .args(&images),
+    )?;
+    let mut ids = BTreeSet::default();
+    let mut sizes = vec![];
+    for (image, line) in images.into_iter().zip(inspected.lines()) {
+        let Some((id, size)) = line.split_once(' ') else {
+            continue;
+        };
+        if ids.insert(id.to_string()) {
+            sizes.push((image.to_string(), size.parse().unwrap_or_default()));
+        }
+    }
+    Ok(sizes)
+}
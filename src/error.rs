@@ -0,0 +1,355 @@
+/// Error type returned by the ops library. Each failure class maps to a distinct process exit code so
+/// that callers (e.g. CI) can tell lint failures apart from build, push or tooling failures.
+use crate::logging::error_format;
+use crate::timing::report_timings;
+use colored::Colorize;
+use serde::Serialize;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+
+/// unclassified failure
+pub const EXIT_FAILURE: u8 = 1;
+/// invalid arguments or configuration, matches the exit code clap uses for argument parsing errors
+pub const EXIT_USAGE: u8 = 2;
+/// a linter (clippy, eslint) reported problems
+pub const EXIT_LINT: u8 = 3;
+/// a docker build failed
+pub const EXIT_BUILD: u8 = 4;
+/// pushing an image to a registry failed
+pub const EXIT_PUSH: u8 = 5;
+/// a required external tool is missing or unreachable (e.g. docker daemon not running, git not installed)
+pub const EXIT_TOOLING: u8 = 6;
+/// a kubernetes rollout failed
+pub const EXIT_DEPLOY: u8 = 7;
+/// cancelled by Ctrl-C, follows the shell convention of 128 + SIGINT
+pub const EXIT_CANCELLED: u8 = 130;
+
+/// Output format of the error printed when a binary fails.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, clap::ValueEnum)]
+pub enum ErrorFormat {
+    #[default]
+    Human,
+    Json,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// a manifest or configuration file (Cargo.toml, Cargo.lock, ops.toml, .pre-commit-config.yaml, a Dockerfile
+    /// template) is malformed
+    #[error("cannot parse `{}`: {message}", path.display())]
+    ManifestParse { path: PathBuf, message: String },
+
+    /// a rendered Dockerfile has problems which would fail its build, see [`crate::docker::lint`]
+    #[error("invalid {stage} Dockerfile:\n{}", problems.iter().map(|problem| format!("  {problem}")).collect::<Vec<_>>().join("\n"))]
+    InvalidDockerfile { stage: String, problems: Vec<String> },
+
+    /// a docker build exited unsuccessfully, includes the failing instruction and the end of its output if they
+    /// could be told from the build's output
+    #[error("docker {stage} failed{}{}{}", fmt_status(status), step.as_ref().map(|step| format!(" at `{step}`")).unwrap_or_default(), if log.is_empty() { String::new() } else { format!(":\n{log}") })]
+    DockerFailed {
+        stage: String,
+        status: Option<i32>,
+        step: Option<String>,
+        log: String,
+    },
+
+    /// pushing an image to a registry failed
+    #[error("failed to push `{image}`: {message}")]
+    PushFailed { image: String, message: String },
+
+    /// the rollout of one or more workloads deployed by ops-deploy failed
+    #[error("rollout failed for {}{}", workloads.join(", "), if *rolled_back { ", rolled back" } else { "" })]
+    DeployFailed { workloads: Vec<String>, rolled_back: bool },
+
+    /// a git pre-receive hook rejected one or more pushed refs
+    #[error("push rejected for {}", refs.join(", "))]
+    PushRejected { refs: Vec<String> },
+
+    /// one or more checks of a git hook run by ops-git-hooks failed
+    #[error("{hook} hook failed: {}", checks.join(", "))]
+    HookFailed { hook: String, checks: Vec<String> },
+
+    /// one or more steps of `ops verify` failed
+    #[error("verify failed: {}", steps.join(", "))]
+    VerifyFailed { steps: Vec<String> },
+
+    /// the command run by ops-workspace-run failed for one or more packages
+    #[error("command failed for {}", packages.join(", "))]
+    RunFailed { packages: Vec<String> },
+
+    /// one or more stages of `ops ci run` failed, exits with the code of the first failure
+    #[error("ci failed: {}", failed.join(", "))]
+    CiFailed { failed: Vec<String>, exit_code: u8 },
+
+    /// `--require-signed` was passed but neither the commit nor a tag pointing at it is signed by an allowed signer
+    #[error("`{rev}` is not signed by an allowed signer: {message}")]
+    SignatureRejected { rev: String, message: String },
+
+    /// rendered files differ from their golden file snapshots or have none
+    #[error("snapshots differ: {}", paths.iter().map(|path| path.display().to_string()).collect::<Vec<_>>().join(", "))]
+    SnapshotMismatch { paths: Vec<PathBuf> },
+
+    /// the state of the git repository could not be resolved (e.g. no base commit for the current branch)
+    #[error("{0}")]
+    GitResolution(String),
+
+    /// `git diff` against the base of incremental commands failed
+    #[error("unable to diff against `{base}`: {message}")]
+    GitDiffFailed { base: String, message: String },
+
+    /// no cargo workspace contains `dir`, `package` is set if `dir` is a package which no workspace has as a member
+    #[error("unable to locate cargo workspace root: {}", if *package { format!("package `{}` is not a member of any workspace", dir.display()) } else { format!("`{}` is not within a cargo workspace", dir.display()) })]
+    MissingWorkspace { dir: PathBuf, package: bool },
+
+    /// cargo clippy reported problems or exited unsuccessfully
+    #[error("clippy failed{}{}", package.as_ref().map(|package| format!(" for package `{package}`")).unwrap_or_default(), fmt_status(status))]
+    ClippyFailed {
+        package: Option<String>,
+        status: Option<i32>,
+    },
+
+    /// rustfmt found files which are not formatted
+    #[error("files are not formatted: {}", files.iter().map(|file| file.display().to_string()).collect::<Vec<_>>().join(", "))]
+    FmtFailed { files: Vec<PathBuf> },
+
+    /// cargo test reported failing tests or exited unsuccessfully
+    #[error("tests failed{}{}", package.as_ref().map(|package| format!(" for package `{package}`")).unwrap_or_default(), fmt_status(status))]
+    TestFailed {
+        package: Option<String>,
+        status: Option<i32>,
+    },
+
+    /// cargo doc reported problems or exited unsuccessfully
+    #[error("cargo doc failed{}", fmt_status(status))]
+    DocFailed { status: Option<i32> },
+
+    /// generated docs contain relative links to pages which do not exist, as `<page> -> <link>`
+    #[error("broken links in the generated docs:\n - {}", links.join("\n - "))]
+    BrokenDocLinks { links: Vec<String> },
+
+    /// the dependency audit found advisories (as `<id> (<package>)`) or crates whose license is not allowed (as
+    /// `<package> (<license>)`)
+    #[error("dependency audit failed:{}", fmt_audit_failure(advisories, licenses))]
+    DependencyAuditFailed {
+        advisories: Vec<String>,
+        licenses: Vec<String>,
+    },
+
+    /// the vulnerability scan of a built image found vulnerabilities (as `<id> (<package>, <severity>)`) at least as
+    /// severe as `fail_on`
+    #[error("image `{image}` has vulnerabilities of severity {fail_on} or higher:\n - {}", vulnerabilities.join("\n - "))]
+    ImageScanFailed {
+        image: String,
+        fail_on: String,
+        vulnerabilities: Vec<String>,
+    },
+
+    /// eslint reported problems or exited unsuccessfully
+    #[error("eslint failed{}", fmt_status(status))]
+    EslintFailed { status: Option<i32> },
+
+    /// prettier or stylelint reported problems or exited unsuccessfully
+    #[error("{tool} failed{}", fmt_status(status))]
+    JsCheckFailed { tool: String, status: Option<i32> },
+
+    /// the provided arguments are invalid or incomplete
+    #[error("{0}")]
+    InvalidInput(String),
+
+    /// an external tool required to run the command is not installed or not reachable
+    #[error("`{program}` is unavailable: {message}")]
+    ToolUnavailable { program: String, message: String },
+
+    /// an external command exited unsuccessfully, includes the tail of its stderr
+    #[error("`{command}` failed{}{}", fmt_status(status), if stderr.is_empty() { String::new() } else { format!(":\n{stderr}") })]
+    CommandFailed {
+        command: String,
+        status: Option<i32>,
+        stderr: String,
+    },
+
+    /// a concurrent build or push holds the lock of an image for longer than `--lock-timeout`
+    #[error("`{name}` is locked by {holder}")]
+    LockUnavailable { name: String, holder: String },
+
+    /// an external command did not exit within its timeout and was killed
+    #[error("`{program}` timed out after {}s", timeout.as_secs())]
+    Timeout { program: String, timeout: Duration },
+
+    /// the operation was cancelled by Ctrl-C
+    #[error("cancelled")]
+    Cancelled,
+
+    #[error("{0}")]
+    Other(String),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Toml(#[from] toml::de::Error),
+
+    #[error(transparent)]
+    TomlSerialize(#[from] toml::ser::Error),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    Yaml(#[from] serde_yaml::Error),
+}
+
+impl Error {
+    pub fn msg(message: impl Into<String>) -> Self {
+        Self::Other(message.into())
+    }
+
+    pub fn manifest(path: impl Into<PathBuf>, message: impl Into<String>) -> Self {
+        Self::ManifestParse {
+            path: path.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl Error {
+    /// Process exit code for this failure class.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Self::ManifestParse { .. }
+            | Self::InvalidDockerfile { .. }
+            | Self::InvalidInput(_)
+            | Self::MissingWorkspace { .. }
+            | Self::Toml(_) => EXIT_USAGE,
+            Self::ClippyFailed { .. }
+            | Self::TestFailed { .. }
+            | Self::DocFailed { .. }
+            | Self::BrokenDocLinks { .. }
+            | Self::DependencyAuditFailed { .. }
+            | Self::ImageScanFailed { .. }
+            | Self::FmtFailed { .. }
+            | Self::EslintFailed { .. }
+            | Self::JsCheckFailed { .. }
+            | Self::PushRejected { .. }
+            | Self::HookFailed { .. }
+            | Self::VerifyFailed { .. }
+            | Self::SignatureRejected { .. }
+            | Self::SnapshotMismatch { .. } => EXIT_LINT,
+            Self::DockerFailed { .. } => EXIT_BUILD,
+            Self::PushFailed { .. } => EXIT_PUSH,
+            Self::DeployFailed { .. } => EXIT_DEPLOY,
+            Self::ToolUnavailable { .. }
+            | Self::GitResolution(_)
+            | Self::GitDiffFailed { .. }
+            | Self::CommandFailed { .. }
+            | Self::Timeout { .. }
+            | Self::LockUnavailable { .. } => EXIT_TOOLING,
+            Self::CiFailed { exit_code, .. } => *exit_code,
+            Self::Cancelled => EXIT_CANCELLED,
+            Self::RunFailed { .. }
+            | Self::Other(_)
+            | Self::Io(_)
+            | Self::TomlSerialize(_)
+            | Self::Json(_)
+            | Self::Yaml(_) => EXIT_FAILURE,
+        }
+    }
+
+    /// Stable snake_case identifier of the failure, included in json error output.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::ManifestParse { .. } => "manifest_parse",
+            Self::InvalidDockerfile { .. } => "invalid_dockerfile",
+            Self::DockerFailed { .. } => "docker_failed",
+            Self::PushFailed { .. } => "push_failed",
+            Self::DeployFailed { .. } => "deploy_failed",
+            Self::PushRejected { .. } => "push_rejected",
+            Self::HookFailed { .. } => "hook_failed",
+            Self::VerifyFailed { .. } => "verify_failed",
+            Self::RunFailed { .. } => "run_failed",
+            Self::CiFailed { .. } => "ci_failed",
+            Self::SignatureRejected { .. } => "signature_rejected",
+            Self::SnapshotMismatch { .. } => "snapshot_mismatch",
+            Self::GitResolution(_) => "git_resolution",
+            Self::GitDiffFailed { .. } => "git_diff_failed",
+            Self::MissingWorkspace { .. } => "missing_workspace",
+            Self::ClippyFailed { .. } => "clippy_failed",
+            Self::TestFailed { .. } => "test_failed",
+            Self::DocFailed { .. } => "doc_failed",
+            Self::BrokenDocLinks { .. } => "broken_doc_links",
+            Self::DependencyAuditFailed { .. } => "dependency_audit_failed",
+            Self::ImageScanFailed { .. } => "image_scan_failed",
+            Self::FmtFailed { .. } => "fmt_failed",
+            Self::EslintFailed { .. } => "eslint_failed",
+            Self::JsCheckFailed { .. } => "js_check_failed",
+            Self::InvalidInput(_) => "invalid_input",
+            Self::ToolUnavailable { .. } => "tool_unavailable",
+            Self::CommandFailed { .. } => "command_failed",
+            Self::Timeout { .. } => "timeout",
+            Self::LockUnavailable { .. } => "lock_unavailable",
+            Self::Cancelled => "cancelled",
+            Self::Other(_) => "other",
+            Self::Io(_) => "io",
+            Self::Toml(_) => "toml",
+            Self::TomlSerialize(_) => "toml_serialize",
+            Self::Json(_) => "json",
+            Self::Yaml(_) => "yaml",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorOutput<'a> {
+    kind: &'a str,
+    message: String,
+    exit_code: u8,
+}
+
+/// Converts the result of a binary's command into its exit code, printing the error to stderr on failure
+/// in the format selected by `--error-format`.
+pub fn exit(result: Result<(), Error>) -> ExitCode {
+    // timings are reported for failed runs as well, those are usually the ones worth looking into
+    if let Err(err) = report_timings() {
+        eprintln!("{} unable to report timings: {err}", "warning:".yellow().bold());
+    }
+    let err = match result {
+        Ok(()) => return ExitCode::SUCCESS,
+        Err(err) => err,
+    };
+    match error_format() {
+        ErrorFormat::Human => eprintln!("{} {err}", "error:".red().bold()),
+        ErrorFormat::Json => {
+            let output = ErrorOutput {
+                kind: err.kind(),
+                message: err.to_string(),
+                exit_code: err.exit_code(),
+            };
+            match serde_json::to_string(&output) {
+                Ok(output) => eprintln!("{output}"),
+                Err(_) => eprintln!("{err}"),
+            }
+        }
+    }
+    ExitCode::from(err.exit_code())
+}
+
+fn fmt_status(status: &Option<i32>) -> String {
+    status
+        .map(|status| format!(" with status {status}"))
+        .unwrap_or_default()
+}
+
+fn fmt_audit_failure(advisories: &[String], licenses: &[String]) -> String {
+    let mut message = String::new();
+    if !advisories.is_empty() {
+        message.push_str(&format!("\nadvisories:\n - {}", advisories.join("\n - ")));
+    }
+    if !licenses.is_empty() {
+        message.push_str(&format!(
+            "\nlicenses which are not allowed:\n - {}",
+            licenses.join("\n - ")
+        ));
+    }
+    message
+}
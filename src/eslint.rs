@@ -1,15 +1,25 @@
-/// Analyzes the current git diff and only performs eslint on the minimal number of changed packages
+/// Analyzes the current git diff and only runs eslint on the javascript/typescript files added or modified since the
+/// current branch diverged, rather than on the whole tree.
+use crate::dry_run::enable_dry_run;
+use crate::error::Error;
+use crate::exec::output;
 use crate::git::diff_name_status_since_branched::*;
-use anyhow::Error;
+use crate::js::{get_changed_files, tool_command};
+use crate::logging::LogArgs;
+use crate::node::PackageManager;
+use crate::report::Report;
 use clap::Parser;
-use colored::Colorize;
 use fancy_regex::Regex;
+use serde::Serialize;
 use serde_yaml::Value;
 use std::fs;
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
+use std::process::Stdio;
+use tracing::{debug, info_span};
 
 const PRE_COMMIT_CONFIG_FILE_NAME: &str = ".pre-commit-config.yaml";
+/// files linted when the repository has no pre-commit config declaring an eslint hook
+const DEFAULT_FILE_REGEX: &str = r"\.(js|jsx|ts|tsx)$";
 
 lazy_static! {
     static ref REPLACE_NEWLINES_REGEX: Regex = Regex::new(r"\n *").unwrap();
@@ -19,66 +29,103 @@ lazy_static! {
 #[clap(author, version, about, long_about = None, trailing_var_arg=true)]
 pub struct EslintArgs {
     /// path to .pre-commit-config.yaml
+    #[clap(env = "OPS_PRE_COMMIT_CONFIG")]
     pub pre_commit_config_path: Option<PathBuf>,
-    /// whether to print commands prior to running
-    #[clap(short, long)]
-    pub verbose: bool,
+    #[clap(flatten)]
+    pub log: LogArgs,
+    /// print the eslint command instead of running it
+    /// - can also be enabled by setting the OPS_DRY_RUN environment variable
+    #[clap(long)]
+    pub dry_run: bool,
+    /// lint the whole tree rather than only the changed files
+    #[clap(short, long, env = "OPS_ALL")]
+    pub all: bool,
+    /// package manager to run eslint with, detected from the node workspace by default
+    #[clap(long, value_enum, env = "OPS_PACKAGE_MANAGER")]
+    pub package_manager: Option<PackageManager>,
+    #[clap(flatten)]
+    pub diff_base: DiffBaseArgs,
     /// eslint args
     #[clap(raw = true)]
     pub eslint_args: Vec<String>,
 }
 
-pub fn eslint(eslint_args: EslintArgs) -> Result<(), Error> {
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct EslintOutput {
+    /// whether the whole tree was linted
+    pub all: bool,
+    /// changed files which were linted, empty if the whole tree was linted
+    pub files: Vec<String>,
+}
+
+impl Report for EslintOutput {
+    fn human(&self) -> String {
+        if self.all {
+            return "linted the whole tree".to_string();
+        }
+        match self.files.len() {
+            0 => "no files to lint".to_string(),
+            1 => "linted 1 file".to_string(),
+            n => format!("linted {n} files"),
+        }
+    }
+}
+
+pub fn eslint(eslint_args: EslintArgs) -> Result<EslintOutput, Error> {
     let EslintArgs {
         pre_commit_config_path,
-        verbose,
+        log: _,
+        dry_run,
+        all,
+        package_manager,
+        diff_base,
         eslint_args,
     } = eslint_args;
 
-    let file_regex = get_eslint_file_regex(pre_commit_config_path)?;
+    if dry_run {
+        enable_dry_run();
+    }
+
+    let _span = info_span!("eslint").entered();
 
-    if verbose {
-        println!("{}", format!("matching files with regex: {file_regex}").dimmed());
+    if all {
+        run_eslint(package_manager, &eslint_args, &[".".to_string()])?;
+        return Ok(EslintOutput { all, files: vec![] });
     }
 
-    let text = git_diff_name_status_since_last_branch()?;
-    let git_statuses = parse_git_statuses(&text)?;
-    let js_file_names = git_statuses
-        .iter()
-        .filter_map(GitStatus::new_file_name)
-        .filter_map(|file_name| {
-            file_regex.is_match(file_name).ok().and_then(|is_match| match is_match {
-                true => Some(file_name),
-                _ => None,
-            })
-        })
-        .collect::<Vec<_>>();
+    let file_regex = get_eslint_file_regex(pre_commit_config_path)?;
+    let js_file_names = get_changed_files(&diff_base, &file_regex)?;
 
     if js_file_names.is_empty() {
-        println!("{}", "no files to lint".dimmed());
-        return Ok(());
+        return Ok(EslintOutput::default());
     }
 
-    if verbose {
-        println!("{}", format!("eslint --fix {}", js_file_names.join(" ")).dimmed());
-    }
+    run_eslint(package_manager, &eslint_args, &js_file_names)?;
+
+    Ok(EslintOutput {
+        all,
+        files: js_file_names,
+    })
+}
 
-    let output = Command::new("echo")
-        .arg("eslint")
-        .args(eslint_args)
-        .args(js_file_names)
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .output()?;
+fn run_eslint(package_manager: Option<PackageManager>, eslint_args: &[String], paths: &[String]) -> Result<(), Error> {
+    debug!("eslint {} {}", eslint_args.join(" "), paths.join(" "));
+
+    let output = match output(
+        tool_command("eslint", package_manager)?
+            .args(eslint_args)
+            .args(paths)
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit()),
+    )? {
+        Some(output) => output,
+        None => return Ok(()),
+    };
 
     if !output.status.success() {
-        return Err(Error::msg(
-            output
-                .status
-                .code()
-                .map(|code| format!("eslint failed with status {code}"))
-                .unwrap_or_else(|| String::from("eslint failed")),
-        ));
+        return Err(Error::EslintFailed {
+            status: output.status.code(),
+        });
     }
 
     Ok(())
@@ -90,63 +137,75 @@ fn get_eslint_file_regex(pre_commit_config_path: Option<PathBuf>) -> Result<Rege
         None => {
             let default_path = Path::new(PRE_COMMIT_CONFIG_FILE_NAME).to_path_buf();
             if !Path::exists(&default_path) {
-                return Err(Error::msg("unable to find pre-commit config file, try passing in a path with the --pre-commit-config-path flag".to_string()));
+                debug!("no {PRE_COMMIT_CONFIG_FILE_NAME} found, linting javascript and typescript files");
+                return Ok(Regex::new(DEFAULT_FILE_REGEX).unwrap());
             }
             default_path
         }
     };
-    let pre_commit_config_path_display = pre_commit_config_path.display();
 
     let pre_commit_config_text = fs::read_to_string(&pre_commit_config_path)?;
     let pre_commit_config: Value = serde_yaml::from_str(&pre_commit_config_text)
-        .map_err(|err| Error::msg(format!("unable to parse `{pre_commit_config_path_display}`: {err}")))?;
+        .map_err(|err| Error::manifest(&pre_commit_config_path, err.to_string()))?;
 
     let eslint_hook_config = pre_commit_config
         .get("repos")
-        .ok_or_else(|| Error::msg(format!("unable to parse `{pre_commit_config_path_display}`: no value `repos` found")))?
+        .ok_or_else(|| Error::manifest(&pre_commit_config_path, "no value `repos` found"))?
         .as_sequence()
-        .ok_or_else(|| Error::msg(format!("unable to parse `{pre_commit_config_path_display}`: expected `repos` to be a sequence")))?
+        .ok_or_else(|| Error::manifest(&pre_commit_config_path, "expected `repos` to be a sequence"))?
         .iter()
         .find(|repo| repo.get("repo").map(|repo| repo == "local").unwrap_or_default())
-        .ok_or_else(|| Error::msg(format!("unable to parse `{pre_commit_config_path_display}`: no repo found with repo \"local\"")))?
+        .ok_or_else(|| Error::manifest(&pre_commit_config_path, "no repo found with repo \"local\""))?
         .get("hooks")
-        .ok_or_else(|| Error::msg(format!("unable to parse `{pre_commit_config_path_display}`: no value `repos[repo == \"local\"].hooks` found")))?
+        .ok_or_else(|| {
+            Error::manifest(
+                &pre_commit_config_path,
+                "no value `repos[repo == \"local\"].hooks` found",
+            )
+        })?
         .as_sequence()
-        .ok_or_else(|| Error::msg(format!("unable to parse `{pre_commit_config_path_display}`: expected `repos[repo == \"local\"].hooks` to be a sequence")))?
+        .ok_or_else(|| {
+            Error::manifest(
+                &pre_commit_config_path,
+                "expected `repos[repo == \"local\"].hooks` to be a sequence",
+            )
+        })?
         .iter()
         .find(|repo| repo.get("id").map(|id| id == "eslint").unwrap_or_default())
-        .ok_or_else(|| Error::msg(format!("unable to parse `{pre_commit_config_path_display}`: no local hook found with id \"eslint\"")))?
+        .ok_or_else(|| Error::manifest(&pre_commit_config_path, "no local hook found with id \"eslint\""))?
         .as_mapping()
         .ok_or_else(|| {
-            Error::msg(format!(
-                "unable to parse `{pre_commit_config_path_display}`: expected `repos[repo == \"local\"].hooks[id == \"eslint\"]` to be a mapping"
-            ))
+            Error::manifest(
+                &pre_commit_config_path,
+                "expected `repos[repo == \"local\"].hooks[id == \"eslint\"]` to be a mapping",
+            )
         })?;
 
     let eslint_hook_config_files = eslint_hook_config
         .get("files")
-        .ok_or_else(|| Error::msg(format!("unable to parse `{pre_commit_config_path_display}`: no value `repos[repo == \"local\"].hooks[id == \"eslint\"].files` found, set a file filter for the javascript file extensions to lint (should be a valid regex)")))?
+        .ok_or_else(|| Error::manifest(&pre_commit_config_path, "no value `repos[repo == \"local\"].hooks[id == \"eslint\"].files` found, set a file filter for the javascript file extensions to lint (should be a valid regex)"))?
         .as_str()
-        .ok_or_else(|| Error::msg(format!("unable to parse `{pre_commit_config_path_display}`: expected `repos[repo == \"local\"].hooks[id == \"eslint\"].files` to be a string, set a file filter for the javascript file extensions to lint (should be a valid regex)")))?;
+        .ok_or_else(|| Error::manifest(&pre_commit_config_path, "expected `repos[repo == \"local\"].hooks[id == \"eslint\"].files` to be a string, set a file filter for the javascript file extensions to lint (should be a valid regex)"))?;
 
     let eslint_hook_config_files = REPLACE_NEWLINES_REGEX.replace_all(eslint_hook_config_files, "");
 
     let eslint_hook_config_exclude = eslint_hook_config
         .get("exclude")
-        .map(|exclude| exclude.as_str().ok_or_else(|| Error::msg(format!("unable to parse `{pre_commit_config_path_display}`: expected `repos[repo == \"local\"].hooks[id == \"eslint\"].exclude` to be a string, `exclude` should be a filter for the javascript file extensions to *not* lint (should be a valid regex non-capturing group i.e. looks like `(?!regex-here)`)"))))
+        .map(|exclude| exclude.as_str().ok_or_else(|| Error::manifest(&pre_commit_config_path, "expected `repos[repo == \"local\"].hooks[id == \"eslint\"].exclude` to be a string, `exclude` should be a filter for the javascript file extensions to *not* lint (should be a valid regex non-capturing group i.e. looks like `(?!regex-here)`)")))
         .transpose()?;
 
     let eslint_hook_config_files = Regex::new(&eslint_hook_config_files).map_err(|err| {
-        Error::msg(format!(
-            "unable to parse `repos[repo == \"local\"].hooks[id == \"eslint\"].files` as a valid regex: {err}"
-        ))
+        Error::manifest(
+            &pre_commit_config_path,
+            format!("unable to parse `repos[repo == \"local\"].hooks[id == \"eslint\"].files` as a valid regex: {err}"),
+        )
     })?;
 
     let eslint_hook_config_exclude = eslint_hook_config_exclude
         .map(|eslint_hook_config_exclude| {
             let eslint_hook_config_exclude = REPLACE_NEWLINES_REGEX.replace_all(eslint_hook_config_exclude, "");
             Regex::new(&eslint_hook_config_exclude)
-                .map_err(|err| Error::msg(format!("unable to parse `repos[repo == \"local\"].hooks[id == \"eslint\"].exclude` as a valid regex: {err}")))
+                .map_err(|err| Error::manifest(&pre_commit_config_path, format!("unable to parse `repos[repo == \"local\"].hooks[id == \"eslint\"].exclude` as a valid regex: {err}")))
         })
         .transpose()?;
 
@@ -0,0 +1,404 @@
+/// Execution core for subprocesses: every side-effecting command is spawned on a shared tokio runtime,
+/// limited by a global job count, with its output streamed through to the terminal while also being
+/// captured. Ctrl-C cancels all in-flight commands, killing their child processes and unwinding so that
+/// temporary files are cleaned up. Commands may be given a timeout and idempotent ones (e.g. git queries,
+/// registry lookups, docker login) are retried so that transient failures don't fail entire runs.
+use crate::dry_run::{is_dry_run, record_command};
+use crate::error::Error;
+use crate::logging::{is_quiet, output_format, use_color};
+use crate::report::ReportFormat;
+use colored::{Color, Colorize};
+use std::cell::RefCell;
+use std::future::Future;
+use std::panic;
+use std::process::{Command, Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader};
+use tokio::runtime::Runtime;
+use tokio::sync::Semaphore;
+use tracing::{debug, warn};
+
+/// environment variable which sets the maximum number of concurrently running subprocesses
+pub const OPS_JOBS_ENV: &str = "OPS_JOBS";
+
+/// environment variable which sets the default timeout in seconds of every subprocess, unset or 0 means no timeout
+pub const OPS_TIMEOUT_ENV: &str = "OPS_TIMEOUT";
+
+/// environment variable which sets how many times idempotent subprocesses are retried, defaults to 2
+pub const OPS_RETRIES_ENV: &str = "OPS_RETRIES";
+
+const DEFAULT_RETRIES: u32 = 2;
+
+/// number of trailing lines of a failed command's stderr which are included in its error
+const STDERR_TAIL_LINES: usize = 20;
+
+/// colors output prefixes are picked from, by label so that a label keeps its color
+const PREFIX_COLORS: [Color; 6] = [
+    Color::Cyan,
+    Color::Yellow,
+    Color::Green,
+    Color::Magenta,
+    Color::Blue,
+    Color::Red,
+];
+
+static JOBS: OnceLock<usize> = OnceLock::new();
+static TIMEOUT: OnceLock<Option<Duration>> = OnceLock::new();
+
+lazy_static! {
+    static ref RUNTIME: Runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("unable to start tokio runtime");
+    static ref JOB_SLOTS: Arc<Semaphore> = Arc::new(Semaphore::new(jobs()));
+}
+
+thread_local! {
+    /// label prefixed to the forwarded output of commands run on this thread, see [`with_output_prefix`]
+    static OUTPUT_PREFIX: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Sets the maximum number of concurrently running subprocesses, must be called before any command is run
+/// to take effect.
+pub fn set_jobs(jobs: usize) {
+    // `--jobs` of the umbrella binary and of its subcommand are both set from OPS_JOBS
+    if JOBS.set(jobs.max(1)).is_err() && JOBS.get() != Some(&jobs.max(1)) {
+        warn!("job limit already set, ignoring --jobs={jobs}");
+    }
+}
+
+/// Maximum number of concurrently running subprocesses: set by `--jobs`, the OPS_JOBS environment variable
+/// or defaulting to the available parallelism.
+pub fn jobs() -> usize {
+    *JOBS.get_or_init(|| {
+        std::env::var(OPS_JOBS_ENV)
+            .ok()
+            .and_then(|jobs| jobs.parse::<usize>().ok())
+            .or_else(|| std::thread::available_parallelism().map(usize::from).ok())
+            .unwrap_or(1)
+            .max(1)
+    })
+}
+
+/// Sets the default timeout of every subprocess, must be called before any command is run to take effect.
+pub fn set_timeout(timeout: Duration) {
+    if TIMEOUT.set(Some(timeout)).is_err() {
+        warn!("timeout already set, ignoring --timeout={}", timeout.as_secs());
+    }
+}
+
+/// Default timeout of every subprocess: set by `--timeout` or the OPS_TIMEOUT environment variable.
+pub fn timeout() -> Option<Duration> {
+    *TIMEOUT.get_or_init(|| {
+        std::env::var(OPS_TIMEOUT_ENV)
+            .ok()
+            .and_then(|timeout| timeout.parse::<u64>().ok())
+            .filter(|timeout| *timeout > 0)
+            .map(Duration::from_secs)
+    })
+}
+
+/// Options controlling how a single subprocess is run.
+#[derive(Clone, Debug)]
+pub struct ExecOptions {
+    /// kill the command if it has not exited after this long
+    pub timeout: Option<Duration>,
+    /// number of additional attempts made if the command fails or times out, only safe for idempotent commands
+    pub retries: u32,
+    /// delay before the first retry, doubled after every attempt
+    pub retry_delay: Duration,
+    /// decides from the output of a failed attempt whether it is worth retrying (e.g. only network errors rather
+    /// than authentication failures), every failure is retried if unset
+    pub retryable: Option<fn(&Output) -> bool>,
+    /// whether the command's output is forwarded to the terminal in addition to being captured
+    pub stream: bool,
+    /// written to the command's stdin (e.g. a password for `docker login --password-stdin`), stdin is closed
+    /// otherwise
+    pub stdin: Option<Vec<u8>>,
+    /// label prefixed to every forwarded line, defaults to the one set with [`with_output_prefix`] on the calling
+    /// thread
+    pub prefix: Option<String>,
+}
+
+impl Default for ExecOptions {
+    fn default() -> Self {
+        Self {
+            timeout: timeout(),
+            retries: 0,
+            retry_delay: Duration::from_secs(1),
+            retryable: None,
+            stream: true,
+            stdin: None,
+            prefix: OUTPUT_PREFIX.with(|prefix| prefix.borrow().clone()),
+        }
+    }
+}
+
+impl ExecOptions {
+    /// Options for idempotent commands: retried as configured by OPS_RETRIES and not streamed to the terminal.
+    pub fn idempotent() -> Self {
+        Self {
+            retries: std::env::var(OPS_RETRIES_ENV)
+                .ok()
+                .and_then(|retries| retries.parse::<u32>().ok())
+                .unwrap_or(DEFAULT_RETRIES),
+            stream: false,
+            ..Default::default()
+        }
+    }
+}
+
+/// Runs `f` with every line forwarded from the commands it runs on this thread prefixed with `label` (e.g. `api
+/// pre-build`), colored by label as docker compose does, so that the interleaved output of builds running side by
+/// side can be told apart.
+pub fn with_output_prefix<T>(label: &str, f: impl FnOnce() -> T) -> T {
+    let previous = OUTPUT_PREFIX.with(|prefix| prefix.replace(Some(label.to_string())));
+    let result = f();
+    OUTPUT_PREFIX.with(|prefix| *prefix.borrow_mut() = previous);
+    result
+}
+
+/// Runs `f` for every one of `items` on up to `workers` threads, each taking the next item once it finished its
+/// previous one, and returns the results in the order of `items`. `f` is passed the index of the worker running it
+/// (e.g. to give every worker a target directory of its own), the index of the item and the item. Once a result
+/// `is_failure` holds for is returned no further items are started and their results are `None`, items already
+/// started are finished.
+pub fn run_concurrently<T: Send, R: Send>(
+    items: Vec<T>,
+    workers: usize,
+    f: impl Fn(usize, usize, T) -> R + Sync,
+    is_failure: impl Fn(&R) -> bool + Sync,
+) -> Vec<Option<R>> {
+    let len = items.len();
+    let items = Mutex::new(items.into_iter().enumerate());
+    let failed = AtomicBool::new(false);
+    let finished = thread::scope(|scope| {
+        (0..workers.min(len).max(1))
+            .map(|worker| {
+                let (items, failed, f, is_failure) = (&items, &failed, &f, &is_failure);
+                scope.spawn(move || {
+                    let mut finished = vec![];
+                    while !failed.load(Ordering::SeqCst) {
+                        let Some((index, item)) = items.lock().unwrap().next() else {
+                            break;
+                        };
+                        let result = f(worker, index, item);
+                        if is_failure(&result) {
+                            failed.store(true, Ordering::SeqCst);
+                        }
+                        finished.push((index, result));
+                    }
+                    finished
+                })
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap_or_else(|panic| panic::resume_unwind(panic)))
+            .collect::<Vec<_>>()
+    });
+    let mut results = (0..len).map(|_| None).collect::<Vec<_>>();
+    for (index, result) in finished {
+        results[index] = Some(result);
+    }
+    results
+}
+
+/// Runs `future` to completion on the shared runtime, cancelling it if Ctrl-C is received.
+pub fn block_on<T>(future: impl Future<Output = Result<T, Error>>) -> Result<T, Error> {
+    RUNTIME.block_on(async {
+        tokio::select! {
+            result = future => result,
+            _ = tokio::signal::ctrl_c() => Err(Error::Cancelled),
+        }
+    })
+}
+
+/// Runs a side-effecting command and returns its output, or records it and returns `None` in dry-run mode.
+pub fn output(command: &mut Command) -> Result<Option<Output>, Error> {
+    if is_dry_run() {
+        record_command(command);
+        return Ok(None);
+    }
+    // the options are created on the calling thread to pick up its output prefix
+    block_on(output_async_with(command, &ExecOptions::default())).map(Some)
+}
+
+/// Runs a command with `options`, retrying on failure, and returns its output if it succeeded. Dry-run mode
+/// is not handled here so this should only be used directly for read-only commands.
+pub fn run(command: &Command, options: &ExecOptions) -> Result<Output, Error> {
+    block_on(run_async(command, options))
+}
+
+/// Side-effecting counterpart of [`run`]: records the command and returns `None` in dry-run mode.
+pub fn run_or_record(command: &Command, options: &ExecOptions) -> Result<Option<Output>, Error> {
+    if is_dry_run() {
+        record_command(command);
+        return Ok(None);
+    }
+    run(command, options).map(Some)
+}
+
+/// Runs a read-only command (e.g. a git query) with retries, returning its trimmed stdout. Read-only commands
+/// run even in dry-run mode as their results are needed to compute the plan.
+pub fn read(command: &Command) -> Result<String, Error> {
+    let output = run(command, &ExecOptions::idempotent())?;
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Async counterpart of [`run`].
+pub async fn run_async(command: &Command, options: &ExecOptions) -> Result<Output, Error> {
+    let mut retry_delay = options.retry_delay;
+    let mut attempt = 0;
+    loop {
+        let result = match output_async_with(command, options).await {
+            Ok(output) if output.status.success() => return Ok(output),
+            Ok(output) if options.retryable.is_some_and(|retryable| !retryable(&output)) => {
+                return Err(Error::CommandFailed {
+                    command: display_command(command),
+                    status: output.status.code(),
+                    stderr: stderr_tail(&output.stderr),
+                })
+            }
+            Ok(output) => Err(Error::CommandFailed {
+                command: display_command(command),
+                status: output.status.code(),
+                stderr: stderr_tail(&output.stderr),
+            }),
+            Err(err @ Error::Timeout { .. }) => Err(err),
+            Err(err) => return Err(err),
+        };
+        if attempt >= options.retries {
+            return result;
+        }
+        attempt += 1;
+        if let Err(err) = result {
+            warn!("{err}, retrying ({attempt}/{})", options.retries);
+        }
+        tokio::time::sleep(retry_delay).await;
+        retry_delay *= 2;
+    }
+}
+
+/// Runs a read-only command with `options` and returns its output whatever its exit status, e.g. for tools which
+/// report their findings on stdout and signal them through their exit status. Dry-run mode is not handled here.
+pub fn output_with(command: &Command, options: &ExecOptions) -> Result<Output, Error> {
+    block_on(output_async_with(command, options))
+}
+
+/// Async counterpart of [`output`] for running several commands concurrently, note that dry-run mode is not
+/// handled here.
+pub async fn output_async(command: &Command) -> Result<Output, Error> {
+    output_async_with(command, &ExecOptions::default()).await
+}
+
+async fn output_async_with(command: &Command, options: &ExecOptions) -> Result<Output, Error> {
+    let _permit = JOB_SLOTS.acquire().await.map_err(|_| Error::Cancelled)?;
+
+    let program = command.get_program().to_string_lossy().to_string();
+    let mut command = clone_command(command);
+    // output is piped so tools which detect a terminal (e.g. cargo) need to be told whether to use colors
+    if std::env::var_os("CARGO_TERM_COLOR").is_none() {
+        command.env("CARGO_TERM_COLOR", if use_color() { "always" } else { "never" });
+    }
+    if !use_color() {
+        command.env("NO_COLOR", "1");
+    }
+
+    let mut child = tokio::process::Command::from(command)
+        .stdin(match options.stdin {
+            Some(_) => Stdio::piped(),
+            None => Stdio::null(),
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|err| match err.kind() {
+            std::io::ErrorKind::NotFound => Error::ToolUnavailable {
+                program: program.clone(),
+                message: "not found, is it installed and on the PATH?".to_string(),
+            },
+            _ => Error::Io(err),
+        })?;
+
+    if let (Some(input), Some(mut stdin)) = (options.stdin.as_ref(), child.stdin.take()) {
+        stdin.write_all(input).await?;
+    }
+
+    // in quiet mode output is only shown if the command fails, stdout is reserved for the result in json mode
+    let forward = options.stream && !is_quiet();
+    let json = output_format() == ReportFormat::Json;
+    let prefix = options.prefix.as_deref().map(|label| {
+        let color = PREFIX_COLORS[label.bytes().map(usize::from).sum::<usize>() % PREFIX_COLORS.len()];
+        format!("{} ", format!("{label} |").color(color))
+    });
+    let prefix = prefix.as_deref().unwrap_or_default();
+    let stdout = stream(child.stdout.take().unwrap(), |line| match forward {
+        true if json => eprintln!("{prefix}{line}"),
+        true => println!("{prefix}{line}"),
+        false => {}
+    });
+    let stderr = stream(child.stderr.take().unwrap(), |line| {
+        if forward {
+            eprintln!("{prefix}{line}")
+        }
+    });
+    let wait = async { tokio::try_join!(stdout, stderr, child.wait()) };
+
+    let (stdout, stderr, status) = match options.timeout {
+        Some(timeout) => tokio::time::timeout(timeout, wait).await.map_err(|_| {
+            debug!("killing `{program}` after {}s", timeout.as_secs());
+            Error::Timeout { program, timeout }
+        })??,
+        None => wait.await?,
+    };
+    if options.stream && is_quiet() && !status.success() {
+        eprint!("{}", String::from_utf8_lossy(&stderr));
+    }
+
+    Ok(Output { status, stdout, stderr })
+}
+
+fn display_command(command: &Command) -> String {
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| arg.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn stderr_tail(stderr: &[u8]) -> String {
+    let stderr = String::from_utf8_lossy(stderr);
+    let lines = stderr.trim().lines().collect::<Vec<_>>();
+    lines[lines.len().saturating_sub(STDERR_TAIL_LINES)..].join("\n")
+}
+
+/// Forwards each line read from `reader` to `forward` while capturing everything read.
+async fn stream(reader: impl AsyncRead + Unpin, forward: impl Fn(&str)) -> Result<Vec<u8>, std::io::Error> {
+    let mut captured = vec![];
+    let mut reader = BufReader::new(reader);
+    let mut line = vec![];
+    while reader.read_until(b'\n', &mut line).await? > 0 {
+        forward(String::from_utf8_lossy(&line).trim_end_matches(['\n', '\r']));
+        captured.append(&mut line);
+    }
+    Ok(captured)
+}
+
+fn clone_command(command: &Command) -> Command {
+    let mut clone = Command::new(command.get_program());
+    clone.args(command.get_args());
+    for (key, value) in command.get_envs() {
+        match value {
+            Some(value) => clone.env(key, value),
+            None => clone.env_remove(key),
+        };
+    }
+    if let Some(current_dir) = command.get_current_dir() {
+        clone.current_dir(current_dir);
+    }
+    clone
+}
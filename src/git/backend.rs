@@ -0,0 +1,230 @@
+/// Queries of the repository's history used to find what changed on the current branch, implemented in-process
+/// with libgit2 or by running the git cli. libgit2 avoids spawning a subprocess per query, which dominates the
+/// startup of the workspace commands on repositories with long histories.
+use crate::error::Error;
+use crate::exec::{read, run, ExecOptions};
+use git2::{BranchType, Delta, DiffFindOptions, DiffOptions, ErrorCode, Oid, Repository};
+use std::env;
+use std::fmt::Write;
+use std::process::Command;
+use tracing::debug;
+
+/// environment variable selecting the git backend: `libgit2` (default) or `cli`
+pub const OPS_GIT_BACKEND_ENV: &str = "OPS_GIT_BACKEND";
+
+pub trait GitBackend {
+    /// Short name of the checked out branch, none if HEAD is detached.
+    fn current_branch(&self) -> Result<Option<String>, Error>;
+
+    /// Id of the commit `rev` resolves to, none if it does not resolve.
+    fn resolve_commit(&self, rev: &str) -> Result<Option<String>, Error>;
+
+    /// Whether `ancestor` is `descendant` or one of its ancestors, like `git merge-base --is-ancestor`.
+    fn is_ancestor(&self, ancestor: &str, descendant: &str) -> Result<bool, Error>;
+
+    /// Best common ancestor of `a` and `b`, none if their histories are unrelated.
+    fn merge_base(&self, a: &str, b: &str) -> Result<Option<String>, Error>;
+
+    /// Full ref names (`refs/heads/..`) of every local branch.
+    fn local_branches(&self) -> Result<Vec<String>, Error>;
+
+    /// Commits of the first-parent history of `rev`, newest first, excluding those reachable from any of `hide`.
+    fn first_parent_history(&self, rev: &str, hide: &[String]) -> Result<Vec<String>, Error>;
+
+    /// Changes of the working tree (including staged changes) relative to `base`, in the format of
+    /// `git diff --name-status <base>`. Renames may be reported as a deletion and an addition.
+    fn diff_name_status(&self, base: &str) -> Result<String, Error>;
+}
+
+/// Backend selected by OPS_GIT_BACKEND for the repository containing the current working directory.
+pub fn git_backend() -> Result<Box<dyn GitBackend>, Error> {
+    match env::var(OPS_GIT_BACKEND_ENV).as_deref() {
+        Ok("cli") => Ok(Box::new(CliBackend)),
+        Ok("libgit2") | Ok("") | Err(_) => Ok(Box::new(Libgit2Backend::open_from_current_dir()?)),
+        Ok(other) => Err(Error::InvalidInput(format!(
+            "unsupported {OPS_GIT_BACKEND_ENV} `{other}`, expected `libgit2` or `cli`"
+        ))),
+    }
+}
+
+/// In-process backend.
+pub struct Libgit2Backend {
+    repo: Repository,
+}
+
+impl Libgit2Backend {
+    pub fn open_from_current_dir() -> Result<Self, Error> {
+        let repo = Repository::discover(env::current_dir()?).map_err(git_error)?;
+        debug!("opened git repository {}", repo.path().display());
+        Ok(Self { repo })
+    }
+
+    fn oid(&self, rev: &str) -> Result<Oid, Error> {
+        let object = self.repo.revparse_single(rev).map_err(git_error)?;
+        Ok(object.peel_to_commit().map_err(git_error)?.id())
+    }
+}
+
+impl GitBackend for Libgit2Backend {
+    fn current_branch(&self) -> Result<Option<String>, Error> {
+        if self.repo.head_detached().map_err(git_error)? {
+            return Ok(None);
+        }
+        let head = match self.repo.head() {
+            Ok(head) => head,
+            Err(err) if err.code() == ErrorCode::UnbornBranch => return Ok(None),
+            Err(err) => return Err(git_error(err)),
+        };
+        Ok(head.shorthand().map(String::from))
+    }
+
+    fn resolve_commit(&self, rev: &str) -> Result<Option<String>, Error> {
+        match self.oid(rev) {
+            Ok(oid) => Ok(Some(oid.to_string())),
+            Err(_) => Ok(None),
+        }
+    }
+
+    fn is_ancestor(&self, ancestor: &str, descendant: &str) -> Result<bool, Error> {
+        let ancestor = self.oid(ancestor)?;
+        let descendant = self.oid(descendant)?;
+        Ok(ancestor == descendant || self.repo.graph_descendant_of(descendant, ancestor).map_err(git_error)?)
+    }
+
+    fn merge_base(&self, a: &str, b: &str) -> Result<Option<String>, Error> {
+        match self.repo.merge_base(self.oid(a)?, self.oid(b)?) {
+            Ok(oid) => Ok(Some(oid.to_string())),
+            Err(err) if err.code() == ErrorCode::NotFound => Ok(None),
+            Err(err) => Err(git_error(err)),
+        }
+    }
+
+    fn local_branches(&self) -> Result<Vec<String>, Error> {
+        let mut branches = vec![];
+        for branch in self.repo.branches(Some(BranchType::Local)).map_err(git_error)? {
+            let (branch, _) = branch.map_err(git_error)?;
+            if let Some(name) = branch.get().name() {
+                branches.push(name.to_string());
+            }
+        }
+        Ok(branches)
+    }
+
+    fn first_parent_history(&self, rev: &str, hide: &[String]) -> Result<Vec<String>, Error> {
+        let mut revwalk = self.repo.revwalk().map_err(git_error)?;
+        revwalk.simplify_first_parent().map_err(git_error)?;
+        revwalk.push(self.oid(rev)?).map_err(git_error)?;
+        for hidden in hide {
+            revwalk.hide(self.oid(hidden)?).map_err(git_error)?;
+        }
+        revwalk
+            .map(|oid| oid.map(|oid| oid.to_string()).map_err(git_error))
+            .collect()
+    }
+
+    fn diff_name_status(&self, base: &str) -> Result<String, Error> {
+        let base_tree = self
+            .repo
+            .find_commit(self.oid(base)?)
+            .and_then(|commit| commit.tree())
+            .map_err(git_error)?;
+        let mut diff = self
+            .repo
+            .diff_tree_to_workdir_with_index(Some(&base_tree), Some(&mut DiffOptions::new()))
+            .map_err(git_error)?;
+        // git diff detects renames by default, only exact ones are detected as inexact detection reads the contents
+        // of every added file while a rename is handled the same as a deletion and an addition by every caller
+        diff.find_similar(Some(DiffFindOptions::new().renames(true).exact_match_only(true)))
+            .map_err(git_error)?;
+
+        let mut text = String::new();
+        for delta in diff.deltas() {
+            let old = delta.old_file().path().map(|path| path.to_string_lossy());
+            let new = delta.new_file().path().map(|path| path.to_string_lossy());
+            let (Some(old), Some(new)) = (old, new) else {
+                continue;
+            };
+            let _ = match delta.status() {
+                Delta::Added => writeln!(text, "A\t{new}"),
+                Delta::Deleted => writeln!(text, "D\t{old}"),
+                Delta::Modified => writeln!(text, "M\t{new}"),
+                Delta::Typechange => writeln!(text, "T\t{new}"),
+                Delta::Renamed => writeln!(text, "R100\t{old}\t{new}"),
+                _ => continue,
+            };
+        }
+        Ok(text.trim().to_string())
+    }
+}
+
+/// Backend running the git cli, for repositories using features libgit2 does not support.
+pub struct CliBackend;
+
+impl GitBackend for CliBackend {
+    fn current_branch(&self) -> Result<Option<String>, Error> {
+        // plumbing rather than parsing `git branch`, whose output is colored or localized depending on the user's
+        // config
+        if !succeeds(Command::new("git").args(["symbolic-ref", "--quiet", "HEAD"]))? {
+            return Ok(None);
+        }
+        read(Command::new("git").args(["symbolic-ref", "--short", "HEAD"])).map(Some)
+    }
+
+    fn resolve_commit(&self, rev: &str) -> Result<Option<String>, Error> {
+        let rev = format!("{rev}^{{commit}}");
+        if !succeeds(Command::new("git").args(["rev-parse", "--verify", "--quiet", &rev]))? {
+            return Ok(None);
+        }
+        read(Command::new("git").args(["rev-parse", &rev])).map(Some)
+    }
+
+    fn is_ancestor(&self, ancestor: &str, descendant: &str) -> Result<bool, Error> {
+        succeeds(Command::new("git").args(["merge-base", "--is-ancestor", ancestor, descendant]))
+    }
+
+    fn merge_base(&self, a: &str, b: &str) -> Result<Option<String>, Error> {
+        // exits unsuccessfully without output if there is no merge base
+        if !succeeds(Command::new("git").args(["merge-base", a, b]))? {
+            return Ok(None);
+        }
+        read(Command::new("git").args(["merge-base", a, b])).map(Some)
+    }
+
+    fn local_branches(&self) -> Result<Vec<String>, Error> {
+        let branches = read(Command::new("git").args(["for-each-ref", "--format=%(refname)", "refs/heads"]))?;
+        Ok(branches.lines().map(String::from).collect())
+    }
+
+    fn first_parent_history(&self, rev: &str, hide: &[String]) -> Result<Vec<String>, Error> {
+        let mut command = Command::new("git");
+        command.args(["rev-list", "--first-parent", rev]);
+        if !hide.is_empty() {
+            command.arg("--not").args(hide);
+        }
+        Ok(read(&command)?.lines().map(String::from).collect())
+    }
+
+    fn diff_name_status(&self, base: &str) -> Result<String, Error> {
+        read(Command::new("git").args(["diff", "--name-status", base]))
+    }
+}
+
+/// Runs a git check whose exit status is its answer (e.g. `merge-base --is-ancestor`), so an unsuccessful exit
+/// is not retried or treated as an error.
+fn succeeds(command: &Command) -> Result<bool, Error> {
+    match run(
+        command,
+        &ExecOptions {
+            stream: false,
+            ..Default::default()
+        },
+    ) {
+        Ok(_) => Ok(true),
+        Err(Error::CommandFailed { .. }) => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+fn git_error(err: git2::Error) -> Error {
+    Error::GitResolution(err.message().to_string())
+}
@@ -1,184 +1,454 @@
 /// Analyzes the current git diff and only performs clippy on the minimal number of changed packages
-use anyhow::Error;
-use std::io::{BufRead, BufReader};
-use std::process::{Command, Stdio};
-
-const REMOTE: &str = "origin";
-
-pub fn git_diff_name_status_since_last_branch() -> Result<String, Error> {
-    let mut child = Command::new("git").arg("branch").stdout(Stdio::piped()).spawn()?;
-    let output = Command::new("grep")
-        .arg("*")
-        .stdin(child.stdout.take().unwrap())
-        .output()?;
-    let branch = String::from_utf8_lossy(&output.stdout);
-    let branch = &branch.trim()[2..];
-
-    let mut remote_branch = None;
-
-    if branch.len() > REMOTE.len()
-        && &branch[..REMOTE.len()] == REMOTE
-        && &branch[REMOTE.len()..REMOTE.len() + 1] == "/"
-    {
-        remote_branch = Some(branch.to_string());
-    } else {
-        let output = Command::new("git")
-            .args(["rev-list", "--first-parent", &format!("{REMOTE}/{branch}")])
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .output()?;
-        if output.status.success() {
-            remote_branch = Some(format!("{REMOTE}/{branch}"));
+use crate::error::Error;
+use crate::git::backend::{git_backend, GitBackend};
+use crate::timing::{time, Phase};
+use clap::Args;
+use std::borrow::Cow;
+use std::collections::HashSet;
+
+const DEFAULT_REMOTE: &str = "origin";
+
+// base which incremental commands compute the changed files against.
+#[derive(Clone, Debug, Default, Args)]
+pub struct DiffBaseArgs {
+    /// ref to compute changes against (e.g. the target branch of a pull request, `origin/main`), changes are taken
+    /// since the merge base of the ref and HEAD -- defaults to the commit the current branch was branched from
+    #[clap(long, env = "OPS_BASE_REF")]
+    pub base: Option<String>,
+
+    /// remote whose counterpart of the current branch is used as the base when --base is not passed, defaults to
+    /// origin
+    #[clap(long, env = "OPS_REMOTE")]
+    pub remote: Option<String>,
+}
+
+/// Diff of the working tree against `--base` if passed, otherwise against the commit the current branch was
+/// branched from: the head of the branch's remote counterpart if HEAD contains it, otherwise the most recent commit
+/// which another local branch contains. Only git itself is run (no shell utilities) so that this also works on
+/// Windows.
+pub fn git_diff_name_status_since_last_branch(diff_base: &DiffBaseArgs) -> Result<String, Error> {
+    let label = match diff_base.base.as_deref() {
+        Some(base) => format!("changes since {base}"),
+        None => "changes since the branch diverged".to_string(),
+    };
+    time(Phase::GitDiff, label, || diff_name_status_since_base(diff_base))
+}
+
+fn diff_name_status_since_base(diff_base: &DiffBaseArgs) -> Result<String, Error> {
+    let DiffBaseArgs { base, remote } = diff_base;
+    let remote = remote.as_deref().unwrap_or(DEFAULT_REMOTE);
+
+    let git = git_backend()?;
+
+    if let Some(base) = base {
+        if git.resolve_commit(base)?.is_none() {
+            return Err(Error::GitResolution(format!("unable to resolve base ref `{base}`")));
         }
+        let base_commit = git
+            .merge_base(base, "HEAD")?
+            .ok_or_else(|| Error::GitResolution(format!("HEAD has no common history with base ref `{base}`")))?;
+        return diff_name_status(git.as_ref(), &base_commit);
     }
 
+    let branch = git
+        .current_branch()?
+        .ok_or_else(|| Error::GitResolution("unable to determine the current branch, HEAD is detached".to_string()))?;
+
+    let remote_branch = match branch.strip_prefix(remote).is_some_and(|rest| rest.starts_with('/')) {
+        true => branch.clone(),
+        false => format!("refs/remotes/{remote}/{branch}"),
+    };
+
     let mut base_commit = None;
 
-    if let Some(remote_branch) = remote_branch.as_ref() {
-        let output = Command::new("git")
-            .args(["rev-parse", &format!("{remote_branch}~0")])
-            .output()?;
-        let remote_branch_head = String::from_utf8_lossy(&output.stdout).trim().to_string();
-        let output = Command::new("git")
-            .args(["merge-base", "--is-ancestor", &remote_branch_head, "HEAD"])
-            .stdout(Stdio::inherit())
-            .stderr(Stdio::inherit())
-            .output()?;
-        if output.status.success() {
+    if let Some(remote_branch_head) = git.resolve_commit(&remote_branch)? {
+        if git.is_ancestor(&remote_branch_head, "HEAD")? {
             base_commit = Some(remote_branch_head);
         }
     }
 
+    // the base is the most recent commit on the branch's first-parent history which another local branch contains
     if base_commit.is_none() {
-        let cur_branch = format!("* {branch}");
-
-        let mut rev_list = Command::new("git")
-            .args(["rev-list", "--first-parent", branch])
-            .stdout(Stdio::piped())
-            .spawn()?;
-
-        let mut branch_contains = Command::new("xargs")
-            .args([
-                "-n1",
-                "-I",
-                "{}",
-                "sh",
-                "-c",
-                "git branch --contains {} && echo 'COMMIT: {}'",
-            ])
-            .stdin(rev_list.stdout.take().unwrap())
-            .stdout(Stdio::piped())
-            .spawn()?;
-
-        let mut branch_contains_lines = BufReader::new(branch_contains.stdout.take().unwrap()).lines();
-
-        for line in branch_contains_lines.by_ref().flatten() {
-            let line = line.trim();
-            if line.len() >= 8 && &line[..8] == "COMMIT: " {
-                continue;
-            }
-            if line != cur_branch {
-                break;
-            }
-        }
-
-        rev_list.kill().ok();
-        branch_contains.kill().ok();
-
-        for line in branch_contains_lines.by_ref().flatten() {
-            if line.len() >= 8 && &line[..8] == "COMMIT: " {
-                base_commit = Some(line[8..].to_string());
-            }
+        let branch_ref = format!("refs/heads/{branch}");
+        let other_branches = git
+            .local_branches()?
+            .into_iter()
+            .filter(|other| *other != branch_ref)
+            .collect::<Vec<_>>();
+        if !other_branches.is_empty() {
+            let unique_commits = git
+                .first_parent_history(&branch, &other_branches)?
+                .into_iter()
+                .collect::<HashSet<_>>();
+            base_commit = git
+                .first_parent_history(&branch, &[])?
+                .into_iter()
+                .find(|commit| !unique_commits.contains(commit));
         }
     }
 
-    let base_commit = base_commit.ok_or_else(|| Error::msg("unable to find base commit for pre-receive hook"))?;
+    let base_commit = base_commit.ok_or_else(|| {
+        Error::GitResolution("unable to find the commit the current branch was branched from, pass --base".to_string())
+    })?;
 
-    let output = Command::new("git")
-        .args(["diff", "--name-status", &base_commit])
-        .output()?;
+    diff_name_status(git.as_ref(), &base_commit)
+}
 
-    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+/// Diff of the working tree against `base_commit`, failures of git itself are reported as failed diffs.
+fn diff_name_status(git: &dyn GitBackend, base_commit: &str) -> Result<String, Error> {
+    git.diff_name_status(base_commit).map_err(|err| match err {
+        Error::GitResolution(message) => Error::GitDiffFailed {
+            base: base_commit.to_string(),
+            message,
+        },
+        err @ Error::CommandFailed { .. } => Error::GitDiffFailed {
+            base: base_commit.to_string(),
+            message: err.to_string(),
+        },
+        err => err,
+    })
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub enum GitStatus<'a> {
-    Added { file: &'a str },
-    Deleted { file: &'a str },
-    FileTypeChanged { file: &'a str },
-    Modified { file: &'a str },
-    Renamed { old: &'a str, new: &'a str },
+    Added {
+        file: Cow<'a, str>,
+    },
+    /// `new` was created as a copy of `old`, which is unchanged
+    Copied {
+        old: Cow<'a, str>,
+        new: Cow<'a, str>,
+    },
+    Deleted {
+        file: Cow<'a, str>,
+    },
+    FileTypeChanged {
+        file: Cow<'a, str>,
+    },
+    Modified {
+        file: Cow<'a, str>,
+    },
+    Renamed {
+        old: Cow<'a, str>,
+        new: Cow<'a, str>,
+    },
+    /// the file has unresolved merge conflicts
+    Unmerged {
+        file: Cow<'a, str>,
+    },
 }
 
 impl GitStatus<'_> {
+    /// Name of the file after the change, none if it was removed.
     pub fn new_file_name(&self) -> Option<&str> {
         match self {
             Self::Added { file } => Some(file),
+            Self::Copied { new, .. } => Some(new),
             Self::Deleted { .. } => None,
             Self::FileTypeChanged { file } => Some(file),
             Self::Modified { file } => Some(file),
             Self::Renamed { new, .. } => Some(new),
+            Self::Unmerged { file } => Some(file),
         }
     }
 
+    /// Name of the file before the change, none if no existing file was changed or removed.
     pub fn old_file_name(&self) -> Option<&str> {
         match self {
             Self::Added { .. } => None,
+            Self::Copied { .. } => None,
             Self::Deleted { file } => Some(file),
             Self::FileTypeChanged { file } => Some(file),
             Self::Modified { file } => Some(file),
             Self::Renamed { old, .. } => Some(old),
+            Self::Unmerged { file } => Some(file),
         }
     }
 }
 
+/// Parses the output of `git diff --name-status` (`R100\told\tnew`) or `git status --porcelain` (`RM old -> new`),
+/// whose two-letter codes hold the staged and unstaged status of each file. Untracked files are reported as added,
+/// ignored files are skipped. Paths git quotes because of special characters are unescaped.
 pub fn parse_git_statuses(text: &str) -> Result<Vec<GitStatus<'_>>, Error> {
-    let lines = text.trim().split('\n');
-
     let mut git_statuses = vec![];
-    for line in lines.into_iter().filter(|x| x.len() >= 3) {
-        if let Some(status) = line.split_whitespace().next() {
-            if status == "A" {
-                git_statuses.push(GitStatus::Added { file: line[2..].trim() });
-            } else if status == "D" {
-                git_statuses.push(GitStatus::Deleted { file: line[2..].trim() });
-            } else if status == "M" || status == "MM" || status == "AM" {
-                git_statuses.push(GitStatus::Modified { file: line[2..].trim() });
-            } else if &status[0..1] == "R" {
-                let mut matched = false;
-                for (i, window) in <str as AsRef<[u8]>>::as_ref(line[2..].trim()).windows(4).enumerate() {
-                    let indices = if window == b" -> " {
-                        Some((2, i + 3, i + 7))
-                    } else if &window[0..1] == b"	" {
-                        Some((2, i + 3, i + 4))
-                    } else {
-                        None
-                    };
-                    let (start1, end1, start2) = match indices {
-                        Some(indices) => indices,
-                        None => continue,
-                    };
-                    let old = line[start1..end1].trim();
-                    let new = line[start2..].trim();
-                    if old == new {
-                        git_statuses.push(GitStatus::Modified { file: new });
-                    } else {
-                        git_statuses.push(GitStatus::Renamed { old, new });
-                    }
-                    matched = true;
-                    break;
-                }
-                if !matched {
-                    println!("{status} {}", &status[0..1]);
-                    return Err(Error::msg(format!("unsupported git status: {line}")));
+    for line in text.lines().map(|line| line.trim_end_matches('\r')) {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let unsupported = || Error::GitResolution(format!("unsupported git status: {line}"));
+
+        let (codes, paths) = match line.split_once('\t') {
+            // name-status: a single status letter, renames and copies are followed by their similarity score and
+            // both paths
+            Some((status, paths)) => {
+                let code = status.chars().next().ok_or_else(unsupported)?;
+                let paths = paths.split('\t').collect::<Vec<_>>();
+                if paths.len() != 1 + matches!(code, 'R' | 'C') as usize {
+                    return Err(unsupported());
                 }
-            } else if status == "T" {
-                git_statuses.push(GitStatus::FileTypeChanged { file: line[2..].trim() });
-            } else {
-                return Err(Error::msg(format!("unsupported git status: {line}")));
+                ([code, ' '], paths)
             }
-        }
+            // porcelain: staged and unstaged status letters followed by a space
+            None => {
+                let mut chars = line.chars();
+                let (Some(staged), Some(unstaged), Some(' ')) = (chars.next(), chars.next(), chars.next()) else {
+                    return Err(unsupported());
+                };
+                ([staged, unstaged], split_porcelain_paths(chars.as_str()))
+            }
+        };
+        let paths = paths
+            .into_iter()
+            .map(|path| unquote(path).ok_or_else(unsupported))
+            .collect::<Result<Vec<_>, _>>()?;
+        let (old, new) = match <[_; 2]>::try_from(paths) {
+            Ok([old, new]) => (old, new),
+            Err(mut paths) if paths.len() == 1 => {
+                let file = paths.remove(0);
+                (file.clone(), file)
+            }
+            Err(_) => return Err(unsupported()),
+        };
+
+        let has = |code: char| codes.contains(&code);
+        let git_status = match codes {
+            ['!', '!'] => continue,
+            ['?', '?'] => GitStatus::Added { file: new },
+            // both sides added or deleted a file, or either side has an unmerged (U) status
+            ['A', 'A'] | ['D', 'D'] => GitStatus::Unmerged { file: new },
+            _ if has('U') => GitStatus::Unmerged { file: new },
+            _ if has('R') && old == new => GitStatus::Modified { file: new },
+            _ if has('R') => GitStatus::Renamed { old, new },
+            _ if has('C') => GitStatus::Copied { old, new },
+            _ if has('D') => GitStatus::Deleted { file: old },
+            _ if has('A') => GitStatus::Added { file: new },
+            _ if has('T') => GitStatus::FileTypeChanged { file: new },
+            _ if has('M') => GitStatus::Modified { file: new },
+            _ => return Err(unsupported()),
+        };
+        git_statuses.push(git_status);
     }
 
     Ok(git_statuses)
 }
+
+/// Splits the `old -> new` paths of a porcelain rename or copy, quoted paths may contain ` -> ` themselves.
+fn split_porcelain_paths(paths: &str) -> Vec<&str> {
+    let old_end = match paths.starts_with('"') {
+        true => {
+            let mut escaped = false;
+            paths.char_indices().skip(1).find_map(|(index, char)| {
+                let end = (!escaped && char == '"').then_some(index + 1);
+                escaped = !escaped && char == '\\';
+                end
+            })
+        }
+        false => paths.find(" -> "),
+    };
+    match old_end.and_then(|old_end| Some((&paths[..old_end], paths[old_end..].strip_prefix(" -> ")?))) {
+        Some((old, new)) => vec![old, new],
+        None => vec![paths],
+    }
+}
+
+/// Path of a git status line, unescaping the C-style escapes of paths git quotes (e.g. `"caf\303\251.rs"`). None if
+/// the quoting is malformed.
+fn unquote(path: &str) -> Option<Cow<'_, str>> {
+    let Some(quoted) = path.strip_prefix('"') else {
+        return Some(Cow::Borrowed(path));
+    };
+    let quoted = quoted.strip_suffix('"')?;
+    let mut bytes = vec![];
+    let mut chars = quoted.chars();
+    while let Some(char) = chars.next() {
+        if char != '\\' {
+            bytes.extend_from_slice(char.encode_utf8(&mut [0; 4]).as_bytes());
+            continue;
+        }
+        let byte = match chars.next()? {
+            'a' => 0x07,
+            'b' => 0x08,
+            'f' => 0x0c,
+            'n' => b'\n',
+            'r' => b'\r',
+            't' => b'\t',
+            'v' => 0x0b,
+            // bytes of non-ascii characters are escaped as three octal digits
+            digit @ '0'..='3' => {
+                let octal = [Some(digit), chars.next(), chars.next()]
+                    .into_iter()
+                    .collect::<Option<String>>()?;
+                u8::from_str_radix(&octal, 8).ok()?
+            }
+            char @ ('"' | '\\') => char as u8,
+            _ => return None,
+        };
+        bytes.push(byte);
+    }
+    String::from_utf8(bytes).ok().map(Cow::Owned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAME_STATUS: &str = include_str!("../../tests/fixtures/git/name_status.txt");
+    const PORCELAIN: &str = include_str!("../../tests/fixtures/git/porcelain.txt");
+
+    fn file(file: &str) -> Cow<'_, str> {
+        Cow::Borrowed(file)
+    }
+
+    #[test]
+    fn parses_name_status() {
+        assert_eq!(
+            parse_git_statuses(NAME_STATUS).unwrap(),
+            vec![
+                GitStatus::Modified {
+                    file: file("src/lib.rs")
+                },
+                GitStatus::Added {
+                    file: file("src/new.rs")
+                },
+                GitStatus::Deleted {
+                    file: file("src/old.rs")
+                },
+                GitStatus::FileTypeChanged {
+                    file: file("scripts/run")
+                },
+                GitStatus::Renamed {
+                    old: file("crates/a/src/old.rs"),
+                    new: file("crates/a/src/new.rs"),
+                },
+                GitStatus::Copied {
+                    old: file("crates/a/src/lib.rs"),
+                    new: file("crates/b/src/lib.rs"),
+                },
+                GitStatus::Unmerged {
+                    file: file("Cargo.lock")
+                },
+                GitStatus::Modified {
+                    file: file("crates/a/café.rs")
+                },
+                GitStatus::Added {
+                    file: file("crates/a/tab\there.rs")
+                },
+                GitStatus::Renamed {
+                    old: file("crates/a/quo\"te.rs"),
+                    new: file("crates/a/back\\slash.rs"),
+                },
+            ],
+        );
+    }
+
+    #[test]
+    fn parses_porcelain() {
+        assert_eq!(
+            parse_git_statuses(PORCELAIN).unwrap(),
+            vec![
+                GitStatus::Modified {
+                    file: file("src/lib.rs")
+                },
+                GitStatus::Modified {
+                    file: file("src/main.rs")
+                },
+                GitStatus::Modified {
+                    file: file("src/both.rs")
+                },
+                GitStatus::Added {
+                    file: file("src/new.rs")
+                },
+                GitStatus::Deleted {
+                    file: file("src/removed.rs")
+                },
+                GitStatus::FileTypeChanged {
+                    file: file("scripts/run")
+                },
+                GitStatus::Renamed {
+                    old: file("crates/a/old.rs"),
+                    new: file("crates/a/new.rs"),
+                },
+                GitStatus::Renamed {
+                    old: file("crates/a/x -> y.rs"),
+                    new: file("crates/a/z.rs"),
+                },
+                GitStatus::Copied {
+                    old: file("crates/a/lib.rs"),
+                    new: file("crates/b/lib.rs"),
+                },
+                GitStatus::Unmerged {
+                    file: file("Cargo.lock")
+                },
+                GitStatus::Unmerged {
+                    file: file("src/added_twice.rs")
+                },
+                GitStatus::Unmerged {
+                    file: file("src/deleted_twice.rs")
+                },
+                GitStatus::Added {
+                    file: file("notes.txt")
+                },
+                GitStatus::Added {
+                    file: file("café.txt")
+                },
+            ],
+        );
+    }
+
+    #[test]
+    fn parses_crlf_line_endings() {
+        assert_eq!(
+            parse_git_statuses(&NAME_STATUS.replace('\n', "\r\n")).unwrap(),
+            parse_git_statuses(NAME_STATUS).unwrap(),
+        );
+        assert_eq!(
+            parse_git_statuses(&PORCELAIN.replace('\n', "\r\n")).unwrap(),
+            parse_git_statuses(PORCELAIN).unwrap(),
+        );
+    }
+
+    #[test]
+    fn skips_blank_lines() {
+        assert_eq!(parse_git_statuses("\n  \r\n").unwrap(), vec![]);
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        for line in [
+            "M",
+            "MM",
+            "MMsrc/lib.rs",
+            "\tsrc/lib.rs",
+            "X\tsrc/lib.rs",
+            "XY src/lib.rs",
+            "M\tsrc/lib.rs\tsrc/main.rs",
+            "R100\tsrc/old.rs",
+            "C075\tsrc/a.rs\tsrc/b.rs\tsrc/c.rs",
+            "M  \"src/unterminated.rs",
+            "M  \"src/unknown\\qescape.rs\"",
+            "M  \"src/trailing\\\"",
+            "M  \"src/truncated\\30\"",
+            "M  \"src/invalid\\303.rs\"",
+        ] {
+            assert!(parse_git_statuses(line).is_err(), "`{line}` was parsed");
+        }
+    }
+
+    #[test]
+    fn file_names() {
+        let [copied, renamed, deleted] = ["C  src/a.rs -> src/b.rs", "R  src/a.rs -> src/b.rs", "D  src/a.rs"]
+            .map(|line| parse_git_statuses(line).unwrap().remove(0));
+        assert_eq!(
+            (copied.old_file_name(), copied.new_file_name()),
+            (None, Some("src/b.rs"))
+        );
+        assert_eq!(
+            (renamed.old_file_name(), renamed.new_file_name()),
+            (Some("src/a.rs"), Some("src/b.rs")),
+        );
+        assert_eq!(
+            (deleted.old_file_name(), deleted.new_file_name()),
+            (Some("src/a.rs"), None)
+        );
+    }
+}
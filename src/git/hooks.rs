@@ -0,0 +1,451 @@
+/// Client-side git hooks: installs pre-commit and pre-push hooks which run ops-workspace-fmt, ops-workspace-clippy
+/// and ops-eslint against the changed files. The installed scripts only call `ops-git-hooks run <hook>`, so the
+/// checks of each hook are configured in ops.toml (`[git.hooks]`) and CI can run exactly what the hooks run.
+use crate::config::OpsConfig;
+use crate::dry_run::{enable_dry_run, remove_path, write_file};
+use crate::error::Error;
+use crate::exec::{output, read};
+use crate::git::diff_name_status_since_branched::DiffBaseArgs;
+use crate::logging::LogArgs;
+use crate::progress::format_duration;
+use crate::report::{serialize_duration, Report};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, info_span, warn};
+
+/// line identifying hook scripts written by `ops-git-hooks install`, other hooks are never overwritten or removed
+/// unless --force is passed
+const MANAGED_MARKER: &str = "# managed by ops-git-hooks";
+
+#[derive(Clone, Debug, Parser)]
+#[clap(author, version, about, long_about = None)]
+pub struct GitHooksArgs {
+    #[clap(subcommand)]
+    pub command: GitHooksCommand,
+}
+
+#[derive(Clone, Debug, Subcommand)]
+pub enum GitHooksCommand {
+    /// write the managed hooks into the repository's hooks directory
+    Install(GitHooksInstallArgs),
+    /// remove the managed hooks
+    Uninstall(GitHooksUninstallArgs),
+    /// run the checks of a hook, as the installed hooks do -- also meant to be run in CI
+    Run(GitHooksRunArgs),
+}
+
+#[derive(Clone, Debug, Args)]
+pub struct GitHooksInstallArgs {
+    /// hooks to install, defaults to every hook
+    #[clap(long, value_enum, value_delimiter = '\n', env = "OPS_GIT_HOOK")]
+    pub hook: Vec<GitHook>,
+
+    /// overwrite existing hooks which were not installed by ops-git-hooks
+    #[clap(long, env = "OPS_FORCE")]
+    pub force: bool,
+
+    #[clap(flatten)]
+    pub log: LogArgs,
+
+    /// print the hook scripts instead of writing them
+    /// - can also be enabled by setting the OPS_DRY_RUN environment variable
+    #[clap(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Clone, Debug, Args)]
+pub struct GitHooksUninstallArgs {
+    /// hooks to remove, defaults to every hook
+    #[clap(long, value_enum, value_delimiter = '\n', env = "OPS_GIT_HOOK")]
+    pub hook: Vec<GitHook>,
+
+    #[clap(flatten)]
+    pub log: LogArgs,
+
+    /// print the hook scripts which would be removed instead of removing them
+    /// - can also be enabled by setting the OPS_DRY_RUN environment variable
+    #[clap(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Clone, Debug, Args)]
+pub struct GitHooksRunArgs {
+    /// hook whose checks to run
+    #[clap(value_enum)]
+    pub hook: GitHook,
+
+    /// checks to run, overrides `git.hooks.<hook>` in ops.toml
+    #[clap(short, long, value_enum, value_delimiter = '\n', env = "OPS_HOOK_CHECK")]
+    pub check: Vec<HookCheck>,
+
+    #[clap(flatten)]
+    pub diff_base: DiffBaseArgs,
+
+    #[clap(flatten)]
+    pub log: LogArgs,
+
+    /// print the check commands instead of running them
+    /// - can also be enabled by setting the OPS_DRY_RUN environment variable
+    #[clap(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum GitHook {
+    /// run before a commit is created
+    PreCommit,
+    /// run before commits are pushed
+    PrePush,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum HookCheck {
+    /// ops-workspace-clippy on the changed packages
+    Clippy,
+    /// ops-workspace-fmt on the changed rust files
+    Fmt,
+    /// ops-eslint on the changed javascript and typescript files
+    Eslint,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HookCheckStatus {
+    Passed,
+    Failed,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct GitHooksInstallOutput {
+    pub hooks_dir: PathBuf,
+    pub installed: Vec<GitHook>,
+    /// existing hooks which were not installed by ops-git-hooks and were overwritten because of --force
+    pub overwritten: Vec<GitHook>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct GitHooksUninstallOutput {
+    pub hooks_dir: PathBuf,
+    pub removed: Vec<GitHook>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct GitHookRunOutput {
+    pub hook: GitHook,
+    pub checks: Vec<HookCheckOutput>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct HookCheckOutput {
+    pub check: HookCheck,
+    pub status: HookCheckStatus,
+    /// why the check failed
+    pub message: Option<String>,
+    #[serde(serialize_with = "serialize_duration")]
+    pub duration: Duration,
+}
+
+impl GitHook {
+    pub const ALL: [GitHook; 2] = [GitHook::PreCommit, GitHook::PrePush];
+
+    /// File name of the hook in the hooks directory.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::PreCommit => "pre-commit",
+            Self::PrePush => "pre-push",
+        }
+    }
+
+    /// Checks run by the hook unless configured otherwise in ops.toml.
+    pub fn default_checks(&self) -> Vec<HookCheck> {
+        match self {
+            Self::PreCommit => vec![HookCheck::Fmt, HookCheck::Eslint],
+            Self::PrePush => vec![HookCheck::Clippy],
+        }
+    }
+
+    fn script(&self) -> String {
+        format!(
+            "#!/bin/sh\n{MANAGED_MARKER}: reinstall with `ops-git-hooks install`, remove with `ops-git-hooks uninstall`\nexec ops-git-hooks run {}\n",
+            self.name()
+        )
+    }
+}
+
+impl HookCheck {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Clippy => "clippy",
+            Self::Fmt => "fmt",
+            Self::Eslint => "eslint",
+        }
+    }
+
+    /// ops binary running the check.
+    fn bin_name(&self) -> &'static str {
+        match self {
+            Self::Clippy => "ops-workspace-clippy",
+            Self::Fmt => "ops-workspace-fmt",
+            Self::Eslint => "ops-eslint",
+        }
+    }
+
+    /// Args making the check only report problems, hooks must not rewrite the working tree.
+    fn check_args(&self) -> &'static [&'static str] {
+        match self {
+            Self::Clippy => &["--no-fix"],
+            Self::Fmt | Self::Eslint => &[],
+        }
+    }
+}
+
+impl GitHookRunOutput {
+    /// Converts the output into an error if any check failed, failing the hook and thereby the commit or push.
+    pub fn result(&self) -> Result<(), Error> {
+        let failed = self
+            .checks
+            .iter()
+            .filter(|check| check.status == HookCheckStatus::Failed)
+            .map(|check| check.check.name().to_string())
+            .collect::<Vec<_>>();
+        match failed.is_empty() {
+            true => Ok(()),
+            false => Err(Error::HookFailed {
+                hook: self.hook.name().to_string(),
+                checks: failed,
+            }),
+        }
+    }
+}
+
+fn hook_names(hooks: &[GitHook]) -> String {
+    hooks.iter().map(GitHook::name).collect::<Vec<_>>().join(", ")
+}
+
+impl Report for GitHooksInstallOutput {
+    fn human(&self) -> String {
+        let mut lines = vec![format!(
+            "installed {} in {}",
+            hook_names(&self.installed),
+            self.hooks_dir.display()
+        )];
+        if !self.overwritten.is_empty() {
+            lines.push(format!("overwrote unmanaged {}", hook_names(&self.overwritten)));
+        }
+        lines.join("\n")
+    }
+}
+
+impl Report for GitHooksUninstallOutput {
+    fn human(&self) -> String {
+        match self.removed.is_empty() {
+            true => "no managed hooks installed".to_string(),
+            false => format!(
+                "removed {} from {}",
+                hook_names(&self.removed),
+                self.hooks_dir.display()
+            ),
+        }
+    }
+}
+
+impl Report for GitHookRunOutput {
+    fn human(&self) -> String {
+        let mut lines = vec![format!("{} summary", self.hook.name()).bold().to_string()];
+        for check in &self.checks {
+            let status = match check.status {
+                HookCheckStatus::Passed => "ok".green(),
+                HookCheckStatus::Failed => "failed".red(),
+            };
+            let detail = match check.message.as_ref() {
+                Some(message) => format!("{} {message}", format_duration(check.duration)),
+                None => format_duration(check.duration),
+            };
+            lines.push(format!("  {:6}  {status:7}  {detail}", check.check.name()));
+        }
+        lines.join("\n")
+    }
+}
+
+pub fn git_hooks_install(git_hooks_install_args: GitHooksInstallArgs) -> Result<GitHooksInstallOutput, Error> {
+    let GitHooksInstallArgs {
+        hook,
+        force,
+        log: _,
+        dry_run,
+    } = git_hooks_install_args;
+
+    let _span = info_span!("git_hooks_install").entered();
+
+    if dry_run {
+        enable_dry_run();
+    }
+
+    let hooks = match hook.is_empty() {
+        true => GitHook::ALL.to_vec(),
+        false => hook,
+    };
+    let hooks_dir = get_hooks_dir()?;
+    debug!("using hooks directory {}", hooks_dir.display());
+    if !dry_run {
+        fs::create_dir_all(&hooks_dir)?;
+    }
+
+    let mut overwritten = vec![];
+    for hook in &hooks {
+        let path = hooks_dir.join(hook.name());
+        if path.exists() && !is_managed(&path)? {
+            if !force {
+                return Err(Error::InvalidInput(format!(
+                    "`{}` was not installed by ops-git-hooks, pass --force to overwrite it",
+                    path.display()
+                )));
+            }
+            warn!("overwriting {}", path.display());
+            overwritten.push(*hook);
+        }
+        write_file(&path, &hook.script())?;
+        if !dry_run {
+            set_executable(&path)?;
+        }
+    }
+
+    Ok(GitHooksInstallOutput {
+        hooks_dir,
+        installed: hooks,
+        overwritten,
+    })
+}
+
+pub fn git_hooks_uninstall(git_hooks_uninstall_args: GitHooksUninstallArgs) -> Result<GitHooksUninstallOutput, Error> {
+    let GitHooksUninstallArgs { hook, log: _, dry_run } = git_hooks_uninstall_args;
+
+    let _span = info_span!("git_hooks_uninstall").entered();
+
+    if dry_run {
+        enable_dry_run();
+    }
+
+    let hooks = match hook.is_empty() {
+        true => GitHook::ALL.to_vec(),
+        false => hook,
+    };
+    let hooks_dir = get_hooks_dir()?;
+
+    let mut removed = vec![];
+    for hook in hooks {
+        let path = hooks_dir.join(hook.name());
+        if !path.exists() {
+            continue;
+        }
+        if !is_managed(&path)? {
+            warn!("leaving {} in place: not installed by ops-git-hooks", path.display());
+            continue;
+        }
+        remove_path(&path)?;
+        removed.push(hook);
+    }
+
+    Ok(GitHooksUninstallOutput { hooks_dir, removed })
+}
+
+pub fn git_hooks_run(git_hooks_run_args: GitHooksRunArgs) -> Result<GitHookRunOutput, Error> {
+    let GitHooksRunArgs {
+        hook,
+        check,
+        diff_base,
+        log: _,
+        dry_run,
+    } = git_hooks_run_args;
+
+    let _span = info_span!("git_hooks_run", hook = hook.name()).entered();
+
+    if dry_run {
+        enable_dry_run();
+    }
+
+    // hooks run in the repository root, as do the checks so that their configs are found
+    let repo_dir = PathBuf::from(read(Command::new("git").args(["rev-parse", "--show-toplevel"]))?);
+    let config = OpsConfig::discover(&repo_dir)?.git.hooks;
+    let configured = match hook {
+        GitHook::PreCommit => config.pre_commit,
+        GitHook::PrePush => config.pre_push,
+    };
+    let checks = match (check.is_empty(), configured.is_empty()) {
+        (false, _) => check,
+        (true, false) => configured,
+        (true, true) => hook.default_checks(),
+    };
+
+    let mut outputs = vec![];
+    for check in checks {
+        let start = Instant::now();
+        info!("running {}", check.name());
+        let mut command = Command::new(get_ops_bin(check.bin_name()));
+        command.args(check.check_args());
+        if let Some(base) = diff_base.base.as_ref() {
+            command.args(["--base", base]);
+        }
+        if let Some(remote) = diff_base.remote.as_ref() {
+            command.args(["--remote", remote]);
+        }
+        command
+            .current_dir(&repo_dir)
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit());
+        let (status, message) = match output(&mut command)? {
+            Some(output) if !output.status.success() => (
+                HookCheckStatus::Failed,
+                output.status.code().map(|code| format!("exit code {code}")),
+            ),
+            _ => (HookCheckStatus::Passed, None),
+        };
+        outputs.push(HookCheckOutput {
+            check,
+            status,
+            message,
+            duration: start.elapsed(),
+        });
+    }
+
+    Ok(GitHookRunOutput { hook, checks: outputs })
+}
+
+/// Hooks directory of the repository containing the current directory, honoring `core.hooksPath`.
+fn get_hooks_dir() -> Result<PathBuf, Error> {
+    let hooks_dir = read(Command::new("git").args(["rev-parse", "--git-path", "hooks"]))?;
+    Ok(env::current_dir()?.join(hooks_dir))
+}
+
+fn is_managed(path: &Path) -> Result<bool, Error> {
+    Ok(fs::read_to_string(path)?.contains(MANAGED_MARKER))
+}
+
+/// The ops binary `name` installed next to the running binary, falling back to looking it up on the PATH.
+fn get_ops_bin(name: &str) -> PathBuf {
+    env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(|dir| dir.join(name)))
+        .filter(|bin| bin.exists())
+        .unwrap_or_else(|| PathBuf::from(name))
+}
+
+#[cfg(unix)]
+fn set_executable(path: &Path) -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_executable(_: &Path) -> Result<(), Error> {
+    Ok(())
+}
@@ -1,6 +1,14 @@
+pub mod backend;
 pub mod diff_name_status_since_branched;
+pub mod hooks;
+pub mod pre_receive;
+pub mod signature;
 
 pub mod prelude {
     use super::*;
+    pub use backend::*;
     pub use diff_name_status_since_branched::*;
+    pub use hooks::*;
+    pub use pre_receive::*;
+    pub use signature::*;
 }
@@ -0,0 +1,323 @@
+/// Server-side git pre-receive hook: reads the `<old> <new> <ref>` lines git passes on stdin, exports the tree of
+/// every pushed branch into a temporary directory (pushed objects are quarantined so no refs or worktrees may be
+/// created until the hook passes), determines the packages affected by the push and runs the configured
+/// checks against them, rejecting the push with a reason per ref if any check fails.
+use crate::cargo::WorkspaceGraph;
+use crate::config::{OpsConfig, OPS_CONFIG_FILE_NAME};
+use crate::error::Error;
+use crate::exec::{output, read, run, ExecOptions};
+use crate::logging::LogArgs;
+use crate::report::Report;
+use crate::temp::temp_dir;
+use clap::{Parser, ValueEnum};
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use tracing::{debug, info, info_span};
+
+const NULL_COMMIT: &str = "0000000000000000000000000000000000000000";
+const DEFAULT_BRANCH: &str = "main";
+
+#[derive(Clone, Debug, Parser)]
+#[clap(author, version, about, long_about = None)]
+pub struct GitPreReceiveArgs {
+    /// checks to run against every pushed branch, overrides `git.pre_receive.checks` in ops.toml
+    #[clap(short, long, value_enum, value_delimiter = '\n', env = "OPS_PRE_RECEIVE_CHECK")]
+    pub check: Vec<PreReceiveCheck>,
+
+    #[clap(flatten)]
+    pub log: LogArgs,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum PreReceiveCheck {
+    /// `cargo clippy` on the affected packages
+    Clippy,
+    /// `cargo fmt --check` on the affected packages
+    Fmt,
+    /// `commitlint` on the pushed commits
+    Commitlint,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PreReceiveOutput {
+    pub refs: Vec<RefOutput>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RefOutput {
+    pub ref_name: String,
+    pub old: String,
+    pub new: String,
+    pub affected_packages: Vec<String>,
+    pub checks: Vec<CheckOutput>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CheckOutput {
+    pub check: PreReceiveCheck,
+    pub passed: bool,
+    /// reason the check failed
+    pub message: Option<String>,
+}
+
+impl PreReceiveCheck {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Clippy => "clippy",
+            Self::Fmt => "fmt",
+            Self::Commitlint => "commitlint",
+        }
+    }
+}
+
+impl RefOutput {
+    pub fn accepted(&self) -> bool {
+        self.checks.iter().all(|check| check.passed)
+    }
+}
+
+impl PreReceiveOutput {
+    /// Converts the output into an error if any ref was rejected, failing the hook and thereby the push.
+    pub fn result(&self) -> Result<(), Error> {
+        let rejected = self
+            .refs
+            .iter()
+            .filter(|git_ref| !git_ref.accepted())
+            .map(|git_ref| git_ref.ref_name.clone())
+            .collect::<Vec<_>>();
+        match rejected.is_empty() {
+            true => Ok(()),
+            false => Err(Error::PushRejected { refs: rejected }),
+        }
+    }
+}
+
+impl Report for PreReceiveOutput {
+    fn human(&self) -> String {
+        let mut lines = vec![];
+        for git_ref in &self.refs {
+            let status = if git_ref.accepted() { "accepted".green() } else { "rejected".red() };
+            lines.push(format!("{} {status}", git_ref.ref_name.bold()));
+            for check in &git_ref.checks {
+                match check.message.as_ref() {
+                    Some(message) => lines.push(format!("  {}: {message}", check.check.name())),
+                    None => lines.push(format!("  {}: ok", check.check.name())),
+                }
+            }
+        }
+        lines.join("\n")
+    }
+}
+
+pub fn git_pre_receive(git_pre_receive_args: GitPreReceiveArgs) -> Result<PreReceiveOutput, Error> {
+    let GitPreReceiveArgs { check, log: _ } = git_pre_receive_args;
+
+    let _span = info_span!("git_pre_receive").entered();
+
+    let config = load_config()?.git.pre_receive;
+    let checks = match (check.is_empty(), config.checks.is_empty()) {
+        (false, _) => check,
+        (true, false) => config.checks.clone(),
+        (true, true) => vec![PreReceiveCheck::Clippy, PreReceiveCheck::Fmt],
+    };
+    let default_branch = config.default_branch.as_deref().unwrap_or(DEFAULT_BRANCH);
+
+    let mut refs = vec![];
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        let mut parts = line.split_whitespace();
+        let (Some(old), Some(new), Some(ref_name)) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(Error::InvalidInput(format!("unexpected pre-receive input: {line}")));
+        };
+        let Some(branch) = ref_name.strip_prefix("refs/heads/") else {
+            debug!("skipping {ref_name}: not a branch");
+            continue;
+        };
+        if new == NULL_COMMIT {
+            debug!("skipping {ref_name}: branch deleted");
+            continue;
+        }
+        if !config.branches.is_empty() && !config.branches.iter().any(|configured| configured == branch) {
+            debug!("skipping {ref_name}: branch is not checked");
+            continue;
+        }
+        info!("checking {ref_name}");
+        refs.push(check_ref(ref_name, old, new, default_branch, &checks)?);
+    }
+
+    Ok(PreReceiveOutput { refs })
+}
+
+/// The ops.toml of the repository's default branch: the hook runs in the bare repository, which has no working tree
+/// to find it in, and reading it from HEAD rather than from the pushed commits keeps a push from changing the checks
+/// it is held to.
+fn load_config() -> Result<OpsConfig, Error> {
+    let path = format!("HEAD:{OPS_CONFIG_FILE_NAME}");
+    let show = run(
+        Command::new("git").args(["show", &path]),
+        &ExecOptions {
+            stream: false,
+            ..Default::default()
+        },
+    );
+    // HEAD is unborn until the first push or has no ops.toml
+    let Ok(show) = show else {
+        debug!("no {path}, using the default config");
+        return Ok(OpsConfig::default());
+    };
+    OpsConfig::parse(&String::from_utf8_lossy(&show.stdout), Path::new(&path))
+}
+
+fn check_ref(
+    ref_name: &str,
+    old: &str,
+    new: &str,
+    default_branch: &str,
+    checks: &[PreReceiveCheck],
+) -> Result<RefOutput, Error> {
+    // new branches are compared against where they diverged from the default branch
+    let base = match old {
+        NULL_COMMIT => {
+            read(Command::new("git").args(["merge-base", &format!("refs/heads/{default_branch}"), new])).ok()
+        }
+        old => Some(old.to_string()),
+    };
+
+    let tree_dir = temp_dir()?;
+    export_tree(new, tree_dir.path())?;
+    let (affected_packages, checks) = run_checks(tree_dir.path(), base.as_deref(), new, checks)?;
+
+    Ok(RefOutput {
+        ref_name: ref_name.to_string(),
+        old: old.to_string(),
+        new: new.to_string(),
+        affected_packages,
+        checks,
+    })
+}
+
+/// Writes the files of `commit` to `dir` without touching the repository's refs or index: the tree is read into
+/// a throwaway index which is then checked out into `dir`, so only git itself is required.
+fn export_tree(commit: &str, dir: &Path) -> Result<(), Error> {
+    let index_dir = temp_dir()?;
+    let index_file = index_dir.path().join("index");
+    read(
+        Command::new("git")
+            .args(["read-tree", commit])
+            .env("GIT_INDEX_FILE", &index_file),
+    )?;
+    read(
+        Command::new("git")
+            .arg(format!("--work-tree={}", dir.display()))
+            .args(["checkout-index", "--all"])
+            .env("GIT_INDEX_FILE", &index_file),
+    )?;
+    Ok(())
+}
+
+fn run_checks(
+    tree_dir: &Path,
+    base: Option<&str>,
+    new: &str,
+    checks: &[PreReceiveCheck],
+) -> Result<(Vec<String>, Vec<CheckOutput>), Error> {
+    let mut affected_packages = vec![];
+    if tree_dir.join("Cargo.toml").exists() {
+        let graph = WorkspaceGraph::load(tree_dir)?;
+        affected_packages = match base {
+            Some(base) => {
+                let files = read(Command::new("git").args(["diff", "--name-only", base, new]))?;
+                graph.affected_by(files.lines()).into_iter().map(String::from).collect()
+            }
+            None => graph.packages().keys().cloned().collect(),
+        };
+        affected_packages.sort();
+    }
+    debug!("affected packages: {}", affected_packages.join(", "));
+
+    let package_args = affected_packages
+        .iter()
+        .flat_map(|package| ["--package", package])
+        .collect::<Vec<_>>();
+
+    let message_dir = temp_dir()?;
+    let mut outputs = vec![];
+    for check in checks {
+        let mut command = match check {
+            PreReceiveCheck::Clippy | PreReceiveCheck::Fmt if affected_packages.is_empty() => continue,
+            PreReceiveCheck::Clippy => {
+                let mut command = Command::new("cargo");
+                command.arg("clippy").args(&package_args).args(["--", "-D", "warnings"]);
+                command
+            }
+            PreReceiveCheck::Fmt => {
+                let mut command = Command::new("cargo");
+                command.arg("fmt").args(&package_args).arg("--check");
+                command
+            }
+            // commitlint reads the commits from the repository itself rather than the exported tree
+            PreReceiveCheck::Commitlint => {
+                let mut command = Command::new("commitlint");
+                let has_parent = || {
+                    // not retried, a root commit has no parent
+                    let parent = format!("{new}~1");
+                    let options = ExecOptions {
+                        stream: false,
+                        ..Default::default()
+                    };
+                    run(
+                        Command::new("git").args(["rev-parse", "--verify", "--quiet", &parent]),
+                        &options,
+                    )
+                    .is_ok()
+                };
+                match base {
+                    Some(base) => command.args(["--from", base, "--to", new]),
+                    None if has_parent() => command.args(["--from", &format!("{new}~1"), "--to", new]),
+                    // a root commit has no parent to lint from, its message is linted on its own
+                    None => {
+                        let message_file = message_dir.path().join("COMMIT_EDITMSG");
+                        fs::write(
+                            &message_file,
+                            read(Command::new("git").args(["log", "-1", "--format=%B", new]))?,
+                        )?;
+                        command.arg(format!("--edit={}", message_file.display()))
+                    }
+                };
+                command.current_dir(env::current_dir()?);
+                command
+            }
+        };
+        if command.get_current_dir().is_none() {
+            command.current_dir(tree_dir);
+        }
+        command.stdout(Stdio::inherit()).stderr(Stdio::inherit());
+        let (passed, message) = match output(&mut command)? {
+            Some(output) if !output.status.success() => (
+                false,
+                Some(format!(
+                    "failed{}",
+                    output
+                        .status
+                        .code()
+                        .map(|code| format!(" with status {code}"))
+                        .unwrap_or_default()
+                )),
+            ),
+            _ => (true, None),
+        };
+        outputs.push(CheckOutput {
+            check: *check,
+            passed,
+            message,
+        });
+    }
+
+    Ok((affected_packages, outputs))
+}
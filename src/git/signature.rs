@@ -0,0 +1,201 @@
+/// Verifies that the commit being built or pushed, or an annotated tag pointing at it, carries a good GPG or SSH
+/// signature from an allowed signer so that release artifacts can be traced back to signed refs. Verification is
+/// delegated to `git verify-commit`/`git verify-tag`: SSH signatures are checked against an allowed signers file
+/// and signatures of either kind can additionally be restricted to a list of key fingerprints in ops.toml.
+use crate::config::{find_config_dir, OpsConfig};
+use crate::error::Error;
+use crate::exec::{read, run, ExecOptions};
+use clap::Args;
+use serde::Serialize;
+use std::iter::once;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tracing::{debug, info};
+
+#[derive(Clone, Debug, Default, Args)]
+pub struct SignatureArgs {
+    /// refuse to continue unless HEAD, or an annotated tag pointing at it, is signed by an allowed signer and the
+    /// working tree has no uncommitted changes
+    #[clap(long, env = "OPS_REQUIRE_SIGNED")]
+    pub require_signed: bool,
+
+    /// ssh allowed signers file to verify signatures against, overrides `git.signing.allowed_signers` in ops.toml
+    #[clap(long, env = "OPS_ALLOWED_SIGNERS")]
+    pub allowed_signers: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SignatureOutput {
+    pub commit: String,
+    /// signed ref, either the commit itself or an annotated tag (`refs/tags/<tag>`) pointing at it
+    pub signed_ref: String,
+    pub signer: Option<String>,
+    pub fingerprint: Option<String>,
+}
+
+#[derive(Default)]
+struct Signature {
+    signer: Option<String>,
+    /// fingerprints of the signing key and, for GPG subkeys, its primary key
+    fingerprints: Vec<String>,
+}
+
+/// Verifies the signature of HEAD in `dir` if `--require-signed` was passed, returning which ref was signed by
+/// whom.
+pub fn verify_signed_head(signature_args: &SignatureArgs, dir: &Path) -> Result<Option<SignatureOutput>, Error> {
+    let SignatureArgs {
+        require_signed,
+        allowed_signers,
+    } = signature_args;
+    if !require_signed {
+        return Ok(None);
+    }
+
+    let config = OpsConfig::discover(dir)?.git.signing;
+    let allowed_signers = match (allowed_signers, config.allowed_signers) {
+        (Some(allowed_signers), _) => Some(allowed_signers.clone()),
+        (None, Some(allowed_signers)) => Some(find_config_dir(dir).unwrap_or(dir).join(allowed_signers)),
+        (None, None) => None,
+    };
+    if let Some(allowed_signers) = allowed_signers.as_ref().filter(|path| !path.is_file()) {
+        return Err(Error::InvalidInput(format!(
+            "allowed signers file `{}` does not exist",
+            allowed_signers.display()
+        )));
+    }
+
+    let commit = read(Command::new("git").args(["rev-parse", "HEAD"]).current_dir(dir))?;
+    let rejected = |message: String| Error::SignatureRejected {
+        rev: commit.clone(),
+        message,
+    };
+
+    // a signed ref says nothing about changes which were never committed
+    let status = read(
+        Command::new("git")
+            .args(["status", "--porcelain", "--untracked-files=no"])
+            .current_dir(dir),
+    )?;
+    if !status.is_empty() {
+        return Err(rejected("the working tree has uncommitted changes".to_string()));
+    }
+
+    let tags = read(
+        Command::new("git")
+            .args([
+                "for-each-ref",
+                "--points-at",
+                "HEAD",
+                "--format=%(objecttype) %(refname)",
+                "refs/tags",
+            ])
+            .current_dir(dir),
+    )?;
+    let candidates = once(("verify-commit", commit.clone())).chain(
+        tags.lines()
+            .filter_map(|line| line.strip_prefix("tag "))
+            .map(|tag| ("verify-tag", tag.to_string())),
+    );
+
+    let mut reasons = vec![];
+    for (subcommand, signed_ref) in candidates {
+        let mut command = Command::new("git");
+        if let Some(allowed_signers) = allowed_signers.as_ref() {
+            command
+                .arg("-c")
+                .arg(format!("gpg.ssh.allowedSignersFile={}", allowed_signers.display()));
+        }
+        command.args([subcommand, "--raw", &signed_ref]).current_dir(dir);
+        let options = ExecOptions {
+            stream: false,
+            ..Default::default()
+        };
+        let name = match subcommand {
+            "verify-commit" => "commit".to_string(),
+            _ => signed_ref.clone(),
+        };
+        let signature = match run(&command, &options) {
+            Ok(output) => parse_signature(&String::from_utf8_lossy(&output.stderr)),
+            Err(Error::CommandFailed { stderr, .. }) => {
+                let reason = stderr.lines().last().unwrap_or("not signed").trim();
+                let reason = reason.strip_prefix("error: ").unwrap_or(reason);
+                debug!("{name} failed verification: {reason}");
+                reasons.push(format!("{name}: {reason}"));
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        let allowed = config.allowed_keys.is_empty()
+            || signature.fingerprints.iter().any(|fingerprint| {
+                config
+                    .allowed_keys
+                    .iter()
+                    .any(|allowed| normalize_fingerprint(allowed) == normalize_fingerprint(fingerprint))
+            });
+        if !allowed {
+            reasons.push(format!(
+                "{name}: signed with {}, which is not in `git.signing.allowed_keys`",
+                signature
+                    .fingerprints
+                    .first()
+                    .map(String::as_str)
+                    .unwrap_or("an unknown key")
+            ));
+            continue;
+        }
+        info!(
+            "{name} is signed{}",
+            signature
+                .signer
+                .as_ref()
+                .map(|signer| format!(" by {signer}"))
+                .unwrap_or_default()
+        );
+        return Ok(Some(SignatureOutput {
+            commit,
+            signed_ref: signed_ref.clone(),
+            signer: signature.signer,
+            fingerprint: signature.fingerprints.into_iter().next(),
+        }));
+    }
+
+    Err(rejected(reasons.join("; ")))
+}
+
+/// Parses the raw output of gpg (status lines) or ssh-keygen printed by `git verify-* --raw`.
+fn parse_signature(raw: &str) -> Signature {
+    let mut signature = Signature::default();
+    for line in raw.lines() {
+        if let Some(rest) = line.strip_prefix("[GNUPG:] GOODSIG ") {
+            signature.signer = rest.split_once(' ').map(|(_, user_id)| user_id.to_string());
+        } else if let Some(rest) = line.strip_prefix("[GNUPG:] VALIDSIG ") {
+            let fields = rest.split_whitespace().collect::<Vec<_>>();
+            signature
+                .fingerprints
+                .extend(fields.first().map(|fingerprint| fingerprint.to_string()));
+            signature.fingerprints.extend(
+                fields
+                    .last()
+                    .filter(|_| fields.len() > 1)
+                    .map(|fingerprint| fingerprint.to_string()),
+            );
+        } else if let Some(rest) = line.strip_prefix("Good \"git\" signature for ") {
+            // `Good "git" signature for <principal> with <algorithm> key <fingerprint>`
+            let mut parts = rest.rsplitn(4, ' ');
+            signature.fingerprints.extend(parts.next().map(String::from));
+            signature.signer = parts
+                .nth(2)
+                .and_then(|principal| principal.strip_suffix(" with"))
+                .map(String::from);
+        }
+    }
+    signature
+}
+
+/// GPG fingerprints are hex and commonly written in groups, SSH fingerprints are case sensitive base64.
+fn normalize_fingerprint(fingerprint: &str) -> String {
+    match fingerprint.starts_with("SHA256:") {
+        true => fingerprint.to_string(),
+        false => fingerprint.replace(' ', "").to_uppercase(),
+    }
+}
@@ -0,0 +1,95 @@
+/// Incremental frontend checks: ops-eslint, ops-prettier and ops-stylelint only pass the files changed since the
+/// current branch diverged to their tool, which is run through the package manager of the node workspace containing
+/// the current directory so that the workspace's locally installed version is used.
+use crate::error::Error;
+use crate::exec::output;
+use crate::git::diff_name_status_since_branched::*;
+use crate::node::{get_node_workspace_root, NodeWorkspace, PackageManager};
+use crate::report::Report;
+use fancy_regex::Regex;
+use serde::Serialize;
+use std::env;
+use std::process::{Command, Stdio};
+use tracing::debug;
+
+pub mod prettier;
+pub mod stylelint;
+
+pub mod prelude {
+    use super::*;
+    pub use super::{get_changed_files, tool_command, JsCheckOutput};
+    pub use prettier::*;
+    pub use stylelint::*;
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct JsCheckOutput {
+    /// whether the whole tree was checked
+    pub all: bool,
+    /// whether problems were fixed in place
+    pub fixed: bool,
+    /// changed files which were checked, empty if the whole tree was checked
+    pub files: Vec<String>,
+}
+
+impl Report for JsCheckOutput {
+    fn human(&self) -> String {
+        let verb = if self.fixed { "fixed" } else { "checked" };
+        if self.all {
+            return format!("{verb} the whole tree");
+        }
+        match self.files.len() {
+            0 => "no files to check".to_string(),
+            1 => format!("{verb} 1 file"),
+            n => format!("{verb} {n} files"),
+        }
+    }
+}
+
+/// Files added or modified since `diff_base` whose path matches `file_regex`, deleted files are never included.
+pub fn get_changed_files(diff_base: &DiffBaseArgs, file_regex: &Regex) -> Result<Vec<String>, Error> {
+    debug!("matching files with regex: {file_regex}");
+    let text = git_diff_name_status_since_last_branch(diff_base)?;
+    let git_statuses = parse_git_statuses(&text)?;
+    Ok(git_statuses
+        .iter()
+        .filter_map(GitStatus::new_file_name)
+        .filter(|file_name| file_regex.is_match(file_name).unwrap_or_default())
+        .map(String::from)
+        .collect())
+}
+
+/// Command running the node binary `program` through the package manager of the node workspace containing the
+/// current directory (e.g. `pnpm exec eslint`), `package_manager` overrides the detected one. Outside of a node
+/// workspace, or if its package manager cannot be detected, `program` is run directly.
+pub fn tool_command(program: &str, package_manager: Option<PackageManager>) -> Result<Command, Error> {
+    let package_manager = match package_manager {
+        Some(package_manager) => Some(package_manager),
+        None => match get_node_workspace_root(&env::current_dir()?) {
+            Ok(workspace_dir) => NodeWorkspace::load(&workspace_dir)?.package_manager().ok(),
+            Err(_) => None,
+        },
+    };
+    let Some(package_manager) = package_manager else {
+        debug!("no node workspace package manager found, running {program} directly");
+        return Ok(Command::new(program));
+    };
+    let (exec, exec_args) = package_manager
+        .exec()
+        .split_first()
+        .expect("package manager exec command is empty");
+    let mut command = Command::new(exec);
+    command.args(exec_args.iter()).arg(program);
+    Ok(command)
+}
+
+/// Runs `command` of the check `tool` with its output streamed, failing if the tool exits unsuccessfully.
+pub(crate) fn run_tool(tool: &str, command: &mut Command) -> Result<(), Error> {
+    match output(command.stdout(Stdio::inherit()).stderr(Stdio::inherit()))? {
+        Some(output) if !output.status.success() => Err(Error::JsCheckFailed {
+            tool: tool.to_string(),
+            status: output.status.code(),
+        }),
+        _ => Ok(()),
+    }
+}
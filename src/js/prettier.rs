@@ -0,0 +1,83 @@
+/// Runs prettier on the files changed since the current branch diverged rather than on the whole tree.
+use crate::dry_run::enable_dry_run;
+use crate::error::Error;
+use crate::git::diff_name_status_since_branched::DiffBaseArgs;
+use crate::js::{get_changed_files, run_tool, tool_command, JsCheckOutput};
+use crate::logging::LogArgs;
+use crate::node::PackageManager;
+use clap::Parser;
+use fancy_regex::Regex;
+use tracing::info_span;
+
+/// files prettier formats out of the box
+const FILE_REGEX: &str = r"\.(js|jsx|mjs|cjs|ts|tsx|mts|cts|json|css|scss|less|html|vue|graphql|gql|md|mdx|yaml|yml)$";
+
+#[derive(Clone, Debug, Parser)]
+#[clap(author, version, about, long_about = None, trailing_var_arg=true)]
+pub struct PrettierArgs {
+    #[clap(flatten)]
+    pub log: LogArgs,
+
+    /// print the prettier command instead of running it
+    /// - can also be enabled by setting the OPS_DRY_RUN environment variable
+    #[clap(long)]
+    pub dry_run: bool,
+
+    /// format the changed files in place instead of only checking them
+    #[clap(long, env = "OPS_FIX")]
+    pub fix: bool,
+
+    /// check the whole tree rather than only the changed files
+    #[clap(short, long, env = "OPS_ALL")]
+    pub all: bool,
+
+    /// package manager to run prettier with, detected from the node workspace by default
+    #[clap(long, value_enum, env = "OPS_PACKAGE_MANAGER")]
+    pub package_manager: Option<PackageManager>,
+
+    #[clap(flatten)]
+    pub diff_base: DiffBaseArgs,
+
+    /// prettier args
+    #[clap(raw = true)]
+    pub prettier_args: Vec<String>,
+}
+
+pub fn prettier(prettier_args: PrettierArgs) -> Result<JsCheckOutput, Error> {
+    let PrettierArgs {
+        log: _,
+        dry_run,
+        fix,
+        all,
+        package_manager,
+        diff_base,
+        prettier_args,
+    } = prettier_args;
+
+    let _span = info_span!("prettier").entered();
+
+    if dry_run {
+        enable_dry_run();
+    }
+
+    let files = match all {
+        true => vec![],
+        false => get_changed_files(&diff_base, &Regex::new(FILE_REGEX).unwrap())?,
+    };
+    if !all && files.is_empty() {
+        return Ok(JsCheckOutput::default());
+    }
+
+    let mut command = tool_command("prettier", package_manager)?;
+    command
+        .arg(if fix { "--write" } else { "--check" })
+        .arg("--ignore-unknown")
+        .args(prettier_args);
+    match all {
+        true => command.arg("."),
+        false => command.args(&files),
+    };
+    run_tool("prettier", &mut command)?;
+
+    Ok(JsCheckOutput { all, fixed: fix, files })
+}
@@ -0,0 +1,84 @@
+/// Runs stylelint on the stylesheets changed since the current branch diverged rather than on the whole tree.
+use crate::dry_run::enable_dry_run;
+use crate::error::Error;
+use crate::git::diff_name_status_since_branched::DiffBaseArgs;
+use crate::js::{get_changed_files, run_tool, tool_command, JsCheckOutput};
+use crate::logging::LogArgs;
+use crate::node::PackageManager;
+use clap::Parser;
+use fancy_regex::Regex;
+use tracing::info_span;
+
+const FILE_REGEX: &str = r"\.(css|scss|sass|less)$";
+/// glob of the files linted with --all, stylelint has no default file set
+const ALL_GLOB: &str = "**/*.{css,scss,sass,less}";
+
+#[derive(Clone, Debug, Parser)]
+#[clap(author, version, about, long_about = None, trailing_var_arg=true)]
+pub struct StylelintArgs {
+    #[clap(flatten)]
+    pub log: LogArgs,
+
+    /// print the stylelint command instead of running it
+    /// - can also be enabled by setting the OPS_DRY_RUN environment variable
+    #[clap(long)]
+    pub dry_run: bool,
+
+    /// fix the reported problems in place where possible
+    #[clap(long, env = "OPS_FIX")]
+    pub fix: bool,
+
+    /// lint the whole tree rather than only the changed files
+    #[clap(short, long, env = "OPS_ALL")]
+    pub all: bool,
+
+    /// package manager to run stylelint with, detected from the node workspace by default
+    #[clap(long, value_enum, env = "OPS_PACKAGE_MANAGER")]
+    pub package_manager: Option<PackageManager>,
+
+    #[clap(flatten)]
+    pub diff_base: DiffBaseArgs,
+
+    /// stylelint args
+    #[clap(raw = true)]
+    pub stylelint_args: Vec<String>,
+}
+
+pub fn stylelint(stylelint_args: StylelintArgs) -> Result<JsCheckOutput, Error> {
+    let StylelintArgs {
+        log: _,
+        dry_run,
+        fix,
+        all,
+        package_manager,
+        diff_base,
+        stylelint_args,
+    } = stylelint_args;
+
+    let _span = info_span!("stylelint").entered();
+
+    if dry_run {
+        enable_dry_run();
+    }
+
+    let files = match all {
+        true => vec![],
+        false => get_changed_files(&diff_base, &Regex::new(FILE_REGEX).unwrap())?,
+    };
+    if !all && files.is_empty() {
+        return Ok(JsCheckOutput::default());
+    }
+
+    let mut command = tool_command("stylelint", package_manager)?;
+    if fix {
+        command.arg("--fix");
+    }
+    command.arg("--allow-empty-input").args(stylelint_args);
+    match all {
+        true => command.arg(ALL_GLOB),
+        false => command.args(&files),
+    };
+    run_tool("stylelint", &mut command)?;
+
+    Ok(JsCheckOutput { all, fixed: fix, files })
+}
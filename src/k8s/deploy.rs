@@ -0,0 +1,456 @@
+/// Applies Kubernetes manifests (e.g. generated by ops-k8s-gen) with kubectl and waits for the rollout of every
+/// Deployment, StatefulSet and DaemonSet they contain, rolling the workloads which existed before and were changed by
+/// the deploy back to their previous revision if a rollout fails. Images pushed with ops-docker-push can be pinned to
+/// the digest the push reported, so that exactly the pushed image is deployed.
+use crate::dry_run::{enable_dry_run, write_file};
+use crate::error::Error;
+use crate::exec::{run, run_or_record, ExecOptions};
+use crate::logging::LogArgs;
+use crate::report::Report;
+use crate::temp::temp_dir;
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::{fs, io};
+use tracing::{info, info_span, warn};
+
+/// kinds of workloads whose rollout is awaited
+const WORKLOAD_KINDS: [&str; 3] = ["Deployment", "StatefulSet", "DaemonSet"];
+/// annotation holding the revision of a Deployment, StatefulSets and DaemonSets number their ControllerRevisions
+const DEPLOYMENT_REVISION_ANNOTATION: &str = "deployment.kubernetes.io/revision";
+
+#[derive(Clone, Debug, Parser)]
+#[clap(author, version, about, long_about = None)]
+pub struct DeployArgs {
+    /// kubeconfig context to deploy to, defaults to kubectl's current context
+    #[clap(long, env = "OPS_KUBE_CONTEXT")]
+    pub context: Option<String>,
+
+    /// namespace of resources which do not declare one, defaults to the context's namespace
+    #[clap(short, long, env = "OPS_K8S_NAMESPACE")]
+    pub namespace: Option<String>,
+
+    /// manifests to apply
+    #[clap(short, long, default_value = "k8s.yaml", env = "OPS_K8S_MANIFESTS")]
+    pub manifests: PathBuf,
+
+    /// json output of ops-docker-push (`--output json`), may be passed several times: images of the pushed
+    /// repositories are deployed by the digest the push reported
+    #[clap(long, value_delimiter = '\n', env = "OPS_PUSH_OUTPUT")]
+    pub push_output: Vec<PathBuf>,
+
+    /// how long to wait for each rollout, in kubectl's duration syntax
+    #[clap(long, default_value = "5m", env = "OPS_ROLLOUT_TIMEOUT")]
+    pub rollout_timeout: String,
+
+    /// leave workloads whose rollout failed as they are instead of rolling them back
+    #[clap(long, env = "OPS_NO_ROLLBACK")]
+    pub no_rollback: bool,
+
+    #[clap(flatten)]
+    pub log: LogArgs,
+
+    /// print the kubectl commands and the manifests instead of applying them
+    /// - can also be enabled by setting the OPS_DRY_RUN environment variable
+    #[clap(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DeployOutput {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context: Option<String>,
+    pub workloads: Vec<DeployedWorkload>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DeployedWorkload {
+    pub kind: String,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    /// images of the workload's containers, by digest if they were pushed
+    pub images: Vec<String>,
+    /// whether the workload existed before, only existing workloads changed by the deploy are rolled back
+    pub existed: bool,
+    /// revision of the workload before the deploy, which it is rolled back to if a rollout fails
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_revision: Option<u64>,
+    /// `metadata.generation` of the workload before the deploy, which only changes if the deploy changed its spec
+    #[serde(skip)]
+    previous_generation: Option<u64>,
+}
+
+impl Report for DeployOutput {
+    fn human(&self) -> String {
+        let mut lines = vec![match self.context.as_ref() {
+            Some(context) => format!("deployed to {context}"),
+            None => "deployed".to_string(),
+        }];
+        for workload in &self.workloads {
+            lines.push(format!(
+                "  {}/{}: {}",
+                workload.kind.to_lowercase(),
+                workload.name,
+                workload.images.join(", ")
+            ));
+        }
+        lines.join("\n")
+    }
+}
+
+/// Push reported by ops-docker-push, possibly as part of a dry run.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+enum PushOutput {
+    Push { reference: Option<String> },
+    DryRun { result: Box<PushOutput> },
+}
+
+impl PushOutput {
+    fn reference(self) -> Option<String> {
+        match self {
+            Self::Push { reference } => reference,
+            Self::DryRun { result } => result.reference(),
+        }
+    }
+}
+
+pub fn deploy(deploy_args: DeployArgs) -> Result<DeployOutput, Error> {
+    let DeployArgs {
+        context,
+        namespace,
+        manifests,
+        push_output,
+        rollout_timeout,
+        no_rollback,
+        log: _,
+        dry_run,
+    } = deploy_args;
+
+    let _span = info_span!("deploy", context).entered();
+
+    if dry_run {
+        enable_dry_run();
+    }
+
+    let references = push_output
+        .iter()
+        .map(|path| read_push_reference(path))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut documents = read_manifests(&manifests)?;
+    for document in documents.iter_mut().filter(|document| is_workload(document)) {
+        pin_images(document, &references);
+    }
+
+    let kubectl = || {
+        let mut command = Command::new("kubectl");
+        if let Some(context) = context.as_ref() {
+            command.arg(format!("--context={context}"));
+        }
+        if let Some(namespace) = namespace.as_ref() {
+            command.arg(format!("--namespace={namespace}"));
+        }
+        command
+    };
+
+    let mut workloads = vec![];
+    for document in &documents {
+        let Some(kind) = document["kind"].as_str().filter(|kind| WORKLOAD_KINDS.contains(kind)) else {
+            continue;
+        };
+        let Some(name) = document["metadata"]["name"].as_str() else {
+            continue;
+        };
+        let namespace = document["metadata"]["namespace"].as_str().map(String::from);
+        let mut workload = DeployedWorkload {
+            kind: kind.to_string(),
+            name: name.to_string(),
+            namespace,
+            images: get_images(document),
+            existed: false,
+            previous_revision: None,
+            previous_generation: None,
+        };
+        if let Some(current) = get_workload(&kubectl, &workload) {
+            workload.existed = true;
+            workload.previous_revision = get_revision(&kubectl, &workload, &current);
+            workload.previous_generation = current["metadata"]["generation"].as_u64();
+        }
+        workloads.push(workload);
+    }
+
+    let manifests = documents
+        .iter()
+        .map(serde_yaml::to_string)
+        .collect::<Result<Vec<_>, _>>()?
+        .join("---\n");
+    let dir = temp_dir()?;
+    let manifests_path = dir.path().join("manifests.yaml");
+    write_file(&manifests_path, &manifests)?;
+    let mut apply = kubectl();
+    apply.args(["apply", "--filename"]).arg(&manifests_path);
+    run_or_record(&apply, &ExecOptions::default())?;
+
+    let workload_command = |workload: &DeployedWorkload, args: &[&str]| {
+        let mut command = kubectl();
+        command.args(
+            workload
+                .namespace
+                .iter()
+                .map(|namespace| format!("--namespace={namespace}")),
+        );
+        command.args(args);
+        command.arg(format!("{}/{}", workload.kind.to_lowercase(), workload.name));
+        command
+    };
+    let mut failed = vec![];
+    for workload in &workloads {
+        let timeout = format!("--timeout={rollout_timeout}");
+        let status = workload_command(workload, &["rollout", "status", &timeout]);
+        if let Err(err) = run_or_record(&status, &ExecOptions::default()) {
+            warn!(
+                "rollout of {}/{} failed: {err}",
+                workload.kind.to_lowercase(),
+                workload.name
+            );
+            failed.push(workload.name.clone());
+        }
+    }
+    if failed.is_empty() {
+        return Ok(DeployOutput { context, workloads });
+    }
+
+    // the deploy is rolled back as a whole, workloads it created have no previous revision to return to and
+    // workloads it left unchanged are already running their previous revision
+    let rolled_back = !no_rollback;
+    if rolled_back {
+        for workload in workloads.iter().filter(|workload| workload.existed) {
+            let generation =
+                get_workload(&kubectl, workload).and_then(|current| current["metadata"]["generation"].as_u64());
+            if generation.is_some() && generation == workload.previous_generation {
+                continue;
+            }
+            info!("rolling back {}/{}", workload.kind.to_lowercase(), workload.name);
+            let to_revision = workload
+                .previous_revision
+                .map(|revision| format!("--to-revision={revision}"));
+            let mut args = vec!["rollout", "undo"];
+            args.extend(to_revision.as_deref());
+            run_or_record(&workload_command(workload, &args), &ExecOptions::default())?;
+        }
+    }
+    Err(Error::DeployFailed {
+        workloads: failed,
+        rolled_back,
+    })
+}
+
+fn read_push_reference(path: &Path) -> Result<String, Error> {
+    let invalid = |message: String| {
+        Error::InvalidInput(format!(
+            "`{}` is not the json output of ops-docker-push: {message}",
+            path.display()
+        ))
+    };
+    serde_json::from_str::<PushOutput>(&fs::read_to_string(path)?)
+        .map_err(|err| invalid(err.to_string()))?
+        .reference()
+        .ok_or_else(|| invalid("no digest was reported for the pushed image".to_string()))
+}
+
+/// Every document of the multi-document yaml file at `path`, empty documents excluded.
+fn read_manifests(path: &Path) -> Result<Vec<Value>, Error> {
+    let text = fs::read_to_string(path).map_err(|err| match err.kind() {
+        io::ErrorKind::NotFound => Error::InvalidInput(format!(
+            "`{}` does not exist, generate it with ops-k8s-gen or pass --manifests",
+            path.display()
+        )),
+        _ => Error::Io(err),
+    })?;
+    let mut documents = vec![];
+    for document in serde_yaml::Deserializer::from_str(&text) {
+        let document = Value::deserialize(document).map_err(|err| Error::manifest(path, err.to_string()))?;
+        if !document.is_null() {
+            documents.push(document);
+        }
+    }
+    Ok(documents)
+}
+
+/// The current state of `workload` in the cluster, none if it does not exist. Read-only, so it is fetched in dry-run
+/// mode as well, without retries as a missing workload fails the request.
+fn get_workload(kubectl: &dyn Fn() -> Command, workload: &DeployedWorkload) -> Option<Value> {
+    let mut command = kubectl();
+    command.args(
+        workload
+            .namespace
+            .iter()
+            .map(|namespace| format!("--namespace={namespace}")),
+    );
+    command.args([
+        "get",
+        &format!("{}/{}", workload.kind.to_lowercase(), workload.name),
+        "--output=json",
+    ]);
+    let output = run(
+        &command,
+        &ExecOptions {
+            stream: false,
+            ..Default::default()
+        },
+    )
+    .ok()?;
+    serde_json::from_slice(&output.stdout).ok()
+}
+
+/// Revision `current`, the state of `workload` in the cluster, is at: the annotation of a Deployment, the highest
+/// revision of the ControllerRevisions a StatefulSet or DaemonSet owns.
+fn get_revision(kubectl: &dyn Fn() -> Command, workload: &DeployedWorkload, current: &Value) -> Option<u64> {
+    if workload.kind == "Deployment" {
+        return current["metadata"]["annotations"][DEPLOYMENT_REVISION_ANNOTATION]
+            .as_str()?
+            .parse()
+            .ok();
+    }
+    let uid = current["metadata"]["uid"].as_str()?;
+    let mut command = kubectl();
+    command.args(
+        workload
+            .namespace
+            .iter()
+            .map(|namespace| format!("--namespace={namespace}")),
+    );
+    command.args(["get", "controllerrevisions", "--output=json"]);
+    let output = run(
+        &command,
+        &ExecOptions {
+            stream: false,
+            ..Default::default()
+        },
+    )
+    .ok()?;
+    let revisions = serde_json::from_slice::<Value>(&output.stdout).ok()?;
+    revisions["items"]
+        .as_array()?
+        .iter()
+        .filter(|revision| {
+            revision["metadata"]["ownerReferences"]
+                .as_array()
+                .is_some_and(|owners| owners.iter().any(|owner| owner["uid"] == uid))
+        })
+        .filter_map(|revision| revision["revision"].as_u64())
+        .max()
+}
+
+fn is_workload(document: &Value) -> bool {
+    document["kind"]
+        .as_str()
+        .is_some_and(|kind| WORKLOAD_KINDS.contains(&kind))
+}
+
+/// Containers of the pod template of a workload manifest. The manifest is only walked, documents without a pod
+/// template are left untouched.
+fn get_containers(document: &mut Value) -> impl Iterator<Item = &mut Value> {
+    let spec = document
+        .get_mut("spec")
+        .and_then(|spec| spec.get_mut("template"))
+        .and_then(|template| template.get_mut("spec"));
+    let mut containers = vec![];
+    if let Some(Value::Object(spec)) = spec {
+        for (key, value) in spec.iter_mut() {
+            if let (true, Value::Array(list)) = (key == "containers" || key == "initContainers", value) {
+                containers.extend(list.iter_mut());
+            }
+        }
+    }
+    containers.into_iter()
+}
+
+/// Replaces the images of `document`'s containers whose repository was pushed with the pushed `references`
+/// (`<repository>@<digest>`).
+fn pin_images(document: &mut Value, references: &[String]) {
+    for container in get_containers(document) {
+        let Some(image) = container["image"].as_str() else {
+            continue;
+        };
+        let repository = get_repository(image);
+        let reference = references
+            .iter()
+            .find(|reference| reference.split('@').next() == Some(repository));
+        if let Some(reference) = reference {
+            container["image"] = Value::String(reference.clone());
+        }
+    }
+}
+
+fn get_images(document: &Value) -> Vec<String> {
+    let mut document = document.clone();
+    get_containers(&mut document)
+        .filter_map(|container| container["image"].as_str().map(String::from))
+        .collect()
+}
+
+/// `image` without its tag or digest.
+fn get_repository(image: &str) -> &str {
+    let image = image.split('@').next().unwrap_or(image);
+    match image.rsplit_once(':') {
+        // a colon followed by a path is the port of the registry
+        Some((repository, tag)) if !tag.contains('/') => repository,
+        _ => image,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REFERENCES: [&str; 1] = ["registry.example.com/api@sha256:0123"];
+
+    fn pin(manifest: &str) -> (String, String) {
+        let mut document: Value = serde_yaml::from_str(manifest).unwrap();
+        let before = serde_yaml::to_string(&document).unwrap();
+        pin_images(&mut document, &REFERENCES.map(String::from));
+        (before, serde_yaml::to_string(&document).unwrap())
+    }
+
+    #[test]
+    fn pins_workload_images() {
+        let (_, pinned) = pin("kind: Deployment
+metadata:
+  name: api
+spec:
+  template:
+    spec:
+      containers:
+        - name: api
+          image: registry.example.com/api:latest
+");
+        assert!(pinned.contains("image: registry.example.com/api@sha256:0123"));
+    }
+
+    #[test]
+    fn leaves_service_untouched() {
+        let (before, after) = pin("kind: Service
+metadata:
+  name: api
+spec:
+  ports:
+    - port: 80
+");
+        assert_eq!(before, after);
+    }
+
+    #[test]
+    fn leaves_config_map_untouched() {
+        let (before, after) = pin("kind: ConfigMap
+metadata:
+  name: api
+data:
+  image: registry.example.com/api:latest
+");
+        assert_eq!(before, after);
+    }
+}
@@ -0,0 +1,364 @@
+/// Generates Kubernetes manifests deploying the images built by ops-docker-build-rust-workspace-service: its json
+/// output (`--output json`) names the image and the binaries of every feature set, which become the containers of
+/// a Deployment per service, or of a Deployment per feature set. Services publishing ports also get a Service.
+use crate::cargo::Metadata;
+use crate::config::package::PackageOpsConfig;
+use crate::config::OpsConfig;
+use crate::dry_run::{enable_dry_run, write_file};
+use crate::error::Error;
+use crate::logging::LogArgs;
+use crate::report::Report;
+use crate::workspace::root::get_workspace_root;
+use clap::{Parser, ValueEnum};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::env;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use tracing::{debug, info_span};
+
+#[derive(Clone, Debug, Parser)]
+#[clap(author, version, about, long_about = None)]
+pub struct K8sGenArgs {
+    /// json output of ops-docker-build-rust-workspace-service (`--output json`) naming the images to deploy, `-`
+    /// reads it from stdin
+    #[clap(long, env = "OPS_BUILD_OUTPUT")]
+    pub build_output: PathBuf,
+
+    /// what to generate
+    #[clap(short, long, value_enum, default_value = "manifests", env = "OPS_K8S_FORMAT")]
+    pub format: K8sFormat,
+
+    /// how the binaries of several feature sets are deployed
+    #[clap(long, value_enum, default_value = "deployments", env = "OPS_K8S_VARIANTS")]
+    pub variants: K8sVariants,
+
+    /// namespace of the generated resources
+    #[clap(short, long, env = "OPS_K8S_NAMESPACE")]
+    pub namespace: Option<String>,
+
+    /// path to write the manifests to, relative to the workspace root -- defaults to `k8s.yaml`, or `values.yaml`
+    /// for helm values
+    #[clap(short, long, env = "OPS_K8S_OUTPUT")]
+    pub output_file: Option<PathBuf>,
+
+    #[clap(flatten)]
+    pub log: LogArgs,
+
+    /// print the manifests which would be written instead of writing them
+    /// - can also be enabled by setting the OPS_DRY_RUN environment variable
+    #[clap(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum K8sFormat {
+    /// Deployment and Service manifests
+    Manifests,
+    /// helm values overlay setting the image and command of every deployment
+    HelmValues,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum K8sVariants {
+    /// a Deployment per feature set, named `{service}-{feature set}` unless it is the default feature set
+    Deployments,
+    /// a single Deployment per service with a container per feature set, ports are only exposed by the first
+    Containers,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct K8sGenOutput {
+    pub path: PathBuf,
+    pub deployments: Vec<String>,
+}
+
+impl Report for K8sGenOutput {
+    fn human(&self) -> String {
+        format!("wrote {}: {}", self.path.display(), self.deployments.join(", "))
+    }
+}
+
+/// Output of ops-docker-build-rust-workspace-service, of one or several services and possibly of a dry run.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+enum BuildOutput {
+    Services { services: Vec<BuiltService> },
+    Service(BuiltService),
+    DryRun { result: Box<BuildOutput> },
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct BuiltService {
+    /// cargo package name of the service
+    service: String,
+    image: String,
+    #[serde(default)]
+    binaries: Vec<String>,
+    #[serde(default)]
+    feature_sets: Vec<BuiltFeatureSet>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct BuiltFeatureSet {
+    name: Option<String>,
+    #[serde(default)]
+    features: Vec<String>,
+    binaries: Vec<String>,
+}
+
+/// Deployment to generate, running a container per binary.
+struct Workload<'a> {
+    name: String,
+    image: &'a str,
+    binaries: Vec<&'a str>,
+    config: &'a PackageOpsConfig,
+}
+
+impl BuildOutput {
+    fn into_services(self) -> Vec<BuiltService> {
+        match self {
+            Self::Services { services } => services,
+            Self::Service(service) => vec![service],
+            Self::DryRun { result } => result.into_services(),
+        }
+    }
+}
+
+pub fn k8s_gen(k8s_gen_args: K8sGenArgs) -> Result<K8sGenOutput, Error> {
+    let K8sGenArgs {
+        build_output,
+        format,
+        variants,
+        namespace,
+        output_file,
+        log: _,
+        dry_run,
+    } = k8s_gen_args;
+
+    let _span = info_span!("k8s_gen").entered();
+
+    if dry_run {
+        enable_dry_run();
+    }
+
+    let build_output = read_build_output(&build_output)?;
+    let workspace_dir = get_workspace_root(&env::current_dir()?)?;
+    env::set_current_dir(&workspace_dir)?;
+    let config = OpsConfig::load(&workspace_dir)?;
+    let metadata = Metadata::load(&workspace_dir)?;
+
+    let mut services = vec![];
+    for built in &build_output {
+        // services are named as in ops.toml, falling back to their package name
+        let name = config
+            .services
+            .iter()
+            .find(|(name, service_config)| service_config.package_name(name) == built.service)
+            .map_or(built.service.as_str(), |(name, _)| name.as_str());
+        let package_config = match metadata.members().find(|member| member.name == built.service) {
+            Some(member) => PackageOpsConfig::load(member.dir())?,
+            None => {
+                debug!("`{}` is not a member of the cargo workspace", built.service);
+                PackageOpsConfig::default()
+            }
+        };
+        services.push((get_k8s_name(name), built, package_config));
+    }
+
+    let mut workloads = vec![];
+    for (name, built, package_config) in &services {
+        let feature_sets = match built.feature_sets.is_empty() {
+            // output of builds which did not report their feature sets
+            true => vec![(String::new(), built.binaries.iter().map(String::as_str).collect())],
+            false => built
+                .feature_sets
+                .iter()
+                .map(|feature_set| {
+                    let suffix = feature_set
+                        .name
+                        .clone()
+                        .unwrap_or_else(|| feature_set.features.join("-"));
+                    (
+                        suffix,
+                        feature_set.binaries.iter().map(String::as_str).collect::<Vec<_>>(),
+                    )
+                })
+                .collect(),
+        };
+        let names_and_binaries = match variants {
+            K8sVariants::Containers => vec![(
+                name.clone(),
+                feature_sets.into_iter().flat_map(|(_, binaries)| binaries).collect(),
+            )],
+            K8sVariants::Deployments => feature_sets
+                .into_iter()
+                .map(|(suffix, binaries)| match suffix.is_empty() {
+                    true => (name.clone(), binaries),
+                    false => (get_k8s_name(&format!("{name}-{suffix}")), binaries),
+                })
+                .collect(),
+        };
+        workloads.extend(names_and_binaries.into_iter().map(|(name, binaries)| Workload {
+            name,
+            image: &built.image,
+            binaries,
+            config: package_config,
+        }));
+    }
+
+    let (contents, default_output) = match format {
+        K8sFormat::Manifests => {
+            let documents = workloads
+                .iter()
+                .flat_map(|workload| get_manifests(workload, namespace.as_deref()))
+                .map(|manifest| serde_yaml::to_string(&manifest))
+                .collect::<Result<Vec<_>, _>>()?;
+            (documents.join("---\n"), "k8s.yaml")
+        }
+        K8sFormat::HelmValues => {
+            let values = workloads
+                .iter()
+                .map(|workload| (workload.name.clone(), get_helm_values(workload)))
+                .collect::<serde_json::Map<_, _>>();
+            let values = serde_yaml::to_string(&values)?;
+            (values, "values.yaml")
+        }
+    };
+    let path = output_file.unwrap_or_else(|| PathBuf::from(default_output));
+    write_file(&path, &contents)?;
+
+    Ok(K8sGenOutput {
+        path,
+        deployments: workloads.into_iter().map(|workload| workload.name).collect(),
+    })
+}
+
+fn read_build_output(path: &Path) -> Result<Vec<BuiltService>, Error> {
+    let text = match path == Path::new("-") {
+        true => {
+            let mut text = String::new();
+            io::stdin().read_to_string(&mut text)?;
+            text
+        }
+        false => std::fs::read_to_string(path)?,
+    };
+    let build_output = serde_json::from_str::<BuildOutput>(&text).map_err(|err| {
+        Error::InvalidInput(format!(
+            "`{}` is not the json output of ops-docker-build-rust-workspace-service: {err}",
+            path.display()
+        ))
+    })?;
+    Ok(build_output.into_services())
+}
+
+/// Deployment of `workload` and, if it publishes ports, the Service exposing them.
+fn get_manifests(workload: &Workload, namespace: Option<&str>) -> Vec<Value> {
+    let mut metadata = json!({ "name": workload.name, "labels": { "app": workload.name } });
+    if let Some(namespace) = namespace {
+        metadata["namespace"] = json!(namespace);
+    }
+    let ports = workload
+        .config
+        .docker
+        .ports
+        .iter()
+        .map(|port| get_ports(port))
+        .collect::<Vec<_>>();
+    let env = workload
+        .config
+        .docker
+        .env
+        .iter()
+        .map(|(name, value)| json!({ "name": name, "value": value }))
+        .collect::<Vec<_>>();
+    let containers = workload
+        .binaries
+        .iter()
+        .enumerate()
+        .map(|(index, binary)| {
+            let mut container = json!({
+                "name": get_k8s_name(binary.rsplit('/').next().unwrap_or(binary)),
+                "image": workload.image,
+                "command": [binary],
+            });
+            // containers of a pod share its network, so only one of them can listen on the ports
+            if index == 0 && !ports.is_empty() {
+                container["ports"] = ports
+                    .iter()
+                    .map(|(_, container_port, protocol)| {
+                        json!({ "containerPort": container_port, "protocol": protocol })
+                    })
+                    .collect();
+            }
+            if !env.is_empty() {
+                container["env"] = json!(env);
+            }
+            container
+        })
+        .collect::<Vec<_>>();
+
+    let mut manifests = vec![json!({
+        "apiVersion": "apps/v1",
+        "kind": "Deployment",
+        "metadata": metadata,
+        "spec": {
+            "selector": { "matchLabels": { "app": workload.name } },
+            "template": {
+                "metadata": { "labels": { "app": workload.name } },
+                "spec": { "containers": containers },
+            },
+        },
+    })];
+    if !ports.is_empty() {
+        let ports = ports
+            .iter()
+            .map(|(port, container_port, protocol)| {
+                json!({ "port": port, "targetPort": container_port, "protocol": protocol })
+            })
+            .collect::<Vec<_>>();
+        manifests.push(json!({
+            "apiVersion": "v1",
+            "kind": "Service",
+            "metadata": metadata,
+            "spec": { "selector": { "app": workload.name }, "ports": ports },
+        }));
+    }
+    manifests
+}
+
+/// Image and command of `workload` in the layout of common helm charts (`image.repository` and `image.tag`).
+fn get_helm_values(workload: &Workload) -> Value {
+    // the tag follows the last colon unless it is part of a registry's port
+    let (repository, tag) = match workload.image.rsplit_once(':') {
+        Some((repository, tag)) if !tag.contains('/') => (repository, tag),
+        _ => (workload.image, "latest"),
+    };
+    json!({
+        "image": { "repository": repository, "tag": tag },
+        "command": workload.binaries.first().map(|binary| vec![binary]).unwrap_or_default(),
+    })
+}
+
+/// Service port, container port and protocol of a port in `docker run --publish` syntax, e.g. `(8080, 80, "TCP")`
+/// for `127.0.0.1:8080:80/tcp`. Ports which are not published on the host are served on the container port.
+fn get_ports(port: &str) -> (u16, u16, String) {
+    let (port, protocol) = port.split_once('/').unwrap_or((port, "tcp"));
+    let mut parts = port.rsplit(':');
+    let container_port = parts.next().and_then(|port| port.parse().ok()).unwrap_or_default();
+    let published_port = parts
+        .next()
+        .and_then(|port| port.parse().ok())
+        .unwrap_or(container_port);
+    (published_port, container_port, protocol.to_uppercase())
+}
+
+/// `name` as a valid name of kubernetes resources: lower case alphanumerics and `-`.
+fn get_k8s_name(name: &str) -> String {
+    name.to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect::<String>()
+        .trim_matches('-')
+        .to_string()
+}
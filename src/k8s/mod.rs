@@ -0,0 +1,8 @@
+pub mod deploy;
+pub mod manifest;
+
+pub mod prelude {
+    use super::*;
+    pub use deploy::*;
+    pub use manifest::*;
+}
@@ -1,15 +1,64 @@
 #[macro_use]
 extern crate lazy_static;
 
+pub mod audit;
+pub mod cargo;
+pub mod ci;
+pub mod cli;
+pub mod config;
 pub mod docker;
+pub mod dry_run;
+pub mod du;
+pub mod error;
 pub mod eslint;
+pub mod exec;
 pub mod git;
+pub mod js;
+pub mod k8s;
+pub mod lock;
+pub mod logging;
+pub mod node;
+pub mod progress;
+pub mod prompt;
+pub mod report;
+pub mod snapshot;
+pub mod temp;
+pub mod timing;
+pub mod verify;
+pub mod workspace;
+pub mod workspace_audit;
 pub mod workspace_clippy;
+pub mod workspace_doc;
+pub mod workspace_fmt;
+pub mod workspace_run;
+pub mod workspace_test;
 
 pub mod prelude {
     use super::*;
+    pub use audit::*;
+    pub use cargo::prelude::*;
+    pub use ci::prelude::*;
+    pub use config::prelude::*;
     pub use docker::prelude::*;
+    pub use dry_run::{enable_dry_run, is_dry_run, print_plan, take_plan, Plan, PlanStep, OPS_DRY_RUN_ENV};
+    pub use du::*;
     pub use eslint::*;
     pub use git::prelude::*;
+    pub use js::prelude::*;
+    pub use k8s::prelude::*;
+    pub use lock::*;
+    pub use logging::*;
+    pub use node::prelude::*;
+    pub use progress::*;
+    pub use report::*;
+    pub use snapshot::*;
+    pub use timing::*;
+    pub use verify::*;
+    pub use workspace::prelude::*;
+    pub use workspace_audit::*;
     pub use workspace_clippy::*;
+    pub use workspace_doc::*;
+    pub use workspace_fmt::*;
+    pub use workspace_run::*;
+    pub use workspace_test::*;
 }
@@ -0,0 +1,301 @@
+/// Advisory locks which serialize builds and pushes of the same image so that concurrent invocations (e.g. retried
+/// CI jobs) cannot interleave and leave a tag pointing at content other than what was built. Locks are always
+/// taken on this machine through an OS file lock, which is released even if ops is killed, and can additionally be
+/// taken in S3 to serialize runners which don't share a docker daemon.
+use crate::config::OpsConfig;
+use crate::dry_run::is_dry_run;
+use crate::error::Error;
+use crate::exec::{read, run, ExecOptions};
+use crate::temp::temp_dir;
+use clap::Args;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, Write};
+use std::process::{self, Command};
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, warn};
+
+/// name of the directory in the system's temporary directory holding the local lock files
+pub const OPS_LOCK_DIR_NAME: &str = "ops-locks";
+
+/// remote locks older than this are considered abandoned (e.g. by a runner which was shut down) and are broken
+const DEFAULT_REMOTE_TTL: Duration = Duration::from_secs(60 * 60);
+
+const LOCAL_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const REMOTE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug, Default, Args)]
+pub struct LockArgs {
+    /// don't lock the image against concurrent builds and pushes
+    #[clap(long, env = "OPS_NO_LOCK")]
+    pub no_lock: bool,
+
+    /// seconds to wait for a concurrent build or push of the same image to finish before failing, defaults to
+    /// waiting indefinitely
+    #[clap(long, env = "OPS_LOCK_TIMEOUT")]
+    pub lock_timeout: Option<u64>,
+}
+
+/// Held lock, released once dropped.
+pub struct LockGuard {
+    _file: File,
+    remote: Option<RemoteLock>,
+}
+
+struct RemoteLock {
+    bucket: String,
+    key: String,
+    etag: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct PutObjectOutput {
+    e_tag: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HeadObjectOutput {
+    e_tag: String,
+    #[serde(default)]
+    metadata: RemoteLockMetadata,
+}
+
+#[derive(Default, Deserialize)]
+struct RemoteLockMetadata {
+    holder: Option<String>,
+    /// unix timestamp after which the lock may be broken
+    expires: Option<String>,
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        if let Some(RemoteLock { bucket, key, etag }) = self.remote.take() {
+            // the lock is only deleted if it is still ours, it may have been broken after expiring
+            let result = read(Command::new("aws").args([
+                "s3api",
+                "delete-object",
+                "--bucket",
+                &bucket,
+                "--key",
+                &key,
+                "--if-match",
+                &etag,
+            ]));
+            if let Err(err) = result {
+                warn!("failed to release lock s3://{bucket}/{key}: {err}");
+            }
+        }
+        // the file lock is released when the file is closed
+    }
+}
+
+/// Locks `name` (e.g. an image reference) against concurrent builds and pushes, `None` if locking is disabled or
+/// in dry-run mode where nothing is built or pushed.
+pub fn lock(name: &str, lock_args: &LockArgs) -> Result<Option<LockGuard>, Error> {
+    let LockArgs { no_lock, lock_timeout } = lock_args;
+    if *no_lock || is_dry_run() {
+        return Ok(None);
+    }
+    let deadline = lock_timeout.map(|timeout| Instant::now() + Duration::from_secs(timeout));
+    let holder = get_holder();
+    let file = lock_local(name, &holder, deadline)?;
+
+    let config = OpsConfig::discover(&env::current_dir()?)?.lock;
+    let remote = match config.remote.as_deref() {
+        Some(remote) => {
+            let ttl = config.ttl.map(Duration::from_secs).unwrap_or(DEFAULT_REMOTE_TTL);
+            Some(lock_remote(remote, name, &holder, ttl, deadline)?)
+        }
+        None => None,
+    };
+    Ok(Some(LockGuard { _file: file, remote }))
+}
+
+fn lock_local(name: &str, holder: &str, deadline: Option<Instant>) -> Result<File, Error> {
+    let dir = env::temp_dir().join(OPS_LOCK_DIR_NAME);
+    fs::create_dir_all(&dir)?;
+    // lock files are never removed, removing a file another process is waiting on would let a third one in
+    let path = dir.join(format!("{}.lock", get_lock_name(name)));
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)?;
+
+    let mut waiting = false;
+    loop {
+        match file.try_lock() {
+            Ok(()) => break,
+            Err(TryLockError::WouldBlock) => {}
+            Err(TryLockError::Error(err)) => return Err(err.into()),
+        }
+        if !waiting {
+            info!("waiting for {name} to be unlocked by {}", read_holder(&mut file));
+            waiting = true;
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(Error::LockUnavailable {
+                name: name.to_string(),
+                holder: read_holder(&mut file),
+            });
+        }
+        sleep(LOCAL_POLL_INTERVAL);
+    }
+
+    file.set_len(0)?;
+    file.rewind()?;
+    file.write_all(holder.as_bytes())?;
+    debug!("locked {name} in {}", path.display());
+    Ok(file)
+}
+
+/// Holder recorded in a local lock file, unknown if the file cannot be read while locked (as on windows).
+fn read_holder(file: &mut File) -> String {
+    let mut holder = String::new();
+    match file.rewind().and_then(|_| file.read_to_string(&mut holder)) {
+        Ok(_) if !holder.trim().is_empty() => holder.trim().to_string(),
+        _ => "another process".to_string(),
+    }
+}
+
+/// Takes the lock by creating an object which must not exist yet, relying on S3's conditional writes.
+fn lock_remote(
+    remote: &str,
+    name: &str,
+    holder: &str,
+    ttl: Duration,
+    deadline: Option<Instant>,
+) -> Result<RemoteLock, Error> {
+    let location = remote
+        .strip_prefix("s3://")
+        .ok_or_else(|| Error::InvalidInput(format!("lock remote `{remote}` is not an `s3://` location")))?;
+    let (bucket, prefix) = location.split_once('/').unwrap_or((location, ""));
+    let key = format!("{}/{}.lock", prefix.trim_end_matches('/'), get_lock_name(name))
+        .trim_start_matches('/')
+        .to_string();
+
+    let body_dir = temp_dir()?;
+    let body = body_dir.path().join("lock");
+    fs::write(&body, holder)?;
+
+    let mut waiting = false;
+    loop {
+        let expires = unix_time() + ttl.as_secs();
+        let mut command = Command::new("aws");
+        command
+            .args([
+                "s3api",
+                "put-object",
+                "--bucket",
+                bucket,
+                "--key",
+                &key,
+                "--if-none-match",
+                "*",
+            ])
+            .arg("--body")
+            .arg(&body)
+            .arg("--metadata")
+            .arg(serde_json::json!({ "holder": holder, "expires": expires.to_string() }).to_string());
+        let options = ExecOptions {
+            stream: false,
+            ..Default::default()
+        };
+        match run(&command, &options) {
+            Ok(output) => {
+                let PutObjectOutput { e_tag } = serde_json::from_slice(&output.stdout)?;
+                debug!("locked {name} in s3://{bucket}/{key}");
+                return Ok(RemoteLock {
+                    bucket: bucket.to_string(),
+                    key,
+                    etag: e_tag,
+                });
+            }
+            Err(Error::CommandFailed { stderr, .. })
+                if stderr.contains("PreconditionFailed") || stderr.contains("ConditionalRequestConflict") => {}
+            Err(err) => return Err(err),
+        }
+
+        let current = match read(Command::new("aws").args(["s3api", "head-object", "--bucket", bucket, "--key", &key]))
+        {
+            Ok(current) => serde_json::from_str::<HeadObjectOutput>(&current)?,
+            // released in the meantime
+            Err(_) => continue,
+        };
+        let current_holder = current.metadata.holder.unwrap_or_else(|| "unknown".to_string());
+        let expired = current
+            .metadata
+            .expires
+            .and_then(|expires| expires.parse::<u64>().ok())
+            .is_some_and(|expires| expires < unix_time());
+        if expired {
+            warn!("breaking expired lock on {name} held by {current_holder}");
+            read(Command::new("aws").args([
+                "s3api",
+                "delete-object",
+                "--bucket",
+                bucket,
+                "--key",
+                &key,
+                "--if-match",
+                &current.e_tag,
+            ]))
+            .ok();
+            continue;
+        }
+        if !waiting {
+            info!("waiting for {name} to be unlocked by {current_holder}");
+            waiting = true;
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(Error::LockUnavailable {
+                name: name.to_string(),
+                holder: current_holder,
+            });
+        }
+        sleep(REMOTE_POLL_INTERVAL);
+    }
+}
+
+/// Human readable identity of this process: user, host and process id.
+fn get_holder() -> String {
+    let user = ["USER", "USERNAME"]
+        .into_iter()
+        .find_map(|name| env::var(name).ok())
+        .unwrap_or_else(|| "unknown".to_string());
+    let host = ["HOSTNAME", "COMPUTERNAME"]
+        .into_iter()
+        .find_map(|name| env::var(name).ok())
+        .unwrap_or_else(|| "localhost".to_string());
+    format!("{user}@{host} (pid {})", process::id())
+}
+
+/// File name safe lock name: the readable part of `name` followed by a hash of it so that names which only differ
+/// in replaced characters don't collide.
+fn get_lock_name(name: &str) -> String {
+    let readable = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' { c } else { '_' })
+        .collect::<String>();
+    let hash = Sha256::digest(name.as_bytes());
+    format!(
+        "{readable}-{}",
+        hash.iter()
+            .take(4)
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>()
+    )
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
@@ -0,0 +1,240 @@
+/// Shared logging configuration: every command logs through `tracing` and the binaries install a subscriber
+/// whose level and output format are controlled by the `-v/-vv/-q` and `--log-format` flags. `--color` controls
+/// colored output of logs, reports and forwarded subprocess output alike, `--output` the format of reports.
+use crate::config::export_config_defaults;
+use crate::error::{Error, ErrorFormat};
+use crate::prompt::set_prompt_mode;
+use crate::report::ReportFormat;
+use crate::timing::{enable_timings, start_timings, TimingsFormat};
+use clap::{ArgAction, ArgMatches, Args, FromArgMatches, Parser, ValueEnum};
+use colored::Colorize;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::OnceLock;
+use std::{env, fmt};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::registry::LookupSpan;
+
+static INTERACTIVE: AtomicBool = AtomicBool::new(false);
+static ERROR_FORMAT: AtomicU8 = AtomicU8::new(ErrorFormat::Human as u8);
+static OUTPUT_FORMAT: AtomicU8 = AtomicU8::new(ReportFormat::Human as u8);
+static QUIET: AtomicBool = AtomicBool::new(false);
+static COLOR: OnceLock<bool> = OnceLock::new();
+
+#[derive(Clone, Debug, Default, Args)]
+pub struct LogArgs {
+    /// increase logging verbosity: `-v` logs commands prior to running them, `-vv` logs everything
+    #[clap(short, long, action = ArgAction::Count, env = "OPS_VERBOSE")]
+    pub verbose: u8,
+
+    /// only output errors and machine-readable results: informational logs, human readable summaries and the
+    /// output of subprocesses (unless they fail) are suppressed
+    #[clap(short, long, conflicts_with = "verbose", env = "OPS_QUIET")]
+    pub quiet: bool,
+
+    /// when to use colors: `auto` colors output written to a terminal unless NO_COLOR is set (CLICOLOR=0 and
+    /// CLICOLOR_FORCE are also respected)
+    #[clap(long, value_enum, default_value = "auto", env = "OPS_COLOR")]
+    pub color: ColorChoice,
+
+    /// format of the command's result printed to stdout: json prints the structured result (including the dry-run
+    /// plan) rather than a human readable summary, forwards subprocess output to stderr and implies
+    /// `--error-format json`
+    #[clap(long, value_enum, default_value = "human", env = "OPS_OUTPUT")]
+    pub output: ReportFormat,
+
+    /// format of log output, logs are always written to stderr
+    #[clap(long, value_enum, default_value = "human", env = "OPS_LOG_FORMAT")]
+    pub log_format: LogFormat,
+
+    /// format of the error printed on failure, json prints a single object with the error's kind, message
+    /// and exit code
+    #[clap(long, value_enum, default_value = "human", env = "OPS_ERROR_FORMAT")]
+    pub error_format: ErrorFormat,
+
+    /// answer yes to confirmations and accept the default of every prompt
+    #[clap(short, long, env = "OPS_YES")]
+    pub yes: bool,
+
+    /// never prompt for missing inputs, also implied when the CI environment variable is set
+    #[clap(long, env = "OPS_NON_INTERACTIVE")]
+    pub non_interactive: bool,
+
+    /// report the time spent in each phase (git diff, dependency resolution, docker builds, pushes) on exit:
+    /// `--timings` prints a summary table to stderr, `--timings=json` and `--timings=html` write a report file
+    #[clap(
+        long,
+        value_enum,
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "summary",
+        env = "OPS_TIMINGS"
+    )]
+    pub timings: Option<TimingsFormat>,
+
+    /// file the json or html timings report is written to, defaults to `ops-timings.json` or `ops-timings.html`
+    #[clap(long, env = "OPS_TIMINGS_FILE")]
+    pub timings_file: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum LogFormat {
+    #[default]
+    Human,
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum ColorChoice {
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// Whether output should be colored, following the NO_COLOR and CLICOLOR conventions in `auto` mode.
+    pub fn use_color(&self) -> bool {
+        let is_set = |name| env::var_os(name).map(|value| !value.is_empty()).unwrap_or_default();
+        match self {
+            Self::Always => true,
+            Self::Never => false,
+            Self::Auto if is_set("NO_COLOR") => false,
+            Self::Auto if is_set("CLICOLOR_FORCE") && env::var_os("CLICOLOR_FORCE").unwrap() != "0" => true,
+            Self::Auto if env::var_os("CLICOLOR").map(|value| value == "0").unwrap_or_default() => false,
+            Self::Auto => std::io::stdout().is_terminal() && std::io::stderr().is_terminal(),
+        }
+    }
+}
+
+impl LogArgs {
+    pub fn level(&self) -> Level {
+        match (self.quiet, self.verbose) {
+            (true, _) => Level::ERROR,
+            (false, 0) => Level::INFO,
+            (false, 1) => Level::DEBUG,
+            (false, _) => Level::TRACE,
+        }
+    }
+}
+
+/// Whether human readable logs are being written to a terminal, in which case interactive output such as
+/// progress bars may be drawn.
+pub fn is_interactive() -> bool {
+    INTERACTIVE.load(Ordering::SeqCst)
+}
+
+/// Whether only errors and machine-readable results should be output.
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::SeqCst)
+}
+
+/// Whether output (including that of subprocesses) should be colored.
+pub fn use_color() -> bool {
+    *COLOR.get_or_init(|| ColorChoice::Auto.use_color())
+}
+
+/// Format in which a failing binary prints its error, see [`crate::error::exit`].
+pub fn error_format() -> ErrorFormat {
+    match ERROR_FORMAT.load(Ordering::SeqCst) {
+        x if x == ErrorFormat::Json as u8 => ErrorFormat::Json,
+        _ => ErrorFormat::Human,
+    }
+}
+
+/// Format in which a binary prints its result, see [`crate::report::report`].
+pub fn output_format() -> ReportFormat {
+    match OUTPUT_FORMAT.load(Ordering::SeqCst) {
+        x if x == ReportFormat::Json as u8 => ReportFormat::Json,
+        _ => ReportFormat::Human,
+    }
+}
+
+/// Installs a global tracing subscriber configured by `log_args`. Does nothing if a subscriber has
+/// already been installed (e.g. by a library consumer).
+pub fn init_logging(log_args: &LogArgs) {
+    start_timings();
+    if let Some(timings) = log_args.timings {
+        enable_timings(timings, log_args.timings_file.clone());
+    }
+    INTERACTIVE.store(
+        log_args.log_format == LogFormat::Human && !log_args.quiet && std::io::stderr().is_terminal(),
+        Ordering::SeqCst,
+    );
+    let error_format = match log_args.output {
+        ReportFormat::Json => ErrorFormat::Json,
+        ReportFormat::Human => log_args.error_format,
+    };
+    ERROR_FORMAT.store(error_format as u8, Ordering::SeqCst);
+    OUTPUT_FORMAT.store(log_args.output as u8, Ordering::SeqCst);
+    QUIET.store(log_args.quiet, Ordering::SeqCst);
+    let color = *COLOR.get_or_init(|| log_args.color.use_color());
+    colored::control::set_override(color);
+    set_prompt_mode(log_args.yes, log_args.non_interactive);
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(log_args.level())
+        .with_ansi(color)
+        .with_writer(std::io::stderr);
+    match log_args.log_format {
+        LogFormat::Human => builder.event_format(HumanFormat).try_init().ok(),
+        LogFormat::Json => builder.json().try_init().ok(),
+    };
+}
+
+/// Parses the command line arguments of a binary and initializes logging from the [`LogArgs`] of the
+/// (sub)command which was invoked.
+pub fn parse<T: Parser>() -> Result<T, Error> {
+    let command = T::command();
+    // a first lenient pass determines the invoked subcommand so that its ops.toml defaults can be applied
+    if let Ok(matches) = command.clone().ignore_errors(true).try_get_matches() {
+        export_config_defaults(&bin_name(), &command, &matches)?;
+    }
+    // clap reads environment variables when args are defined so the command is rebuilt after exporting defaults
+    let matches = T::command().get_matches();
+    init_logging(&LogArgs::from_arg_matches(leaf_matches(&matches)).unwrap_or_default());
+    Ok(T::from_arg_matches(&matches).unwrap_or_else(|err| err.exit()))
+}
+
+/// File name of the running binary, e.g. `ops-docker-build`.
+pub fn bin_name() -> String {
+    env::args_os()
+        .next()
+        .and_then(|arg| {
+            Path::new(&arg)
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+        })
+        .unwrap_or_else(|| "ops".to_string())
+}
+
+fn leaf_matches(matches: &ArgMatches) -> &ArgMatches {
+    match matches.subcommand() {
+        Some((_, matches)) => leaf_matches(matches),
+        None => matches,
+    }
+}
+
+/// Plain message-only log lines which mimic regular command output: debug and trace events are dimmed,
+/// warnings and errors are prefixed with their level.
+struct HumanFormat;
+
+impl<S, N> FormatEvent<S, N> for HumanFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(&self, ctx: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_>) -> fmt::Result {
+        let mut message = String::new();
+        ctx.format_fields(Writer::new(&mut message), event)?;
+        let message = match *event.metadata().level() {
+            Level::ERROR => format!("{} {message}", "error:".red().bold()),
+            Level::WARN => format!("{} {message}", "warning:".yellow().bold()),
+            Level::INFO => message,
+            Level::DEBUG | Level::TRACE => message.dimmed().to_string(),
+        };
+        writeln!(writer, "{message}")
+    }
+}
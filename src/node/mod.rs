@@ -0,0 +1,11 @@
+pub mod package_manager;
+pub mod workspace;
+
+pub use package_manager::*;
+pub use workspace::*;
+
+pub mod prelude {
+    use super::*;
+    pub use package_manager::*;
+    pub use workspace::*;
+}
@@ -0,0 +1,141 @@
+/// Package managers of node workspaces and the commands ops runs them with when building images.
+use crate::error::Error;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::Path;
+use tracing::debug;
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum PackageManager {
+    Npm,
+    /// yarn classic (1.x)
+    Yarn,
+    /// yarn 2 and later
+    YarnBerry,
+    Pnpm,
+}
+
+impl PackageManager {
+    /// Detects the package manager of the workspace rooted at `workspace_dir` from the `packageManager` field of
+    /// its package.json (`package_manager_field`), falling back to the lockfile checked into the workspace.
+    pub fn detect(workspace_dir: &Path, package_manager_field: Option<&str>) -> Result<Self, Error> {
+        let yarn = || match workspace_dir.join(".yarnrc.yml").exists() {
+            true => Self::YarnBerry,
+            false => Self::Yarn,
+        };
+        if let Some(field) = package_manager_field {
+            let (name, version) = field.split_once('@').unwrap_or((field, ""));
+            let package_manager = match name {
+                "npm" => Some(Self::Npm),
+                "pnpm" => Some(Self::Pnpm),
+                "yarn" => match version.split('.').next().and_then(|major| major.parse::<u32>().ok()) {
+                    Some(1) => Some(Self::Yarn),
+                    Some(_) => Some(Self::YarnBerry),
+                    None => Some(yarn()),
+                },
+                _ => None,
+            };
+            if let Some(package_manager) = package_manager {
+                debug!("using {package_manager} from the `packageManager` field of package.json");
+                return Ok(package_manager);
+            }
+        }
+        for (lockfile, package_manager) in [
+            ("pnpm-lock.yaml", Self::Pnpm),
+            ("yarn.lock", yarn()),
+            ("package-lock.json", Self::Npm),
+        ] {
+            if workspace_dir.join(lockfile).exists() {
+                debug!("using {package_manager} as the workspace contains a {lockfile}");
+                return Ok(package_manager);
+            }
+        }
+        Err(Error::InvalidInput(format!(
+            "unable to detect the package manager of `{}`: no `packageManager` field in package.json and no lockfile found, pass --package-manager",
+            workspace_dir.display()
+        )))
+    }
+
+    /// Executable of the package manager.
+    pub fn program(&self) -> &'static str {
+        match self {
+            Self::Npm => "npm",
+            Self::Yarn | Self::YarnBerry => "yarn",
+            Self::Pnpm => "pnpm",
+        }
+    }
+
+    pub fn lockfile(&self) -> &'static str {
+        match self {
+            Self::Npm => "package-lock.json",
+            Self::Yarn | Self::YarnBerry => "yarn.lock",
+            Self::Pnpm => "pnpm-lock.yaml",
+        }
+    }
+
+    /// Workspace level files (besides package.json and the lockfile) which configure installs, copied into images
+    /// if they exist.
+    pub fn config_files(&self) -> &'static [&'static str] {
+        match self {
+            Self::Npm => &[".npmrc"],
+            Self::Yarn => &[".npmrc", ".yarnrc"],
+            Self::YarnBerry => &[".yarnrc.yml", ".yarn/releases", ".yarn/plugins", ".yarn/patches"],
+            Self::Pnpm => &[".npmrc", "pnpm-workspace.yaml", ".pnpmfile.cjs", "patches"],
+        }
+    }
+
+    /// Instruction making the package manager available in the official node images, which only ship npm and yarn
+    /// classic.
+    pub fn setup(&self) -> Option<&'static str> {
+        match self {
+            Self::Npm | Self::Yarn => None,
+            Self::YarnBerry | Self::Pnpm => Some("RUN corepack enable"),
+        }
+    }
+
+    /// Command installing the dependencies of every workspace package exactly as locked.
+    pub fn install(&self) -> &'static str {
+        match self {
+            Self::Npm => "npm ci",
+            Self::Yarn => "yarn install --frozen-lockfile",
+            Self::YarnBerry => "yarn install --immutable",
+            Self::Pnpm => "pnpm install --frozen-lockfile",
+        }
+    }
+
+    /// Command prefix running a binary installed in the workspace's node_modules, never installing it on the fly.
+    pub fn exec(&self) -> &'static [&'static str] {
+        match self {
+            Self::Npm => &["npm", "exec", "--no", "--"],
+            Self::Yarn | Self::YarnBerry => &["yarn"],
+            Self::Pnpm => &["pnpm", "exec"],
+        }
+    }
+
+    /// Command running `script` of the package in the current directory.
+    pub fn run(&self, script: &str) -> String {
+        format!("{} run {script}", self.program())
+    }
+
+    /// Command removing dev dependencies after the build, none if the package manager cannot do so in place.
+    pub fn prune(&self) -> Option<&'static str> {
+        match self {
+            Self::Npm => Some("npm prune --omit=dev"),
+            Self::Yarn => Some("yarn install --frozen-lockfile --production --ignore-scripts --prefer-offline"),
+            // `yarn workspaces focus --production` requires the workspace-tools plugin before yarn 4
+            Self::YarnBerry => None,
+            Self::Pnpm => Some("pnpm prune --prod"),
+        }
+    }
+}
+
+impl fmt::Display for PackageManager {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::YarnBerry => write!(f, "yarn (berry)"),
+            package_manager => write!(f, "{}", package_manager.program()),
+        }
+    }
+}
@@ -0,0 +1,235 @@
+/// Model of a node monorepo's workspace packages (declared through `workspaces` in the root package.json or
+/// `packages` in pnpm-workspace.yaml) and the dependencies between them, the node counterpart of
+/// [`crate::cargo::WorkspaceGraph`].
+use crate::cargo::expand_member_pattern;
+use crate::error::Error;
+use crate::node::package_manager::PackageManager;
+use crate::timing::{time, Phase};
+use path_absolutize::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+/// dependency fields whose workspace packages are needed to build a package, peer dependencies are provided by the
+/// packages depending on it
+const DEPENDENCY_FIELDS: [&str; 3] = ["dependencies", "devDependencies", "optionalDependencies"];
+
+#[derive(Clone, Debug, Serialize)]
+pub struct NodePackage {
+    pub name: String,
+    /// directory of the package relative to the workspace root
+    pub path: PathBuf,
+    /// names of the workspace packages this package depends on
+    pub dependencies: BTreeSet<String>,
+    /// names of the scripts declared in the package's package.json
+    pub scripts: BTreeSet<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct NodeWorkspace {
+    root: PathBuf,
+    /// value of the root package.json's `packageManager` field
+    package_manager: Option<String>,
+    packages: BTreeMap<String, NodePackage>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct PackageJson {
+    package_manager: Option<String>,
+    workspaces: Option<PackageJsonWorkspaces>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+enum PackageJsonWorkspaces {
+    Patterns(Vec<String>),
+    /// yarn classic's `{ "packages": [...], "nohoist": [...] }` form
+    Config {
+        #[serde(default)]
+        packages: Vec<String>,
+    },
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+struct PnpmWorkspace {
+    packages: Vec<String>,
+}
+
+impl NodeWorkspace {
+    /// Loads every package of the workspace rooted at `workspace_dir`, a workspace without any workspace patterns
+    /// consists of the root package only.
+    pub fn load(workspace_dir: &Path) -> Result<Self, Error> {
+        time(Phase::DependencyResolution, "node workspace", || {
+            Self::load_packages(workspace_dir)
+        })
+    }
+
+    fn load_packages(workspace_dir: &Path) -> Result<Self, Error> {
+        let root_manifest_path = workspace_dir.join("package.json");
+        let root_manifest = read_package_json(&root_manifest_path)?;
+
+        let (patterns_path, patterns) = get_workspace_patterns(workspace_dir, &root_manifest)?;
+        let (excluded, included): (Vec<_>, Vec<_>) = patterns.iter().partition(|pattern| pattern.starts_with('!'));
+        let excluded = excluded
+            .into_iter()
+            .map(|pattern| expand_member_pattern(workspace_dir, &patterns_path, &pattern[1..]))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .flatten()
+            .collect::<BTreeSet<_>>();
+
+        let mut package_paths = BTreeSet::from([PathBuf::new()]);
+        for pattern in included {
+            for package_path in expand_member_pattern(workspace_dir, &patterns_path, pattern)? {
+                if excluded.contains(&package_path) {
+                    debug!("excluding workspace package {}", package_path.display());
+                    continue;
+                }
+                if workspace_dir.join(&package_path).join("package.json").exists() {
+                    package_paths.insert(package_path);
+                }
+            }
+        }
+
+        let mut manifests = vec![];
+        for package_path in package_paths {
+            let manifest_path = workspace_dir.join(&package_path).join("package.json");
+            let manifest = fs::read_to_string(&manifest_path)?;
+            let manifest = serde_json::from_str::<serde_json::Value>(&manifest)
+                .map_err(|err| Error::manifest(&manifest_path, err.to_string()))?;
+            let name = match manifest.get("name").and_then(serde_json::Value::as_str) {
+                Some(name) => name.to_string(),
+                // unnamed packages (usually the workspace root) cannot be depended on
+                None if package_path.as_os_str().is_empty() => continue,
+                None => return Err(Error::manifest(&manifest_path, "missing string key `name`")),
+            };
+            manifests.push((name, package_path, manifest));
+        }
+
+        let package_names = manifests
+            .iter()
+            .map(|(name, _, _)| name.clone())
+            .collect::<BTreeSet<_>>();
+        let packages = manifests
+            .into_iter()
+            .map(|(name, path, manifest)| {
+                let dependencies = DEPENDENCY_FIELDS
+                    .iter()
+                    .filter_map(|field| manifest.get(field).and_then(serde_json::Value::as_object))
+                    .flat_map(|dependencies| dependencies.keys())
+                    .filter(|dependency| package_names.contains(*dependency) && **dependency != name)
+                    .cloned()
+                    .collect();
+                let scripts = manifest
+                    .get("scripts")
+                    .and_then(serde_json::Value::as_object)
+                    .map(|scripts| scripts.keys().cloned().collect())
+                    .unwrap_or_default();
+                let package = NodePackage {
+                    name: name.clone(),
+                    path,
+                    dependencies,
+                    scripts,
+                };
+                (name, package)
+            })
+            .collect();
+
+        Ok(Self {
+            root: workspace_dir.to_path_buf(),
+            package_manager: root_manifest.package_manager,
+            packages,
+        })
+    }
+
+    /// Directory of the workspace root.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Every workspace package by name.
+    pub fn packages(&self) -> &BTreeMap<String, NodePackage> {
+        &self.packages
+    }
+
+    pub fn package(&self, name: &str) -> Option<&NodePackage> {
+        self.packages.get(name)
+    }
+
+    /// Package whose directory is `path` (relative to the workspace root).
+    pub fn package_at(&self, path: &Path) -> Option<&NodePackage> {
+        self.packages.values().find(|package| package.path == path)
+    }
+
+    /// Detects the workspace's package manager, see [`PackageManager::detect`].
+    pub fn package_manager(&self) -> Result<PackageManager, Error> {
+        PackageManager::detect(&self.root, self.package_manager.as_deref())
+    }
+
+    /// `name` and every workspace package it transitively depends on, ordered so that each package comes after its
+    /// dependencies.
+    pub fn build_order(&self, name: &str) -> Vec<&NodePackage> {
+        let mut ordered = vec![];
+        let mut visited = BTreeSet::default();
+        self.visit(name, &mut visited, &mut ordered);
+        ordered
+    }
+
+    fn visit<'a>(&'a self, name: &str, visited: &mut BTreeSet<&'a str>, ordered: &mut Vec<&'a NodePackage>) {
+        let Some(package) = self.packages.get(name) else {
+            return;
+        };
+        if !visited.insert(&package.name) {
+            return;
+        }
+        for dependency in package.dependencies.iter() {
+            self.visit(dependency, visited, ordered);
+        }
+        ordered.push(package);
+    }
+}
+
+/// Root of the node workspace containing `dir`: the closest ancestor whose package.json declares `workspaces` or
+/// which contains a pnpm-workspace.yaml, otherwise the closest ancestor containing a package.json.
+pub fn get_node_workspace_root(dir: &Path) -> Result<PathBuf, Error> {
+    let dir = dir.absolutize()?.to_path_buf();
+    for ancestor in dir.ancestors() {
+        if ancestor.join("pnpm-workspace.yaml").exists() {
+            return Ok(ancestor.to_path_buf());
+        }
+        let manifest_path = ancestor.join("package.json");
+        if manifest_path.exists() && read_package_json(&manifest_path)?.workspaces.is_some() {
+            return Ok(ancestor.to_path_buf());
+        }
+    }
+    dir.ancestors()
+        .find(|ancestor| ancestor.join("package.json").exists())
+        .map(Path::to_path_buf)
+        .ok_or_else(|| Error::InvalidInput(format!("`{}` is not within a node package", dir.display())))
+}
+
+fn read_package_json(manifest_path: &Path) -> Result<PackageJson, Error> {
+    serde_json::from_str(&fs::read_to_string(manifest_path)?)
+        .map_err(|err| Error::manifest(manifest_path, err.to_string()))
+}
+
+/// Workspace patterns of the workspace rooted at `workspace_dir` along with the file declaring them, pnpm reads them
+/// from pnpm-workspace.yaml instead of package.json.
+fn get_workspace_patterns(workspace_dir: &Path, root_manifest: &PackageJson) -> Result<(PathBuf, Vec<String>), Error> {
+    let pnpm_workspace_path = workspace_dir.join("pnpm-workspace.yaml");
+    if pnpm_workspace_path.exists() {
+        let pnpm_workspace = serde_yaml::from_str::<PnpmWorkspace>(&fs::read_to_string(&pnpm_workspace_path)?)
+            .map_err(|err| Error::manifest(&pnpm_workspace_path, err.to_string()))?;
+        return Ok((pnpm_workspace_path, pnpm_workspace.packages));
+    }
+    let patterns = match root_manifest.workspaces.clone() {
+        Some(PackageJsonWorkspaces::Patterns(patterns)) => patterns,
+        Some(PackageJsonWorkspaces::Config { packages }) => packages,
+        None => vec![],
+    };
+    Ok((workspace_dir.join("package.json"), patterns))
+}
@@ -0,0 +1,130 @@
+/// Progress reporting for long running multi-step operations: draws a progress bar while attached to a
+/// terminal, falling back to plain log lines otherwise.
+use crate::error::Error;
+use crate::logging::is_interactive;
+use crate::report::serialize_duration;
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::Serialize;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+pub struct Progress {
+    len: usize,
+    bar: Option<ProgressBar>,
+    rows: Vec<SummaryRow>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SummaryRow {
+    pub task: String,
+    pub succeeded: bool,
+    #[serde(serialize_with = "serialize_duration")]
+    pub duration: Duration,
+}
+
+impl Progress {
+    /// Creates a new progress reporter for `len` steps.
+    pub fn new(title: impl Into<String>, len: usize) -> Self {
+        let title: String = title.into();
+        let bar = is_interactive().then(|| {
+            let bar = ProgressBar::new(len as u64);
+            bar.set_style(
+                ProgressStyle::with_template("{prefix:.bold} [{bar:30.cyan/blue}] {pos}/{len} {wide_msg} ({elapsed})")
+                    .unwrap()
+                    .progress_chars("=> "),
+            );
+            bar.set_prefix(title);
+            bar
+        });
+        Self { len, bar, rows: vec![] }
+    }
+
+    /// Runs a single step, hiding the progress bar while it runs so that output of child processes is not
+    /// interleaved with the bar, and records its outcome in the summary.
+    pub fn run<T>(&mut self, task: impl Into<String>, f: impl FnOnce() -> Result<T, Error>) -> Result<T, Error> {
+        let task = task.into();
+        let start = Instant::now();
+        let result = match self.bar.as_ref() {
+            Some(bar) => {
+                bar.set_message(task.clone());
+                bar.suspend(f)
+            }
+            None => {
+                info!("[{}/{}] {task}", self.rows.len() + 1, self.len);
+                f()
+            }
+        };
+        self.rows.push(SummaryRow {
+            task,
+            succeeded: result.is_ok(),
+            duration: start.elapsed(),
+        });
+        if let Some(bar) = self.bar.as_ref() {
+            bar.inc(1);
+        }
+        result
+    }
+
+    /// Outcomes of the steps run so far.
+    pub fn rows(&self) -> &[SummaryRow] {
+        &self.rows
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        if let Some(bar) = self.bar.take() {
+            bar.finish_and_clear();
+        }
+    }
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 60 {
+        format!("{}m {:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{:.1}s", duration.as_secs_f64())
+    }
+}
+
+/// Formats `bytes` with a binary unit, e.g. `1.5 GiB`.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024. && unit < UNITS.len() - 1 {
+        value /= 1024.;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{bytes} B"),
+        _ => format!("{value:.1} {}", UNITS[unit]),
+    }
+}
+
+/// Parses a size such as `20GB`, `512MiB` or `1024`: decimal units (`kB`, `MB`, ...) are powers of 1000 as printed
+/// by docker, binary units (`KiB`, `MiB`, ...) powers of 1024.
+pub fn parse_bytes(size: &str) -> Result<u64, Error> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(size.len());
+    let (value, unit) = size.split_at(split);
+    let multiplier: u64 = match unit.trim().to_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1000,
+        "m" | "mb" => 1000_u64.pow(2),
+        "g" | "gb" => 1000_u64.pow(3),
+        "t" | "tb" => 1000_u64.pow(4),
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        "tib" => 1 << 40,
+        unit => return Err(Error::InvalidInput(format!("unknown size unit `{unit}` in `{size}`"))),
+    };
+    let value = value
+        .parse::<f64>()
+        .map_err(|_| Error::InvalidInput(format!("invalid size `{size}`")))?;
+    Ok((value * multiplier as f64) as u64)
+}
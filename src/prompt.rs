@@ -0,0 +1,98 @@
+/// Interactive prompts for inputs which were not passed on the command line. Prompts are only shown when both
+/// stdin and stderr are attached to a terminal; `--yes` accepts every default and `--non-interactive` (or running
+/// in CI) never prompts so that missing inputs fail immediately.
+use crate::error::Error;
+use colored::Colorize;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static YES: AtomicBool = AtomicBool::new(false);
+static NON_INTERACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Sets how prompts are answered, see `--yes` and `--non-interactive`.
+pub fn set_prompt_mode(yes: bool, non_interactive: bool) {
+    YES.store(yes, Ordering::SeqCst);
+    NON_INTERACTIVE.store(non_interactive, Ordering::SeqCst);
+}
+
+/// Whether the user can be prompted for input.
+pub fn can_prompt() -> bool {
+    !YES.load(Ordering::SeqCst)
+        && !NON_INTERACTIVE.load(Ordering::SeqCst)
+        && std::env::var_os("CI").is_none()
+        && io::stdin().is_terminal()
+        && io::stderr().is_terminal()
+}
+
+/// Prompts for a line of text, returning `default` if the answer is empty. With `--yes` the default is returned
+/// without prompting, otherwise `None` is returned if prompting is not possible.
+pub fn input(prompt: &str, default: Option<&str>) -> Result<Option<String>, Error> {
+    if YES.load(Ordering::SeqCst) {
+        return Ok(default.map(String::from));
+    }
+    if !can_prompt() {
+        return Ok(None);
+    }
+    let hint = default.map(|default| format!(" [{default}]")).unwrap_or_default();
+    let answer = read_answer(&format!("{}{}: ", prompt.bold(), hint.dimmed()))?;
+    Ok(match answer.is_empty() {
+        true => default.map(String::from),
+        false => Some(answer),
+    })
+}
+
+/// Prompts for one of `options`, returning its index. A sole option is selected without prompting when prompting
+/// is possible or `--yes` is set, otherwise `None` is returned if prompting is not possible.
+pub fn select(prompt: &str, options: &[String]) -> Result<Option<usize>, Error> {
+    if options.len() == 1 && (can_prompt() || YES.load(Ordering::SeqCst)) {
+        return Ok(Some(0));
+    }
+    if !can_prompt() || options.is_empty() {
+        return Ok(None);
+    }
+    eprintln!("{}", prompt.bold());
+    for (i, option) in options.iter().enumerate() {
+        eprintln!("  {}) {option}", i + 1);
+    }
+    loop {
+        let answer = read_answer(&format!("{}: ", "select an option".bold()))?;
+        match answer.parse::<usize>() {
+            Ok(i) if (1..=options.len()).contains(&i) => return Ok(Some(i - 1)),
+            _ => match options.iter().position(|option| *option == answer) {
+                Some(i) => return Ok(Some(i)),
+                None => eprintln!("expected a number between 1 and {}", options.len()),
+            },
+        }
+    }
+}
+
+/// Asks for confirmation before proceeding: `--yes` always confirms, otherwise `default` is returned if prompting
+/// is not possible.
+pub fn confirm(prompt: &str, default: bool) -> Result<bool, Error> {
+    if YES.load(Ordering::SeqCst) {
+        return Ok(true);
+    }
+    if !can_prompt() {
+        return Ok(default);
+    }
+    let hint = if default { "[Y/n]" } else { "[y/N]" };
+    loop {
+        let answer = read_answer(&format!("{} {}: ", prompt.bold(), hint.dimmed()))?;
+        match &*answer.to_lowercase() {
+            "" => return Ok(default),
+            "y" | "yes" => return Ok(true),
+            "n" | "no" => return Ok(false),
+            _ => eprintln!("expected y or n"),
+        }
+    }
+}
+
+fn read_answer(prompt: &str) -> Result<String, Error> {
+    eprint!("{prompt}");
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    if io::stdin().lock().read_line(&mut answer)? == 0 {
+        return Err(Error::Cancelled);
+    }
+    Ok(answer.trim().to_string())
+}
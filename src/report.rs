@@ -0,0 +1,116 @@
+/// Rendering of the structured results returned by ops commands. Library functions only return results,
+/// binaries hand them to a [`Reporter`] to be printed.
+use crate::dry_run::{is_dry_run, take_reported_plan, Plan};
+use crate::error::Error;
+use crate::logging::{is_quiet, output_format};
+use crate::progress::{format_duration, SummaryRow};
+use clap::ValueEnum;
+use colored::Colorize;
+use serde::{Serialize, Serializer};
+use std::time::Duration;
+
+/// A structured command result which can be rendered for humans.
+pub trait Report: Serialize {
+    /// human readable rendering of the result, printed once the command completes
+    fn human(&self) -> String;
+}
+
+pub trait Reporter {
+    fn report<R: Report>(&self, report: &R) -> Result<(), Error>;
+}
+
+/// Output format of a command's result.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum ReportFormat {
+    #[default]
+    Human,
+    Json,
+}
+
+impl ReportFormat {
+    /// Prints `report` to stdout in this format.
+    pub fn report<R: Report>(&self, report: &R) -> Result<(), Error> {
+        match self {
+            Self::Human => HumanReporter.report(report),
+            Self::Json => JsonReporter.report(report),
+        }
+    }
+}
+
+/// Prints `report` to stdout in the format selected by `--output`. In json mode the dry-run plan is included in
+/// the same document so that stdout holds a single json value.
+pub fn report<R: Report>(report: &R) -> Result<(), Error> {
+    match output_format() {
+        ReportFormat::Json if is_dry_run() => JsonReporter.report(&DryRunReport {
+            result: report,
+            plan: take_reported_plan()?,
+        }),
+        format => format.report(report),
+    }
+}
+
+#[derive(Serialize)]
+struct DryRunReport<'a, R> {
+    result: &'a R,
+    plan: Plan,
+}
+
+impl<R: Report> Report for DryRunReport<'_, R> {
+    fn human(&self) -> String {
+        self.result.human()
+    }
+}
+
+/// Prints human readable reports to stdout, nothing in quiet mode.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HumanReporter;
+
+impl Reporter for HumanReporter {
+    fn report<R: Report>(&self, report: &R) -> Result<(), Error> {
+        if is_quiet() {
+            return Ok(());
+        }
+        let human = report.human();
+        if !human.is_empty() {
+            println!("{human}");
+        }
+        Ok(())
+    }
+}
+
+/// Prints reports to stdout as pretty printed json.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JsonReporter;
+
+impl Reporter for JsonReporter {
+    fn report<R: Report>(&self, report: &R) -> Result<(), Error> {
+        println!("{}", serde_json::to_string_pretty(report)?);
+        Ok(())
+    }
+}
+
+impl Report for () {
+    fn human(&self) -> String {
+        String::new()
+    }
+}
+
+/// Renders the outcomes of a command's steps as an aligned summary table.
+pub fn summary_table(title: &str, rows: &[SummaryRow]) -> String {
+    let width = rows.iter().map(|row| row.task.len()).max().unwrap_or_default();
+    let mut lines = vec![format!("{title} summary").bold().to_string()];
+    for row in rows {
+        let status = if row.succeeded { "ok".green() } else { "failed".red() };
+        lines.push(format!(
+            "  {:width$}  {status:6}  {}",
+            row.task,
+            format_duration(row.duration)
+        ));
+    }
+    lines.join("\n")
+}
+
+/// Serializes durations as fractional seconds.
+pub fn serialize_duration<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
@@ -0,0 +1,176 @@
+/// Golden file snapshots of generated files, e.g. the Dockerfiles rendered by [`crate::docker::dockerfile`], so that
+/// changes to them caused by an ops upgrade surface as a reviewable diff instead of silently changing builds.
+/// Snapshots are plain files which are created or updated when updating is requested, either explicitly or by
+/// setting the OPS_UPDATE_SNAPSHOTS environment variable.
+use crate::dry_run::write_file;
+use crate::error::Error;
+use serde::Serialize;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+/// environment variable enabling the creation and updating of snapshots
+pub const OPS_UPDATE_SNAPSHOTS_ENV: &str = "OPS_UPDATE_SNAPSHOTS";
+
+/// lines of unchanged context shown around every change in a diff
+const DIFF_CONTEXT: usize = 3;
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case", tag = "status")]
+pub enum SnapshotStatus {
+    Matches,
+    /// no snapshot exists yet and updating was not requested
+    Missing,
+    Created,
+    Updated,
+    /// the snapshot differs and updating was not requested, `diff` turns the snapshot into the actual contents
+    Differs {
+        diff: String,
+    },
+}
+
+impl SnapshotStatus {
+    /// Whether the actual contents are (now) pinned by the snapshot.
+    pub fn is_ok(&self) -> bool {
+        matches!(self, Self::Matches | Self::Created | Self::Updated)
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            Self::Matches => "matches",
+            Self::Missing => "missing",
+            Self::Created => "created",
+            Self::Updated => "updated",
+            Self::Differs { .. } => "differs",
+        }
+    }
+}
+
+/// Whether OPS_UPDATE_SNAPSHOTS is set to a truthy value.
+pub fn is_update_snapshots() -> bool {
+    env::var(OPS_UPDATE_SNAPSHOTS_ENV)
+        .map(|value| !matches!(&*value.trim().to_lowercase(), "" | "0" | "false"))
+        .unwrap_or_default()
+}
+
+/// Compares `actual` with the snapshot at `path`, writing it if `update` is set and the snapshot is missing or
+/// differs.
+pub fn check_snapshot(path: &Path, actual: &str, update: bool) -> Result<SnapshotStatus, Error> {
+    let actual = normalize(actual);
+    let expected = match fs::read_to_string(path) {
+        Ok(expected) => Some(normalize(&expected)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err.into()),
+    };
+    let status = match expected {
+        Some(expected) if expected == actual => return Ok(SnapshotStatus::Matches),
+        Some(_) if update => SnapshotStatus::Updated,
+        None if update => SnapshotStatus::Created,
+        Some(expected) => {
+            return Ok(SnapshotStatus::Differs {
+                diff: diff_lines(&expected, &actual),
+            })
+        }
+        None => return Ok(SnapshotStatus::Missing),
+    };
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    write_file(path, &actual)?;
+    Ok(status)
+}
+
+/// Asserts that `actual` matches the snapshot at `path`, for use in downstream tests. The snapshot is created or
+/// updated instead if OPS_UPDATE_SNAPSHOTS is set.
+///
+/// # Panics
+/// If the snapshot is missing or differs from `actual`, with a diff of the two.
+pub fn assert_snapshot(path: impl AsRef<Path>, actual: &str) {
+    let path = path.as_ref();
+    match check_snapshot(path, actual, is_update_snapshots()) {
+        Ok(SnapshotStatus::Missing) => panic!(
+            "snapshot {} does not exist, rerun with {OPS_UPDATE_SNAPSHOTS_ENV}=1 to create it",
+            path.display()
+        ),
+        Ok(SnapshotStatus::Differs { diff }) => panic!(
+            "snapshot {} differs, rerun with {OPS_UPDATE_SNAPSHOTS_ENV}=1 to update it:\n{diff}",
+            path.display()
+        ),
+        Ok(_) => {}
+        Err(err) => panic!("failed to check snapshot {}: {err}", path.display()),
+    }
+}
+
+/// Snapshots always end with a single newline and use `\n` line endings regardless of the platform.
+fn normalize(contents: &str) -> String {
+    format!("{}\n", contents.replace("\r\n", "\n").trim_end_matches('\n'))
+}
+
+/// Line diff from `expected` to `actual` with removed lines prefixed by `-`, added lines by `+` and context lines by
+/// a space, unchanged stretches without changes nearby are elided.
+pub fn diff_lines(expected: &str, actual: &str) -> String {
+    let expected = expected.lines().collect::<Vec<_>>();
+    let actual = actual.lines().collect::<Vec<_>>();
+
+    // common prefix and suffix are trimmed first, keeping the longest common subsequence table small for the
+    // typical diff of a few changed lines
+    let prefix = expected.iter().zip(&actual).take_while(|(a, b)| a == b).count();
+    let suffix = expected[prefix..]
+        .iter()
+        .rev()
+        .zip(actual[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old = &expected[prefix..expected.len() - suffix];
+    let new = &actual[prefix..actual.len() - suffix];
+
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = match old[i] == new[j] {
+                true => lcs[i + 1][j + 1] + 1,
+                false => lcs[i + 1][j].max(lcs[i][j + 1]),
+            };
+        }
+    }
+
+    let mut lines = expected[..prefix].iter().map(|line| (' ', *line)).collect::<Vec<_>>();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push((' ', old[i]));
+            i += 1;
+            j += 1;
+        } else if j < new.len() && (i == old.len() || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            lines.push(('+', new[j]));
+            j += 1;
+        } else {
+            lines.push(('-', old[i]));
+            i += 1;
+        }
+    }
+    lines.extend(expected[expected.len() - suffix..].iter().map(|line| (' ', *line)));
+
+    let changed = lines
+        .iter()
+        .enumerate()
+        .filter(|(_, (kind, _))| *kind != ' ')
+        .map(|(index, _)| index)
+        .collect::<Vec<_>>();
+    let mut output = vec![];
+    let mut last = None;
+    for (index, (kind, line)) in lines.iter().enumerate() {
+        let near_change = changed
+            .iter()
+            .any(|changed| index + DIFF_CONTEXT >= *changed && index <= changed + DIFF_CONTEXT);
+        if !near_change {
+            continue;
+        }
+        if last.is_some_and(|last| index > last + 1) || (last.is_none() && index > 0) {
+            output.push("...".to_string());
+        }
+        output.push(format!("{kind}{line}"));
+        last = Some(index);
+    }
+    output.join("\n")
+}
@@ -0,0 +1,12 @@
+/// Temporary directories created by ops. They share a common name prefix so that leftovers of interrupted
+/// commands can be told apart from other temporary files, e.g. by `ops du`.
+use crate::error::Error;
+use tempfile::TempDir;
+
+/// name prefix of every temporary directory created by ops
+pub const OPS_TEMP_DIR_PREFIX: &str = "ops-";
+
+/// Creates a temporary directory in the system's temporary directory, removed once dropped.
+pub fn temp_dir() -> Result<TempDir, Error> {
+    Ok(tempfile::Builder::new().prefix(OPS_TEMP_DIR_PREFIX).tempdir()?)
+}
@@ -0,0 +1,261 @@
+/// Timings of the phases commands spend their time in (git diff, dependency resolution, docker pre-build, docker
+/// build and push), recorded wherever those phases run and reported at exit when `--timings` is passed, similar to
+/// cargo's `--timings`: as a summary table on stderr or as a json or html report file.
+use crate::error::Error;
+use crate::progress::format_duration;
+use crate::report::serialize_duration;
+use clap::ValueEnum;
+use colored::Colorize;
+use serde::Serialize;
+use std::cell::Cell;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+lazy_static! {
+    static ref TIMINGS: Mutex<Vec<PhaseTiming>> = Mutex::new(vec![]);
+    static ref REPORT: Mutex<Option<(TimingsFormat, Option<PathBuf>)>> = Mutex::new(None);
+}
+
+static START: OnceLock<Instant> = OnceLock::new();
+
+thread_local! {
+    /// whether a phase is being timed on this thread, phases nested in another phase are attributed to the outer one
+    static IN_PHASE: Cell<bool> = const { Cell::new(false) };
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum TimingsFormat {
+    /// summary table printed to stderr
+    Summary,
+    /// json report written to `ops-timings.json`
+    Json,
+    /// html report written to `ops-timings.html`
+    Html,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    GitDiff,
+    DependencyResolution,
+    DockerPreBuild,
+    DockerBuild,
+    Push,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PhaseTiming {
+    pub phase: Phase,
+    /// what the phase ran on, e.g. the image tag built
+    pub label: String,
+    /// offset of the phase's start from the start of the command
+    #[serde(serialize_with = "serialize_duration")]
+    pub start: Duration,
+    #[serde(serialize_with = "serialize_duration")]
+    pub duration: Duration,
+    pub succeeded: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TimingsReport {
+    /// wall time of the command
+    #[serde(serialize_with = "serialize_duration")]
+    pub total: Duration,
+    pub phases: Vec<PhaseTiming>,
+}
+
+impl Phase {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::GitDiff => "git diff",
+            Self::DependencyResolution => "dependency resolution",
+            Self::DockerPreBuild => "docker pre-build",
+            Self::DockerBuild => "docker build",
+            Self::Push => "push",
+        }
+    }
+}
+
+impl TimingsFormat {
+    fn default_path(&self) -> Option<&'static str> {
+        match self {
+            Self::Summary => None,
+            Self::Json => Some("ops-timings.json"),
+            Self::Html => Some("ops-timings.html"),
+        }
+    }
+}
+
+/// Marks the start of the command, the start of every phase is reported relative to it.
+pub fn start_timings() {
+    START.get_or_init(Instant::now);
+}
+
+/// Reports the recorded timings in `format` when the binary exits, see [`report_timings`]. `path` overrides the
+/// file json and html reports are written to.
+pub fn enable_timings(format: TimingsFormat, path: Option<PathBuf>) {
+    start_timings();
+    *REPORT.lock().unwrap() = Some((format, path));
+}
+
+/// Runs `f` as `phase`, recording its duration. Phases run within another phase on the same thread are not recorded
+/// separately, e.g. the docker build of a pre-build only counts as docker pre-build.
+pub fn time<T, E>(phase: Phase, label: impl Into<String>, f: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+    if IN_PHASE.with(Cell::get) {
+        return f();
+    }
+    let start = Instant::now();
+    IN_PHASE.with(|in_phase| in_phase.set(true));
+    let result = f();
+    IN_PHASE.with(|in_phase| in_phase.set(false));
+    TIMINGS.lock().unwrap().push(PhaseTiming {
+        phase,
+        label: label.into(),
+        start: start.saturating_duration_since(*START.get_or_init(|| start)),
+        duration: start.elapsed(),
+        succeeded: result.is_ok(),
+    });
+    result
+}
+
+/// Timings recorded so far, ordered by start.
+pub fn timings() -> TimingsReport {
+    let mut phases = TIMINGS.lock().unwrap().clone();
+    phases.sort_by_key(|timing| timing.start);
+    TimingsReport {
+        total: START.get().map(Instant::elapsed).unwrap_or_default(),
+        phases,
+    }
+}
+
+/// Prints or writes the timings report if `--timings` was passed.
+pub fn report_timings() -> Result<(), Error> {
+    let Some((format, path)) = REPORT.lock().unwrap().clone() else {
+        return Ok(());
+    };
+    let report = timings();
+    let path = path.or_else(|| format.default_path().map(PathBuf::from));
+    match (format, path) {
+        (TimingsFormat::Summary, _) | (_, None) => eprintln!("{}", report.summary()),
+        (TimingsFormat::Json, Some(path)) => write_report(&path, &serde_json::to_string_pretty(&report)?)?,
+        (TimingsFormat::Html, Some(path)) => write_report(&path, &report.html())?,
+    }
+    Ok(())
+}
+
+fn write_report(path: &Path, contents: &str) -> Result<(), Error> {
+    fs::write(path, contents)?;
+    info!("wrote timings to {}", path.display());
+    Ok(())
+}
+
+impl TimingsReport {
+    /// Totals per phase, in the order phases are listed in [`Phase`].
+    pub fn totals(&self) -> Vec<(Phase, Duration)> {
+        let mut totals = Vec::<(Phase, Duration)>::new();
+        for timing in &self.phases {
+            match totals.iter_mut().find(|(phase, _)| *phase == timing.phase) {
+                Some((_, total)) => *total += timing.duration,
+                None => totals.push((timing.phase, timing.duration)),
+            }
+        }
+        totals.sort_by_key(|(phase, _)| *phase);
+        totals
+    }
+
+    fn summary(&self) -> String {
+        let width = self
+            .phases
+            .iter()
+            .map(|timing| timing.phase.name().len())
+            .chain(["total".len()])
+            .max()
+            .unwrap_or_default();
+        let mut lines = vec!["timings".bold().to_string()];
+        for timing in &self.phases {
+            let status = if timing.succeeded { "".normal() } else { " failed".red() };
+            lines.push(format!(
+                "  {:width$}  {:>8}  {}{status}",
+                timing.phase.name(),
+                format_duration(timing.duration),
+                timing.label
+            ));
+        }
+        let totals = self
+            .totals()
+            .into_iter()
+            .map(|(phase, total)| format!("{} {}", phase.name(), format_duration(total)))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let line = format!("  {:width$}  {:>8}  {totals}", "total", format_duration(self.total));
+        lines.push(line.trim_end().to_string());
+        lines.join("\n")
+    }
+
+    /// Standalone html page with a table of the phases and a bar per phase showing when it ran.
+    fn html(&self) -> String {
+        let total = self.total.as_secs_f64().max(f64::EPSILON);
+        let mut rows = String::new();
+        for timing in &self.phases {
+            let _ = writeln!(
+                rows,
+                r#"<tr><td>{}</td><td>{}</td><td class="num">{:.2}s</td><td class="num">{:.2}s</td><td class="bar"><div class="{}" style="margin-left:{:.2}%;width:{:.2}%"></div></td></tr>"#,
+                timing.phase.name(),
+                escape_html(&timing.label),
+                timing.start.as_secs_f64(),
+                timing.duration.as_secs_f64(),
+                if timing.succeeded { "ok" } else { "failed" },
+                timing.start.as_secs_f64() / total * 100.,
+                (timing.duration.as_secs_f64() / total * 100.).max(0.1),
+            );
+        }
+        let mut totals = String::new();
+        for (phase, duration) in self.totals() {
+            let _ = writeln!(
+                totals,
+                r#"<tr><td>{}</td><td class="num">{:.2}s</td></tr>"#,
+                phase.name(),
+                duration.as_secs_f64()
+            );
+        }
+        format!(
+            r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>ops timings</title>
+<style>
+body {{ font-family: sans-serif; margin: 2em; }}
+table {{ border-collapse: collapse; margin-bottom: 2em; }}
+td, th {{ padding: 0.2em 0.8em; text-align: left; border-bottom: 1px solid #ddd; }}
+.num {{ text-align: right; font-variant-numeric: tabular-nums; }}
+.bar {{ width: 40em; }}
+.bar div {{ height: 1em; }}
+.ok {{ background: #4a90d9; }}
+.failed {{ background: #d94a4a; }}
+</style>
+</head>
+<body>
+<h1>ops timings</h1>
+<p>total {:.2}s</p>
+<table>
+<tr><th>phase</th><th>total</th></tr>
+{totals}</table>
+<table>
+<tr><th>phase</th><th></th><th>start</th><th>duration</th><th></th></tr>
+{rows}</table>
+</body>
+</html>
+"#,
+            self.total.as_secs_f64()
+        )
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
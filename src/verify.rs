@@ -0,0 +1,267 @@
+/// Local quality pipeline intended as the single pre-commit/pre-push entry point: computes the packages affected
+/// by the changes made since the current branch diverged once and runs fmt, clippy, tests and a dependency audit
+/// against them, cheapest first, reporting every step in a single summary.
+use crate::cargo::WorkspaceGraph;
+use crate::dry_run::enable_dry_run;
+use crate::error::Error;
+use crate::exec::{output, run, ExecOptions};
+use crate::git::diff_name_status_since_branched::*;
+use crate::logging::LogArgs;
+use crate::progress::{format_duration, Progress};
+use crate::report::{serialize_duration, Report};
+use crate::workspace::root::get_workspace_root;
+use clap::{Parser, ValueEnum};
+use colored::Colorize;
+use serde::Serialize;
+use std::env;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+use tracing::{debug, info_span, warn};
+
+#[derive(Clone, Debug, Parser)]
+#[clap(author, version, about, long_about = None)]
+pub struct VerifyArgs {
+    /// steps to run, defaults to every step
+    #[clap(short, long, value_enum, value_delimiter = '\n', env = "OPS_VERIFY_STEP")]
+    pub step: Vec<VerifyStep>,
+
+    /// verify every package rather than only those affected by the current git diff
+    #[clap(short, long, env = "OPS_ALL")]
+    pub all: bool,
+
+    /// keep running the remaining steps after a step fails
+    #[clap(short, long, env = "OPS_KEEP_GOING")]
+    pub keep_going: bool,
+
+    #[clap(flatten)]
+    pub diff_base: DiffBaseArgs,
+
+    #[clap(flatten)]
+    pub log: LogArgs,
+
+    /// print the commands instead of running them
+    /// - can also be enabled by setting the OPS_DRY_RUN environment variable
+    #[clap(long)]
+    pub dry_run: bool,
+}
+
+/// Steps in the order they are run.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum VerifyStep {
+    /// `cargo fmt --check` on the affected packages
+    Fmt,
+    /// `cargo clippy` on the affected packages
+    Clippy,
+    /// `cargo test` on the affected packages
+    Test,
+    /// `cargo audit`, only run if Cargo.lock changed
+    Audit,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VerifyStatus {
+    Passed,
+    Failed,
+    Skipped,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct VerifyOutput {
+    pub affected_packages: Vec<String>,
+    pub steps: Vec<VerifyStepOutput>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct VerifyStepOutput {
+    pub step: VerifyStep,
+    pub status: VerifyStatus,
+    /// why the step failed or was skipped
+    pub message: Option<String>,
+    #[serde(serialize_with = "serialize_duration")]
+    pub duration: Duration,
+}
+
+impl VerifyStep {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Fmt => "fmt",
+            Self::Clippy => "clippy",
+            Self::Test => "test",
+            Self::Audit => "audit",
+        }
+    }
+}
+
+impl VerifyOutput {
+    /// Converts the output into an error if any step failed.
+    pub fn result(&self) -> Result<(), Error> {
+        let failed = self
+            .steps
+            .iter()
+            .filter(|step| step.status == VerifyStatus::Failed)
+            .map(|step| step.step.name().to_string())
+            .collect::<Vec<_>>();
+        match failed.is_empty() {
+            true => Ok(()),
+            false => Err(Error::VerifyFailed { steps: failed }),
+        }
+    }
+}
+
+impl Report for VerifyOutput {
+    fn human(&self) -> String {
+        let mut lines = vec!["verify summary".bold().to_string()];
+        for step in &self.steps {
+            let status = match step.status {
+                VerifyStatus::Passed => "ok".green(),
+                VerifyStatus::Failed => "failed".red(),
+                VerifyStatus::Skipped => "skipped".dimmed(),
+            };
+            let detail = match (step.status, step.message.as_ref()) {
+                (VerifyStatus::Skipped, Some(message)) => message.clone(),
+                (_, Some(message)) => format!("{} {message}", format_duration(step.duration)),
+                (_, None) => format_duration(step.duration),
+            };
+            lines.push(format!("  {:6}  {status:7}  {detail}", step.step.name()));
+        }
+        lines.join("\n")
+    }
+}
+
+pub fn verify(verify_args: VerifyArgs) -> Result<VerifyOutput, Error> {
+    let VerifyArgs {
+        step,
+        all,
+        keep_going,
+        diff_base,
+        log: _,
+        dry_run,
+    } = verify_args;
+
+    let _span = info_span!("verify").entered();
+
+    if dry_run {
+        enable_dry_run();
+    }
+
+    let workspace_dir = get_workspace_root(&env::current_dir()?)?;
+    env::set_current_dir(&workspace_dir)?;
+
+    // the diff and package graph are computed once and shared by every step
+    let graph = WorkspaceGraph::load(&workspace_dir)?;
+    let (mut affected_packages, lockfile_changed) = match all {
+        true => (graph.packages().keys().cloned().collect::<Vec<_>>(), true),
+        false => {
+            let text = git_diff_name_status_since_last_branch(&diff_base)?;
+            let git_statuses = parse_git_statuses(&text)?;
+            let files = git_statuses
+                .iter()
+                .flat_map(|git_status| [git_status.old_file_name(), git_status.new_file_name()])
+                .flatten()
+                .collect::<Vec<_>>();
+            (
+                graph
+                    .affected_by(files.iter().copied())
+                    .into_iter()
+                    .map(String::from)
+                    .collect(),
+                files.contains(&"Cargo.lock"),
+            )
+        }
+    };
+    affected_packages.sort();
+    debug!("affected packages: {}", affected_packages.join(", "));
+
+    let mut steps = match step.is_empty() {
+        true => VerifyStep::value_variants().to_vec(),
+        false => step,
+    };
+    steps.sort();
+    steps.dedup();
+
+    let package_args = affected_packages
+        .iter()
+        .flat_map(|package| ["--package", package])
+        .collect::<Vec<_>>();
+
+    let mut progress = Progress::new("verify", steps.len());
+    let mut outputs = vec![];
+    let mut failed = false;
+    for step in steps {
+        let skipped = |message: &str| VerifyStepOutput {
+            step,
+            status: VerifyStatus::Skipped,
+            message: Some(message.to_string()),
+            duration: Duration::ZERO,
+        };
+        if failed && !keep_going {
+            outputs.push(skipped("a previous step failed"));
+            continue;
+        }
+        let mut command = Command::new("cargo");
+        match step {
+            VerifyStep::Fmt | VerifyStep::Clippy | VerifyStep::Test if affected_packages.is_empty() => {
+                outputs.push(skipped("no affected packages"));
+                continue;
+            }
+            VerifyStep::Fmt => command.arg("fmt").args(&package_args).arg("--check"),
+            VerifyStep::Clippy => {
+                command
+                    .arg("clippy")
+                    .args(&package_args)
+                    .args(["--all-targets", "--", "-D", "warnings"])
+            }
+            VerifyStep::Test => command.arg("test").args(&package_args),
+            VerifyStep::Audit if !lockfile_changed => {
+                outputs.push(skipped("Cargo.lock is unchanged"));
+                continue;
+            }
+            VerifyStep::Audit if !is_cargo_audit_installed() => {
+                warn!("skipping dependency audit: cargo-audit is not installed");
+                outputs.push(skipped("cargo-audit is not installed"));
+                continue;
+            }
+            VerifyStep::Audit => command.arg("audit"),
+        };
+        command.stdout(Stdio::inherit()).stderr(Stdio::inherit());
+
+        let start = Instant::now();
+        let result = progress.run(step.name(), || match output(&mut command)? {
+            Some(output) if !output.status.success() => Err(Error::CommandFailed {
+                command: format!("cargo {}", step.name()),
+                status: output.status.code(),
+                stderr: String::new(),
+            }),
+            _ => Ok(()),
+        });
+        let (status, message) = match result {
+            Ok(()) => (VerifyStatus::Passed, None),
+            Err(err @ Error::CommandFailed { .. }) => (VerifyStatus::Failed, Some(err.to_string())),
+            Err(err) => return Err(err),
+        };
+        failed |= status == VerifyStatus::Failed;
+        outputs.push(VerifyStepOutput {
+            step,
+            status,
+            message,
+            duration: start.elapsed(),
+        });
+    }
+
+    Ok(VerifyOutput {
+        affected_packages,
+        steps: outputs,
+    })
+}
+
+fn is_cargo_audit_installed() -> bool {
+    let mut command = Command::new("cargo");
+    command.args(["audit", "--version"]);
+    let options = ExecOptions {
+        stream: false,
+        ..Default::default()
+    };
+    run(&command, &options).is_ok()
+}
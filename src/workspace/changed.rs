@@ -0,0 +1,225 @@
+/// Detects the cargo packages changed since the current branch diverged (or since `--base`), the change detection
+/// ops-workspace-clippy runs on, exposed so that other tools (e.g. an xtask) can drive their own commands with it.
+use crate::cargo::{Metadata, WorkspaceGraph, WorkspacePackage};
+use crate::config::{get_pattern_matcher, OpsConfig};
+use crate::error::Error;
+use crate::git::diff_name_status_since_branched::*;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use tracing::{debug, warn};
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum NonMemberPolicy {
+    /// silently skip non-member packages
+    Skip,
+    /// skip non-member packages with a warning
+    #[default]
+    Warn,
+    /// fail if any non-member package changed
+    Error,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ChangedPackage {
+    pub name: String,
+    /// directory of the package relative to the workspace root
+    pub path: PathBuf,
+    pub reason: ChangeReason,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeReason {
+    /// files of the package changed
+    Changed,
+    /// the package transitively depends on a changed package
+    Dependent,
+    /// a workspace level file such as the Cargo.toml, Cargo.lock or rust-toolchain file changed, which can affect
+    /// every member
+    WorkspaceManifest,
+}
+
+/// Workspace members of the workspace rooted at `workspace_dir` whose rust files, manifest or other build inputs
+/// (see [`DockerContextConfig::include`](crate::config::DockerContextConfig::include) and
+/// [`PackageConfig::sources`](crate::config::PackageConfig::sources)) changed since `diff_base`, ordered by name.
+/// `with_dependents` includes every member which transitively depends on a changed package. If a workspace level
+/// file changed (see [`WorkspaceGraph::affects_every_package`]) every member is returned with
+/// [`ChangeReason::WorkspaceManifest`].
+pub fn changed_packages(
+    workspace_dir: &Path,
+    diff_base: &DiffBaseArgs,
+    non_members: NonMemberPolicy,
+    with_dependents: bool,
+) -> Result<Vec<ChangedPackage>, Error> {
+    let graph = WorkspaceGraph::from_metadata(&Metadata::load(workspace_dir)?)?;
+    changed_packages_of(&graph, workspace_dir, diff_base, non_members, with_dependents)
+}
+
+/// [`changed_packages`] for an already loaded workspace graph.
+pub fn changed_packages_of(
+    graph: &WorkspaceGraph,
+    workspace_dir: &Path,
+    diff_base: &DiffBaseArgs,
+    non_members: NonMemberPolicy,
+    with_dependents: bool,
+) -> Result<Vec<ChangedPackage>, Error> {
+    let text = git_diff_name_status_since_last_branch(diff_base)?;
+    let git_statuses = parse_git_statuses(&text)?;
+
+    let changed_package = |package: &WorkspacePackage, reason| ChangedPackage {
+        name: package.name.clone(),
+        path: package.path.clone(),
+        reason,
+    };
+
+    let files = git_statuses
+        .iter()
+        .flat_map(|git_status| [git_status.old_file_name(), git_status.new_file_name()])
+        .flatten()
+        .collect::<Vec<_>>();
+    if let Some(file) = files.iter().find(|file| graph.affects_every_package(Path::new(file))) {
+        debug!("found changes in {file}, every member is affected");
+        return Ok(graph
+            .members()
+            .map(|package| changed_package(package, ChangeReason::WorkspaceManifest))
+            .collect());
+    }
+
+    // `[docker.context] include` patterns are relative to the directory of the package containing the file
+    let include = get_pattern_matcher(
+        "[docker.context] include",
+        &OpsConfig::load(workspace_dir)?.docker.context.include,
+    )?;
+    let mut package_dirs = HashMap::<PathBuf, PathBuf>::default();
+    let mut no_package_dirs = HashSet::<PathBuf>::default();
+    let mut package_paths = HashSet::<PathBuf>::default();
+    let mut no_package_paths = HashSet::<PathBuf>::default();
+    for file in files {
+        let path = Path::new(file);
+        let source = is_source_file(path);
+        package_paths.extend(
+            graph
+                .packages_sourcing(path)
+                .into_iter()
+                .map(|package| package.path.clone()),
+        );
+        match get_cargo_package_of_file(workspace_dir, path, &mut package_dirs, &mut no_package_dirs) {
+            Some(package_path) => {
+                let included = path
+                    .strip_prefix(&package_path)
+                    .is_ok_and(|path| include.matched_path_or_any_parents(path, false).is_ignore());
+                if source || included {
+                    package_paths.insert(package_path);
+                }
+            }
+            None if source => {
+                no_package_paths.insert(path.to_path_buf());
+            }
+            None => {}
+        }
+    }
+
+    if !no_package_paths.is_empty() {
+        let formatted_paths = no_package_paths
+            .into_iter()
+            .map(|x| x.display().to_string())
+            .collect::<Vec<_>>()
+            .join("\n - ");
+        return Err(Error::InvalidInput(format!(
+            "rust files were found outside of a cargo package:\n - {formatted_paths}"
+        )));
+    }
+
+    let member_paths = graph
+        .members()
+        .map(|package| package.path.clone())
+        .collect::<HashSet<_>>();
+    let (package_paths, non_member_paths): (BTreeSet<_>, BTreeSet<_>) = package_paths
+        .into_iter()
+        .partition(|package_path| member_paths.contains(package_path));
+
+    if !non_member_paths.is_empty() {
+        let formatted_paths = non_member_paths
+            .iter()
+            .map(|x| x.display().to_string())
+            .collect::<Vec<_>>()
+            .join("\n - ");
+        match non_members {
+            NonMemberPolicy::Skip => debug!("skipping packages which are not workspace members:\n - {formatted_paths}"),
+            NonMemberPolicy::Warn => warn!("skipping packages which are not workspace members:\n - {formatted_paths}"),
+            NonMemberPolicy::Error => {
+                return Err(Error::InvalidInput(format!(
+                    "changes were found in packages which are not workspace members:\n - {formatted_paths}"
+                )))
+            }
+        }
+    }
+
+    let mut changed = graph
+        .members()
+        .filter(|package| package_paths.contains(&package.path))
+        .map(|package| (&*package.name, changed_package(package, ChangeReason::Changed)))
+        .collect::<BTreeMap<_, _>>();
+    if with_dependents {
+        let dependents = graph
+            .transitive_dependents_of(changed.keys().copied())
+            .into_iter()
+            .map(|package_name| &graph.packages()[package_name])
+            .filter(|package| package.member)
+            .collect::<Vec<_>>();
+        for package in dependents {
+            changed
+                .entry(&*package.name)
+                .or_insert_with(|| changed_package(package, ChangeReason::Dependent));
+        }
+    }
+
+    Ok(changed.into_values().collect())
+}
+
+/// Whether changes to `path` always affect the package containing it: rust files and manifests.
+fn is_source_file(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| name == "Cargo.toml")
+        || path.extension().is_some_and(|extension| extension == "rs")
+}
+
+/// Directory of the cargo package containing `path`, both relative to `workspace_dir`, or `None` if `path` is
+/// outside of any package. The package of every directory walked through is cached in `package_dirs`, and
+/// directories outside of any package in `no_package_dirs`.
+fn get_cargo_package_of_file(
+    workspace_dir: &Path,
+    path: &Path,
+    package_dirs: &mut HashMap<PathBuf, PathBuf>,
+    no_package_dirs: &mut HashSet<PathBuf>,
+) -> Option<PathBuf> {
+    let mut cur_path = path;
+    let mut package_sub_dirs = vec![];
+    while let Some(parent) = cur_path.parent() {
+        package_sub_dirs.push(parent);
+        if no_package_dirs.contains(parent) {
+            break;
+        }
+
+        if let Some(package_path) = package_dirs.get(parent).map(|package_path| package_path.to_path_buf()) {
+            for dir in package_sub_dirs {
+                package_dirs.insert(dir.to_path_buf(), package_path.clone());
+            }
+            return Some(package_path);
+        }
+        if workspace_dir.join(parent).join("Cargo.toml").exists() {
+            for dir in package_sub_dirs {
+                package_dirs.insert(dir.to_path_buf(), parent.to_path_buf());
+            }
+            return Some(parent.to_path_buf());
+        }
+
+        cur_path = parent;
+    }
+
+    for dir in package_sub_dirs {
+        no_package_dirs.insert(dir.to_path_buf());
+    }
+    None
+}
@@ -0,0 +1,170 @@
+/// Emits the dependency graph of the internal crates in a cargo workspace, optionally highlighting
+/// which packages are affected by the changes made since the current branch diverged.
+use crate::cargo::WorkspaceGraph;
+use crate::error::Error;
+use crate::git::diff_name_status_since_branched::*;
+use crate::logging::LogArgs;
+use crate::workspace::root::get_workspace_root;
+use clap::{Parser, ValueEnum};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use std::env;
+use std::path::PathBuf;
+use tracing::{debug, info_span};
+
+#[derive(Clone, Debug, Parser)]
+#[clap(author, version, about, long_about = None)]
+pub struct WorkspaceGraphArgs {
+    /// output format of the graph
+    #[clap(short, long, value_enum, default_value = "dot", env = "OPS_GRAPH_FORMAT")]
+    pub format: GraphFormat,
+
+    /// highlight packages affected by the changes made since the current branch diverged
+    /// (changed packages and every package which transitively depends on them)
+    #[clap(short, long, env = "OPS_AFFECTED")]
+    pub affected: bool,
+
+    #[clap(flatten)]
+    pub diff_base: DiffBaseArgs,
+
+    #[clap(flatten)]
+    pub log: LogArgs,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum GraphFormat {
+    Dot,
+    Mermaid,
+    Json,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DependencyGraph {
+    pub nodes: Vec<DependencyGraphNode>,
+    pub edges: Vec<DependencyGraphEdge>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DependencyGraphNode {
+    pub name: String,
+    pub path: PathBuf,
+    pub affected: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DependencyGraphEdge {
+    /// name of the dependent package
+    pub from: String,
+    /// name of the package depended on
+    pub to: String,
+}
+
+pub fn workspace_graph(workspace_graph_args: WorkspaceGraphArgs) -> Result<(), Error> {
+    let WorkspaceGraphArgs {
+        format,
+        affected,
+        diff_base,
+        log: _,
+    } = workspace_graph_args;
+
+    let _span = info_span!("workspace_graph").entered();
+
+    let workspace_dir = get_workspace_root(&env::current_dir()?)?;
+    env::set_current_dir(&workspace_dir)?;
+    let graph = WorkspaceGraph::load(&workspace_dir)?;
+    let packages = graph.packages();
+
+    let affected_package_names = if affected {
+        let affected_package_names = get_affected_package_names(&graph, &diff_base)?;
+        debug!("found {} affected packages", affected_package_names.len());
+        affected_package_names
+    } else {
+        BTreeSet::default()
+    };
+
+    let graph = DependencyGraph {
+        nodes: packages
+            .values()
+            .map(|package| DependencyGraphNode {
+                name: package.name.clone(),
+                path: package.path.clone(),
+                affected: affected_package_names.contains(&package.name),
+            })
+            .collect(),
+        edges: packages
+            .values()
+            .flat_map(|package| {
+                package.dependencies.iter().map(|dependency| DependencyGraphEdge {
+                    from: package.name.clone(),
+                    to: dependency.clone(),
+                })
+            })
+            .collect(),
+    };
+
+    println!("{}", render_graph(&graph, format)?);
+
+    Ok(())
+}
+
+pub fn render_graph(graph: &DependencyGraph, format: GraphFormat) -> Result<String, Error> {
+    Ok(match format {
+        GraphFormat::Dot => {
+            let mut lines = vec!["digraph workspace {".to_string()];
+            for node in graph.nodes.iter() {
+                if node.affected {
+                    lines.push(format!(r##"  "{}" [style=filled, fillcolor="#f96"];"##, node.name));
+                } else {
+                    lines.push(format!(r#"  "{}";"#, node.name));
+                }
+            }
+            for edge in graph.edges.iter() {
+                lines.push(format!(r#"  "{}" -> "{}";"#, edge.from, edge.to));
+            }
+            lines.push("}".to_string());
+            lines.join("\n")
+        }
+        GraphFormat::Mermaid => {
+            let mut lines = vec!["graph TD".to_string()];
+            let ids = graph
+                .nodes
+                .iter()
+                .enumerate()
+                .map(|(i, node)| (&*node.name, format!("n{i}")))
+                .collect::<HashMap<_, _>>();
+            for node in graph.nodes.iter() {
+                lines.push(format!(r#"  {}["{}"]"#, ids[&*node.name], node.name));
+            }
+            for edge in graph.edges.iter() {
+                lines.push(format!("  {} --> {}", ids[&*edge.from], ids[&*edge.to]));
+            }
+            let affected_ids = graph
+                .nodes
+                .iter()
+                .filter(|node| node.affected)
+                .map(|node| &*ids[&*node.name])
+                .collect::<Vec<_>>();
+            if !affected_ids.is_empty() {
+                lines.push("  classDef affected fill:#f96".to_string());
+                lines.push(format!("  class {} affected", affected_ids.join(",")));
+            }
+            lines.join("\n")
+        }
+        GraphFormat::Json => serde_json::to_string_pretty(graph)?,
+    })
+}
+
+/// Returns the names of all packages changed since `diff_base` along with every package which transitively
+/// depends on them, see [`WorkspaceGraph::affected_by`].
+pub(crate) fn get_affected_package_names(
+    graph: &WorkspaceGraph,
+    diff_base: &DiffBaseArgs,
+) -> Result<BTreeSet<String>, Error> {
+    let text = git_diff_name_status_since_last_branch(diff_base)?;
+    let git_statuses = parse_git_statuses(&text)?;
+    let files = git_statuses
+        .iter()
+        .flat_map(|git_status| [git_status.old_file_name(), git_status.new_file_name()])
+        .flatten();
+    Ok(graph.affected_by(files).into_iter().map(String::from).collect())
+}
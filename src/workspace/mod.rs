@@ -0,0 +1,12 @@
+pub mod changed;
+pub mod graph;
+pub mod prune;
+pub mod root;
+
+pub mod prelude {
+    use super::*;
+    pub use changed::*;
+    pub use graph::*;
+    pub use prune::*;
+    pub use root::*;
+}
@@ -0,0 +1,274 @@
+/// Removes stale build artifacts from a workspace's target directory, in the spirit of cargo-sweep: profiles which
+/// have not been built in a while, artifacts of packages which are no longer part of the dependency graph and
+/// artifacts which have not been rebuilt within the maximum age. Old pre-build images created by
+/// ops-docker-build-rust-workspace-service are removed as well.
+use crate::cargo::Metadata;
+use crate::dry_run::{enable_dry_run, is_dry_run, remove_path};
+use crate::error::Error;
+use crate::exec::{read, run_or_record, ExecOptions};
+use crate::logging::LogArgs;
+use crate::progress::format_bytes;
+use crate::report::Report;
+use crate::workspace::root::get_workspace_root;
+use clap::Parser;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, SystemTime};
+use std::{env, fs};
+use tracing::{debug, info_span, warn};
+use walkdir::WalkDir;
+
+/// directories of a profile holding per-crate artifacts named `{crate}-{hash}`
+const ARTIFACT_DIRS: [&str; 4] = [".fingerprint", "build", "deps", "incremental"];
+
+#[derive(Clone, Debug, Parser)]
+#[clap(author, version, about, long_about = None)]
+pub struct WorkspacePruneArgs {
+    /// artifacts and images which have not been updated within this many days are removed
+    #[clap(long, default_value = "30", env = "OPS_MAX_AGE")]
+    pub max_age: u64,
+
+    /// only prune the target directory, leaving docker images untouched
+    #[clap(long, env = "OPS_SKIP_DOCKER")]
+    pub skip_docker: bool,
+
+    #[clap(flatten)]
+    pub log: LogArgs,
+
+    /// report what would be removed instead of removing it
+    /// - can also be enabled by setting the OPS_DRY_RUN environment variable
+    #[clap(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct WorkspacePruneOutput {
+    pub removed: Vec<PrunedItem>,
+    /// total size of the removed items
+    pub bytes: u64,
+    pub dry_run: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PrunedItem {
+    /// path of a target directory entry or reference of a docker image
+    pub name: String,
+    pub reason: PruneReason,
+    pub bytes: u64,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PruneReason {
+    /// a profile directory none of whose artifacts were updated within the maximum age
+    StaleProfile,
+    /// artifacts of a crate which is no longer in the dependency graph
+    RemovedCrate,
+    /// artifacts which were not updated within the maximum age
+    Outdated,
+    /// pre-build images older than the maximum age
+    DockerImage,
+}
+
+impl PruneReason {
+    fn description(&self) -> &'static str {
+        match self {
+            Self::StaleProfile => "stale profiles",
+            Self::RemovedCrate => "removed crates",
+            Self::Outdated => "outdated artifacts",
+            Self::DockerImage => "pre-build images",
+        }
+    }
+}
+
+impl Report for WorkspacePruneOutput {
+    fn human(&self) -> String {
+        if self.removed.is_empty() {
+            return "nothing to prune".to_string();
+        }
+        let mut totals = BTreeMap::<PruneReason, (usize, u64)>::default();
+        for item in &self.removed {
+            let total = totals.entry(item.reason).or_default();
+            total.0 += 1;
+            total.1 += item.bytes;
+        }
+        let mut lines = totals
+            .into_iter()
+            .map(|(reason, (count, bytes))| format!("  {:20} {count:6}  {}", reason.description(), format_bytes(bytes)))
+            .collect::<Vec<_>>();
+        let verb = if self.dry_run { "would free" } else { "freed" };
+        lines.insert(0, format!("{verb} {}", format_bytes(self.bytes)));
+        lines.join("\n")
+    }
+}
+
+pub fn workspace_prune(workspace_prune_args: WorkspacePruneArgs) -> Result<WorkspacePruneOutput, Error> {
+    let WorkspacePruneArgs {
+        max_age,
+        skip_docker,
+        log: _,
+        dry_run,
+    } = workspace_prune_args;
+
+    let _span = info_span!("workspace_prune").entered();
+
+    if dry_run {
+        enable_dry_run();
+    }
+
+    let workspace_dir = get_workspace_root(&env::current_dir()?)?;
+    let max_age = Duration::from_secs(max_age * 24 * 60 * 60);
+    let cutoff = SystemTime::now().checked_sub(max_age).unwrap_or(SystemTime::UNIX_EPOCH);
+
+    let target_info = Metadata::load(&workspace_dir)?.target_info();
+    let mut removed = vec![];
+    if target_info.target_dir.exists() {
+        for profile_dir in get_profile_dirs(&target_info.target_dir)? {
+            prune_profile(&profile_dir, &target_info.crate_names, cutoff, &mut removed)?;
+        }
+    } else {
+        debug!("{} does not exist", target_info.target_dir.display());
+    }
+    if !skip_docker {
+        prune_docker_images(max_age, &mut removed)?;
+    }
+
+    let bytes = removed.iter().map(|item| item.bytes).sum();
+    Ok(WorkspacePruneOutput {
+        removed,
+        bytes,
+        dry_run: is_dry_run(),
+    })
+}
+
+/// Profile directories (e.g. `target/debug`, `target/x86_64-unknown-linux-gnu/release`) within `target_dir`.
+pub(crate) fn get_profile_dirs(target_dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut profile_dirs = vec![];
+    for entry in fs::read_dir(target_dir)? {
+        let path = entry?.path();
+        if !path.is_dir() {
+            continue;
+        }
+        if path.join(".fingerprint").is_dir() {
+            profile_dirs.push(path);
+            continue;
+        }
+        // profiles of cross compilation targets are nested in a directory named after the target triple
+        for entry in fs::read_dir(&path)? {
+            let path = entry?.path();
+            if path.join(".fingerprint").is_dir() {
+                profile_dirs.push(path);
+            }
+        }
+    }
+    Ok(profile_dirs)
+}
+
+fn prune_profile(
+    profile_dir: &Path,
+    crate_names: &BTreeSet<String>,
+    cutoff: SystemTime,
+    removed: &mut Vec<PrunedItem>,
+) -> Result<(), Error> {
+    let fingerprint_dir = profile_dir.join(".fingerprint");
+    let last_built = fs::read_dir(&fingerprint_dir)?
+        .filter_map(|entry| entry.ok()?.metadata().ok()?.modified().ok())
+        .max();
+    if last_built.map(|last_built| last_built < cutoff).unwrap_or(true) {
+        return prune_path(profile_dir, PruneReason::StaleProfile, removed);
+    }
+
+    for artifact_dir in ARTIFACT_DIRS.map(|dir| profile_dir.join(dir)) {
+        if !artifact_dir.is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(&artifact_dir)? {
+            let entry = entry?;
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            let Some(crate_name) = get_crate_name(&file_name) else {
+                continue;
+            };
+            // `lib` prefixed library artifacts can't be told apart from crates whose name starts with `lib`
+            let known = crate_names.contains(&crate_name)
+                || crate_name
+                    .strip_prefix("lib")
+                    .map(|crate_name| crate_names.contains(crate_name))
+                    .unwrap_or_default();
+            if !known {
+                prune_path(&entry.path(), PruneReason::RemovedCrate, removed)?;
+            } else if entry.metadata()?.modified()? < cutoff {
+                prune_path(&entry.path(), PruneReason::Outdated, removed)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Crate name of an artifact named `{crate}-{hash}[.ext]`, with `-` replaced by `_`.
+fn get_crate_name(file_name: &str) -> Option<String> {
+    let stem = file_name.split('.').next()?;
+    let (crate_name, hash) = stem.rsplit_once('-')?;
+    match !crate_name.is_empty() && hash.chars().all(|char| char.is_ascii_alphanumeric()) {
+        true => Some(crate_name.replace('-', "_")),
+        false => None,
+    }
+}
+
+fn prune_path(path: &Path, reason: PruneReason, removed: &mut Vec<PrunedItem>) -> Result<(), Error> {
+    let bytes = get_size(path);
+    debug!("pruning {} ({})", path.display(), format_bytes(bytes));
+    remove_path(path)?;
+    removed.push(PrunedItem {
+        name: path.display().to_string(),
+        reason,
+        bytes,
+    });
+    Ok(())
+}
+
+/// Total size of the files within `path`, symlinks are not followed.
+pub(crate) fn get_size(path: &Path) -> u64 {
+    WalkDir::new(path)
+        .into_iter()
+        .filter_map(|entry| entry.ok()?.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+/// Removes pre-build images (tagged `*-pre-build`) created before `max_age`, skipping docker entirely if it is not
+/// available.
+fn prune_docker_images(max_age: Duration, removed: &mut Vec<PrunedItem>) -> Result<(), Error> {
+    let images = read(Command::new("docker").args([
+        "image",
+        "ls",
+        "--filter",
+        &format!("until={}h", max_age.as_secs() / 3600),
+        "--format",
+        "{{.Repository}}:{{.Tag}}",
+    ]));
+    let images = match images {
+        Ok(images) => images,
+        Err(err) => {
+            warn!("skipping docker images: {err}");
+            return Ok(());
+        }
+    };
+    for image in images.lines().filter(|image| image.ends_with("-pre-build")) {
+        let bytes = read(Command::new("docker").args(["image", "inspect", "--format", "{{.Size}}", image]))?
+            .parse::<u64>()
+            .unwrap_or_default();
+        debug!("pruning {image} ({})", format_bytes(bytes));
+        let mut command = Command::new("docker");
+        command.args(["image", "rm", image]);
+        run_or_record(&command, &ExecOptions::default())?;
+        removed.push(PrunedItem {
+            name: image.to_string(),
+            reason: PruneReason::DockerImage,
+            bytes,
+        });
+    }
+    Ok(())
+}
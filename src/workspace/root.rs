@@ -0,0 +1,75 @@
+/// Resolution of the cargo workspace root of a directory, following cargo's own rules: an explicit
+/// `package.workspace` key wins, otherwise the closest ancestor `[workspace]` manifest which has the package as
+/// a member, so that nested workspaces and virtual (`[workspace]`-only) manifests resolve correctly.
+use crate::cargo::get_workspace_member_paths;
+use crate::error::Error;
+use path_absolutize::*;
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml::Value;
+use tracing::debug;
+
+/// Returns the root directory of the cargo workspace which `dir` belongs to. `dir` may be a package directory,
+/// the root of a virtual workspace or any directory nested within either.
+pub fn get_workspace_root(dir: &Path) -> Result<PathBuf, Error> {
+    let dir = dir.absolutize()?.to_path_buf();
+
+    // the package which `dir` is part of, if any
+    let package_dir = dir.ancestors().find(|dir| {
+        read_manifest(dir)
+            .map(|manifest| manifest.get("package").is_some())
+            .unwrap_or_default()
+    });
+
+    if let Some(package_dir) = package_dir {
+        let manifest = read_manifest(package_dir).unwrap();
+        if manifest.get("workspace").is_some() {
+            return Ok(package_dir.to_path_buf());
+        }
+        if let Some(workspace) = manifest.get("package").and_then(|package| package.get("workspace")) {
+            let workspace = workspace.as_str().ok_or_else(|| {
+                Error::manifest(
+                    package_dir.join("Cargo.toml"),
+                    "key `package.workspace` must be a string",
+                )
+            })?;
+            return Ok(package_dir.join(workspace).absolutize()?.to_path_buf());
+        }
+        for workspace_dir in package_dir.ancestors().skip(1) {
+            let is_workspace = read_manifest(workspace_dir)
+                .map(|manifest| manifest.get("workspace").is_some())
+                .unwrap_or_default();
+            if !is_workspace {
+                continue;
+            }
+            let relative_package_dir = package_dir.strip_prefix(workspace_dir).unwrap();
+            if get_workspace_member_paths(workspace_dir)?.contains(relative_package_dir) {
+                return Ok(workspace_dir.to_path_buf());
+            }
+            debug!(
+                "skipping workspace {}: {} is not a member",
+                workspace_dir.display(),
+                relative_package_dir.display()
+            );
+        }
+        return Err(Error::MissingWorkspace {
+            dir: package_dir.to_path_buf(),
+            package: true,
+        });
+    }
+
+    dir.ancestors()
+        .find(|dir| {
+            read_manifest(dir)
+                .map(|manifest| manifest.get("workspace").is_some())
+                .unwrap_or_default()
+        })
+        .map(Path::to_path_buf)
+        .ok_or(Error::MissingWorkspace { dir, package: false })
+}
+
+fn read_manifest(dir: &Path) -> Option<Value> {
+    fs::read_to_string(dir.join("Cargo.toml"))
+        .ok()
+        .and_then(|manifest| manifest.parse::<Value>().ok())
+}
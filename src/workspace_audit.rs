@@ -0,0 +1,432 @@
+/// Audits the dependencies of the repository when their lockfiles change: the workspace's Cargo.lock is checked for
+/// RustSec advisories with cargo-audit and the licenses of the external crates are checked against the allowlist of
+/// ops.toml, while changed package-lock.json and pnpm-lock.yaml files are checked with `npm audit` and `pnpm audit`.
+/// Advisories can be ignored through `[dependency_audit] ignore` in ops.toml.
+use crate::cargo::Metadata;
+use crate::config::{DependencyAuditConfig, OpsConfig};
+use crate::error::Error;
+use crate::exec::{output_with, read, run, ExecOptions};
+use crate::git::diff_name_status_since_branched::*;
+use crate::logging::LogArgs;
+use crate::report::Report;
+use crate::workspace::root::get_workspace_root;
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeSet;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tracing::{debug, info_span, warn};
+
+const CARGO_LOCKFILE: &str = "Cargo.lock";
+const NPM_LOCKFILE: &str = "package-lock.json";
+const PNPM_LOCKFILE: &str = "pnpm-lock.yaml";
+const YARN_LOCKFILE: &str = "yarn.lock";
+
+#[derive(Clone, Debug, Parser)]
+#[clap(author, version, about, long_about = None)]
+pub struct WorkspaceAuditArgs {
+    /// audit every lockfile of the repository rather than only those changed by the current git diff
+    #[clap(short, long, env = "OPS_ALL")]
+    pub all: bool,
+
+    /// ids of advisories to ignore in addition to `[dependency_audit] ignore` of ops.toml, may be passed several
+    /// times
+    #[clap(long, value_delimiter = '\n', env = "OPS_AUDIT_IGNORE")]
+    pub ignore: Vec<String>,
+
+    #[clap(flatten)]
+    pub diff_base: DiffBaseArgs,
+
+    #[clap(flatten)]
+    pub log: LogArgs,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct WorkspaceAuditOutput {
+    /// audited lockfiles relative to the workspace root
+    pub lockfiles: Vec<PathBuf>,
+    pub advisories: Vec<Advisory>,
+    pub license_violations: Vec<LicenseViolation>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Advisory {
+    /// e.g. `RUSTSEC-2023-0071` or `GHSA-xxxx-xxxx-xxxx`
+    pub id: String,
+    pub package: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    pub title: String,
+    /// lockfile the vulnerable package is locked in
+    pub lockfile: PathBuf,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct LicenseViolation {
+    pub package: String,
+    pub version: String,
+    /// none if the crate only points to a license file
+    pub license: Option<String>,
+}
+
+impl WorkspaceAuditOutput {
+    /// Converts the output into an error if any advisory or license violation was found.
+    pub fn result(&self) -> Result<(), Error> {
+        if self.advisories.is_empty() && self.license_violations.is_empty() {
+            return Ok(());
+        }
+        Err(Error::DependencyAuditFailed {
+            advisories: self
+                .advisories
+                .iter()
+                .map(|advisory| format!("{} ({})", advisory.id, advisory.package))
+                .collect(),
+            licenses: self
+                .license_violations
+                .iter()
+                .map(|violation| format!("{} ({})", violation.package, fmt_license(&violation.license)))
+                .collect(),
+        })
+    }
+}
+
+impl Report for WorkspaceAuditOutput {
+    fn human(&self) -> String {
+        if self.lockfiles.is_empty() {
+            return "no lockfiles to audit".to_string();
+        }
+        let lockfiles = self
+            .lockfiles
+            .iter()
+            .map(|lockfile| lockfile.display().to_string())
+            .collect::<Vec<_>>();
+        let mut lines = vec![format!("audited {}", lockfiles.join(", "))];
+        for advisory in &self.advisories {
+            let version = advisory
+                .version
+                .as_ref()
+                .map(|version| format!(" {version}"))
+                .unwrap_or_default();
+            lines.push(format!(
+                "  {} {}{version}: {}",
+                advisory.id, advisory.package, advisory.title
+            ));
+        }
+        for violation in &self.license_violations {
+            lines.push(format!(
+                "  license of {} {} is not allowed: {}",
+                violation.package,
+                violation.version,
+                fmt_license(&violation.license)
+            ));
+        }
+        lines.join("\n")
+    }
+}
+
+/// Report of `cargo audit --json`, only the fields ops relies on are parsed.
+#[derive(Debug, Deserialize)]
+struct CargoAuditReport {
+    vulnerabilities: CargoAuditVulnerabilities,
+}
+
+#[derive(Debug, Deserialize)]
+struct CargoAuditVulnerabilities {
+    list: Vec<CargoAuditVulnerability>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CargoAuditVulnerability {
+    advisory: CargoAuditAdvisory,
+    package: CargoAuditPackage,
+}
+
+#[derive(Debug, Deserialize)]
+struct CargoAuditAdvisory {
+    id: String,
+    title: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CargoAuditPackage {
+    name: String,
+    version: String,
+}
+
+pub fn workspace_audit(workspace_audit_args: WorkspaceAuditArgs) -> Result<WorkspaceAuditOutput, Error> {
+    let WorkspaceAuditArgs {
+        all,
+        ignore,
+        diff_base,
+        log: _,
+    } = workspace_audit_args;
+
+    let _span = info_span!("workspace_audit").entered();
+
+    let workspace_dir = get_workspace_root(&env::current_dir()?)?;
+    debug!("using workspace root {}", workspace_dir.display());
+    env::set_current_dir(&workspace_dir)?;
+
+    let DependencyAuditConfig {
+        ignore: ignored_advisories,
+        allowed_licenses,
+        license_exceptions,
+    } = OpsConfig::load(&workspace_dir)?.dependency_audit;
+    let ignore = ignored_advisories.into_iter().chain(ignore).collect::<BTreeSet<_>>();
+
+    let lockfiles = match all {
+        true => read(Command::new("git").args([
+            "ls-files",
+            "--",
+            &format!(":(glob){CARGO_LOCKFILE}"),
+            &format!(":(glob)**/{NPM_LOCKFILE}"),
+            &format!(":(glob)**/{PNPM_LOCKFILE}"),
+            &format!(":(glob)**/{YARN_LOCKFILE}"),
+        ]))?
+        .lines()
+        .map(PathBuf::from)
+        .collect::<BTreeSet<_>>(),
+        false => {
+            let text = git_diff_name_status_since_last_branch(&diff_base)?;
+            parse_git_statuses(&text)?
+                .iter()
+                .filter_map(GitStatus::new_file_name)
+                .map(PathBuf::from)
+                .filter(|path| {
+                    *path == Path::new(CARGO_LOCKFILE)
+                        || path.file_name().is_some_and(|name| {
+                            [NPM_LOCKFILE, PNPM_LOCKFILE, YARN_LOCKFILE]
+                                .iter()
+                                .any(|lockfile| name == *lockfile)
+                        })
+                })
+                .collect()
+        }
+    };
+
+    let mut output = WorkspaceAuditOutput::default();
+    for lockfile in lockfiles {
+        let file_name = lockfile.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+        let advisories = match file_name {
+            CARGO_LOCKFILE => {
+                if !allowed_licenses.is_empty() {
+                    output.license_violations =
+                        check_licenses(&Metadata::load(&workspace_dir)?, &allowed_licenses, &license_exceptions);
+                }
+                cargo_audit(&lockfile, &ignore)?
+            }
+            NPM_LOCKFILE | PNPM_LOCKFILE => js_audit(&lockfile)?,
+            _ => {
+                warn!(
+                    "skipping {}: auditing yarn lockfiles is not supported",
+                    lockfile.display()
+                );
+                continue;
+            }
+        };
+        output
+            .advisories
+            .extend(advisories.into_iter().filter(|advisory| !ignore.contains(&advisory.id)));
+        output.lockfiles.push(lockfile);
+    }
+    Ok(output)
+}
+
+/// Advisories affecting the crates locked in the Cargo.lock at `lockfile`, cargo-audit skips the `ignore`d ones itself.
+fn cargo_audit(lockfile: &Path, ignore: &BTreeSet<String>) -> Result<Vec<Advisory>, Error> {
+    let options = ExecOptions {
+        stream: false,
+        ..Default::default()
+    };
+    if run(Command::new("cargo").args(["audit", "--version"]), &options).is_err() {
+        return Err(Error::ToolUnavailable {
+            program: "cargo-audit".to_string(),
+            message: "install it with `cargo install cargo-audit`".to_string(),
+        });
+    }
+    let mut command = Command::new("cargo");
+    command.args(["audit", "--json", "--file"]).arg(lockfile);
+    for id in ignore {
+        command.args(["--ignore", id]);
+    }
+    debug!("{command:?}");
+    // cargo-audit exits unsuccessfully if it found vulnerabilities, its report tells whether it ran at all
+    let audit_output = output_with(&command, &options)?;
+    let report =
+        serde_json::from_slice::<CargoAuditReport>(&audit_output.stdout).map_err(|_| Error::CommandFailed {
+            command: "cargo audit".to_string(),
+            status: audit_output.status.code(),
+            stderr: String::from_utf8_lossy(&audit_output.stderr).trim().to_string(),
+        })?;
+    Ok(report
+        .vulnerabilities
+        .list
+        .into_iter()
+        .map(|vulnerability| Advisory {
+            id: vulnerability.advisory.id,
+            package: vulnerability.package.name,
+            version: Some(vulnerability.package.version),
+            title: vulnerability.advisory.title,
+            lockfile: lockfile.to_path_buf(),
+        })
+        .collect())
+}
+
+/// Advisories affecting the packages locked in the npm or pnpm `lockfile`, reported by the package manager's audit.
+fn js_audit(lockfile: &Path) -> Result<Vec<Advisory>, Error> {
+    let dir = lockfile
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let program = match lockfile.ends_with(NPM_LOCKFILE) {
+        true => "npm",
+        false => "pnpm",
+    };
+    let mut command = Command::new(program);
+    command.args(["audit", "--json"]).current_dir(dir);
+    if program == "npm" {
+        // the lockfile is audited as is, node_modules need not be installed
+        command.arg("--package-lock-only");
+    }
+    debug!("{command:?}");
+    // audits exit unsuccessfully if they found vulnerabilities, their report tells whether they ran at all
+    let audit_output = output_with(
+        &command,
+        &ExecOptions {
+            stream: false,
+            ..Default::default()
+        },
+    )?;
+    let failed = |stderr: String| Error::CommandFailed {
+        command: format!("{program} audit"),
+        status: audit_output.status.code(),
+        stderr,
+    };
+    let report = serde_json::from_slice::<Value>(&audit_output.stdout)
+        .map_err(|_| failed(String::from_utf8_lossy(&audit_output.stderr).trim().to_string()))?;
+    if let Some(error) = report.get("error") {
+        return Err(failed(error["summary"].as_str().unwrap_or_default().to_string()));
+    }
+
+    let advisory = |package: &str, version: Option<&str>, advisory: &Value| Advisory {
+        id: get_advisory_id(advisory),
+        package: package.to_string(),
+        version: version.map(String::from),
+        title: advisory["title"].as_str().unwrap_or_default().to_string(),
+        lockfile: lockfile.to_path_buf(),
+    };
+    let mut advisories = vec![];
+    // npm 7 and later report vulnerable packages along with the advisories (or vulnerable dependencies) causing them
+    for (package, vulnerability) in report["vulnerabilities"].as_object().into_iter().flatten() {
+        for via in vulnerability["via"].as_array().into_iter().flatten() {
+            if via.is_object() {
+                advisories.push(advisory(package, None, via));
+            }
+        }
+    }
+    // pnpm reports advisories in the format of npm 6
+    for entry in report["advisories"]
+        .as_object()
+        .into_iter()
+        .flat_map(|advisories| advisories.values())
+    {
+        let package = entry["module_name"].as_str().unwrap_or_default();
+        let version = entry["findings"][0]["version"].as_str();
+        advisories.push(advisory(package, version, entry));
+    }
+    Ok(advisories)
+}
+
+/// Id of a js audit's advisory: the GHSA id its url or `github_advisory_id` points to, its npm id otherwise.
+fn get_advisory_id(advisory: &Value) -> String {
+    advisory["github_advisory_id"]
+        .as_str()
+        .or_else(|| {
+            advisory["url"]
+                .as_str()
+                .and_then(|url| url.rsplit('/').next())
+                .filter(|id| id.starts_with("GHSA-"))
+        })
+        .map(String::from)
+        .or_else(|| {
+            [&advisory["source"], &advisory["id"]]
+                .into_iter()
+                .find_map(|id| id.as_u64())
+                .map(|id| id.to_string())
+        })
+        .unwrap_or_default()
+}
+
+/// External crates of the resolved dependency graph whose license expression is not satisfied by `allowed_licenses`.
+fn check_licenses(
+    metadata: &Metadata,
+    allowed_licenses: &[String],
+    license_exceptions: &[String],
+) -> Vec<LicenseViolation> {
+    metadata
+        .packages
+        .iter()
+        .filter(|package| package.source.is_some() && !license_exceptions.contains(&package.name))
+        .filter(|package| {
+            !package
+                .license
+                .as_ref()
+                .is_some_and(|license| is_license_allowed(license, allowed_licenses))
+        })
+        .map(|package| LicenseViolation {
+            package: package.name.clone(),
+            version: package.version.clone(),
+            license: package.license.clone(),
+        })
+        .collect()
+}
+
+/// Whether the SPDX license `expression` is satisfied by `allowed_licenses`: one alternative of an `OR` (or of the
+/// legacy `/` separator) and every operand of an `AND` must be allowed, `WITH` exceptions are not checked.
+fn is_license_allowed(expression: &str, allowed_licenses: &[String]) -> bool {
+    let expression = expression.replace('(', " ( ").replace(')', " ) ").replace('/', " OR ");
+    let mut tokens = expression.split_whitespace().peekable();
+    parse_or(&mut tokens, allowed_licenses)
+}
+
+type Tokens<'a> = std::iter::Peekable<std::str::SplitWhitespace<'a>>;
+
+fn parse_or(tokens: &mut Tokens, allowed_licenses: &[String]) -> bool {
+    let mut allowed = parse_and(tokens, allowed_licenses);
+    while tokens.next_if_eq(&"OR").is_some() {
+        allowed |= parse_and(tokens, allowed_licenses);
+    }
+    allowed
+}
+
+fn parse_and(tokens: &mut Tokens, allowed_licenses: &[String]) -> bool {
+    let mut allowed = parse_license(tokens, allowed_licenses);
+    while tokens.next_if_eq(&"AND").is_some() {
+        allowed &= parse_license(tokens, allowed_licenses);
+    }
+    allowed
+}
+
+fn parse_license(tokens: &mut Tokens, allowed_licenses: &[String]) -> bool {
+    let allowed = match tokens.next() {
+        Some("(") => {
+            let allowed = parse_or(tokens, allowed_licenses);
+            tokens.next_if_eq(&")");
+            allowed
+        }
+        Some(license) => allowed_licenses
+            .iter()
+            .any(|allowed| allowed == license.trim_end_matches('+')),
+        None => false,
+    };
+    if tokens.next_if_eq(&"WITH").is_some() {
+        tokens.next();
+    }
+    allowed
+}
+
+fn fmt_license(license: &Option<String>) -> &str {
+    license.as_deref().unwrap_or("no license expression")
+}
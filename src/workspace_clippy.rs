@@ -2,317 +2,505 @@
 /// Note that if any changes are made to the workspace level Cargo.toml or Cargo.lock a full workspace
 /// level run of cargo clippy is currently required (to capture the case of breaking changes due to
 /// changed external dependencies).
-use crate::git::diff_name_status_since_branched::*;
-use anyhow::Error;
+use crate::cargo::{Metadata, WorkspaceGraph};
+use crate::config::OpsConfig;
+use crate::dry_run::enable_dry_run;
+use crate::error::Error;
+use crate::exec::{output, read, run_concurrently, with_output_prefix};
+use crate::git::diff_name_status_since_branched::DiffBaseArgs;
+use crate::logging::LogArgs;
+use crate::progress::{format_duration, Progress};
+use crate::report::{serialize_duration, Report};
+use crate::workspace::changed::*;
+use crate::workspace::root::get_workspace_root;
 use clap::Parser;
-use colored::Colorize;
-use std::collections::{HashMap, HashSet, VecDeque};
-use std::fs;
-use std::path::{Path, PathBuf};
+use fancy_regex::Regex;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::path::Path;
 use std::process::{Command, Stdio};
-use toml::Value;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, info_span};
+
+const CLIPPY_JOBS_ENV: &str = "OPS_CLIPPY_JOBS";
+const BATCH_ENV: &str = "OPS_BATCH";
 
 #[derive(Clone, Debug, Parser)]
 #[clap(author, version, about, long_about = None, trailing_var_arg=true)]
 pub struct WorkspaceClippyArgs {
-    /// whether to print commands prior to running
-    #[clap(short, long)]
-    pub verbose: bool,
+    #[clap(flatten)]
+    pub log: LogArgs,
+
+    /// print the clippy commands instead of running them
+    /// - can also be enabled by setting the OPS_DRY_RUN environment variable
+    #[clap(long)]
+    pub dry_run: bool,
+
+    /// how to handle changed files in cargo packages which are not members of the workspace (e.g. vendored
+    /// or example crates excluded from `workspace.members`)
+    #[clap(long, value_enum, default_value = "warn", env = "OPS_NON_MEMBERS")]
+    pub non_members: NonMemberPolicy,
+
+    /// rustup toolchain to run clippy with (e.g. `nightly`), defaults to the workspace's toolchain: nightly-only
+    /// flags are only passed when the toolchain is a nightly one
+    #[clap(long, env = "OPS_TOOLCHAIN")]
+    pub toolchain: Option<String>,
+
+    /// also lint every workspace member which transitively depends on a changed package, as api changes in a
+    /// library can break its dependents
+    #[clap(long, env = "OPS_WITH_DEPENDENTS")]
+    pub with_dependents: bool,
+
+    /// report lints without applying clippy's fixes, defaults to `clippy.no_fix` in ops.toml
+    #[clap(long, env = "OPS_NO_FIX")]
+    pub no_fix: bool,
+
+    /// features to lint with instead of every feature, may be passed several times -- defaults to
+    /// `clippy.features` in ops.toml
+    #[clap(long, value_delimiter = '\n', env = "OPS_FEATURES")]
+    pub features: Vec<String>,
+
+    /// don't lint the workspace members the linted packages depend on, every changed package is then linted on
+    /// its own -- defaults to `clippy.no_deps` in ops.toml
+    #[clap(long, env = "OPS_NO_DEPS")]
+    pub no_deps: bool,
+
+    /// maximum number of packages to lint concurrently, defaults to one at a time: every concurrent clippy run
+    /// besides the first builds in a target directory of its own (`target/clippy-<n>`), unlike the subprocess
+    /// limit set by OPS_JOBS
+    #[clap(short, long, env = CLIPPY_JOBS_ENV)]
+    pub jobs: Option<usize>,
+
+    /// lint every changed package with a single cargo invocation, which shares the build of their dependencies
+    /// but mixes their output -- defaults to `clippy.batch` in ops.toml, conflicts with `--jobs`
+    #[clap(long, env = BATCH_ENV)]
+    pub batch: bool,
+
+    #[clap(flatten)]
+    pub diff_base: DiffBaseArgs,
 
-    /// docker build args
+    /// cargo clippy args, args following a further `--` are passed to clippy itself after `-D warnings` (e.g.
+    /// `-- --locked -- -W clippy::pedantic`)
     #[clap(value_parser)]
     pub clippy_args: Vec<String>,
 }
 
-pub fn workspace_clippy(worspace_clippy_args: WorkspaceClippyArgs) -> Result<(), Error> {
-    let WorkspaceClippyArgs { clippy_args, verbose } = worspace_clippy_args;
-    let text = git_diff_name_status_since_last_branch()?;
-    let git_statuses = parse_git_statuses(&text)?;
-
-    let mut package_paths = HashMap::<PathBuf, PathBuf>::default();
-    let mut no_package_dirs = HashSet::<PathBuf>::default();
-    let mut no_package_paths = HashSet::<PathBuf>::default();
-
-    for git_status in git_statuses {
-        let mut existing = None;
-        let mut removed = None;
-        match git_status {
-            GitStatus::Added { file } | GitStatus::FileTypeChanged { file } | GitStatus::Modified { file } => {
-                existing = Some(Path::new(file));
-            }
-            GitStatus::Deleted { file } => {
-                removed = Some(Path::new(file));
-            }
-            GitStatus::Renamed { old, new } => {
-                removed = Some(Path::new(old));
-                existing = Some(Path::new(new));
-            }
-        }
+/// Arguments of the clippy commands, combined from the flags and ops.toml.
+#[derive(Clone, Debug)]
+struct ClippyOptions {
+    fix: bool,
+    /// features to enable, every feature if empty
+    features: Vec<String>,
+    no_deps: bool,
+    /// args of cargo clippy
+    cargo_args: Vec<String>,
+    /// args of clippy itself, passed after `--`
+    lint_args: Vec<String>,
+    /// continue linting the other packages after one fails to compile
+    keep_going: bool,
+}
 
-        if let Some(existing) = existing {
-            let file_name = existing.display().to_string();
-            if file_name == "Cargo.toml" || file_name == "Cargo.lock" {
-                return workspace_run(verbose);
-            }
-            get_cargo_package_of_file(
-                existing,
-                &mut package_paths,
-                &mut no_package_dirs,
-                &mut no_package_paths,
-            )?;
-        }
+/// Toolchain clippy is run with.
+#[derive(Clone, Debug)]
+struct Toolchain {
+    /// rustup toolchain override, passed as `+{name}`
+    name: Option<String>,
+    nightly: bool,
+}
 
-        if let Some(removed) = removed {
-            let file_name = removed.display().to_string();
-            if file_name == "Cargo.toml" || file_name == "Cargo.lock" {
-                return workspace_run(verbose);
-            }
-            get_cargo_package_of_file(removed, &mut package_paths, &mut no_package_dirs, &mut no_package_paths)?;
+impl Toolchain {
+    /// Detects whether the `name` toolchain (or the workspace's toolchain if none) is a nightly one.
+    fn detect(name: Option<String>) -> Result<Self, Error> {
+        let mut command = Command::new("rustc");
+        if let Some(name) = name.as_ref() {
+            command.arg(format!("+{name}"));
         }
+        let version = read(command.arg("--version"))?;
+        let nightly = version.contains("-nightly") || version.contains("-dev");
+        debug!(
+            "detected {} toolchain: {version}",
+            if nightly { "nightly" } else { "stable" }
+        );
+        Ok(Self { name, nightly })
     }
 
-    if !no_package_paths.is_empty() {
-        let formatted_paths = no_package_paths
-            .into_iter()
-            .map(|x| x.display().to_string())
-            .collect::<Vec<_>>()
-            .join("\n - ");
-        return Err(Error::msg(format!(
-            "cannot run ops-clippy: rust files were found outside of a cargo package:\n - {formatted_paths}"
-        )));
+    /// `cargo clippy` command, applying fixes unless disabled, `packages` limits it to the listed packages.
+    fn clippy_command(&self, packages: &[&str], options: &ClippyOptions) -> Command {
+        let mut command = Command::new("cargo");
+        if let Some(name) = self.name.as_ref() {
+            command.arg(format!("+{name}"));
+        }
+        command.arg("clippy");
+        for package in packages {
+            command.args(["--package", package]);
+        }
+        if options.keep_going {
+            command.arg("--keep-going");
+        }
+        if options.fix {
+            command.args(["--fix", "--allow-dirty", "--allow-staged"]);
+            // only accepted by nightly cargo, `--fix` itself is stable
+            if self.nightly {
+                command.arg("-Zunstable-options");
+            }
+        }
+        match options.features.is_empty() {
+            true => command.arg("--all-features"),
+            false => command.args(["--features", &options.features.join(",")]),
+        };
+        command.args(&options.cargo_args).args(["--", "-D", "warnings"]);
+        if options.no_deps {
+            command.arg("--no-deps");
+        }
+        command.args(&options.lint_args);
+        command
     }
+}
 
-    let package_paths = package_paths.into_values().collect::<HashSet<_>>();
+lazy_static! {
+    static ref ANSI_ESCAPE_REGEX: Regex = Regex::new(r"\x1b\[[0-9;]*m").unwrap();
+    static ref DIAGNOSTIC_REGEX: Regex = Regex::new(
+        r"^(warning|error)(\[\w+\])?: (?!.* generated \d+ warnings?|could not compile|aborting due to|build failed)"
+    )
+    .unwrap();
+    /// cargo's summary of the warnings of a package's target, e.g. "warning: `api` (lib) generated 2 warnings"
+    static ref WARNINGS_SUMMARY_REGEX: Regex = Regex::new(r"^warning: `([^`]+)` \([^)]*\) generated (\d+) warnings?").unwrap();
+    /// cargo's summary of a package's target which failed to compile, e.g. "error: could not compile `api` (lib) due
+    /// to 2 previous errors; 1 warning emitted"
+    static ref ERRORS_SUMMARY_REGEX: Regex =
+        Regex::new(r"^error: could not compile `([^`]+)` \([^)]*\) due to (\d+) previous errors?(?:; (\d+) warnings? emitted)?")
+            .unwrap();
+}
 
-    let workspace_cargo = fs::read_to_string("Cargo.toml")?.parse::<Value>()?;
-    let workspace_dependencies = workspace_cargo
-        .get("workspace")
-        .ok_or_else(|| Error::msg("cannot parse workspace Cargo.toml: missing key `workspace`"))?
-        .get("dependencies")
-        .ok_or_else(|| Error::msg("cannot parse workspace Cargo.toml: missing key `workspace.dependencies`"))?
-        .as_table()
-        .ok_or_else(|| Error::msg("cannot parse workspace Cargo.toml: key `workspace.dependencies` must be a table"))?;
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct WorkspaceClippyOutput {
+    /// whether clippy was run across the whole workspace rather than per package
+    pub workspace_run: bool,
+    pub packages: Vec<ClippyPackageOutput>,
+}
 
-    let mut internal_crate_path_map = HashMap::<String, PathBuf>::default();
+#[derive(Clone, Debug, Serialize)]
+pub struct ClippyPackageOutput {
+    /// linted package, none for a workspace level run
+    pub package: Option<String>,
+    pub warnings: usize,
+    pub errors: usize,
+    #[serde(serialize_with = "serialize_duration")]
+    pub duration: Duration,
+}
 
-    let mut package_cargos = HashMap::<String, Value>::from_iter(
-        package_paths
+impl Report for WorkspaceClippyOutput {
+    fn human(&self) -> String {
+        if self.packages.is_empty() {
+            return "no packages to lint".to_string();
+        }
+        self.packages
             .iter()
-            .map(|package_path| {
-                let package_cargo = fs::read_to_string(package_path.join("Cargo.toml"))?.parse::<Value>()?;
-                let package_name = package_cargo
-                    .get("package")
-                    .ok_or_else(|| {
-                        Error::msg(format!(
-                            "cannot parse `{}/Cargo.toml`: missing key `package`",
-                            package_path.display()
-                        ))
-                    })?
-                    .get("name")
-                    .ok_or_else(|| {
-                        Error::msg(format!(
-                            "cannot parse `{}/Cargo.toml`: missing key `package.name`",
-                            package_path.display()
-                        ))
-                    })?
-                    .as_str()
-                    .ok_or_else(|| {
-                        Error::msg(format!(
-                            "cannot parse `{}/Cargo.toml`: key `package.name` must be a string",
-                            package_path.display()
-                        ))
-                    })?
-                    .to_string();
-                internal_crate_path_map.insert(package_name.clone(), package_path.to_path_buf());
-                Ok((package_name, package_cargo))
+            .map(|package| {
+                format!(
+                    "{}: {} warnings, {} errors ({})",
+                    package.package.as_deref().unwrap_or("workspace"),
+                    package.warnings,
+                    package.errors,
+                    format_duration(package.duration),
+                )
             })
-            .collect::<Result<Vec<_>, Error>>()?
-            .into_iter(),
-    );
-
-    let changed_package_names = package_cargos.keys().map(String::from).collect::<HashSet<_>>();
-
-    let mut external_crates = HashSet::<&str>::default();
-
-    for (package_name, spec) in workspace_dependencies {
-        match spec.get("path") {
-            Some(path) => {
-                let path = path.as_str().ok_or_else(|| {
-                    Error::msg(format!(
-                        "cannot parse workspace Cargo.toml: key `package.{package_name}.path` must be a string"
-                    ))
-                })?;
-                internal_crate_path_map.insert(package_name.clone(), Path::new(path).to_path_buf());
-            }
-            None => {
-                external_crates.insert(package_name);
-            }
-        };
+            .collect::<Vec<_>>()
+            .join("\n")
     }
+}
 
-    let mut top_level_changed_package_names = changed_package_names;
-
-    let mut queue = VecDeque::from_iter(package_cargos.keys().map(String::from));
-    let mut analyzed_package_names = HashSet::<String>::default();
-    while !queue.is_empty() {
-        let package_name = queue.pop_front().unwrap();
-        analyzed_package_names.insert(package_name.clone());
-
-        if !package_cargos.contains_key(&package_name) {
-            let package_path = internal_crate_path_map.get(&package_name).ok_or_else(|| {
-                Error::msg(format!(
-                    "an unexpected error occurred: unable to find path to crate {package_name}"
-                ))
-            })?;
-            let package_cargo = fs::read_to_string(package_path.join("Cargo.toml"))?.parse::<Value>()?;
-            package_cargos.insert(package_name.clone(), package_cargo);
-        }
-        let package_cargo = package_cargos.get(&package_name).unwrap();
-
-        let package_dependencies = match package_cargo.get("dependencies") {
-            Some(package_dependencies) => package_dependencies.as_table().ok_or_else(|| {
-                Error::msg(format!(
-                    "cannot parse {package_name} Cargo.toml: key `dependencies` must be a table"
-                ))
-            })?,
-            None => continue,
-        };
+pub fn workspace_clippy(worspace_clippy_args: WorkspaceClippyArgs) -> Result<WorkspaceClippyOutput, Error> {
+    let WorkspaceClippyArgs {
+        clippy_args,
+        log: _,
+        dry_run,
+        non_members,
+        toolchain,
+        with_dependents,
+        no_fix,
+        features,
+        no_deps,
+        jobs: jobs_arg,
+        batch,
+        diff_base,
+    } = worspace_clippy_args;
 
-        for (package_dependency_name, _) in package_dependencies {
-            top_level_changed_package_names.remove(package_dependency_name);
-            if !analyzed_package_names.contains(package_dependency_name)
-                && internal_crate_path_map.contains_key(package_dependency_name)
-            {
-                queue.push_back(package_dependency_name.clone());
-            }
-        }
+    let _span = info_span!("workspace_clippy").entered();
+
+    if dry_run {
+        enable_dry_run();
     }
 
-    if verbose {
-        if top_level_changed_package_names.is_empty() {
-            println!("{}", "no package changes found".dimmed());
-        } else {
-            println!(
-                "{}",
-                "found changes in these packages (and possibly in their internal dependencies):".dimmed()
-            );
-            for package_name in top_level_changed_package_names.iter() {
-                let package_path = internal_crate_path_map.get(&**package_name).ok_or_else(|| {
-                    Error::msg(format!(
-                        "an unexpected error occurred: unable to find package location with name `{package_name}`"
-                    ))
-                })?;
-                println!("{}", format!(" - {package_name} ({})", package_path.display()).dimmed());
-            }
-            println!();
+    let workspace_dir = get_workspace_root(&env::current_dir()?)?;
+    debug!("using workspace root {}", workspace_dir.display());
+    env::set_current_dir(&workspace_dir)?;
+
+    let toolchain = Toolchain::detect(toolchain)?;
+
+    let config = OpsConfig::load(&workspace_dir)?.clippy;
+    let mut clippy_args = clippy_args.splitn(2, |arg| arg == "--");
+    let options = ClippyOptions {
+        fix: !no_fix && !config.no_fix,
+        features: match features.is_empty() {
+            true => config.features,
+            false => features,
+        },
+        no_deps: no_deps || config.no_deps,
+        cargo_args: clippy_args.next().unwrap_or_default().to_vec(),
+        lint_args: config
+            .lint_args
+            .into_iter()
+            .chain(clippy_args.next().unwrap_or_default().iter().cloned())
+            .collect(),
+        keep_going: false,
+    };
+    // flags override environment variables, which override ops.toml, only the flags themselves conflict
+    let jobs_flag = jobs_arg.is_some() && env::var(CLIPPY_JOBS_ENV).ok().and_then(|jobs| jobs.parse().ok()) != jobs_arg;
+    let batch_flag = batch
+        && !env::var(BATCH_ENV)
+            .map(|value| !matches!(&*value.trim().to_lowercase(), "" | "0" | "false"))
+            .unwrap_or_default();
+    if jobs_flag && batch_flag {
+        return Err(Error::InvalidInput(
+            "--batch and --jobs cannot be used together".to_string(),
+        ));
+    }
+    let batch = !jobs_flag && (batch || (config.batch && jobs_arg.is_none()));
+
+    let metadata = Metadata::load(&workspace_dir)?;
+    let graph = WorkspaceGraph::from_metadata(&metadata)?;
+    let changed = changed_packages_of(&graph, &workspace_dir, &diff_base, non_members, with_dependents)?;
+    if changed
+        .iter()
+        .any(|package| package.reason == ChangeReason::WorkspaceManifest)
+    {
+        return workspace_run(&toolchain, &options);
+    }
+
+    let changed_package_names = changed.iter().map(|package| &*package.name).collect::<BTreeSet<_>>();
+
+    // packages which other changed packages (transitively) depend on are skipped, unless dependencies are not linted
+    let changed_dependencies = match options.no_deps {
+        true => BTreeSet::new(),
+        false => graph.transitive_dependencies_of(changed_package_names.iter().copied()),
+    };
+    let top_level_changed_package_names = changed_package_names
+        .into_iter()
+        .filter(|package_name| !changed_dependencies.contains(package_name))
+        .map(String::from)
+        .collect::<Vec<_>>();
+
+    if top_level_changed_package_names.is_empty() {
+        debug!("no package changes found");
+    } else {
+        debug!("found changes in these packages (and possibly in their internal dependencies):");
+        for package_name in top_level_changed_package_names.iter() {
+            debug!(" - {package_name} ({})", graph.packages()[package_name].path.display());
         }
     }
-    for package_name in top_level_changed_package_names {
-        let cmd = "cargo";
-        let mut args = vec![
-            "clippy",
-            "--package",
-            &package_name,
-            "--fix",
-            "--allow-dirty",
-            "--allow-staged",
-            "--all-features",
-            "-Zunstable-options",
-        ];
-        args.append(&mut clippy_args.iter().map(|x| &**x).collect());
-        args.append(&mut vec!["--", "-D", "warnings"]);
-        if verbose {
-            println!("{}", vec![cmd, &args.join(" ")].join(" ").dimmed());
+    let concurrency = match jobs_arg {
+        Some(jobs) => jobs.min(top_level_changed_package_names.len()).max(1),
+        None => 1,
+    };
+    let packages = match concurrency {
+        _ if top_level_changed_package_names.is_empty() => vec![],
+        _ if batch => {
+            let mut progress = Progress::new("clippy", 1);
+            progress.run(top_level_changed_package_names.join(", "), || {
+                lint_batch(&toolchain, &options, &top_level_changed_package_names)
+            })?
+        }
+        1 => {
+            let mut progress = Progress::new("clippy", top_level_changed_package_names.len());
+            let mut packages = vec![];
+            for package_name in &top_level_changed_package_names {
+                packages.push(progress.run(&**package_name, || {
+                    lint_package(&toolchain, &options, package_name, None)
+                })?);
+            }
+            packages
         }
-        let output = Command::new(cmd)
-            .args(args)
-            .stdout(Stdio::inherit())
-            .stderr(Stdio::inherit())
-            .output()?;
+        _ => lint_packages_concurrently(
+            &toolchain,
+            &options,
+            &top_level_changed_package_names,
+            concurrency,
+            &metadata.target_directory,
+        )?,
+    };
+
+    Ok(WorkspaceClippyOutput {
+        workspace_run: false,
+        packages,
+    })
+}
 
+/// Lints `package_names` with up to `concurrency` clippy runs at a time. Cargo locks its target directory for the
+/// whole build, so every run besides the first uses a target directory of its own below `target_dir`. No further
+/// packages are linted once one fails.
+fn lint_packages_concurrently(
+    toolchain: &Toolchain,
+    options: &ClippyOptions,
+    package_names: &[String],
+    concurrency: usize,
+    target_dir: &Path,
+) -> Result<Vec<ClippyPackageOutput>, Error> {
+    let len = package_names.len();
+    run_concurrently(
+        package_names.iter().collect(),
+        concurrency,
+        |worker, index, package_name| {
+            let target_dir = (worker > 0).then(|| target_dir.join(format!("clippy-{worker}")));
+            info!("[{}/{len}] {package_name}", index + 1);
+            with_output_prefix(package_name, || {
+                lint_package(toolchain, options, package_name, target_dir.as_deref())
+            })
+        },
+        Result::is_err,
+    )
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+/// Lints `package_names` with a single cargo invocation, attributing the diagnostics to the packages by cargo's
+/// summary lines: diagnostics of the changed packages' dependencies are not counted.
+fn lint_batch(
+    toolchain: &Toolchain,
+    options: &ClippyOptions,
+    package_names: &[String],
+) -> Result<Vec<ClippyPackageOutput>, Error> {
+    let options = ClippyOptions {
+        keep_going: true,
+        ..options.clone()
+    };
+    let mut command = toolchain.clippy_command(&package_names.iter().map(String::as_str).collect::<Vec<_>>(), &options);
+    debug!("{command:?}");
+    let start = Instant::now();
+    let mut counts = BTreeMap::<&str, (usize, usize)>::new();
+    if let Some(output) = output(command.stdout(Stdio::inherit()).stderr(Stdio::inherit()))? {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let stderr = ANSI_ESCAPE_REGEX.replace_all(&stderr, "");
+        let mut failed = vec![];
+        for line in stderr.lines() {
+            let count = |captures: &fancy_regex::Captures, group: usize| {
+                captures
+                    .get(group)
+                    .and_then(|count| count.as_str().parse::<usize>().ok())
+                    .unwrap_or_default()
+            };
+            if let Ok(Some(captures)) = WARNINGS_SUMMARY_REGEX.captures(line) {
+                if let Some(package_name) = package_names.iter().find(|name| **name == captures[1]) {
+                    counts.entry(package_name).or_default().0 += count(&captures, 2);
+                }
+            } else if let Ok(Some(captures)) = ERRORS_SUMMARY_REGEX.captures(line) {
+                if !failed.iter().any(|name| *name == captures[1]) {
+                    failed.push(captures[1].to_string());
+                }
+                if let Some(package_name) = package_names.iter().find(|name| **name == captures[1]) {
+                    let counts = counts.entry(package_name).or_default();
+                    counts.0 += count(&captures, 3);
+                    counts.1 += count(&captures, 2);
+                }
+            }
+        }
         if !output.status.success() {
-            return Err(Error::msg(""));
+            return Err(Error::ClippyFailed {
+                package: (!failed.is_empty()).then(|| failed.join(", ")),
+                status: output.status.code(),
+            });
         }
     }
-
-    Ok(())
+    let duration = start.elapsed();
+    Ok(package_names
+        .iter()
+        .map(|package_name| {
+            let (warnings, errors) = counts.get(&**package_name).copied().unwrap_or_default();
+            ClippyPackageOutput {
+                package: Some(package_name.clone()),
+                warnings,
+                errors,
+                duration,
+            }
+        })
+        .collect())
 }
 
-fn workspace_run(verbose: bool) -> Result<(), Error> {
-    if verbose {
-        println!(
-            "{}",
-            "found changes in workspace Cargo.toml, requires full clippy rerun".dimmed()
-        );
-    }
-    let cmd = "cargo";
-    let args = [
-        "clippy",
-        "--fix",
-        "--allow-dirty",
-        "--allow-staged",
-        "--all-features",
-        "-Zunstable-options",
-        "--",
-        "-D",
-        "warnings",
-    ];
-    if verbose {
-        println!("{}", vec![cmd, &args.join(" ")].join(" ").dimmed());
-    }
-    let output = Command::new(cmd)
-        .args(args)
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .output()?;
-
-    if !output.status.success() {
-        return Err(Error::msg(""));
+/// Lints a single package, `target_dir` overrides cargo's target directory.
+fn lint_package(
+    toolchain: &Toolchain,
+    options: &ClippyOptions,
+    package_name: &str,
+    target_dir: Option<&Path>,
+) -> Result<ClippyPackageOutput, Error> {
+    let mut command = toolchain.clippy_command(&[package_name], options);
+    if let Some(target_dir) = target_dir {
+        command.env("CARGO_TARGET_DIR", target_dir);
     }
-    Ok(())
+    debug!("{command:?}");
+    let start = Instant::now();
+    let (warnings, errors) = match output(command.stdout(Stdio::inherit()).stderr(Stdio::inherit()))? {
+        Some(output) if !output.status.success() => {
+            return Err(Error::ClippyFailed {
+                package: Some(package_name.to_string()),
+                status: output.status.code(),
+            })
+        }
+        Some(output) => count_diagnostics(&output.stderr),
+        None => (0, 0),
+    };
+    Ok(ClippyPackageOutput {
+        package: Some(package_name.to_string()),
+        warnings,
+        errors,
+        duration: start.elapsed(),
+    })
 }
 
-fn get_cargo_package_of_file(
-    path: &Path,
-    package_paths: &mut HashMap<PathBuf, PathBuf>,
-    no_package_dirs: &mut HashSet<PathBuf>,
-    no_package_paths: &mut HashSet<PathBuf>,
-) -> Result<(), Error> {
-    match (
-        &*path.display().to_string(),
-        path.extension().and_then(std::ffi::OsStr::to_str),
-    ) {
-        ("Cargo.toml", _) | (_, Some("rs")) => {}
-        _ => return Ok(()),
-    };
-    let mut cur_path = path;
-    let mut package_sub_dirs = vec![];
-    while let Some(parent) = cur_path.parent() {
-        package_sub_dirs.push(parent);
-        if no_package_dirs.contains(parent) {
-            break;
-        }
+fn workspace_run(toolchain: &Toolchain, options: &ClippyOptions) -> Result<WorkspaceClippyOutput, Error> {
+    let start = Instant::now();
+    debug!("found changes in workspace Cargo.toml, requires full clippy rerun");
+    let mut command = toolchain.clippy_command(&[], options);
+    debug!("{command:?}");
+    let output = output(command.stdout(Stdio::inherit()).stderr(Stdio::inherit()))?;
 
-        if let Some(package_path) = package_paths.get(parent).map(|package_path| package_path.to_path_buf()) {
-            for dir in package_sub_dirs {
-                package_paths.insert(dir.to_path_buf(), package_path.clone());
+    let (warnings, errors) = match output {
+        Some(output) => {
+            if !output.status.success() {
+                return Err(Error::ClippyFailed {
+                    package: None,
+                    status: output.status.code(),
+                });
             }
-            return Ok(());
+            count_diagnostics(&output.stderr)
         }
-        if parent.join("Cargo.toml").exists() {
-            for dir in package_sub_dirs {
-                package_paths.insert(dir.to_path_buf(), parent.to_path_buf());
+        None => (0, 0),
+    };
+    Ok(WorkspaceClippyOutput {
+        workspace_run: true,
+        packages: vec![ClippyPackageOutput {
+            package: None,
+            warnings,
+            errors,
+            duration: start.elapsed(),
+        }],
+    })
+}
+
+/// Counts the `(warnings, errors)` emitted by clippy, excluding cargo's trailing summary lines.
+fn count_diagnostics(stderr: &[u8]) -> (usize, usize) {
+    let stderr = String::from_utf8_lossy(stderr);
+    let stderr = ANSI_ESCAPE_REGEX.replace_all(&stderr, "");
+    let mut counts = (0, 0);
+    for line in stderr.lines() {
+        if let Ok(Some(captures)) = DIAGNOSTIC_REGEX.captures(line) {
+            match captures.get(1).map(|level| level.as_str()) {
+                Some("warning") => counts.0 += 1,
+                _ => counts.1 += 1,
             }
-            return Ok(());
         }
-
-        cur_path = parent;
     }
-
-    for dir in package_sub_dirs {
-        no_package_dirs.insert(dir.to_path_buf());
-    }
-    no_package_paths.insert(path.to_path_buf());
-
-    Ok(())
+    counts
 }
@@ -0,0 +1,283 @@
+/// Builds the rustdoc of the packages changed since the current branch diverged and of every member depending on
+/// them, so that docs stay current without rebuilding the whole workspace's. The generated pages can be checked for
+/// relative links to pages which do not exist, as `cargo deadlinks` does, and the merged docs of the target
+/// directory published to a directory or an S3 bucket for internal doc hosting.
+use crate::cargo::{Metadata, WorkspaceGraph};
+use crate::dry_run::{enable_dry_run, is_dry_run};
+use crate::error::Error;
+use crate::exec::{output, run_or_record, ExecOptions};
+use crate::git::diff_name_status_since_branched::DiffBaseArgs;
+use crate::logging::LogArgs;
+use crate::progress::format_duration;
+use crate::report::{serialize_duration, Report};
+use crate::workspace::changed::*;
+use crate::workspace::root::get_workspace_root;
+use clap::Parser;
+use fancy_regex::Regex;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+use std::{env, fs};
+use tracing::{debug, info, info_span};
+use walkdir::WalkDir;
+
+/// target kinds rustdoc generates a crate directory for
+const DOCUMENTED_TARGET_KINDS: [&str; 7] = ["lib", "rlib", "dylib", "cdylib", "staticlib", "proc-macro", "bin"];
+
+lazy_static! {
+    static ref LINK_REGEX: Regex = Regex::new(r#"\b(?:href|src)="([^"]*)""#).unwrap();
+}
+
+#[derive(Clone, Debug, Parser)]
+#[clap(author, version, about, long_about = None, trailing_var_arg=true)]
+pub struct WorkspaceDocArgs {
+    #[clap(flatten)]
+    pub log: LogArgs,
+
+    /// print the cargo doc and publishing commands instead of running them
+    /// - can also be enabled by setting the OPS_DRY_RUN environment variable
+    #[clap(long)]
+    pub dry_run: bool,
+
+    /// document every workspace member rather than only the changed ones
+    #[clap(long, env = "OPS_ALL")]
+    pub all: bool,
+
+    /// fail on broken intra-doc links and on relative links of the generated pages to pages which do not exist
+    #[clap(long, env = "OPS_CHECK_LINKS")]
+    pub check_links: bool,
+
+    /// directory or `s3://<bucket>/<prefix>` location the docs of the target directory are copied to once built,
+    /// existing docs of other packages are kept
+    #[clap(long, env = "OPS_DOC_PUBLISH")]
+    pub publish: Option<String>,
+
+    /// how to handle changed files in cargo packages which are not members of the workspace
+    #[clap(long, value_enum, default_value = "warn", env = "OPS_NON_MEMBERS")]
+    pub non_members: NonMemberPolicy,
+
+    #[clap(flatten)]
+    pub diff_base: DiffBaseArgs,
+
+    /// cargo doc args (e.g. `--all-features --document-private-items`)
+    #[clap(value_parser)]
+    pub doc_args: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct WorkspaceDocOutput {
+    pub packages: Vec<String>,
+    /// directory the docs were generated in
+    pub doc_dir: PathBuf,
+    /// number of relative links checked, none if links were not checked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checked_links: Option<usize>,
+    /// location the docs were published to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub published: Option<String>,
+    #[serde(serialize_with = "serialize_duration")]
+    pub duration: Duration,
+}
+
+impl Report for WorkspaceDocOutput {
+    fn human(&self) -> String {
+        if self.packages.is_empty() {
+            return "no packages to document".to_string();
+        }
+        let mut lines = vec![format!(
+            "documented {} in {} ({})",
+            self.packages.join(", "),
+            self.doc_dir.display(),
+            format_duration(self.duration),
+        )];
+        if let Some(checked_links) = self.checked_links {
+            lines.push(format!("checked {checked_links} links"));
+        }
+        if let Some(published) = self.published.as_ref() {
+            lines.push(format!("published to {published}"));
+        }
+        lines.join("\n")
+    }
+}
+
+pub fn workspace_doc(workspace_doc_args: WorkspaceDocArgs) -> Result<WorkspaceDocOutput, Error> {
+    let WorkspaceDocArgs {
+        log: _,
+        dry_run,
+        all,
+        check_links,
+        publish,
+        non_members,
+        diff_base,
+        doc_args,
+    } = workspace_doc_args;
+
+    let _span = info_span!("workspace_doc").entered();
+
+    if dry_run {
+        enable_dry_run();
+    }
+
+    let workspace_dir = get_workspace_root(&env::current_dir()?)?;
+    debug!("using workspace root {}", workspace_dir.display());
+    env::set_current_dir(&workspace_dir)?;
+
+    let metadata = Metadata::load(&workspace_dir)?;
+    let doc_dir = metadata.target_directory.join("doc");
+    let package_names = match all {
+        true => metadata
+            .members()
+            .map(|package| package.name.clone())
+            .collect::<Vec<_>>(),
+        false => {
+            let graph = WorkspaceGraph::from_metadata(&metadata)?;
+            changed_packages_of(&graph, &workspace_dir, &diff_base, non_members, true)?
+                .into_iter()
+                .map(|package| package.name)
+                .collect()
+        }
+    };
+    if package_names.is_empty() {
+        debug!("no package changes found");
+        return Ok(WorkspaceDocOutput {
+            doc_dir,
+            ..Default::default()
+        });
+    }
+
+    let start = Instant::now();
+    let mut command = Command::new("cargo");
+    command.args(["doc", "--no-deps"]);
+    for package_name in &package_names {
+        command.args(["--package", package_name]);
+    }
+    command.args(&doc_args);
+    if check_links {
+        let rustdoc_flags = env::var("RUSTDOCFLAGS").unwrap_or_default();
+        command.env(
+            "RUSTDOCFLAGS",
+            format!("{rustdoc_flags} -D rustdoc::broken_intra_doc_links").trim_start(),
+        );
+    }
+    debug!("{command:?}");
+    if let Some(output) = output(command.stdout(Stdio::inherit()).stderr(Stdio::inherit()))? {
+        if !output.status.success() {
+            return Err(Error::DocFailed {
+                status: output.status.code(),
+            });
+        }
+    }
+
+    // the docs were not generated in dry-run mode
+    let checked_links = match check_links && !is_dry_run() {
+        true => {
+            let crate_dirs = metadata
+                .members()
+                .filter(|package| package_names.contains(&package.name))
+                .flat_map(|package| package.targets.iter())
+                .filter(|target| {
+                    target
+                        .kind
+                        .iter()
+                        .any(|kind| DOCUMENTED_TARGET_KINDS.contains(&&**kind))
+                })
+                .map(|target| doc_dir.join(target.name.replace('-', "_")))
+                .collect::<BTreeSet<_>>();
+            let (checked_links, broken_links) = find_broken_links(&doc_dir, &crate_dirs)?;
+            if !broken_links.is_empty() {
+                return Err(Error::BrokenDocLinks { links: broken_links });
+            }
+            Some(checked_links)
+        }
+        false => None,
+    };
+
+    if let Some(publish) = publish.as_ref() {
+        info!("publishing {} to {publish}", doc_dir.display());
+        publish_docs(&doc_dir, publish)?;
+    }
+
+    Ok(WorkspaceDocOutput {
+        packages: package_names,
+        doc_dir,
+        checked_links,
+        published: publish,
+        duration: start.elapsed(),
+    })
+}
+
+/// Checks the relative links of the html pages in `crate_dirs`, returning the number of checked links and the
+/// broken ones as `<page> -> <link>` with the page relative to `doc_dir`.
+fn find_broken_links(doc_dir: &Path, crate_dirs: &BTreeSet<PathBuf>) -> Result<(usize, Vec<String>), Error> {
+    let mut checked_links = 0;
+    let mut broken_links = vec![];
+    for crate_dir in crate_dirs.iter().filter(|crate_dir| crate_dir.is_dir()) {
+        for entry in WalkDir::new(crate_dir) {
+            let entry = entry.map_err(|err| Error::msg(format!("unable to read generated docs: {err}")))?;
+            if entry.path().extension().is_none_or(|extension| extension != "html") {
+                continue;
+            }
+            let page = fs::read_to_string(entry.path())?;
+            let page_dir = entry.path().parent().unwrap_or(doc_dir);
+            for captures in LINK_REGEX.captures_iter(&page).flatten() {
+                let Some(link) = captures.get(1).map(|link| link.as_str()) else {
+                    continue;
+                };
+                // only the page part of relative links is checked, external links, anchors and the templates of
+                // links built by inline scripts are skipped
+                let target = link.split(['#', '?']).next().unwrap_or_default();
+                if target.is_empty() || target.starts_with('/') || target.contains(':') || target.contains("${") {
+                    continue;
+                }
+                checked_links += 1;
+                if !normalize(&page_dir.join(target)).exists() {
+                    let page = entry.path().strip_prefix(doc_dir).unwrap_or(entry.path());
+                    broken_links.push(format!("{} -> {link}", page.display()));
+                }
+            }
+        }
+    }
+    Ok((checked_links, broken_links))
+}
+
+/// `path` with `.` and `..` components resolved lexically.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// Copies the contents of `doc_dir` into the directory or `s3://` location `publish`, overwriting the pages of the
+/// documented packages while keeping the others.
+fn publish_docs(doc_dir: &Path, publish: &str) -> Result<(), Error> {
+    let command = match publish.starts_with("s3://") {
+        true => {
+            let mut command = Command::new("aws");
+            command
+                .args(["s3", "sync", "--only-show-errors"])
+                .arg(doc_dir)
+                .arg(publish);
+            command
+        }
+        false => {
+            if !is_dry_run() {
+                fs::create_dir_all(publish)?;
+            }
+            let mut command = Command::new("cp");
+            command.arg("-R").arg(doc_dir.join(".")).arg(publish);
+            command
+        }
+    };
+    run_or_record(&command, &ExecOptions::idempotent())?;
+    Ok(())
+}
@@ -0,0 +1,231 @@
+/// Analyzes the current git diff and only checks (or fixes) the formatting of the rust files it touches rather than
+/// of the whole workspace, reporting every file which is not formatted. Files are formatted with rustfmt directly
+/// using the edition of the package they belong to, so the workspace's rustfmt.toml applies as with `cargo fmt`.
+use crate::cargo::WorkspaceGraph;
+use crate::dry_run::enable_dry_run;
+use crate::error::Error;
+use crate::exec::output;
+use crate::git::diff_name_status_since_branched::*;
+use crate::logging::LogArgs;
+use crate::progress::{format_duration, Progress};
+use crate::report::{serialize_duration, Report};
+use crate::workspace::root::get_workspace_root;
+use clap::Parser;
+use fancy_regex::Regex;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+use std::{env, fs};
+use toml::Value;
+use tracing::{debug, info_span};
+
+/// edition rustfmt formats with when a package does not declare one, matching cargo's default
+const DEFAULT_EDITION: &str = "2015";
+
+#[derive(Clone, Debug, Parser)]
+#[clap(author, version, about, long_about = None)]
+pub struct WorkspaceFmtArgs {
+    #[clap(flatten)]
+    pub log: LogArgs,
+
+    /// print the rustfmt commands instead of running them
+    /// - can also be enabled by setting the OPS_DRY_RUN environment variable
+    #[clap(long)]
+    pub dry_run: bool,
+
+    /// format the changed files in place instead of only checking them
+    #[clap(long, env = "OPS_FIX")]
+    pub fix: bool,
+
+    #[clap(flatten)]
+    pub diff_base: DiffBaseArgs,
+}
+
+lazy_static! {
+    static ref ANSI_ESCAPE_REGEX: Regex = Regex::new(r"\x1b(\[[0-9;]*m|\(B)").unwrap();
+    /// `Diff in <file>:<line>:` as printed by current rustfmt versions, `Diff in <file> at line <line>:` by older ones
+    static ref DIFF_REGEX: Regex = Regex::new(r"^Diff in (.+?)(?::(\d+)| at line (\d+)):$").unwrap();
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct WorkspaceFmtOutput {
+    /// whether the files were formatted in place
+    pub fixed: bool,
+    /// changed rust files which were checked or formatted
+    pub checked: Vec<PathBuf>,
+    /// files which are not formatted, empty when fixing
+    pub violations: Vec<FmtViolation>,
+    #[serde(serialize_with = "serialize_duration")]
+    pub duration: Duration,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct FmtViolation {
+    /// path relative to the workspace root
+    pub file: PathBuf,
+    /// first line of every misformatted section
+    pub lines: Vec<usize>,
+}
+
+impl Report for WorkspaceFmtOutput {
+    fn human(&self) -> String {
+        if self.checked.is_empty() {
+            return "no rust files to format".to_string();
+        }
+        if self.fixed {
+            return format!(
+                "formatted {} files ({})",
+                self.checked.len(),
+                format_duration(self.duration)
+            );
+        }
+        let mut lines = vec![format!(
+            "checked {} files, {} not formatted ({})",
+            self.checked.len(),
+            self.violations.len(),
+            format_duration(self.duration)
+        )];
+        for violation in &self.violations {
+            lines.push(format!(
+                "  {}: line{} {}",
+                violation.file.display(),
+                if violation.lines.len() == 1 { "" } else { "s" },
+                violation
+                    .lines
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
+        lines.join("\n")
+    }
+}
+
+impl WorkspaceFmtOutput {
+    /// Converts the output into an error if any file is not formatted.
+    pub fn result(&self) -> Result<(), Error> {
+        match self.violations.is_empty() {
+            true => Ok(()),
+            false => Err(Error::FmtFailed {
+                files: self.violations.iter().map(|violation| violation.file.clone()).collect(),
+            }),
+        }
+    }
+}
+
+pub fn workspace_fmt(workspace_fmt_args: WorkspaceFmtArgs) -> Result<WorkspaceFmtOutput, Error> {
+    let WorkspaceFmtArgs {
+        log: _,
+        dry_run,
+        fix,
+        diff_base,
+    } = workspace_fmt_args;
+
+    let _span = info_span!("workspace_fmt").entered();
+
+    if dry_run {
+        enable_dry_run();
+    }
+
+    let start = Instant::now();
+    let workspace_dir = get_workspace_root(&env::current_dir()?)?;
+    debug!("using workspace root {}", workspace_dir.display());
+    env::set_current_dir(&workspace_dir)?;
+
+    let text = git_diff_name_status_since_last_branch(&diff_base)?;
+    let git_statuses = parse_git_statuses(&text)?;
+    let graph = WorkspaceGraph::load(&workspace_dir)?;
+
+    // changed files grouped by the innermost package containing them, as files are formatted with its edition
+    let mut package_files = BTreeMap::<&Path, Vec<PathBuf>>::default();
+    for file in git_statuses.iter().filter_map(GitStatus::new_file_name) {
+        let file = Path::new(file);
+        if file.extension().and_then(|extension| extension.to_str()) != Some("rs") || !file.exists() {
+            continue;
+        }
+        match graph.package_of_path(file) {
+            Some(package) => package_files.entry(&package.path).or_default().push(file.to_path_buf()),
+            None => debug!("skipping {}: not part of a workspace package", file.display()),
+        }
+    }
+
+    let mut progress = Progress::new("fmt", package_files.len());
+    let mut checked = vec![];
+    let mut violations = BTreeMap::<PathBuf, Vec<usize>>::default();
+    for (package_path, files) in package_files {
+        let edition = get_edition(&workspace_dir, package_path)?;
+        let mut command = Command::new("rustfmt");
+        command.args(["--edition", &edition]);
+        if !fix {
+            command.arg("--check");
+        }
+        command.args(&files);
+        debug!("{command:?}");
+        let output = progress.run(package_path.display().to_string(), || {
+            output(command.stdout(Stdio::inherit()).stderr(Stdio::inherit()))
+        })?;
+        checked.extend(files);
+
+        let Some(output) = output else {
+            continue;
+        };
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stdout = ANSI_ESCAPE_REGEX.replace_all(&stdout, "");
+        let mut found = false;
+        for line in stdout.lines() {
+            if let Ok(Some(captures)) = DIFF_REGEX.captures(line) {
+                let file = Path::new(&captures[1]);
+                let file = file.strip_prefix(&workspace_dir).unwrap_or(file).to_path_buf();
+                let line = captures
+                    .get(2)
+                    .or_else(|| captures.get(3))
+                    .and_then(|line| line.as_str().parse::<usize>().ok())
+                    .unwrap_or_default();
+                violations.entry(file).or_default().push(line);
+                found = true;
+            }
+        }
+        // rustfmt exits unsuccessfully both for misformatted files and for files it cannot parse
+        if !output.status.success() && !found {
+            return Err(Error::CommandFailed {
+                command: "rustfmt".to_string(),
+                status: output.status.code(),
+                stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            });
+        }
+    }
+
+    Ok(WorkspaceFmtOutput {
+        fixed: fix,
+        checked,
+        violations: violations
+            .into_iter()
+            .map(|(file, lines)| FmtViolation { file, lines })
+            .collect(),
+        duration: start.elapsed(),
+    })
+}
+
+/// Edition of the package at `package_path`, which may be inherited from `workspace.package.edition`.
+fn get_edition(workspace_dir: &Path, package_path: &Path) -> Result<String, Error> {
+    let manifest_path = workspace_dir.join(package_path).join("Cargo.toml");
+    let manifest = fs::read_to_string(&manifest_path)?.parse::<Value>()?;
+    let edition = manifest.get("package").and_then(|package| package.get("edition"));
+    let edition = match edition {
+        Some(edition) if edition.get("workspace").and_then(Value::as_bool) == Some(true) => {
+            let workspace_manifest = fs::read_to_string(workspace_dir.join("Cargo.toml"))?.parse::<Value>()?;
+            workspace_manifest
+                .get("workspace")
+                .and_then(|workspace| workspace.get("package"))
+                .and_then(|package| package.get("edition"))
+                .and_then(Value::as_str)
+                .map(String::from)
+        }
+        Some(edition) => edition.as_str().map(String::from),
+        None => None,
+    };
+    Ok(edition.unwrap_or_else(|| DEFAULT_EDITION.to_string()))
+}
@@ -0,0 +1,234 @@
+/// Runs an arbitrary command once for every package changed since the current branch diverged (see
+/// [`changed_packages`]), in the package's directory and with `{package}` and `{path}` replaced by the package's
+/// name and its path relative to the workspace root, e.g. `ops-workspace-run -- cargo doc --no-deps`.
+use crate::dry_run::enable_dry_run;
+use crate::error::Error;
+use crate::exec::{jobs, output, run_concurrently, set_jobs};
+use crate::git::diff_name_status_since_branched::DiffBaseArgs;
+use crate::logging::LogArgs;
+use crate::progress::format_duration;
+use crate::report::{serialize_duration, Report};
+use crate::workspace::changed::*;
+use crate::workspace::root::get_workspace_root;
+use clap::Parser;
+use colored::Colorize;
+use serde::Serialize;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, info_span};
+
+#[derive(Clone, Debug, Parser)]
+#[clap(author, version, about, long_about = None, trailing_var_arg=true)]
+pub struct WorkspaceRunArgs {
+    #[clap(flatten)]
+    pub log: LogArgs,
+
+    /// print the commands instead of running them
+    /// - can also be enabled by setting the OPS_DRY_RUN environment variable
+    #[clap(long)]
+    pub dry_run: bool,
+
+    /// stop starting the command for further packages once it failed for a package, it is otherwise run for every
+    /// package and all failures are reported at the end
+    #[clap(long, env = "OPS_FAIL_FAST")]
+    pub fail_fast: bool,
+
+    /// maximum number of packages to run the command for concurrently, defaults to the available parallelism
+    #[clap(short, long, env = "OPS_JOBS")]
+    pub jobs: Option<usize>,
+
+    /// how to handle changed files in cargo packages which are not members of the workspace
+    #[clap(long, value_enum, default_value = "warn", env = "OPS_NON_MEMBERS")]
+    pub non_members: NonMemberPolicy,
+
+    /// also run the command for every workspace member which transitively depends on a changed package
+    #[clap(long, env = "OPS_WITH_DEPENDENTS")]
+    pub with_dependents: bool,
+
+    #[clap(flatten)]
+    pub diff_base: DiffBaseArgs,
+
+    /// command to run for every changed package, `{package}` and `{path}` are replaced with the package's name and
+    /// path
+    #[clap(value_parser, required = true)]
+    pub command: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunStatus {
+    Succeeded,
+    Failed,
+    /// not run as the command already failed for another package and --fail-fast was passed
+    Skipped,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct WorkspaceRunOutput {
+    pub packages: Vec<RunPackageOutput>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RunPackageOutput {
+    pub package: String,
+    pub path: PathBuf,
+    pub reason: ChangeReason,
+    pub status: RunStatus,
+    /// exit code of the command if it failed
+    pub exit_code: Option<i32>,
+    #[serde(serialize_with = "serialize_duration")]
+    pub duration: Duration,
+}
+
+impl WorkspaceRunOutput {
+    /// Converts the output into an error if the command failed for any package.
+    pub fn result(&self) -> Result<(), Error> {
+        let failed = self
+            .packages
+            .iter()
+            .filter(|package| package.status == RunStatus::Failed)
+            .map(|package| package.package.clone())
+            .collect::<Vec<_>>();
+        match failed.is_empty() {
+            true => Ok(()),
+            false => Err(Error::RunFailed { packages: failed }),
+        }
+    }
+}
+
+impl Report for WorkspaceRunOutput {
+    fn human(&self) -> String {
+        if self.packages.is_empty() {
+            return "no changed packages".to_string();
+        }
+        let width = self
+            .packages
+            .iter()
+            .map(|package| package.package.len())
+            .max()
+            .unwrap_or_default();
+        let mut lines = vec!["run summary".bold().to_string()];
+        for package in &self.packages {
+            let status = match package.status {
+                RunStatus::Succeeded => "ok".green(),
+                RunStatus::Failed => "failed".red(),
+                RunStatus::Skipped => "skipped".dimmed(),
+            };
+            let detail = match (package.status, package.exit_code) {
+                (RunStatus::Skipped, _) => String::new(),
+                (_, Some(exit_code)) => format!("{} exit code {exit_code}", format_duration(package.duration)),
+                (_, None) => format_duration(package.duration),
+            };
+            let line = format!("  {:width$}  {status:7}  {detail}", package.package);
+            lines.push(line.trim_end().to_string());
+        }
+        lines.join("\n")
+    }
+}
+
+pub fn workspace_run(workspace_run_args: WorkspaceRunArgs) -> Result<WorkspaceRunOutput, Error> {
+    let WorkspaceRunArgs {
+        log: _,
+        dry_run,
+        fail_fast,
+        jobs: jobs_arg,
+        non_members,
+        with_dependents,
+        diff_base,
+        command,
+    } = workspace_run_args;
+
+    let _span = info_span!("workspace_run").entered();
+
+    if dry_run {
+        enable_dry_run();
+    }
+    if let Some(jobs) = jobs_arg {
+        set_jobs(jobs);
+    }
+
+    let workspace_dir = get_workspace_root(&env::current_dir()?)?;
+    debug!("using workspace root {}", workspace_dir.display());
+    env::set_current_dir(&workspace_dir)?;
+
+    let changed = changed_packages(&workspace_dir, &diff_base, non_members, with_dependents)?;
+    if changed.is_empty() {
+        debug!("no package changes found");
+        return Ok(WorkspaceRunOutput::default());
+    }
+
+    let len = changed.len();
+    let outputs = run_concurrently(
+        changed.iter().collect(),
+        jobs(),
+        |_, index, package| {
+            info!("[{}/{len}] {}", index + 1, package.name);
+            let output = run_for_package(&workspace_dir, package, &command);
+            if output.as_ref().is_ok_and(|output| output.status == RunStatus::Failed) {
+                error!("command failed for {}", package.name);
+            }
+            output
+        },
+        // without --fail-fast every package runs regardless of failures
+        |output| {
+            fail_fast
+                && output
+                    .as_ref()
+                    .map_or(true, |output| output.status == RunStatus::Failed)
+        },
+    );
+
+    Ok(WorkspaceRunOutput {
+        packages: outputs
+            .into_iter()
+            .zip(&changed)
+            .map(|(output, package)| output.unwrap_or_else(|| skipped(package)))
+            .collect::<Result<Vec<_>, _>>()?,
+    })
+}
+
+/// Runs `command` in the directory of `package`, a command exiting unsuccessfully is reported in the output rather
+/// than as an error.
+fn run_for_package(
+    workspace_dir: &Path,
+    package: &ChangedPackage,
+    command: &[String],
+) -> Result<RunPackageOutput, Error> {
+    let args = command
+        .iter()
+        .map(|arg| {
+            arg.replace("{package}", &package.name)
+                .replace("{path}", &package.path.display().to_string())
+        })
+        .collect::<Vec<_>>();
+    let mut command = Command::new(&args[0]);
+    command.args(&args[1..]).current_dir(workspace_dir.join(&package.path));
+    debug!("{command:?}");
+
+    let start = Instant::now();
+    let (status, exit_code) = match output(command.stdout(Stdio::inherit()).stderr(Stdio::inherit()))? {
+        Some(output) if !output.status.success() => (RunStatus::Failed, output.status.code()),
+        _ => (RunStatus::Succeeded, None),
+    };
+    Ok(RunPackageOutput {
+        package: package.name.clone(),
+        path: package.path.clone(),
+        reason: package.reason,
+        status,
+        exit_code,
+        duration: start.elapsed(),
+    })
+}
+
+fn skipped(package: &ChangedPackage) -> Result<RunPackageOutput, Error> {
+    Ok(RunPackageOutput {
+        package: package.name.clone(),
+        path: package.path.clone(),
+        reason: package.reason,
+        status: RunStatus::Skipped,
+        exit_code: None,
+        duration: Duration::ZERO,
+    })
+}
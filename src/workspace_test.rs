@@ -0,0 +1,235 @@
+/// Analyzes the current git diff and only runs the tests of the changed packages and of every package which
+/// transitively depends on them. Changes to the workspace level Cargo.toml or Cargo.lock are ignored unless
+/// `--all-on-manifest-change` is passed, in which case they trigger a full workspace level run of cargo test (to
+/// capture the case of breaking changes due to changed external dependencies).
+use crate::cargo::WorkspaceGraph;
+use crate::dry_run::enable_dry_run;
+use crate::error::Error;
+use crate::exec::output;
+use crate::git::diff_name_status_since_branched::*;
+use crate::logging::LogArgs;
+use crate::progress::{format_duration, Progress};
+use crate::report::{serialize_duration, Report};
+use crate::workspace::root::get_workspace_root;
+use clap::Parser;
+use fancy_regex::Regex;
+use serde::Serialize;
+use std::env;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+use tracing::{debug, info_span};
+
+#[derive(Clone, Debug, Parser)]
+#[clap(author, version, about, long_about = None, trailing_var_arg=true)]
+pub struct WorkspaceTestArgs {
+    #[clap(flatten)]
+    pub log: LogArgs,
+
+    /// print the test commands instead of running them
+    /// - can also be enabled by setting the OPS_DRY_RUN environment variable
+    #[clap(long)]
+    pub dry_run: bool,
+
+    /// run the tests of the whole workspace if the workspace level Cargo.toml or Cargo.lock changed, such changes
+    /// are otherwise ignored
+    #[clap(long, env = "OPS_ALL_ON_MANIFEST_CHANGE")]
+    pub all_on_manifest_change: bool,
+
+    #[clap(flatten)]
+    pub diff_base: DiffBaseArgs,
+
+    /// cargo test args (e.g. `-- --nocapture`)
+    #[clap(value_parser)]
+    pub test_args: Vec<String>,
+}
+
+lazy_static! {
+    static ref ANSI_ESCAPE_REGEX: Regex = Regex::new(r"\x1b\[[0-9;]*m").unwrap();
+    static ref TEST_RESULT_REGEX: Regex =
+        Regex::new(r"^test result: \w+\. (\d+) passed; (\d+) failed; (\d+) ignored").unwrap();
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct WorkspaceTestOutput {
+    /// whether the tests were run across the whole workspace rather than per package
+    pub workspace_run: bool,
+    pub packages: Vec<TestPackageOutput>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TestPackageOutput {
+    /// tested package, none for a workspace level run
+    pub package: Option<String>,
+    pub passed: usize,
+    pub failed: usize,
+    pub ignored: usize,
+    #[serde(serialize_with = "serialize_duration")]
+    pub duration: Duration,
+}
+
+impl Report for WorkspaceTestOutput {
+    fn human(&self) -> String {
+        if self.packages.is_empty() {
+            return "no packages to test".to_string();
+        }
+        self.packages
+            .iter()
+            .map(|package| {
+                format!(
+                    "{}: {} passed, {} failed, {} ignored ({})",
+                    package.package.as_deref().unwrap_or("workspace"),
+                    package.passed,
+                    package.failed,
+                    package.ignored,
+                    format_duration(package.duration),
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+pub fn workspace_test(workspace_test_args: WorkspaceTestArgs) -> Result<WorkspaceTestOutput, Error> {
+    let WorkspaceTestArgs {
+        log: _,
+        dry_run,
+        all_on_manifest_change,
+        diff_base,
+        test_args,
+    } = workspace_test_args;
+
+    let _span = info_span!("workspace_test").entered();
+
+    if dry_run {
+        enable_dry_run();
+    }
+
+    let workspace_dir = get_workspace_root(&env::current_dir()?)?;
+    debug!("using workspace root {}", workspace_dir.display());
+    env::set_current_dir(&workspace_dir)?;
+
+    let text = git_diff_name_status_since_last_branch(&diff_base)?;
+    let git_statuses = parse_git_statuses(&text)?;
+    let files = git_statuses
+        .iter()
+        .flat_map(|git_status| [git_status.old_file_name(), git_status.new_file_name()])
+        .flatten()
+        .collect::<Vec<_>>();
+
+    let manifest_changed = files.iter().any(|file| *file == "Cargo.toml" || *file == "Cargo.lock");
+    if manifest_changed {
+        if all_on_manifest_change {
+            debug!("found changes in workspace Cargo.toml or Cargo.lock, requires full test rerun");
+            return workspace_run(&test_args);
+        }
+        debug!("ignoring changes in workspace Cargo.toml or Cargo.lock, pass --all-on-manifest-change to test every package");
+    }
+
+    let graph = WorkspaceGraph::load(&workspace_dir)?;
+    let affected_package_names = graph
+        .affected_by(
+            files
+                .iter()
+                .copied()
+                .filter(|file| *file != "Cargo.toml" && *file != "Cargo.lock"),
+        )
+        .into_iter()
+        .map(String::from)
+        .collect::<Vec<_>>();
+
+    if affected_package_names.is_empty() {
+        debug!("no package changes found");
+    } else {
+        debug!("found changes in these packages (or in their internal dependencies):");
+        for package_name in affected_package_names.iter() {
+            debug!(" - {package_name} ({})", graph.packages()[package_name].path.display());
+        }
+    }
+
+    let mut progress = Progress::new("test", affected_package_names.len());
+    let mut outputs = vec![];
+    for package_name in affected_package_names {
+        let mut command = test_command(Some(&package_name), &test_args);
+        debug!("{command:?}");
+        let start = Instant::now();
+        let (passed, failed, ignored) =
+            progress.run(&*package_name, || run_tests(&mut command, Some(&package_name)))?;
+        outputs.push(TestPackageOutput {
+            package: Some(package_name),
+            passed,
+            failed,
+            ignored,
+            duration: start.elapsed(),
+        });
+    }
+
+    Ok(WorkspaceTestOutput {
+        workspace_run: false,
+        packages: outputs,
+    })
+}
+
+fn workspace_run(test_args: &[String]) -> Result<WorkspaceTestOutput, Error> {
+    let start = Instant::now();
+    let mut command = test_command(None, test_args);
+    debug!("{command:?}");
+    let (passed, failed, ignored) = run_tests(&mut command, None)?;
+    Ok(WorkspaceTestOutput {
+        workspace_run: true,
+        packages: vec![TestPackageOutput {
+            package: None,
+            passed,
+            failed,
+            ignored,
+            duration: start.elapsed(),
+        }],
+    })
+}
+
+/// `cargo test` command, `package` limits it to a single package.
+fn test_command(package: Option<&str>, test_args: &[String]) -> Command {
+    let mut command = Command::new("cargo");
+    command.arg("test");
+    match package {
+        Some(package) => command.args(["--package", package]),
+        None => command.arg("--workspace"),
+    };
+    command.args(test_args);
+    command
+}
+
+/// Runs the tests, returning the number of `(passed, failed, ignored)` tests.
+fn run_tests(command: &mut Command, package: Option<&str>) -> Result<(usize, usize, usize), Error> {
+    let output = match output(command.stdout(Stdio::inherit()).stderr(Stdio::inherit()))? {
+        Some(output) => output,
+        None => return Ok((0, 0, 0)),
+    };
+    if !output.status.success() {
+        return Err(Error::TestFailed {
+            package: package.map(String::from),
+            status: output.status.code(),
+        });
+    }
+    Ok(count_tests(&output.stdout))
+}
+
+/// Sums the `test result` lines cargo prints for every test binary and doc test run.
+fn count_tests(stdout: &[u8]) -> (usize, usize, usize) {
+    let stdout = String::from_utf8_lossy(stdout);
+    let stdout = ANSI_ESCAPE_REGEX.replace_all(&stdout, "");
+    let mut counts = (0, 0, 0);
+    for line in stdout.lines() {
+        if let Ok(Some(captures)) = TEST_RESULT_REGEX.captures(line) {
+            let count = |index: usize| {
+                captures
+                    .get(index)
+                    .and_then(|count| count.as_str().parse::<usize>().ok())
+                    .unwrap_or_default()
+            };
+            counts.0 += count(1);
+            counts.1 += count(2);
+            counts.2 += count(3);
+        }
+    }
+    counts
+}
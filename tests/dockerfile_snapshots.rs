@@ -0,0 +1,204 @@
+//! Golden file snapshots of the Dockerfiles generated for rust workspace services, so that changes to them show up
+//! as a diff of `tests/snapshots` in review. Run with OPS_UPDATE_SNAPSHOTS=1 to regenerate the snapshots.
+use ops::cargo::graph::WorkspacePackage;
+use ops::config::package::{HealthcheckConfig, PackageDockerConfig};
+use ops::docker::build_rust_workspace::FeatureSet;
+use ops::docker::dockerfile::{CacheMounts, PreBuildDockerfile, RuntimeBase, ServiceDockerfile};
+use ops::docker::platform::{get_platforms, Platform};
+use ops::snapshot::assert_snapshot;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+const CARGO_LOCK: &str = r#"version = 3
+
+[[package]]
+name = "api"
+version = "0.1.0"
+dependencies = [
+ "serde",
+ "shared",
+]
+
+[[package]]
+name = "serde"
+version = "1.0.200"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+checksum = "ddc6f9cc94d67c0e21aaf7eda3a010fd3af78ebf6e096aa6e2e13c79749cce4f"
+
+[[package]]
+name = "shared"
+version = "0.1.0"
+dependencies = [
+ "serde",
+]
+"#;
+
+/// source replacement printed by `cargo vendor`
+const VENDOR: &str = r#"[source.crates-io]
+replace-with = "vendored-sources"
+
+[source.vendored-sources]
+directory = "/tmp/ops-vendor"
+"#;
+
+fn snapshot_path(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("snapshots")
+        .join(name)
+}
+
+fn shared() -> WorkspacePackage {
+    WorkspacePackage {
+        name: "shared".to_string(),
+        path: PathBuf::from("crates/shared"),
+        dependencies: BTreeSet::new(),
+        binary: false,
+        member: true,
+        workspace_dependency: true,
+    }
+}
+
+fn pre_build<'a>(
+    feature_sets: &'a [FeatureSet<'a>],
+    local_crates: &'a [&'a WorkspacePackage],
+    platforms: &'a [Platform],
+) -> PreBuildDockerfile<'a> {
+    PreBuildDockerfile {
+        service_name: "api",
+        profile: "release",
+        feature_sets,
+        rust_version: None,
+        build_image: None,
+        rust_toolchain: false,
+        cargo_lock: CARGO_LOCK,
+        cargo_config: None,
+        vendor: None,
+        copy: &[],
+        pre_build_omit: &[],
+        platforms,
+        musl: false,
+        local_crates,
+        cache_mounts: None,
+        template: None,
+    }
+}
+
+fn service<'a>(
+    feature_sets: &'a [FeatureSet<'a>],
+    docker: &'a PackageDockerConfig,
+    platforms: &'a [Platform],
+) -> ServiceDockerfile<'a> {
+    ServiceDockerfile {
+        pre_build_image: "api:latest-release-pre-build",
+        service_name: "api",
+        service_dir: Path::new("services/api"),
+        profile: "release",
+        feature_sets,
+        bins: &[],
+        runtime_image: None,
+        entrypoint: None,
+        docker,
+        platforms,
+        cache_mounts: None,
+        template: None,
+    }
+}
+
+#[test]
+fn pre_build_dockerfile() {
+    let shared = shared();
+    let dockerfile = pre_build(&[FeatureSet::default()], &[&shared], &[]).render().unwrap();
+    assert_snapshot(snapshot_path("pre_build.Dockerfile"), &dockerfile);
+}
+
+#[test]
+fn pre_build_dockerfile_vendored_with_cache_mounts() {
+    let shared = shared();
+    let copy = ["COPY migrations migrations".to_string()];
+    let dockerfile = PreBuildDockerfile {
+        rust_version: Some("1.80"),
+        rust_toolchain: true,
+        vendor: Some(VENDOR),
+        copy: &copy,
+        cache_mounts: Some(CacheMounts::Sccache),
+        ..pre_build(&[FeatureSet::default()], &[&shared], &[])
+    }
+    .render()
+    .unwrap();
+    assert_snapshot(snapshot_path("pre_build_vendored_sccache.Dockerfile"), &dockerfile);
+}
+
+#[test]
+fn pre_build_dockerfile_multi_platform() {
+    let shared = shared();
+    let feature_sets = [FeatureSet::parse("name=api-metrics:metrics").unwrap()];
+    let platforms = get_platforms(&["linux/amd64".to_string(), "linux/arm64".to_string()]).unwrap();
+    let dockerfile = pre_build(&feature_sets, &[&shared], &platforms).render().unwrap();
+    assert_snapshot(snapshot_path("pre_build_multi_platform.Dockerfile"), &dockerfile);
+}
+
+#[test]
+fn pre_build_dockerfile_musl() {
+    let shared = shared();
+    let dockerfile = PreBuildDockerfile {
+        musl: RuntimeBase::Alpine.musl(),
+        ..pre_build(&[FeatureSet::default()], &[&shared], &[])
+    }
+    .render()
+    .unwrap();
+    assert_snapshot(snapshot_path("pre_build_musl.Dockerfile"), &dockerfile);
+}
+
+#[test]
+fn service_dockerfile() {
+    let docker = PackageDockerConfig::default();
+    let dockerfile = service(&[FeatureSet::default()], &docker, &[]).render().unwrap();
+    assert_snapshot(snapshot_path("service.Dockerfile"), &dockerfile);
+}
+
+#[test]
+fn service_dockerfile_with_runtime_settings() {
+    let docker = PackageDockerConfig {
+        ports: vec!["8080:80".to_string()],
+        healthcheck: Some(HealthcheckConfig {
+            command: vec!["/app/api".to_string(), "healthcheck".to_string()],
+            interval: Some("30s".to_string()),
+            ..Default::default()
+        }),
+        env: BTreeMap::from([("RUST_LOG".to_string(), "info".to_string())]),
+        user: Some("nobody".to_string()),
+    };
+    let feature_sets = [
+        FeatureSet::default(),
+        FeatureSet::parse("name=api-metrics:metrics").unwrap(),
+    ];
+    let dockerfile = ServiceDockerfile {
+        entrypoint: Some("api"),
+        cache_mounts: Some(CacheMounts::Registry),
+        ..service(&feature_sets, &docker, &[])
+    }
+    .render()
+    .unwrap();
+    assert_snapshot(snapshot_path("service_runtime_settings.Dockerfile"), &dockerfile);
+}
+
+#[test]
+fn service_dockerfile_multi_platform() {
+    let docker = PackageDockerConfig::default();
+    let platforms = get_platforms(&["linux/amd64".to_string(), "linux/arm64".to_string()]).unwrap();
+    let dockerfile = service(&[FeatureSet::default()], &docker, &platforms).render().unwrap();
+    assert_snapshot(snapshot_path("service_multi_platform.Dockerfile"), &dockerfile);
+}
+
+#[test]
+fn service_dockerfile_static() {
+    let docker = PackageDockerConfig::default();
+    let dockerfile = ServiceDockerfile {
+        runtime_image: Some("gcr.io/distroless/static"),
+        ..service(&[FeatureSet::default()], &docker, &[])
+    }
+    .render()
+    .unwrap();
+    assert_snapshot(snapshot_path("service_static.Dockerfile"), &dockerfile);
+}